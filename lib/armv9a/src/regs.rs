@@ -52,8 +52,12 @@ define_bits!(
     EC[31 - 26],
     // Instruction Length for synchronous exceptions.
     IL[25 - 25],
+    // Instruction Specific Syndrome.
+    ISS[24 - 00],
     // Instruction syndrome valid.
     ISV[24 - 24],
+    // Implementation Defined SError (EC == SError)
+    IDS[24 - 24],
     // Syndrome Access Size (ISV == '1')
     SAS[23 - 22],
     // Syndrome Sign Extend (ISV == '1')
@@ -68,6 +72,8 @@ define_bits!(
     VNCR[13 - 13],
     // Synchronous Error Type
     SET[12 - 11],
+    // Asynchronous Error Type (EC == SError)
+    AET[12 - 10],
     // FAR not Valid
     FNV[10 - 10],
     // External Abort type
@@ -77,7 +83,9 @@ define_bits!(
     S1PTW[7 - 7],
     // Write not Read.
     WNR[6 - 6],
-    DFSC[5 - 0]
+    DFSC[5 - 0],
+    // Trapped WFx instruction type (EC == WFx)
+    TI[1 - 0]
 );
 
 impl EsrEl2 {
@@ -93,6 +101,8 @@ impl EsrEl2 {
 }
 
 pub const ESR_EL1_EC_UNKNOWN: u64 = 0;
+pub const ESR_EL1_EC_DATA_ABORT_LOWER_EL: u64 = 36;
+pub const ESR_EL1_EC_DATA_ABORT_SAME_EL: u64 = 37;
 pub const ESR_EL2_EC_UNKNOWN: u64 = 0;
 pub const ESR_EL2_EC_WFX: u64 = 1;
 pub const ESR_EL2_EC_FPU: u64 = 7;
@@ -109,6 +119,10 @@ pub const NON_EMULATABLE_ABORT_MASK: u64 =
     EsrEl2::EC | EsrEl2::SET | EsrEl2::FNV | EsrEl2::EA | EsrEl2::DFSC;
 pub const EMULATABLE_ABORT_MASK: u64 =
     NON_EMULATABLE_ABORT_MASK | EsrEl2::ISV | EsrEl2::SAS | EsrEl2::SF | EsrEl2::WNR;
+pub const WFX_EXIT_MASK: u64 = EsrEl2::EC | EsrEl2::IL | EsrEl2::TI;
+pub const SYSREG_EXIT_MASK: u64 = EsrEl2::EC | EsrEl2::IL | EsrEl2::ISS;
+pub const SERROR_EXIT_MASK: u64 =
+    EsrEl2::EC | EsrEl2::IDS | EsrEl2::AET | EsrEl2::EA | EsrEl2::DFSC;
 
 define_register!(SP);
 define_sys_register!(SP_EL0);
@@ -125,7 +139,49 @@ define_sys_register!(
 );
 
 define_sys_register!(SPSR_EL1);
+
+define_sys_register!(
+    PMCR_EL0,   // ref. D19.4.7
+    N[15 - 11], // Number of event counters
+    C[2 - 2],   // Cycle counter reset
+    P[1 - 1],   // Event counter reset
+    E[0 - 0]    // Enable
+);
+define_sys_register!(PMCNTENSET_EL0);
+define_sys_register!(PMCNTENCLR_EL0);
+define_sys_register!(PMOVSSET_EL0);
+define_sys_register!(PMOVSCLR_EL0);
+define_sys_register!(PMINTENSET_EL1);
+define_sys_register!(PMINTENCLR_EL1);
+define_sys_register!(PMCCNTR_EL0);
+define_sys_register!(PMCCFILTR_EL0);
+define_sys_register!(PMSELR_EL0);
+define_sys_register!(PMXEVCNTR_EL0);
+define_sys_register!(PMXEVTYPER_EL0);
+define_sys_register!(PMUSERENR_EL0);
+
+define_sys_register!(
+    MDCR_EL2,     // ref. D19.2.83
+    HPME[7 - 7],  // Enables the counters reserved for EL2
+    TPM[6 - 6],   // Traps the PMU registers
+    TPMCR[5 - 5], // Traps PMCR_EL0
+    HPMN[4 - 0]   // Number of counters accessible from EL1 and EL0
+);
+define_sys_register!(
+    MDSCR_EL1,    // ref. D19.2.84
+    MDE[15 - 15], // Monitor debug events
+    KDE[13 - 13], // Local (kernel) debug enable
+    SS[0 - 0]     // Software step control
+);
 define_sys_register!(ELR_EL1);
+define_sys_register!(FAR_EL1);
+
+define_sys_register!(
+    PAR_EL1,     // ref. D17.2.113
+    PA[47 - 12], // Output address
+    NS[9 - 9],   // Non-secure
+    F[0 - 0]     // Translation aborted
+);
 define_sys_register!(ELR_EL2);
 define_sys_register!(TPIDR_EL2);
 
@@ -367,7 +423,18 @@ define_sys_register!(
     OFFSET[11 - 0]
 );
 
-define_sys_register!(CPTR_EL2, TAM[30 - 30]);
+define_sys_register!(
+    CPTR_EL2, // Ref. D19.2.31
+    TAM[30 - 30],
+    TFP[10 - 10],
+    TZ[8 - 8]
+);
+
+// ZCR_EL2: S3_4_C1_C2_0, Ref. D19.2.174
+define_sys_register!(S3_4_C1_C2_0, LEN[3 - 0]);
+
+// ZCR_EL1: S3_0_C1_C2_0, Ref. D19.2.173
+define_sys_register!(S3_0_C1_C2_0, LEN[3 - 0]);
 
 // GIC-related
 define_sys_register!(
@@ -450,11 +517,17 @@ define_bits!(
 );
 
 define_sys_register!(ID_AA64PFR0_EL1);
-define_bits!(AA64PFR0, AMU[47 - 44], SVE[35 - 32]);
+define_bits!(
+    AA64PFR0,
+    AMU[47 - 44],
+    MPAM[43 - 40],
+    SVE[35 - 32],
+    RAS[31 - 28]
+);
 define_iss_id!(ISS_ID_AA64PFR0_EL1, 3, 0, 0, 4, 0);
 
 define_sys_register!(ID_AA64PFR1_EL1);
-define_bits!(AA64PFR1, MTE[11 - 8]);
+define_bits!(AA64PFR1, MPAM_frac[19 - 16], RAS_frac[15 - 12], MTE[11 - 8]);
 define_iss_id!(ISS_ID_AA64PFR1_EL1, 3, 0, 0, 4, 1);
 
 // TODO: current compiler doesn't understand this sysreg
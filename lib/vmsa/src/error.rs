@@ -11,6 +11,11 @@ pub enum Error {
     MmRefcountError,
     MmWrongParentChild,
     MmSubtableError,
+    /// The access isn't allowed by the permissions of the entry
+    MmPermissionFault,
+    /// The walk of the tables ends at the level short of the one asked for,
+    /// or the entries at the level aren't in the state the operation needs
+    MmRttLevel(usize),
     MmErrorOthers,
 }
 
@@ -28,6 +33,8 @@ impl From<Error> for usize {
             Error::MmRefcountError => 17,
             Error::MmWrongParentChild => 18,
             Error::MmSubtableError => 19,
+            Error::MmPermissionFault => 20,
+            Error::MmRttLevel(_) => 21,
             Error::MmErrorOthers => 99,
         }
     }
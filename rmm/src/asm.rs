@@ -53,3 +53,28 @@ pub fn dcache_flush(addr: usize, len: usize) {
         }
     }
 }
+
+/// Translates `va` through both stages of the EL1&0 regime of the running realm
+/// as if it were read at EL1, returning the resulting PAR_EL1.
+/// PAR_EL1 of the realm is left untouched.
+#[inline(always)]
+pub fn at_s12e1r(va: u64) -> u64 {
+    use armv9a::regs::PAR_EL1;
+
+    unsafe {
+        let saved = PAR_EL1.get();
+        asm!("at s12e1r, {}", "isb", in(reg) va);
+        let par = PAR_EL1.get();
+        PAR_EL1.set(saved);
+        par
+    }
+}
+
+#[inline(always)]
+pub fn icache_invalidate_all() {
+    unsafe {
+        asm!("ic iallu");
+        asm!("dsb ish");
+        asm!("isb");
+    }
+}
@@ -0,0 +1,86 @@
+use super::{get_cpu_id, try_this_cpu, LastExit};
+
+use core::fmt;
+
+/// What the CPU was doing when RMM panicked.
+/// Formatting it doesn't allocate, so it can be logged even when
+/// the panic comes from an allocation failure.
+pub struct PanicDump {
+    cpu: usize,
+    last_exit: Option<LastExit>,
+}
+
+impl PanicDump {
+    pub fn new(cpu: usize, last_exit: Option<LastExit>) -> Self {
+        Self { cpu, last_exit }
+    }
+
+    /// Collects the state of the current CPU. The PerCpu is skipped
+    /// if it is held, as the panic may have happened while holding it.
+    pub fn collect() -> Self {
+        let cpu = get_cpu_id();
+        let last_exit = try_this_cpu().and_then(|this| this.last_exit());
+        Self::new(cpu, last_exit)
+    }
+}
+
+impl fmt::Display for PanicDump {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "cpu {}", self.cpu)?;
+        let exit = match self.last_exit {
+            Some(exit) => exit,
+            None => return write!(f, ", no realm exit"),
+        };
+        match exit.realm_id {
+            Some(id) => write!(f, ", realm {}", id)?,
+            None => write!(f, ", realm -")?,
+        }
+        match exit.vcpuid {
+            Some(vcpuid) => write!(f, ", REC {}", vcpuid)?,
+            None => write!(f, ", REC -")?,
+        }
+        write!(
+            f,
+            ", last exit {:?} ESR {:#x} ELR {:#x}",
+            exit.kind, exit.esr, exit.elr
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::exception::trap::Kind;
+
+    use alloc::format;
+
+    extern crate alloc;
+
+    #[test]
+    fn panic_dump_fields() {
+        let exit = LastExit {
+            realm_id: Some(7),
+            vcpuid: Some(1),
+            kind: Kind::Synchronous,
+            esr: 0x5e00_0000,
+            elr: 0x4000_0ff8,
+        };
+        assert_eq!(
+            format!("{}", PanicDump::new(2, Some(exit))),
+            "cpu 2, realm 7, REC 1, last exit Synchronous ESR 0x5e000000 ELR 0x40000ff8"
+        );
+        let exit = LastExit {
+            realm_id: None,
+            vcpuid: None,
+            ..exit
+        };
+        assert_eq!(
+            format!("{}", PanicDump::new(2, Some(exit))),
+            "cpu 2, realm -, REC -, last exit Synchronous ESR 0x5e000000 ELR 0x40000ff8"
+        );
+        assert_eq!(
+            format!("{}", PanicDump::new(0, None)),
+            "cpu 0, no realm exit"
+        );
+    }
+}
@@ -0,0 +1,151 @@
+use crate::realm::feature::IdRegs;
+
+use armv9a::regs::*;
+use spin::Once;
+
+/// Features of the PEs, parsed from the ID registers once on boot
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CpuFeatures {
+    sve: bool,
+    ras: bool,
+    pmu: Option<u8>,
+    lpa2: bool,
+    pa_bits: usize,
+    brps: usize,
+    wrps: usize,
+    csv2: u8,
+    ssbs: bool,
+}
+
+const PMUVER_IMP_DEF: u64 = 0xf;
+const TGRAN4_52_BIT: u64 = 0b0001;
+// ID_AA64PFR0_EL1.CSV2 and ID_AA64PFR1_EL1.SSBS
+const CSV2_SHIFT: u64 = 56;
+const SSBS_SHIFT: u64 = 4;
+
+impl CpuFeatures {
+    pub fn parse(raw: &IdRegs) -> Self {
+        let pfr0 = AA64PFR0(raw.pfr0);
+        let dfr0 = AA64DFR0(raw.dfr0);
+        let mmfr0 = raw.mmfr0;
+
+        let pmu = match dfr0.get_masked_value(AA64DFR0::PMUVer) {
+            0 | PMUVER_IMP_DEF => None,
+            ver => Some(ver as u8),
+        };
+        let pa_bits = match mmfr0 & ID_AA64MMFR0_EL1::PARange {
+            0b0000 => 32,
+            0b0001 => 36,
+            0b0010 => 40,
+            0b0011 => 42,
+            0b0100 => 44,
+            0b0101 => 48,
+            _ => 52,
+        };
+
+        Self {
+            sve: pfr0.get_masked_value(AA64PFR0::SVE) != 0,
+            ras: pfr0.get_masked_value(AA64PFR0::RAS) != 0,
+            pmu,
+            lpa2: (mmfr0 & ID_AA64MMFR0_EL1::TGran4) >> ID_AA64MMFR0_EL1::TGran4.trailing_zeros()
+                == TGRAN4_52_BIT,
+            pa_bits,
+            brps: dfr0.get_masked_value(AA64DFR0::BRPs) as usize + 1,
+            wrps: dfr0.get_masked_value(AA64DFR0::WRPs) as usize + 1,
+            csv2: ((raw.pfr0 >> CSV2_SHIFT) & 0xf) as u8,
+            ssbs: (raw.pfr1 >> SSBS_SHIFT) & 0xf != 0,
+        }
+    }
+
+    pub fn sve(&self) -> bool {
+        self.sve
+    }
+
+    pub fn ras(&self) -> bool {
+        self.ras
+    }
+
+    /// PMU version (ID_AA64DFR0_EL1.PMUVer) if an architected PMU is implemented
+    pub fn pmu(&self) -> Option<u8> {
+        self.pmu
+    }
+
+    /// 52-bit addresses with the 4KB granule
+    pub fn lpa2(&self) -> bool {
+        self.lpa2
+    }
+
+    pub fn pa_bits(&self) -> usize {
+        self.pa_bits
+    }
+
+    /// Number of breakpoints implemented
+    pub fn brps(&self) -> usize {
+        self.brps
+    }
+
+    /// Number of watchpoints implemented
+    pub fn wrps(&self) -> usize {
+        self.wrps
+    }
+
+    /// Level of the protection against Spectre-v2 (1) and Spectre-BHB (3)
+    pub fn csv2(&self) -> u8 {
+        self.csv2
+    }
+
+    /// Speculative Store Bypass Safe, which the realm controls with PSTATE.SSBS
+    pub fn ssbs(&self) -> bool {
+        self.ssbs
+    }
+}
+
+static CPU_FEATURES: Once<CpuFeatures> = Once::new();
+
+/// Features of the PEs, which are read from the hardware on the first call
+pub fn cpu_features() -> &'static CpuFeatures {
+    CPU_FEATURES.call_once(|| CpuFeatures::parse(&IdRegs::read()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_id_regs() {
+        // SVE, RAS, PMUv3 for Armv8.1, 48-bit PA
+        let raw = IdRegs {
+            pfr0: 0x1101_0001_1011_1112,
+            dfr0: 0x0000_0000_1030_5408,
+            mmfr0: 0x0000_0000_0010_1125,
+            ..Default::default()
+        };
+        let features = CpuFeatures::parse(&raw);
+        assert!(features.sve());
+        assert!(features.ras());
+        assert_eq!(features.pmu(), Some(4));
+        assert!(!features.lpa2());
+        assert_eq!(features.pa_bits(), 48);
+        assert_eq!((features.brps(), features.wrps()), (6, 4));
+        assert_eq!(features.csv2(), 1);
+        assert!(!features.ssbs());
+
+        // nothing optional but SSBS, LPA2 with 52-bit PA, IMPLEMENTATION DEFINED PMU
+        let raw = IdRegs {
+            pfr0: 0x0000_0000_0000_0011,
+            pfr1: 0x0000_0000_0000_0020,
+            dfr0: 0x0000_0000_0000_0f06,
+            mmfr0: 0x0000_0000_1000_0006,
+            ..Default::default()
+        };
+        let features = CpuFeatures::parse(&raw);
+        assert!(!features.sve());
+        assert!(!features.ras());
+        assert_eq!(features.pmu(), None);
+        assert!(features.lpa2());
+        assert_eq!(features.pa_bits(), 52);
+        assert_eq!((features.brps(), features.wrps()), (1, 1));
+        assert_eq!(features.csv2(), 0);
+        assert!(features.ssbs());
+    }
+}
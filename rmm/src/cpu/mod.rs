@@ -0,0 +1,267 @@
+pub mod dump;
+pub mod features;
+
+use crate::config::{NUM_OF_CPU, NUM_OF_CPU_PER_CLUSTER};
+use crate::exception::trap::Kind;
+use crate::realm::fpu::LazyFp;
+use crate::realm::pmu::PmuState;
+
+use armv9a::regs::*;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spinning_top::{Spinlock, SpinlockGuard};
+
+#[no_mangle]
+pub extern "C" fn get_cpu_id() -> usize {
+    let (cluster, core) = id();
+    cluster * NUM_OF_CPU_PER_CLUSTER + core
+}
+
+#[inline(always)]
+pub fn id() -> (usize, usize) {
+    unsafe {
+        (
+            MPIDR_EL1.get_masked_value(MPIDR_EL1::AFF2) as usize,
+            MPIDR_EL1.get_masked_value(MPIDR_EL1::AFF1) as usize,
+        )
+    }
+}
+
+/// The last exception taken from a realm on the CPU.
+/// It holds copies rather than the REC, which may be destroyed since.
+#[derive(Clone, Copy, Debug)]
+pub struct LastExit {
+    /// Realm id and VCPU id of the REC which was running
+    pub realm_id: Option<usize>,
+    pub vcpuid: Option<usize>,
+    pub kind: Kind,
+    pub esr: u32,
+    pub elr: u64,
+}
+
+/// State of RMM kept for each CPU
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PerCpu {
+    /// PA of the REC running on the CPU
+    rec: Option<usize>,
+    /// Realm id and VCPU id of the running REC
+    running: Option<(usize, usize)>,
+    /// VCPU whose context is loaded on the CPU,
+    /// which is also held in TPIDR_EL2 for the exception vectors
+    vcpu: Option<usize>,
+    /// Number of realm entries and exits on the CPU
+    entries: u64,
+    exits: u64,
+    lazy_fp: LazyFp,
+    /// PMU registers of the host while a realm with the PMU is running
+    host_pmu: PmuState,
+    /// MDSCR_EL1 of the RMM while a realm is running
+    host_mdscr: u64,
+    last_exit: Option<LastExit>,
+}
+
+impl PerCpu {
+    const fn new() -> Self {
+        Self {
+            rec: None,
+            running: None,
+            vcpu: None,
+            entries: 0,
+            exits: 0,
+            lazy_fp: LazyFp::new(),
+            host_pmu: PmuState::new(0),
+            host_mdscr: 0,
+            last_exit: None,
+        }
+    }
+
+    pub fn rec(&self) -> Option<usize> {
+        self.rec
+    }
+
+    pub fn vcpu(&self) -> Option<usize> {
+        self.vcpu
+    }
+
+    pub fn entries(&self) -> u64 {
+        self.entries
+    }
+
+    pub fn exits(&self) -> u64 {
+        self.exits
+    }
+
+    pub fn enter(&mut self, rec: usize, realm_id: usize, vcpuid: usize) {
+        self.rec = Some(rec);
+        self.running = Some((realm_id, vcpuid));
+        self.entries += 1;
+    }
+
+    pub fn exit(&mut self) {
+        self.rec = None;
+        self.running = None;
+        self.exits += 1;
+    }
+
+    pub fn set_vcpu(&mut self, vcpu: Option<usize>) {
+        self.vcpu = vcpu;
+    }
+
+    pub fn record_exit(&mut self, kind: Kind, esr: u32, elr: u64) {
+        self.last_exit = Some(LastExit {
+            realm_id: self.running.map(|(realm_id, _)| realm_id),
+            vcpuid: self.running.map(|(_, vcpuid)| vcpuid),
+            kind,
+            esr,
+            elr,
+        });
+    }
+
+    pub fn last_exit(&self) -> Option<LastExit> {
+        self.last_exit
+    }
+
+    pub fn lazy_fp_mut(&mut self) -> &mut LazyFp {
+        &mut self.lazy_fp
+    }
+
+    pub fn host_pmu_mut(&mut self) -> &mut PmuState {
+        &mut self.host_pmu
+    }
+
+    pub fn host_mdscr_mut(&mut self) -> &mut u64 {
+        &mut self.host_mdscr
+    }
+}
+
+/// PerCpu of each CPU indexed by `get_cpu_id()`
+struct PerCpuTable<const N: usize>([Spinlock<PerCpu>; N]);
+
+impl<const N: usize> PerCpuTable<N> {
+    // only used to initialize each element of the array
+    #[allow(clippy::declare_interior_mutable_const)]
+    const INIT: Spinlock<PerCpu> = Spinlock::new(PerCpu::new());
+
+    const fn new() -> Self {
+        Self([Self::INIT; N])
+    }
+
+    fn get(&self, cpu: usize) -> SpinlockGuard<'_, PerCpu> {
+        self.0[cpu].lock()
+    }
+
+    fn try_get(&self, cpu: usize) -> Option<SpinlockGuard<'_, PerCpu>> {
+        self.0.get(cpu)?.try_lock()
+    }
+}
+
+static PER_CPU: PerCpuTable<NUM_OF_CPU> = PerCpuTable::new();
+
+/// PerCpu of the current CPU.
+/// Do not hold it while running a realm, as the exception handlers take it.
+pub fn this_cpu() -> SpinlockGuard<'static, PerCpu> {
+    PER_CPU.get(get_cpu_id())
+}
+
+/// PerCpu of the current CPU unless it's already taken,
+/// for the paths which must not wait, e.g., the panic handler.
+pub fn try_this_cpu() -> Option<SpinlockGuard<'static, PerCpu>> {
+    PER_CPU.try_get(get_cpu_id())
+}
+
+/// Flags set when RMM faults while servicing the realm running on a CPU.
+/// They are kept out of PerCpu, as the fault may be taken with it locked.
+struct RealmFaults<const N: usize>([AtomicBool; N]);
+
+impl<const N: usize> RealmFaults<N> {
+    // only used to initialize each element of the array
+    #[allow(clippy::declare_interior_mutable_const)]
+    const INIT: AtomicBool = AtomicBool::new(false);
+
+    const fn new() -> Self {
+        Self([Self::INIT; N])
+    }
+
+    fn set(&self, cpu: usize) {
+        self.0[cpu].store(true, Ordering::Relaxed);
+    }
+
+    fn get(&self, cpu: usize) -> bool {
+        self.0[cpu].load(Ordering::Relaxed)
+    }
+
+    fn take(&self, cpu: usize) -> bool {
+        self.0[cpu].swap(false, Ordering::Relaxed)
+    }
+}
+
+static REALM_FAULTS: RealmFaults<NUM_OF_CPU> = RealmFaults::new();
+
+pub fn set_realm_fault() {
+    REALM_FAULTS.set(get_cpu_id());
+}
+
+pub fn realm_fault() -> bool {
+    REALM_FAULTS.get(get_cpu_id())
+}
+
+/// Returns whether the last realm entry on the CPU has faulted and clears it
+pub fn take_realm_fault() -> bool {
+    REALM_FAULTS.take(get_cpu_id())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn per_cpu_isolation() {
+        let table = PerCpuTable::<2>::new();
+
+        // a guard is dropped before the next one is taken,
+        // the same CPU can't be locked twice
+        let mut cpu0 = table.get(0);
+        cpu0.enter(0x8800_0000, 3, 0);
+        assert_eq!(cpu0.rec(), Some(0x8800_0000));
+        drop(cpu0);
+        let mut cpu1 = table.get(1);
+        assert_eq!(cpu1.rec(), None);
+
+        cpu1.enter(0x8800_1000, 4, 1);
+        cpu1.set_vcpu(Some(0x1234));
+        drop(cpu1);
+        let mut cpu0 = table.get(0);
+        cpu0.exit();
+        assert_eq!(cpu0.rec(), None);
+        assert_eq!(cpu0.vcpu(), None);
+        assert_eq!((cpu0.entries(), cpu0.exits()), (1, 1));
+        drop(cpu0);
+        let cpu1 = table.get(1);
+        assert_eq!(cpu1.rec(), Some(0x8800_1000));
+        assert_eq!(cpu1.vcpu(), Some(0x1234));
+        assert_eq!((cpu1.entries(), cpu1.exits()), (1, 0));
+        drop(cpu1);
+
+        let faults = RealmFaults::<2>::new();
+        faults.set(1);
+        assert!(!faults.take(0));
+        assert!(faults.get(1));
+        assert!(faults.take(1));
+        assert!(!faults.take(1));
+
+        let mut cpu1 = table.get(1);
+        cpu1.record_exit(Kind::Irq, 0, 0x8000);
+        let exit = cpu1.last_exit().unwrap();
+        drop(cpu1);
+        assert_eq!(
+            (exit.realm_id, exit.vcpuid, exit.elr),
+            (Some(4), Some(1), 0x8000)
+        );
+        let cpu0 = table.get(0);
+        assert!(cpu0.last_exit().is_none());
+
+        assert!(table.try_get(0).is_none());
+        assert!(table.try_get(2).is_none());
+        drop(cpu0);
+        assert!(table.try_get(0).is_some());
+    }
+}
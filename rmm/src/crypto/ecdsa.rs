@@ -0,0 +1,100 @@
+use super::Error;
+
+use alloc::vec::Vec;
+use ecdsa::signature::hazmat::{PrehashSigner, PrehashVerifier};
+use p384::ecdsa::{Signature as P384Signature, SigningKey, VerifyingKey};
+
+pub const PRIVATE_KEY_SIZE: usize = 48;
+/// r || s of the signature
+pub const SIGNATURE_SIZE: usize = 96;
+
+/// P-384 private key
+#[derive(Clone)]
+pub struct PrivateKey(SigningKey);
+
+impl PrivateKey {
+    pub fn from_bytes(key: &[u8]) -> Result<Self, Error> {
+        if key.len() != PRIVATE_KEY_SIZE {
+            return Err(Error::InvalidKey);
+        }
+        SigningKey::from_slice(key)
+            .map(Self)
+            .map_err(|_| Error::InvalidKey)
+    }
+}
+
+pub struct Signature([u8; SIGNATURE_SIZE]);
+
+impl Signature {
+    pub fn as_slice(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// Signs the digest of a message, which must be at least half the size of the key.
+pub fn sign(key: &PrivateKey, digest: &[u8]) -> Result<Signature, Error> {
+    let signature: P384Signature = key
+        .0
+        .sign_prehash(digest)
+        .map_err(|_| Error::SigningFailed)?;
+
+    let mut out = [0u8; SIGNATURE_SIZE];
+    out.copy_from_slice(&signature.to_bytes());
+    Ok(Signature(out))
+}
+
+/// Public key of `key` as an uncompressed SEC1 point
+pub fn public_key(key: &PrivateKey) -> Vec<u8> {
+    key.0
+        .verifying_key()
+        .to_encoded_point(false)
+        .as_bytes()
+        .to_vec()
+}
+
+pub fn verify(public_key: &[u8], digest: &[u8], signature: &Signature) -> Result<(), Error> {
+    let key = VerifyingKey::from_sec1_bytes(public_key).map_err(|_| Error::InvalidKey)?;
+    let signature =
+        P384Signature::from_slice(signature.as_slice()).map_err(|_| Error::InvalidSignature)?;
+    key.verify_prehash(digest, &signature)
+        .map_err(|_| Error::InvalidSignature)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const DIGEST: [u8; 48] = [0xa5; 48];
+
+    #[test]
+    fn sign_and_verify() {
+        let key = PrivateKey::from_bytes(&[0x11; PRIVATE_KEY_SIZE]).unwrap();
+        let public = public_key(&key);
+        assert_eq!(public.len(), 1 + PRIVATE_KEY_SIZE * 2);
+        assert_eq!(public[0], 0x04);
+
+        let signature = sign(&key, &DIGEST).unwrap();
+        assert_eq!(verify(&public, &DIGEST, &signature), Ok(()));
+
+        let mut other = DIGEST;
+        other[0] ^= 1;
+        assert_eq!(
+            verify(&public, &other, &signature),
+            Err(Error::InvalidSignature)
+        );
+
+        let other_key = PrivateKey::from_bytes(&[0x22; PRIVATE_KEY_SIZE]).unwrap();
+        assert_eq!(
+            verify(&public_key(&other_key), &DIGEST, &signature),
+            Err(Error::InvalidSignature)
+        );
+    }
+
+    #[test]
+    fn invalid_keys() {
+        assert!(PrivateKey::from_bytes(&[0x11; 32]).is_err());
+        // zero isn't a valid scalar
+        assert!(PrivateKey::from_bytes(&[0; PRIVATE_KEY_SIZE]).is_err());
+        assert!(verify(&[0; 97], &DIGEST, &Signature([1; SIGNATURE_SIZE])).is_err());
+    }
+}
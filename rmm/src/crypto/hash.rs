@@ -0,0 +1,148 @@
+use super::Error;
+use crate::rmi::{HASH_ALGO_SHA256, HASH_ALGO_SHA512};
+
+use alloc::boxed::Box;
+use sha2::digest::DynDigest;
+use sha2::{Digest, Sha256, Sha512};
+
+/// Incremental hash function
+pub trait Hasher {
+    fn update(&mut self, data: &[u8]);
+    /// Writes the digest into the beginning of `out` and resets the state.
+    fn finalize(&mut self, out: &mut [u8]) -> Result<(), Error>;
+    fn output_len(&self) -> usize;
+}
+
+impl<D: DynDigest> Hasher for D {
+    fn update(&mut self, data: &[u8]) {
+        DynDigest::update(self, data);
+    }
+
+    fn finalize(&mut self, out: &mut [u8]) -> Result<(), Error> {
+        let len = DynDigest::output_size(self);
+        let out = out.get_mut(0..len).ok_or(Error::OutputBufferTooSmall)?;
+        self.finalize_into_reset(out)
+            .map_err(|_| Error::OutputBufferTooSmall)
+    }
+
+    fn output_len(&self) -> usize {
+        DynDigest::output_size(self)
+    }
+}
+
+/// Hash algorithms which can be configured for a realm
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HashAlgo {
+    Sha256,
+    Sha512,
+}
+
+impl HashAlgo {
+    pub fn hasher(self) -> Box<dyn Hasher> {
+        match self {
+            HashAlgo::Sha256 => Box::new(Sha256::new()),
+            HashAlgo::Sha512 => Box::new(Sha512::new()),
+        }
+    }
+
+    pub fn output_len(self) -> usize {
+        match self {
+            HashAlgo::Sha256 => <Sha256 as Digest>::output_size(),
+            HashAlgo::Sha512 => <Sha512 as Digest>::output_size(),
+        }
+    }
+}
+
+impl TryFrom<u8> for HashAlgo {
+    type Error = u8;
+
+    fn try_from(hash_algo: u8) -> Result<Self, Self::Error> {
+        match hash_algo {
+            HASH_ALGO_SHA256 => Ok(HashAlgo::Sha256),
+            HASH_ALGO_SHA512 => Ok(HashAlgo::Sha512),
+            _ => Err(hash_algo),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn digest(algo: HashAlgo, data: &[u8]) -> [u8; 64] {
+        let mut out = [0u8; 64];
+        let mut hasher = algo.hasher();
+        hasher.update(data);
+        hasher.finalize(&mut out).unwrap();
+        out
+    }
+
+    // FIPS 180-2, Appendix B.1 and B.2
+    #[test]
+    fn sha256_kat() {
+        let out = digest(HashAlgo::Sha256, b"abc");
+        assert_eq!(
+            out[0..32],
+            [
+                0xba, 0x78, 0x16, 0xbf, 0x8f, 0x01, 0xcf, 0xea, 0x41, 0x41, 0x40, 0xde, 0x5d, 0xae,
+                0x22, 0x23, 0xb0, 0x03, 0x61, 0xa3, 0x96, 0x17, 0x7a, 0x9c, 0xb4, 0x10, 0xff, 0x61,
+                0xf2, 0x00, 0x15, 0xad
+            ]
+        );
+        assert_eq!(out[32..], [0; 32]);
+
+        let out = digest(
+            HashAlgo::Sha256,
+            b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq",
+        );
+        assert_eq!(
+            out[0..32],
+            [
+                0x24, 0x8d, 0x6a, 0x61, 0xd2, 0x06, 0x38, 0xb8, 0xe5, 0xc0, 0x26, 0x93, 0x0c, 0x3e,
+                0x60, 0x39, 0xa3, 0x3c, 0xe4, 0x59, 0x64, 0xff, 0x21, 0x67, 0xf6, 0xec, 0xed, 0xd4,
+                0x19, 0xdb, 0x06, 0xc1
+            ]
+        );
+    }
+
+    // FIPS 180-2, Appendix C.1
+    #[test]
+    fn sha512_kat() {
+        let out = digest(HashAlgo::Sha512, b"abc");
+        assert_eq!(
+            out,
+            [
+                0xdd, 0xaf, 0x35, 0xa1, 0x93, 0x61, 0x7a, 0xba, 0xcc, 0x41, 0x73, 0x49, 0xae, 0x20,
+                0x41, 0x31, 0x12, 0xe6, 0xfa, 0x4e, 0x89, 0xa9, 0x7e, 0xa2, 0x0a, 0x9e, 0xee, 0xe6,
+                0x4b, 0x55, 0xd3, 0x9a, 0x21, 0x92, 0x99, 0x2a, 0x27, 0x4f, 0xc1, 0xa8, 0x36, 0xba,
+                0x3c, 0x23, 0xa3, 0xfe, 0xeb, 0xbd, 0x45, 0x4d, 0x44, 0x23, 0x64, 0x3c, 0xe8, 0x0e,
+                0x2a, 0x9a, 0xc9, 0x4f, 0xa5, 0x4c, 0xa4, 0x9f
+            ]
+        );
+    }
+
+    #[test]
+    fn hasher_reset_and_sizes() {
+        let mut hasher = HashAlgo::Sha256.hasher();
+        assert_eq!(hasher.output_len(), 32);
+        assert_eq!(HashAlgo::Sha512.output_len(), 64);
+
+        let mut short = [0u8; 16];
+        assert_eq!(
+            hasher.finalize(&mut short),
+            Err(Error::OutputBufferTooSmall)
+        );
+
+        // the state is reset by finalize
+        let mut out = [0u8; 64];
+        hasher.update(b"abc");
+        hasher.finalize(&mut out).unwrap();
+        hasher.update(b"abc");
+        let mut again = [0u8; 64];
+        hasher.finalize(&mut again).unwrap();
+        assert_eq!(out, again);
+
+        assert_eq!(HashAlgo::try_from(HASH_ALGO_SHA512), Ok(HashAlgo::Sha512));
+        assert_eq!(HashAlgo::try_from(2), Err(2));
+    }
+}
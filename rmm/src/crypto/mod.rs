@@ -0,0 +1,10 @@
+pub mod ecdsa;
+pub mod hash;
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    OutputBufferTooSmall,
+    InvalidKey,
+    InvalidSignature,
+    SigningFailed,
+}
@@ -3,26 +3,22 @@ extern crate alloc;
 use super::Context;
 use crate::asm::smc;
 use crate::rmi;
-use crate::rmi::error::Error;
+use crate::rmi::dispatch::{Dispatcher, Handler};
 use crate::Monitor;
 
-use alloc::boxed::Box;
-use alloc::collections::btree_map::BTreeMap;
 use alloc::collections::vec_deque::VecDeque;
 use spin::mutex::Mutex;
 
-pub type Handler = Box<dyn Fn(&[usize], &mut [usize], &Monitor) -> Result<(), Error>>;
-
 pub struct Mainloop {
     pub queue: Mutex<VecDeque<Context>>, // TODO: we need a more realistic queue considering multi-core environments if needed
-    pub on_event: BTreeMap<usize, Handler>,
+    pub dispatcher: Dispatcher,
 }
 
 impl Mainloop {
     pub fn new() -> Self {
         Self {
             queue: Mutex::new(VecDeque::new()),
-            on_event: BTreeMap::new(),
+            dispatcher: Dispatcher::new(),
         }
     }
 
@@ -32,6 +28,8 @@ impl Mainloop {
         rmi::realm::set_event_handler(self);
         rmi::rec::set_event_handler(self);
         rmi::rtt::set_event_handler(self);
+        #[cfg(debug_assertions)]
+        rmi::selftest::set_event_handler(self);
         rmi::version::set_event_handler(self);
     }
 
@@ -56,7 +54,8 @@ impl Mainloop {
                 self.queue.lock().push_back(ctx);
             },
             || {
-                let ctx = Context::new(rmi::NOT_SUPPORTED_YET);
+                let mut ctx = Context::new(rmi::NOT_SUPPORTED_YET);
+                ctx.resize_ret(1);
                 self.queue.lock().push_back(ctx);
             },
         );
@@ -65,19 +64,12 @@ impl Mainloop {
     pub fn run(&self, monitor: &Monitor) {
         loop {
             let mut ctx = self.queue.lock().pop_front().unwrap(); // TODO: remove unwrap here, by introducing a more realistic queue
-            if self.on_event.is_empty() {
+            if self.dispatcher.is_empty() {
                 panic!("There is no registered event handler.");
             }
 
-            match self.on_event.get(&ctx.cmd) {
-                Some(handler) => {
-                    ctx.do_rmi(|arg, ret| handler(arg, ret, monitor));
-                }
-                None => {
-                    error!("Not registered event: {:X}", ctx.cmd);
-                    ctx.init_arg(&[rmi::RET_FAIL]);
-                }
-            };
+            let cmd = ctx.cmd();
+            ctx.do_rmi(|arg, ret| self.dispatcher.dispatch(cmd, arg, ret, monitor));
 
             ctx.cmd = rmi::REQ_COMPLETE;
             self.dispatch(ctx);
@@ -85,6 +77,6 @@ impl Mainloop {
     }
 
     pub fn add_event_handler(&mut self, code: usize, handler: Handler) {
-        self.on_event.insert(code, handler);
+        self.dispatcher.register(code, handler);
     }
 }
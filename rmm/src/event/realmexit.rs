@@ -7,7 +7,7 @@ const EXIT_SYNC_TYPE_MASK: usize = 15 << EXIT_SYNC_TYPE_SHIFT; // 0b1111_0000
 ///
 /// It can't cover all of RmiRecExitReason types.
 /// Because the following types of RmiRecExitReason
-/// are set to ExitReason using RSI:
+/// are set to ExitReason using RSI or while handling a data abort:
 /// - RMI_EXIT_RIPAS_CHANGE
 /// - RMI_EXIT_HOST_CALL
 #[derive(Debug)]
@@ -18,6 +18,12 @@ pub enum RecExitReason {
     FIQ = 2,
     PSCI = 3,
     SError = 4,
+    /// IRQ taken while a maintenance interrupt of the virtual GIC is asserted
+    Maintenance = 5,
+    /// IRQ taken while the virtual timer of the realm is asserted
+    VirtualTimer = 6,
+    /// IRQ taken while the physical timer of the realm is asserted
+    PhysicalTimer = 7,
     Undefined = REC_EXIT_REASON_MASK, // fixed, 0b1111
 }
 
@@ -29,7 +35,10 @@ impl Into<u64> for RecExitReason {
             RecExitReason::FIQ => 2,
             RecExitReason::PSCI => 3,
             RecExitReason::SError => 4,
-            RecExitReason::Undefined => 7,
+            RecExitReason::Maintenance => 5,
+            RecExitReason::VirtualTimer => 6,
+            RecExitReason::PhysicalTimer => 7,
+            RecExitReason::Undefined => REC_EXIT_REASON_MASK as u64,
         }
     }
 }
@@ -42,6 +51,9 @@ impl From<usize> for RecExitReason {
             2 => RecExitReason::FIQ,
             3 => RecExitReason::PSCI,
             4 => RecExitReason::SError,
+            5 => RecExitReason::Maintenance,
+            6 => RecExitReason::VirtualTimer,
+            7 => RecExitReason::PhysicalTimer,
             _ => RecExitReason::Undefined,
         }
     }
@@ -55,6 +67,10 @@ pub enum ExitSyncType {
     RSI = 1 << EXIT_SYNC_TYPE_SHIFT,
     DataAbort = 2 << EXIT_SYNC_TYPE_SHIFT,
     InstAbort = 3 << EXIT_SYNC_TYPE_SHIFT,
+    WFx = 4 << EXIT_SYNC_TYPE_SHIFT,
+    SysReg = 5 << EXIT_SYNC_TYPE_SHIFT,
+    /// Debug exception taken from the realm, including BRK
+    Debug = 6 << EXIT_SYNC_TYPE_SHIFT,
     Undefined = EXIT_SYNC_TYPE_MASK, // fixed, 0b1111_0000
 }
 
@@ -71,6 +87,9 @@ impl From<usize> for ExitSyncType {
             1 => ExitSyncType::RSI,
             2 => ExitSyncType::DataAbort,
             3 => ExitSyncType::InstAbort,
+            4 => ExitSyncType::WFx,
+            5 => ExitSyncType::SysReg,
+            6 => ExitSyncType::Debug,
             _ => ExitSyncType::Undefined,
         }
     }
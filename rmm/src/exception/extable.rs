@@ -0,0 +1,79 @@
+//! Fixups for the accesses of RMM which may fault while servicing a realm.
+//!
+//! Each access which may fault is written in assembly and registers the range
+//! of its instructions along with a landing pad, at which the exception handler
+//! resumes after a fault within the range. Faults anywhere else are fatal,
+//! as the code taking them can't go on.
+
+use vmsa::error::Error as MmError;
+
+core::arch::global_asm!(include_str!("extable.s"));
+
+extern "C" {
+    fn copy_faultable(dst: *mut u8, src: *const u8, len: usize) -> usize;
+    static copy_faultable_begin: u8;
+    static copy_faultable_end: u8;
+    static copy_faultable_fixup: u8;
+}
+
+/// Instructions in `begin..end` which may fault, and where to resume on a fault
+struct Entry {
+    begin: u64,
+    end: u64,
+    fixup: u64,
+}
+
+fn entries() -> [Entry; 1] {
+    let addr = |label: *const u8| label as u64;
+    // Safety: only the addresses of the labels are taken
+    unsafe {
+        [Entry {
+            begin: addr(core::ptr::addr_of!(copy_faultable_begin)),
+            end: addr(core::ptr::addr_of!(copy_faultable_end)),
+            fixup: addr(core::ptr::addr_of!(copy_faultable_fixup)),
+        }]
+    }
+}
+
+/// Returns the landing pad for a fault at `elr`, or `None` if the access
+/// at `elr` hasn't registered one.
+pub fn fixup(elr: u64) -> Option<u64> {
+    entries()
+        .iter()
+        .find(|entry| (entry.begin..entry.end).contains(&elr))
+        .map(|entry| entry.fixup)
+}
+
+/// Copies `src` to `dst`, which fails instead of faulting RMM
+/// if either of them can't be accessed.
+///
+/// # Safety
+/// `dst` must be valid for writes of `src.len()` bytes unless the write faults.
+pub unsafe fn copy(dst: *mut u8, src: &[u8]) -> Result<(), MmError> {
+    match copy_faultable(dst, src.as_ptr(), src.len()) {
+        0 => Ok(()),
+        _ => Err(MmError::MmErrorOthers),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn landing_pads() {
+        let [entry] = entries();
+        assert!(entry.begin < entry.end && entry.end < entry.fixup);
+        assert_eq!(fixup(entry.begin), Some(entry.fixup));
+        assert_eq!(fixup(entry.end - 4), Some(entry.fixup));
+        // the access returned from isn't covered
+        assert_eq!(fixup(entry.end), None);
+        assert_eq!(fixup(0), None);
+
+        let src: [u8; 32] = core::array::from_fn(|i| i as u8);
+        let mut dst = [0u8; 32];
+        assert_eq!(unsafe { copy(dst.as_mut_ptr(), &src) }, Ok(()));
+        assert_eq!(dst, src);
+        assert_eq!(unsafe { copy(dst.as_mut_ptr(), &[]) }, Ok(()));
+    }
+}
@@ -0,0 +1,25 @@
+/*
+ * Accesses of RMM to the memory of a realm or of the host, which may fault
+ * when the memory changes hands under RMM. A fault taken between the
+ * _begin and _end labels of an access resumes at its _fixup label.
+ */
+.section .text
+
+/* x0: dst, x1: src, x2: len. Returns 0 once copied, or 1 on a fault */
+.global copy_faultable
+.global copy_faultable_begin
+.global copy_faultable_end
+.global copy_faultable_fixup
+copy_faultable:
+	cbz x2, 2f
+copy_faultable_begin:
+1:	ldrb w3, [x1], #1
+	strb w3, [x0], #1
+	subs x2, x2, #1
+	b.ne 1b
+copy_faultable_end:
+2:	mov x0, #0
+	ret
+copy_faultable_fixup:
+	mov x0, #1
+	ret
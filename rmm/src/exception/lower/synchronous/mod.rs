@@ -1 +1,2 @@
+pub mod rsi;
 pub mod sys_reg;
@@ -0,0 +1,81 @@
+use crate::cpu::features::cpu_features;
+use crate::exception::trap;
+use crate::realm::context::Context;
+use crate::rmi::call::{RmiArgs, RmiResult};
+use crate::rsi;
+use crate::rsi::psci;
+
+/// Answers the RSI commands and the SMCs which need neither RMM nor the host
+/// (e.g., RSI_VERSION, SMCCC_VERSION) in place.
+/// The other commands are forwarded to RMM (RET_TO_RMM).
+pub fn handle(context: &mut Context) -> u64 {
+    let handler: fn(&RmiArgs<'_>) -> RmiResult = match context.gp_regs[0] as usize {
+        rsi::ABI_VERSION => version,
+        psci::SMCCC_VERSION => smccc_version,
+        psci::PSCI_VERSION => psci_version,
+        psci::SMCCC_ARCH_FEATURES => arch_features,
+        fid if psci::is_arch_call(fid) => arch_not_supported,
+        _ => return trap::RET_TO_RMM,
+    };
+    handler(&RmiArgs::new(&context.gp_regs)).write(&mut context.gp_regs);
+    trap::RET_TO_REC
+}
+
+fn version(_args: &RmiArgs<'_>) -> RmiResult {
+    RmiResult::new(rsi::VERSION)
+}
+
+fn smccc_version(_args: &RmiArgs<'_>) -> RmiResult {
+    RmiResult::new(psci::smccc_version())
+}
+
+fn psci_version(_args: &RmiArgs<'_>) -> RmiResult {
+    RmiResult::new(psci::psci_version())
+}
+
+fn arch_features(args: &RmiArgs<'_>) -> RmiResult {
+    RmiResult::new(psci::arch_features(args.get(0), cpu_features()))
+}
+
+/// The workarounds and the unknown Arm Architecture Calls
+fn arch_not_supported(_args: &RmiArgs<'_>) -> RmiResult {
+    RmiResult::new(psci::PsciReturn::NOT_SUPPORTED)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn standard_service_versions() {
+        let mut context = Context::default();
+
+        context.gp_regs[0] = psci::SMCCC_VERSION as u64;
+        assert_eq!(handle(&mut context), trap::RET_TO_REC);
+        assert_eq!(context.gp_regs[0], 0x1_0002);
+
+        context.gp_regs[0] = psci::PSCI_VERSION as u64;
+        assert_eq!(handle(&mut context), trap::RET_TO_REC);
+        assert_eq!(context.gp_regs[0], 0x1_0001);
+
+        // the other standard service calls still go to RMM
+        context.gp_regs[0] = psci::SMC32::CPU_ON as u64;
+        assert_eq!(handle(&mut context), trap::RET_TO_RMM);
+        assert_eq!(context.gp_regs[0], psci::SMC32::CPU_ON as u64);
+    }
+
+    #[test]
+    fn unknown_arch_calls() {
+        let mut context = Context::default();
+        for fid in [
+            psci::SMCCC_ARCH_WORKAROUND_1,
+            psci::SMCCC_ARCH_WORKAROUND_3,
+            0x8000_0002,
+            0xc000_0001,
+        ] {
+            context.gp_regs[0] = fid as u64;
+            assert_eq!(handle(&mut context), trap::RET_TO_REC);
+            assert_eq!(context.gp_regs[0] as usize, psci::PsciReturn::NOT_SUPPORTED);
+        }
+    }
+}
@@ -1,32 +1,50 @@
+use crate::asm;
 use crate::exception::trap;
+use crate::exception::trap::syndrome::{CacheOp, MsrMrsIss};
 use crate::realm::context::Context;
 use crate::realm::vcpu::VCPU;
 
-use armv9a::regs::*;
+use armv9a::regs::PAR_EL1;
 
-fn check_sysreg_id_access(esr: u64) -> bool {
-    let esr = ISS::new(esr);
-    (esr.get_masked(ISS::Op0) | esr.get_masked(ISS::Op1) | esr.get_masked(ISS::CRn)) == ISS::Op0
+/// Emulates ID register reads.
+/// Accesses to any other register are forwarded to the host (RET_TO_RMM).
+pub fn handle(vcpu: &mut VCPU<Context>, iss: &MsrMrsIss) -> u64 {
+    if iss.is_id_reg() {
+        return handle_sysreg_id(vcpu, iss);
+    }
+    debug!("forward system register access to host: {:?}", iss);
+    trap::RET_TO_RMM
 }
 
-pub fn handle(vcpu: &mut VCPU<Context>, esr: u64) -> u64 {
-    if check_sysreg_id_access(esr) {
-        handle_sysreg_id(vcpu, esr);
+/// Emulates a trapped cache maintenance operation.
+/// Data caches are coherent for everything that can observe realm memory,
+/// so operations on it are safely ignored. Instruction cache invalidation
+/// is upgraded to invalidating the whole instruction cache of the PE.
+/// Returns the address as `Err` if the operation targets a VA which isn't
+/// mapped to realm memory, e.g., unmapped or NS memory.
+pub fn handle_cache_op(vcpu: &VCPU<Context>, iss: &MsrMrsIss, op: CacheOp) -> Result<(), u64> {
+    if op.by_va() {
+        let va = match iss.rt {
+            31 => 0,
+            rt => vcpu.context.gp_regs[rt as usize],
+        };
+        let par = asm::at_s12e1r(va);
+        if par & (PAR_EL1::F | PAR_EL1::NS) != 0 {
+            warn!("{:?} on an address not owned by the realm: {:#X}", op, va);
+            return Err(va);
+        }
     }
-    trap::RET_TO_REC
+    trace!("emulate {:?}", op);
+    if op.is_icache() {
+        asm::icache_invalidate_all();
+    }
+    Ok(())
 }
 
-fn handle_sysreg_id(vcpu: &mut VCPU<Context>, esr: u64) -> u64 {
-    let esr = ISS::new(esr);
-    let il = esr.get_masked_value(ISS::IL);
-    let rt = esr.get_masked_value(ISS::Rt) as usize;
-    // direction: 0b0 - write, 0b1 - read
-    let direction = esr.get_masked_value(ISS::Direction);
+fn handle_sysreg_id(vcpu: &mut VCPU<Context>, iss: &MsrMrsIss) -> u64 {
+    let rt = iss.rt as usize;
 
-    if il == 0 {
-        error!("Exception taken from 32bit arch. Realm needs to be 64bit(arm64).");
-    }
-    if direction == 0 {
+    if !iss.is_read {
         warn!("Unable to write id system reg. Will ignore this request!");
         return trap::RET_TO_REC;
     }
@@ -35,53 +53,7 @@ fn handle_sysreg_id(vcpu: &mut VCPU<Context>, esr: u64) -> u64 {
         return trap::RET_TO_REC;
     }
 
-    let idreg = esr.get_masked(ISS::Op0)
-        | esr.get_masked(ISS::Op1)
-        | esr.get_masked(ISS::CRn)
-        | esr.get_masked(ISS::CRm)
-        | esr.get_masked(ISS::Op2);
-
-    let mut mask: u64 = match idreg as u32 {
-        ISS_ID_AA64ISAR1_EL1 => AA64ISAR1::GPI | AA64ISAR1::GPA | AA64ISAR1::APA | AA64ISAR1::API,
-        ISS_ID_AA64PFR0_EL1 => AA64PFR0::AMU | AA64PFR0::SVE,
-        ISS_ID_AA64PFR1_EL1 => AA64PFR1::MTE,
-        ISS_ID_AA64DFR0_EL1 => {
-            AA64DFR0::BRBE
-                | AA64DFR0::MTPMU
-                | AA64DFR0::TraceBuffer
-                | AA64DFR0::TraceFilt
-                | AA64DFR0::PMSVer
-                | AA64DFR0::CTX_CMPs
-                | AA64DFR0::WRPs
-                | AA64DFR0::BRPs
-                | AA64DFR0::PMUVer
-                | AA64DFR0::TraceVer
-                | AA64DFR0::DebugVer
-        }
-        _ => 0,
-    };
-    mask = !mask;
-
-    vcpu.context.gp_regs[rt] = match idreg as u32 {
-        ISS_ID_AA64PFR0_EL1 => unsafe { ID_AA64PFR0_EL1.get() & mask },
-        ISS_ID_AA64PFR1_EL1 => unsafe { ID_AA64PFR1_EL1.get() & mask },
-        //ISS_ID_AA64ZFR0_EL1 => unsafe { ID_AA64ZFR0_EL1.get()  & mask },
-        ISS_ID_AA64DFR0_EL1 => unsafe {
-            let mut dfr0_set = AA64DFR0(0);
-            dfr0_set.set_masked_value(AA64DFR0::DebugVer, 6);
-            dfr0_set.set_masked_value(AA64DFR0::BRPs, 1);
-            dfr0_set.set_masked_value(AA64DFR0::WRPs, 1);
-            ID_AA64DFR0_EL1.get() & mask | dfr0_set.get()
-        },
-        ISS_ID_AA64DFR1_EL1 => unsafe { ID_AA64DFR1_EL1.get() & mask },
-        ISS_ID_AA64AFR0_EL1 => unsafe { ID_AA64AFR0_EL1.get() & mask },
-        ISS_ID_AA64AFR1_EL1 => unsafe { ID_AA64AFR1_EL1.get() & mask },
-        ISS_ID_AA64ISAR0_EL1 => unsafe { ID_AA64ISAR0_EL1.get() & mask },
-        ISS_ID_AA64ISAR1_EL1 => unsafe { ID_AA64ISAR1_EL1.get() & mask },
-        ISS_ID_AA64MMFR0_EL1 => unsafe { ID_AA64MMFR0_EL1.get() & mask },
-        ISS_ID_AA64MMFR1_EL1 => unsafe { ID_AA64MMFR1_EL1.get() & mask },
-        ISS_ID_AA64MMFR2_EL1 => unsafe { ID_AA64MMFR2_EL1.get() & mask }, //0x10211122,
-        _ => 0x0,
-    };
+    let idreg = iss.encoding();
+    vcpu.context.gp_regs[rt] = vcpu.realm.lock().id_regs.get(idreg).unwrap_or(0);
     trap::RET_TO_REC
 }
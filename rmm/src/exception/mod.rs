@@ -1,3 +1,4 @@
+pub mod extable;
 pub mod lower;
 pub mod trap;
 
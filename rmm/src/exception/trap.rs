@@ -1,18 +1,28 @@
+#[cfg(test)]
+pub(crate) mod fixture;
 mod frame;
+pub(crate) mod helper;
+mod ratelimit;
 pub mod syndrome;
 
 use self::frame::TrapFrame;
+use self::helper::*;
+use self::ratelimit::Throttle;
+use self::syndrome::CacheOp;
 use self::syndrome::Fault;
+use self::syndrome::SErrorIss;
 use self::syndrome::Syndrome;
+use super::extable;
 use super::lower::synchronous;
 use crate::cpu;
 use crate::event::realmexit::{ExitSyncType, RecExitReason};
 use crate::mm::translation::PageTable;
 use crate::realm::context::Context;
+use crate::realm::fpu;
+use crate::realm::inject::{self, DataAbort};
+use crate::realm::timer;
 use crate::realm::vcpu::VCPU;
 
-use armv9a::regs::*;
-
 #[repr(u16)]
 #[derive(Debug, Copy, Clone)]
 pub enum Source {
@@ -23,7 +33,7 @@ pub enum Source {
 }
 
 #[repr(u16)]
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum Kind {
     Synchronous,
     Irq,
@@ -47,86 +57,104 @@ pub struct Info {
 #[no_mangle]
 #[allow(unused_variables)]
 pub extern "C" fn handle_exception(info: Info, esr: u32, tf: &mut TrapFrame) {
-    match info.kind {
-        Kind::Synchronous => match Syndrome::from(esr) {
-            Syndrome::Brk(b) => {
-                debug!("brk #{}", b);
-                debug!("{:?}\nESR: {:X}\n{:#X?}", info, esr, tf);
-                tf.elr += 4; //continue
-            }
-            Syndrome::PCAlignmentFault => {
-                debug!("PCAlignmentFault");
-            }
-            Syndrome::DataAbort(fault) => {
-                let far = unsafe { FAR_EL2.get() };
-                debug!("Data Abort (higher), far:{:X}", far);
-                match fault {
-                    Fault::AddressSize { level } => {
-                        debug!("address size, level:{}", level);
-                    }
-                    Fault::Translation { level } => {
-                        debug!("translation, level:{}, esr:{:X}", level, esr);
-                        PageTable::get_ref().map(far as usize, true);
-                        tf.elr += 4; //continue
-                    }
-                    Fault::AccessFlag { level } => {
-                        debug!("access flag, level:{}", level);
-                    }
-                    Fault::Permission { level } => {
-                        debug!("permission, level:{}", level);
-                    }
-                    Fault::Alignment => {
-                        debug!("alignment");
-                    }
-                    Fault::TLBConflict => {
-                        debug!("tlb conflict");
-                    }
-                    Fault::Other(_x) => {
-                        debug!("other");
-                    }
+    match (info.kind, Syndrome::from(esr)) {
+        (Kind::Synchronous, Syndrome::Brk(b)) => {
+            debug!("brk #{}", b);
+            debug!("{:?}\nESR: {:X}\n{:#X?}", info, esr, tf);
+            tf.elr += 4; //continue
+        }
+        (Kind::Synchronous, Syndrome::DataAbort(Fault::Translation { level }))
+            if cpu::try_this_cpu().map_or(false, |cpu| cpu.rec().is_none()) =>
+        {
+            let far = read_far_el2();
+            debug!("translation, level:{}, esr:{:X}, far:{:X}", level, esr, far);
+            PageTable::get_ref().map(far as usize, true);
+            tf.elr += 4; //continue
+        }
+        _ => {
+            // PerCpu may have been locked when the exception was taken
+            let rec = cpu::try_this_cpu().and_then(|cpu| cpu.rec());
+            match (classify(&info, esr, rec.is_some()), extable::fixup(tf.elr)) {
+                (FaultClass::Recoverable, Some(fixup)) => {
+                    error!(
+                        "{} while servicing REC {:X?}, far:{:X}. Tearing down the realm",
+                        syndrome::describe(esr),
+                        rec,
+                        read_far_el2()
+                    );
+                    cpu::set_realm_fault();
+                    // the access sees the fault instead of going on with garbage
+                    tf.elr = fixup;
+                }
+                _ => {
+                    panic!(
+                        "{:?} and esr {:?}, TrapFrame: {:?} on cpu::id {:?}",
+                        info,
+                        syndrome::describe(esr),
+                        tf,
+                        cpu::id()
+                    );
                 }
             }
-            Syndrome::InstructionAbort(v) => {
-                debug!("Instruction Abort (higher)");
-            }
-            Syndrome::HVC => {
-                debug!("HVC");
-            }
-            Syndrome::SMC => {
-                debug!("SMC");
-            }
-            Syndrome::SysRegInst => {
-                debug!("SysRegInst");
-            }
-            Syndrome::WFX => {
-                debug!("WFX");
-            }
-            Syndrome::Other(v) => {
-                debug!("Other");
-            }
-            undefined => {
-                panic!(
-                    "{:?} and esr {:x}, TrapFrame: {:?} on cpu::id {:?}",
-                    info,
-                    esr,
-                    tf,
-                    cpu::id()
-                );
-            }
-        },
-        _ => {
-            panic!(
-                "Unknown exception! Info={:?}, ESR={:x} on CPU {:?}",
-                info,
-                esr,
-                cpu::id()
-            );
         }
     }
 }
 
+/// How an exception taken in RMM itself is handled
+#[derive(Debug, PartialEq)]
+enum FaultClass {
+    /// Caused by memory or errors of the realm being serviced,
+    /// so tearing down the realm is enough to contain it
+    Recoverable,
+    /// A bug of RMM or an error which can't be contained
+    Fatal,
+}
+
+/// Classifies the exception taken in RMM.
+/// `in_realm` tells if RMM is servicing a realm exit on the CPU.
+fn classify(info: &Info, esr: u32, in_realm: bool) -> FaultClass {
+    if !in_realm || !matches!(info.source, Source::CurrentSPEL0 | Source::CurrentSPELx) {
+        return FaultClass::Fatal;
+    }
+
+    match info.kind {
+        Kind::Synchronous => match Syndrome::from(esr) {
+            // faults on the memory given by the realm or the host, e.g., on GPC faults
+            Syndrome::DataAbort(
+                Fault::AddressSize { .. }
+                | Fault::Translation { .. }
+                | Fault::AccessFlag { .. }
+                | Fault::Permission { .. }
+                | Fault::Other(_),
+            ) => FaultClass::Recoverable,
+            _ => FaultClass::Fatal,
+        },
+        Kind::SError if !SErrorIss::from(esr).is_uncontainable() => FaultClass::Recoverable,
+        _ => FaultClass::Fatal,
+    }
+}
+
+/// Logs a trap taken from a realm unless it's collapsed by the rate limiter.
+macro_rules! trap_debug {
+    ($throttle:expr, $($arg:tt)+) => {
+        if $throttle.allows() {
+            debug!($($arg)+);
+        }
+    };
+}
+
 pub const RET_TO_REC: u64 = 0;
 pub const RET_TO_RMM: u64 = 1;
+
+/// Immediates of HVC reserved for the services of RMM, which are called
+/// with the function ID in x0 as through SMC
+const HVC_IMM_RMM: core::ops::RangeInclusive<u16> = 0xff00..=0xffff;
+
+/// Tells an HVC calling RMM apart from the ones forwarded to the host
+fn is_rmm_hvc(esr: u32) -> bool {
+    Syndrome::hvc_imm(esr).map_or(false, |imm| HVC_IMM_RMM.contains(&imm))
+}
+
 /// This function is called when an exception occurs from LowerAArch64.
 /// To enter RMM (EL2), return 1. Otherwise, return 0 to go back to EL1.
 /// The `info` parameter specifies source (first 16 bits) and kind (following 16
@@ -144,86 +172,166 @@ pub extern "C" fn handle_lower_exception(
     esr: u32,
     vcpu: &mut VCPU<Context>,
     tf: &mut TrapFrame,
+) -> u64 {
+    cpu::this_cpu().record_exit(info.kind, esr, vcpu.context.elr);
+    let throttle = ratelimit::record(info.kind, esr);
+    match throttle {
+        Throttle::Summary(n) => debug!("{:?}: ESR {:#X}: {} occurrences", info.kind, esr, n),
+        Throttle::Flush {
+            kind,
+            esr: last,
+            repeats,
+        } => debug!("{:?}: ESR {:#X}: {} occurrences", kind, last, repeats),
+        _ => {}
+    }
+    dispatch(info, esr, vcpu, tf, throttle, &Cpu)
+}
+
+/// Handles the exception taken from the realm, with the syndrome
+/// registers read from `regs`, and sets up the exit in `tf`.
+fn dispatch(
+    info: Info,
+    esr: u32,
+    vcpu: &mut VCPU<Context>,
+    tf: &mut TrapFrame,
+    throttle: Throttle,
+    regs: &impl SysRegs,
 ) -> u64 {
     match info.kind {
         // TODO: adjust elr according to the decision that kvm made
         Kind::Synchronous => match Syndrome::from(esr) {
+            // ELR already points to the instruction after HVC
+            Syndrome::HVC if is_rmm_hvc(esr) => {
+                trap_debug!(
+                    throttle,
+                    "Synchronous: HVC to RMM: {:#X}",
+                    vcpu.context.gp_regs[0]
+                );
+                rmm_call(vcpu, tf)
+            }
             Syndrome::HVC => {
-                debug!("Synchronous: HVC: {:#X}", vcpu.context.gp_regs[0]);
-
-                // Inject undefined exception to the realm
-                unsafe {
-                    SPSR_EL1.set(vcpu.context.spsr);
-                    ELR_EL1.set(vcpu.context.elr);
-
-                    let esr = EsrEl1::new(0)
-                        .set_masked_value(EsrEl1::EC, ESR_EL1_EC_UNKNOWN)
-                        .set_bits(EsrEl1::IL)
-                        .get();
-
-                    ESR_EL1.set(esr);
-                }
-
-                // Return to realm's exception handler
-                let vbar = vcpu.context.sys_regs.vbar;
-                const SPSR_EL2_MODE_EL1H_OFFSET: u64 = 0x200;
-                vcpu.context.elr = vbar + SPSR_EL2_MODE_EL1H_OFFSET;
-
+                // the other immediates are left to the host
+                trap_debug!(throttle, "Synchronous: HVC: {:#X}", vcpu.context.gp_regs[0]);
                 tf.regs[0] = RecExitReason::Sync(ExitSyncType::Undefined).into();
                 tf.regs[1] = esr as u64;
                 tf.regs[2] = 0;
-                tf.regs[3] = unsafe { FAR_EL2.get() };
-                RET_TO_REC
+                tf.regs[3] = 0;
+                RET_TO_RMM
             }
             Syndrome::SMC => {
-                tf.regs[0] = RecExitReason::Sync(ExitSyncType::RSI).into();
-                tf.regs[1] = vcpu.context.gp_regs[0]; // RSI command
-                advance_pc(vcpu);
-                RET_TO_RMM
+                let ret = rmm_call(vcpu, tf);
+                advance_pc(&mut vcpu.context, esr);
+                ret
             }
             Syndrome::InstructionAbort(_) | Syndrome::DataAbort(_) => {
-                debug!("Synchronous: InstructionAbort | DataAbort");
+                trap_debug!(throttle, "Synchronous: {}", syndrome::describe(esr));
                 if let Syndrome::InstructionAbort(_) = Syndrome::from(esr) {
                     tf.regs[0] = RecExitReason::Sync(ExitSyncType::InstAbort).into()
                 } else {
                     tf.regs[0] = RecExitReason::Sync(ExitSyncType::DataAbort).into();
                 }
-                tf.regs[1] = esr as u64;
-                tf.regs[2] = unsafe { HPFAR_EL2.get() };
-                tf.regs[3] = unsafe { FAR_EL2.get() };
-                let fipa = unsafe { HPFAR_EL2.get_masked(HPFAR_EL2::FIPA) } << 8;
-                debug!("fipa: {:X}", fipa);
-                debug!("esr_el2: {:X}", esr);
-                debug!("vcpu: {:?}", vcpu);
+                tf.regs[1] = match Syndrome::data_abort_iss(esr) {
+                    Some(iss) => iss.esr(),
+                    None => esr as u64,
+                };
+                tf.regs[2] = regs.hpfar_el2();
+                tf.regs[3] = regs.far_el2();
+                let fipa = fault_ipa(regs);
+                trap_debug!(throttle, "fipa: {:X}", fipa);
+                trap_debug!(throttle, "vcpu: {:?}", vcpu);
                 RET_TO_RMM
             }
-            Syndrome::SysRegInst => {
-                debug!("Synchronous: MRS, MSR System Register Instruction");
-                let ret = synchronous::sys_reg::handle(vcpu, esr as u64);
-                advance_pc(vcpu);
+            Syndrome::MsrMrs(iss) => {
+                trap_debug!(throttle, "Synchronous: {}", syndrome::describe(esr));
+                if let Some(op) = CacheOp::decode(&iss) {
+                    match synchronous::sys_reg::handle_cache_op(vcpu, &iss, op) {
+                        Ok(()) => advance_pc(&mut vcpu.context, esr),
+                        Err(va) => inject_data_abort(&mut vcpu.context, regs, va),
+                    }
+                    return RET_TO_REC;
+                }
+                if iss.is_sys_inst() {
+                    // the other system instructions (e.g., AT, TLBI) aren't emulated
+                    trap_debug!(throttle, "Undefined system instruction: {:?}", iss);
+                    inject::undefined(&mut vcpu.context, regs);
+                    return RET_TO_REC;
+                }
+                let ret = synchronous::sys_reg::handle(vcpu, &iss);
+                if ret == RET_TO_RMM {
+                    tf.regs[0] = RecExitReason::Sync(ExitSyncType::SysReg).into();
+                    tf.regs[1] = esr as u64;
+                    tf.regs[2] = 0;
+                    tf.regs[3] = 0;
+                }
+                advance_pc(&mut vcpu.context, esr);
                 ret
             }
-            Syndrome::WFX => {
-                debug!("Synchronous: WFx");
-                tf.regs[0] = RecExitReason::Sync(ExitSyncType::Undefined).into();
+            Syndrome::SimdFp => {
+                trap_debug!(throttle, "Synchronous: FP/SIMD access");
+                fpu::handle_trap(&vcpu.context);
+                RET_TO_REC
+            }
+            Syndrome::Sve => {
+                trap_debug!(throttle, "Synchronous: SVE access");
+                match vcpu.context.sve {
+                    Some(_) => fpu::handle_trap(&vcpu.context),
+                    // SVE isn't configured for the realm
+                    None => inject::undefined(&mut vcpu.context, regs),
+                }
+                RET_TO_REC
+            }
+            Syndrome::WFx(wfx) => {
+                trap_debug!(throttle, "Synchronous: {:?}", wfx);
+                tf.regs[0] = RecExitReason::Sync(ExitSyncType::WFx).into();
                 tf.regs[1] = esr as u64;
-                tf.regs[2] = unsafe { HPFAR_EL2.get() };
-                tf.regs[3] = unsafe { FAR_EL2.get() };
-                advance_pc(vcpu);
+                tf.regs[2] = wfx as u64;
+                tf.regs[3] = 0;
+                advance_pc(&mut vcpu.context, esr);
                 RET_TO_RMM
             }
-            undefined => {
-                debug!("Synchronous: Other");
+            Syndrome::Debug(_) | Syndrome::Brk(_) => {
+                trap_debug!(throttle, "Synchronous: {}", syndrome::describe(esr));
+                tf.regs[0] = RecExitReason::Sync(ExitSyncType::Debug).into();
+                tf.regs[1] = esr as u64;
+                tf.regs[2] = 0;
+                tf.regs[3] = 0;
+                RET_TO_RMM
+            }
+            // AArch32 is only supported at EL0 of the realm, and RMM emulates
+            // none of its instructions, so they are undefined for the realm
+            // rather than passed to the host
+            _ if matches!(info.source, Source::LowerAArch32) => {
+                trap_debug!(throttle, "Synchronous: AArch32 {}", syndrome::describe(esr));
+                inject::undefined(&mut vcpu.context, regs);
+                RET_TO_REC
+            }
+            _ => {
+                trap_debug!(throttle, "Synchronous: {:?}", syndrome::describe(esr));
                 tf.regs[0] = RecExitReason::Sync(ExitSyncType::Undefined).into();
                 tf.regs[1] = esr as u64;
-                tf.regs[2] = unsafe { HPFAR_EL2.get() };
-                tf.regs[3] = unsafe { FAR_EL2.get() };
+                tf.regs[2] = regs.hpfar_el2();
+                tf.regs[3] = regs.far_el2();
                 RET_TO_RMM
             }
         },
+        Kind::SError => {
+            trap_debug!(throttle, "SError: {:?}", SErrorIss::from(esr));
+            tf.regs[0] = RecExitReason::SError.into();
+            tf.regs[1] = esr as u64;
+            tf.regs[2] = 0;
+            tf.regs[3] = 0;
+            RET_TO_RMM
+        }
         Kind::Irq => {
-            debug!("IRQ");
-            tf.regs[0] = RecExitReason::IRQ.into();
+            trap_debug!(throttle, "IRQ");
+            let misr = regs.ich_misr_el2();
+            tf.regs[0] = match misr {
+                0 => {
+                    let (cntv_ctl, cntp_ctl) = timer_ctls(regs);
+                    timer::irq_exit_reason(cntv_ctl, cntp_ctl).into()
+                }
+                _ => RecExitReason::Maintenance.into(),
+            };
             // IRQ isn't interpreted with esr. It just hold previsou info. Void them out.
             tf.regs[1] = 0;
             tf.regs[2] = 0;
@@ -232,17 +340,311 @@ pub extern "C" fn handle_lower_exception(
         }
         _ => {
             error!(
-                "Unknown exception! Info={:?}, ESR={:x} on CPU {:?}",
+                "Unknown exception! Info={:?}, ESR={:x} on CPU {:?}, REC {:X?}",
                 info,
                 esr,
-                cpu::id()
+                cpu::id(),
+                cpu::this_cpu().rec()
             );
             RET_TO_REC
         }
     }
 }
 
+/// Handles a call to RMM in place, or lets the RSI handlers take it.
+fn rmm_call(vcpu: &mut VCPU<Context>, tf: &mut TrapFrame) -> u64 {
+    let mut ret = synchronous::rsi::handle(&mut vcpu.context);
+    if cpu::realm_fault() {
+        // the realm is torn down on the exit
+        ret = RET_TO_RMM;
+    }
+    if ret == RET_TO_RMM {
+        tf.regs[0] = RecExitReason::Sync(ExitSyncType::RSI).into();
+        tf.regs[1] = vcpu.context.gp_regs[0]; // RSI command
+    }
+    ret
+}
+
+/// Reports a synchronous external abort on a cache maintenance operation
+/// to the realm as if it were taken at EL1.
+fn inject_data_abort(context: &mut Context, regs: &impl SysRegs, far: u64) {
+    const ISS_CM: u64 = 1 << 8;
+    const ISS_WNR: u64 = 1 << 6;
+    const ISS_DFSC_SEA: u64 = 0b01_0000;
+
+    let fault = DataAbort {
+        iss: ISS_CM | ISS_WNR | ISS_DFSC_SEA,
+        far,
+    };
+    inject::data_abort(context, regs, &fault);
+}
+
+/// Steps the realm over the trapped instruction, which is A64, A32 or T32
 #[inline(always)]
-fn advance_pc(vcpu: &mut VCPU<Context>) {
-    vcpu.context.elr += 4;
+fn advance_pc(context: &mut Context, esr: u32) {
+    context.elr += Syndrome::instruction_len(esr);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rsi;
+    use crate::rsi::psci;
+
+    #[test]
+    fn rsi_version_in_place() {
+        let mut context = Context::default();
+        context.gp_regs[0] = rsi::ABI_VERSION as u64;
+        context.elr = 0x8000_0000;
+
+        assert_eq!(synchronous::rsi::handle(&mut context), RET_TO_REC);
+        advance_pc(&mut context, SMC);
+        assert_eq!(context.gp_regs[0], 1 << 16);
+        assert_eq!(context.elr, 0x8000_0004);
+
+        context.gp_regs[0] = rsi::HOST_CALL as u64;
+        assert_eq!(synchronous::rsi::handle(&mut context), RET_TO_RMM);
+        assert_eq!(context.gp_regs[0], rsi::HOST_CALL as u64);
+    }
+
+    #[test]
+    fn exception_classification() {
+        const SYNC: Info = Info {
+            source: Source::CurrentSPELx,
+            kind: Kind::Synchronous,
+        };
+        const SERROR: Info = Info {
+            source: Source::CurrentSPELx,
+            kind: Kind::SError,
+        };
+        const IRQ: Info = Info {
+            source: Source::CurrentSPELx,
+            kind: Kind::Irq,
+        };
+        const LOWER: Info = Info {
+            source: Source::LowerAArch64,
+            kind: Kind::Synchronous,
+        };
+        // level 3 permission fault on a write
+        const PERMISSION: u32 = 0x9600_004f;
+        // granule protection check fault
+        const GPC: u32 = 0x9600_0028;
+
+        // (info, esr, in_realm, expected)
+        let cases = [
+            (SYNC, PERMISSION, true, FaultClass::Recoverable),
+            (SYNC, GPC, true, FaultClass::Recoverable),
+            (SYNC, PERMISSION, false, FaultClass::Fatal),
+            (LOWER, PERMISSION, true, FaultClass::Fatal),
+            // alignment fault
+            (SYNC, 0x9600_0021, true, FaultClass::Fatal),
+            // instruction abort
+            (SYNC, 0x8600_000f, true, FaultClass::Fatal),
+            // undefined instruction
+            (SYNC, 0x0200_0000, true, FaultClass::Fatal),
+            // asynchronous SError, recoverable or uncontainable
+            (SERROR, 0xbe00_0e11, true, FaultClass::Recoverable),
+            (SERROR, 0xbe00_0011, true, FaultClass::Fatal),
+            (SERROR, 0xbe00_0e11, false, FaultClass::Fatal),
+            (IRQ, 0, true, FaultClass::Fatal),
+        ];
+        for (info, esr, in_realm, expected) in cases {
+            assert_eq!(
+                classify(&info, esr, in_realm),
+                expected,
+                "{:?} ESR: {:#x}",
+                info,
+                esr
+            );
+        }
+    }
+
+    // HVC #0 and SMC #0 from AArch64
+    const HVC: u32 = 0x5a00_0000;
+    const SMC: u32 = 0x5e00_0000;
+
+    const SYNC: Info = Info {
+        source: Source::LowerAArch64,
+        kind: Kind::Synchronous,
+    };
+
+    #[test]
+    fn hvc_arm() {
+        assert!(matches!(Syndrome::from(HVC), Syndrome::HVC));
+
+        // HVC #0, #1 and the one below the immediates of RMM
+        for esr in [HVC, 0x5a00_0001, 0x5a00_feff] {
+            let (vcpu, mut tf) = fixture::Builder::new()
+                .gp_reg(0, 0xc400_0150)
+                .elr(0x8000_1000)
+                .spsr(0x3c4)
+                .sys_regs(|regs| regs.far = 0x1234)
+                .build();
+            let mut vcpu = vcpu.lock();
+            let regs = fixture::Regs {
+                far: 0x5678,
+                ..Default::default()
+            };
+
+            let ret = dispatch(SYNC, esr, &mut vcpu, &mut tf, Throttle::Log, &regs);
+            // forwarded to the host with the immediate in the syndrome
+            assert_eq!(ret, RET_TO_RMM, "{:#x}", esr);
+            assert_eq!(
+                tf.regs[0..4],
+                [
+                    RecExitReason::Sync(ExitSyncType::Undefined).into(),
+                    esr as u64,
+                    0,
+                    0
+                ],
+                "{:#x}",
+                esr
+            );
+            // nothing is injected to the realm
+            assert_eq!(vcpu.context.injected, None, "{:#x}", esr);
+            assert_eq!(regs.el1.get(), fixture::El1::default(), "{:#x}", esr);
+            assert_eq!(vcpu.context.spsr, 0x3c4);
+            assert_eq!(vcpu.context.sys_regs.far, 0x1234);
+            // which resumes after HVC once the host has handled it
+            assert_eq!(vcpu.context.elr, 0x8000_1000);
+            assert_eq!(vcpu.context.gp_regs[0], 0xc400_0150);
+        }
+    }
+
+    #[test]
+    fn data_abort_arm() {
+        // (ESR taken, ESR forwarded to RMM)
+        let cases = [
+            // str w1, [x2] to a level 3 translation fault
+            (0x9381_0047, 0x9381_0047),
+            // without ISV, the access fields are left out
+            (0x9281_0047, 0x9200_0047),
+        ];
+        for (esr, forwarded) in cases {
+            let (vcpu, mut tf) = fixture::Builder::new().build();
+            let mut vcpu = vcpu.lock();
+            let regs = fixture::Regs {
+                far: 0x8800_0123,
+                hpfar: 0x88_0000,
+                ..Default::default()
+            };
+
+            let ret = dispatch(SYNC, esr, &mut vcpu, &mut tf, Throttle::Log, &regs);
+            assert_eq!(ret, RET_TO_RMM, "{:#x}", esr);
+            assert_eq!(
+                tf.regs[0..4],
+                [
+                    RecExitReason::Sync(ExitSyncType::DataAbort).into(),
+                    forwarded,
+                    0x88_0000,
+                    0x8800_0123
+                ],
+                "{:#x}",
+                esr
+            );
+            // the realm is resumed at the access once the host has handled it
+            assert_eq!(vcpu.context.elr, fixture::ELR);
+        }
+    }
+
+    #[test]
+    fn sys_inst_arm() {
+        // tlbi vmalle1
+        const TLBI: u32 = 0x6210_23ee;
+
+        let (vcpu, mut tf) = fixture::Builder::new().build();
+        let mut vcpu = vcpu.lock();
+        let regs = fixture::Regs::default();
+
+        let ret = dispatch(SYNC, TLBI, &mut vcpu, &mut tf, Throttle::Log, &regs);
+        assert_eq!(ret, RET_TO_REC);
+        assert_eq!(tf.regs[0..4], [0; 4]);
+        // the realm takes an undefined exception instead of skipping it
+        assert_eq!(vcpu.context.elr, fixture::VBAR + 0x200);
+        assert_eq!(vcpu.context.sys_regs.esr_el1, 0x0200_0000);
+        assert_eq!(
+            regs.el1.get(),
+            fixture::El1 {
+                spsr: 0x3c5,
+                elr: fixture::ELR,
+                esr: 0x0200_0000,
+                far: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn smc_arm() {
+        assert!(matches!(Syndrome::from(SMC), Syndrome::SMC));
+
+        // (x0, handled in place, x0 returned to the realm)
+        let cases = [
+            (rsi::ABI_VERSION, RET_TO_REC, rsi::VERSION),
+            (psci::PSCI_VERSION, RET_TO_REC, psci::psci_version()),
+            (psci::SMCCC_VERSION, RET_TO_REC, psci::smccc_version()),
+            (rsi::HOST_CALL, RET_TO_RMM, rsi::HOST_CALL),
+            (rsi::IPA_STATE_GET, RET_TO_RMM, rsi::IPA_STATE_GET),
+        ];
+        for (cmd, expected, x0) in cases {
+            let (vcpu, mut tf) = fixture::Builder::new().gp_reg(0, cmd as u64).build();
+            let mut vcpu = vcpu.lock();
+            let regs = fixture::Regs::default();
+
+            let ret = dispatch(SYNC, SMC, &mut vcpu, &mut tf, Throttle::Log, &regs);
+            assert_eq!(ret, expected, "{:#x}", cmd);
+            let exit: [u64; 4] = match expected {
+                // the exit to the host takes the RSI command
+                RET_TO_RMM => [
+                    RecExitReason::Sync(ExitSyncType::RSI).into(),
+                    cmd as u64,
+                    0,
+                    0,
+                ],
+                _ => [0; 4],
+            };
+            assert_eq!(tf.regs[0..4], exit, "{:#x}", cmd);
+            assert_eq!(vcpu.context.gp_regs[0], x0 as u64, "{:#x}", cmd);
+            assert_eq!(vcpu.context.elr, fixture::ELR + 4);
+        }
+    }
+
+    #[test]
+    fn hvc_routing() {
+        assert_eq!(Syndrome::hvc_imm(0x5a00_ff01), Some(0xff01));
+        assert_eq!(Syndrome::hvc_imm(0x5a00_0001), Some(0x1));
+        assert_eq!(Syndrome::hvc_imm(SMC), None);
+
+        // (ESR, called RMM)
+        let cases = [
+            (HVC, false),
+            (0x5a00_0001, false),
+            (0x5a00_feff, false),
+            (0x5a00_ff00, true),
+            (0x5a00_ffff, true),
+            // SMC #0xff00
+            (0x5e00_ff00, false),
+        ];
+        for (esr, expected) in cases {
+            assert_eq!(is_rmm_hvc(esr), expected, "{:#x}", esr);
+        }
+    }
+
+    #[test]
+    fn il_step_size() {
+        // WFI of T32 (16-bit), then of A32 and A64
+        const WFI_T16: u32 = 0x0400_0000;
+        const WFI: u32 = 0x0600_0000;
+        // A32 data abort with a valid syndrome
+        const DATA_ABORT_A32: u32 = 0x9300_0046;
+
+        let (vcpu, _tf) = fixture::Builder::new().build();
+        let mut vcpu = vcpu.lock();
+        for (esr, step) in [(WFI_T16, 2), (WFI, 4), (DATA_ABORT_A32, 4), (SMC, 4)] {
+            assert_eq!(Syndrome::instruction_len(esr), step, "{:#x}", esr);
+            let elr = vcpu.context.elr;
+            advance_pc(&mut vcpu.context, esr);
+            assert_eq!(vcpu.context.elr, elr + step, "{:#x}", esr);
+        }
+        assert_eq!(vcpu.context.elr, fixture::ELR + 14);
+    }
 }
@@ -0,0 +1,136 @@
+//! Fixtures for the tests of the trap handlers, which build the VCPU, its
+//! context and the trap frame with the realm running at EL1h.
+
+use super::frame::TrapFrame;
+use super::helper::SysRegs;
+use crate::realm::context::{Context, SystemRegister};
+use crate::realm::vcpu::VCPU;
+
+use alloc::sync::Arc;
+use core::cell::Cell;
+use spin::mutex::Mutex;
+
+/// ELR of the realm before the trap
+pub const ELR: u64 = 0x8000_0000;
+/// VBAR_EL1 of the realm
+pub const VBAR: u64 = 0x8008_0000;
+// EL1h with all the exceptions masked
+const SPSR_EL1H: u64 = 0x3c5;
+
+pub struct Builder {
+    context: Context,
+    tf: TrapFrame,
+}
+
+impl Builder {
+    pub fn new() -> Self {
+        let mut context = Context {
+            elr: ELR,
+            spsr: SPSR_EL1H,
+            ..Default::default()
+        };
+        context.sys_regs.vbar = VBAR;
+        let tf = TrapFrame {
+            _res: 0,
+            elr: 0,
+            spsr: 0,
+            regs: [0; 31],
+        };
+        Self { context, tf }
+    }
+
+    pub fn gp_reg(mut self, n: usize, val: u64) -> Self {
+        self.context.gp_regs[n] = val;
+        self
+    }
+
+    pub fn elr(mut self, elr: u64) -> Self {
+        self.context.elr = elr;
+        self
+    }
+
+    pub fn spsr(mut self, spsr: u64) -> Self {
+        self.context.spsr = spsr;
+        self
+    }
+
+    /// Sets the system registers of the realm, e.g., `|regs| regs.far = ..`
+    pub fn sys_regs(mut self, f: impl FnOnce(&mut SystemRegister)) -> Self {
+        f(&mut self.context.sys_regs);
+        self
+    }
+
+    /// A VCPU of a realm of its own, along with the trap frame
+    pub fn build(self) -> (Arc<Mutex<VCPU<Context>>>, TrapFrame) {
+        let vcpu = VCPU::new(crate::realm::test::realm());
+        vcpu.lock().context = self.context;
+        (vcpu, self.tf)
+    }
+}
+
+/// EL1 registers of the exception injected to the realm
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct El1 {
+    pub spsr: u64,
+    pub elr: u64,
+    pub esr: u64,
+    pub far: u64,
+}
+
+/// Registers accessed on the exception in place of the ones of the CPU
+#[derive(Default)]
+pub struct Regs {
+    pub far: u64,
+    pub hpfar: u64,
+    pub misr: u64,
+    pub cntv_ctl: u64,
+    pub cntp_ctl: u64,
+    /// Written by the handlers
+    pub el1: Cell<El1>,
+}
+
+impl Regs {
+    fn write_el1(&self, f: impl FnOnce(&mut El1)) {
+        let mut el1 = self.el1.get();
+        f(&mut el1);
+        self.el1.set(el1);
+    }
+}
+
+impl SysRegs for Regs {
+    fn far_el2(&self) -> u64 {
+        self.far
+    }
+
+    fn hpfar_el2(&self) -> u64 {
+        self.hpfar
+    }
+
+    fn ich_misr_el2(&self) -> u64 {
+        self.misr
+    }
+
+    fn cntv_ctl_el0(&self) -> u64 {
+        self.cntv_ctl
+    }
+
+    fn cntp_ctl_el0(&self) -> u64 {
+        self.cntp_ctl
+    }
+
+    fn set_spsr_el1(&self, val: u64) {
+        self.write_el1(|el1| el1.spsr = val);
+    }
+
+    fn set_elr_el1(&self, val: u64) {
+        self.write_el1(|el1| el1.elr = val);
+    }
+
+    fn set_esr_el1(&self, val: u64) {
+        self.write_el1(|el1| el1.esr = val);
+    }
+
+    fn set_far_el1(&self, val: u64) {
+        self.write_el1(|el1| el1.far = val);
+    }
+}
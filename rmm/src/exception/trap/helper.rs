@@ -0,0 +1,143 @@
+//! Safe accessors of the system registers read on exceptions taken to EL2.
+//!
+//! The fault address registers are only valid for the exception being handled,
+//! so they must be read before anything else can take an exception to EL2,
+//! e.g., before touching memory of the realm or unmasking interrupts.
+//! The interrupt and timer state must be read before the state of the REC
+//! is saved on its exit, which puts back the one of the host.
+//! The EL1 registers of an exception injected to the realm are written last,
+//! once the handler has decided to return to the realm without saving its context.
+
+use armv9a::regs::*;
+
+/// Registers accessed on exceptions, which are the ones of the CPU but for the tests
+pub trait SysRegs {
+    fn far_el2(&self) -> u64;
+    fn hpfar_el2(&self) -> u64;
+    fn ich_misr_el2(&self) -> u64;
+    fn cntv_ctl_el0(&self) -> u64;
+    fn cntp_ctl_el0(&self) -> u64;
+    fn set_spsr_el1(&self, val: u64);
+    fn set_elr_el1(&self, val: u64);
+    fn set_esr_el1(&self, val: u64);
+    fn set_far_el1(&self, val: u64);
+}
+
+pub struct Cpu;
+
+impl SysRegs for Cpu {
+    fn far_el2(&self) -> u64 {
+        unsafe { FAR_EL2.get() }
+    }
+
+    fn hpfar_el2(&self) -> u64 {
+        unsafe { HPFAR_EL2.get() }
+    }
+
+    fn ich_misr_el2(&self) -> u64 {
+        unsafe { ICH_MISR_EL2.get() }
+    }
+
+    fn cntv_ctl_el0(&self) -> u64 {
+        unsafe { CNTV_CTL_EL0.get() }
+    }
+
+    fn cntp_ctl_el0(&self) -> u64 {
+        unsafe { CNTP_CTL_EL0.get() }
+    }
+
+    fn set_spsr_el1(&self, val: u64) {
+        unsafe { SPSR_EL1.set(val) }
+    }
+
+    fn set_elr_el1(&self, val: u64) {
+        unsafe { ELR_EL1.set(val) }
+    }
+
+    fn set_esr_el1(&self, val: u64) {
+        unsafe { ESR_EL1.set(val) }
+    }
+
+    fn set_far_el1(&self, val: u64) {
+        unsafe { FAR_EL1.set(val) }
+    }
+}
+
+/// IPA of the granule of a stage 2 fault, which is HPFAR_EL2.FIPA
+pub fn fault_ipa(regs: &impl SysRegs) -> u64 {
+    (regs.hpfar_el2() & HPFAR_EL2::FIPA) << 8
+}
+
+/// CNTV_CTL_EL0 and CNTP_CTL_EL0 of the realm
+pub fn timer_ctls(regs: &impl SysRegs) -> (u64, u64) {
+    (regs.cntv_ctl_el0(), regs.cntp_ctl_el0())
+}
+
+/// Faulting virtual address of the exception being handled
+pub fn read_far_el2() -> u64 {
+    Cpu.far_el2()
+}
+
+/// Makes the realm take a synchronous exception with `esr` from `elr` in the mode
+/// of `spsr`, which are the registers its handler returns with
+pub fn write_el1_exception(regs: &impl SysRegs, spsr: u64, elr: u64, esr: u64) {
+    regs.set_spsr_el1(spsr);
+    regs.set_elr_el1(elr);
+    regs.set_esr_el1(esr);
+}
+
+/// Faulting virtual address of the exception taken by the realm
+pub fn write_far_el1(regs: &impl SysRegs, far: u64) {
+    regs.set_far_el1(far);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::exception::trap::fixture::{El1, Regs};
+
+    #[test]
+    fn register_fields() {
+        // NS and the bits below FIPA aren't part of the IPA
+        let regs = Regs {
+            hpfar: (1 << 63) | (0x88_0001 << 4) | 0xf,
+            cntv_ctl: 0b101,
+            cntp_ctl: 0b001,
+            ..Default::default()
+        };
+        assert_eq!(fault_ipa(&regs), 0x8_8000_1000);
+        assert_eq!(timer_ctls(&regs), (0b101, 0b001));
+
+        let regs = Regs {
+            hpfar: HPFAR_EL2::FIPA,
+            ..Default::default()
+        };
+        assert_eq!(fault_ipa(&regs), ((1 << 52) - 1) & !0xfff);
+    }
+
+    #[test]
+    fn exception_writes() {
+        let regs = Regs::default();
+        write_el1_exception(&regs, 0x3c5, 0x8000_1000, 0x9600_0050);
+        assert_eq!(
+            regs.el1.get(),
+            El1 {
+                spsr: 0x3c5,
+                elr: 0x8000_1000,
+                esr: 0x9600_0050,
+                far: 0,
+            }
+        );
+
+        // only FAR_EL1 is written
+        let before = regs.el1.get();
+        write_far_el1(&regs, 0x8000_1234);
+        assert_eq!(
+            regs.el1.get(),
+            El1 {
+                far: 0x8000_1234,
+                ..before
+            }
+        );
+    }
+}
@@ -0,0 +1,166 @@
+use super::Kind;
+use crate::config::NUM_OF_CPU;
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Number of repetitions of the same trap reason collapsed into a summary
+pub const PERIOD: u64 = 256;
+
+// Never a key of a trap, as Kind takes at most 16 bits above the ESR
+const NO_REASON: u64 = u64::MAX;
+
+/// What to log for a trap taken from a realm
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Throttle {
+    /// Log the trap as usual
+    Log,
+    /// The trap repeats the previous one, don't log it
+    Suppress,
+    /// The trap repeats the previous one which has been taken this many
+    /// times since it was last logged or summarized
+    Summary(u64),
+    /// The trap ends a run of another reason, which has been taken this many
+    /// times since it was last logged or summarized. Log the summary of the
+    /// run, then the trap as usual
+    Flush { kind: Kind, esr: u32, repeats: u64 },
+}
+
+impl Throttle {
+    pub fn allows(&self) -> bool {
+        matches!(self, Self::Log | Self::Flush { .. })
+    }
+}
+
+fn key(kind: Kind, esr: u32) -> u64 {
+    ((kind as u64) << 32) | esr as u64
+}
+
+fn reason(key: u64) -> (Kind, u32) {
+    let kind = match key >> 32 {
+        0 => Kind::Synchronous,
+        1 => Kind::Irq,
+        2 => Kind::Fiq,
+        _ => Kind::SError,
+    };
+    (kind, key as u32)
+}
+
+/// Collapses the identical trap reasons taken in a row on a CPU
+pub struct RateLimiter {
+    last: AtomicU64,
+    repeats: AtomicU64,
+}
+
+impl RateLimiter {
+    pub const fn new() -> Self {
+        Self {
+            last: AtomicU64::new(NO_REASON),
+            repeats: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record(&self, kind: Kind, esr: u32) -> Throttle {
+        let reason = key(kind, esr);
+        let last = self.last.swap(reason, Ordering::Relaxed);
+        if last != reason {
+            let repeats = self.repeats.swap(0, Ordering::Relaxed) % PERIOD;
+            if last == NO_REASON || repeats == 0 {
+                return Throttle::Log;
+            }
+            let (kind, esr) = self::reason(last);
+            return Throttle::Flush { kind, esr, repeats };
+        }
+
+        let repeats = self.repeats.fetch_add(1, Ordering::Relaxed) + 1;
+        match repeats % PERIOD {
+            0 => Throttle::Summary(PERIOD),
+            _ => Throttle::Suppress,
+        }
+    }
+}
+
+/// RateLimiter of each CPU indexed by `get_cpu_id()`
+struct RateLimiters<const N: usize>([RateLimiter; N]);
+
+impl<const N: usize> RateLimiters<N> {
+    // only used to initialize each element of the array
+    #[allow(clippy::declare_interior_mutable_const)]
+    const INIT: RateLimiter = RateLimiter::new();
+
+    const fn new() -> Self {
+        Self([Self::INIT; N])
+    }
+}
+
+static LIMITERS: RateLimiters<NUM_OF_CPU> = RateLimiters::new();
+
+/// Records a trap taken on the current CPU and decides how to log it
+pub fn record(kind: Kind, esr: u32) -> Throttle {
+    LIMITERS.0[crate::cpu::get_cpu_id()].record(kind, esr)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn collapse_identical_traps() {
+        const SMC: u32 = 0x5e00_0000;
+        let limiter = RateLimiter::new();
+
+        let lines = (0..1000)
+            .map(|_| limiter.record(Kind::Synchronous, SMC))
+            .filter(|throttle| *throttle != Throttle::Suppress)
+            .count();
+        assert_eq!(lines, 1 + 999 / PERIOD as usize);
+
+        // another reason breaks the run
+        assert_eq!(
+            limiter.record(Kind::Irq, 0),
+            Throttle::Flush {
+                kind: Kind::Synchronous,
+                esr: SMC,
+                repeats: 999 % PERIOD
+            }
+        );
+        assert_eq!(limiter.record(Kind::Synchronous, SMC), Throttle::Log);
+        for _ in 1..PERIOD {
+            assert_eq!(limiter.record(Kind::Synchronous, SMC), Throttle::Suppress);
+        }
+        assert_eq!(
+            limiter.record(Kind::Synchronous, SMC),
+            Throttle::Summary(PERIOD)
+        );
+    }
+
+    #[test]
+    fn flush_leftover_on_new_reason() {
+        const HVC: u32 = 0x5a00_0000;
+        const WFI: u32 = 0x0600_0000;
+        let limiter = RateLimiter::new();
+
+        assert_eq!(limiter.record(Kind::Synchronous, HVC), Throttle::Log);
+        // nothing has been suppressed since the last log
+        assert_eq!(limiter.record(Kind::Synchronous, WFI), Throttle::Log);
+
+        for _ in 0..3 {
+            assert_eq!(limiter.record(Kind::Synchronous, WFI), Throttle::Suppress);
+        }
+        let flush = limiter.record(Kind::SError, HVC);
+        assert!(flush.allows());
+        assert_eq!(
+            flush,
+            Throttle::Flush {
+                kind: Kind::Synchronous,
+                esr: WFI,
+                repeats: 3
+            }
+        );
+
+        // or since the last summary
+        for _ in 0..PERIOD {
+            limiter.record(Kind::SError, HVC);
+        }
+        assert_eq!(limiter.record(Kind::Irq, 0), Throttle::Log);
+    }
+}
@@ -1,4 +1,6 @@
-use armv9a::regs::ESR_EL2;
+use armv9a::bits_in_reg;
+use armv9a::regs::{EsrEl2, ESR_EL2, ISS};
+use core::fmt;
 
 #[derive(Debug, Copy, Clone)]
 pub enum Fault {
@@ -27,6 +29,266 @@ impl From<u32> for Fault {
     }
 }
 
+/// Access information of a data abort (ISS[23:14]).
+/// It's provided by the hardware only when ISS.ISV is set.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct AccessSyndrome {
+    /// Syndrome Access Size (0: byte, 1: halfword, 2: word, 3: doubleword)
+    pub sas: u8,
+    /// Syndrome Sign Extend
+    pub sse: bool,
+    /// Syndrome Register Transfer, 31 means xzr
+    pub srt: u8,
+    /// Sixty-Four bit general-purpose register transfer
+    pub sf: bool,
+}
+
+/// Decoded ISS of a data abort
+#[derive(Debug, Copy, Clone)]
+pub struct DataAbortIss {
+    pub fault: Fault,
+    /// `None` if the syndrome is not valid (ISS.ISV == 0),
+    /// in this case the register index can't be trusted.
+    pub access: Option<AccessSyndrome>,
+    /// Write not Read
+    pub wnr: bool,
+    /// The fault is on a stage 2 translation for a stage 1 translation table walk
+    pub s1ptw: bool,
+    esr: u32,
+}
+
+impl From<u32> for DataAbortIss {
+    fn from(origin: u32) -> Self {
+        let esr = EsrEl2::new(origin as u64);
+        let access = match esr.get_masked(EsrEl2::ISV) {
+            0 => None,
+            _ => Some(AccessSyndrome {
+                sas: esr.get_masked_value(EsrEl2::SAS) as u8,
+                sse: esr.get_masked(EsrEl2::SSE) != 0,
+                srt: esr.get_masked_value(EsrEl2::SRT) as u8,
+                sf: esr.get_masked(EsrEl2::SF) != 0,
+            }),
+        };
+
+        DataAbortIss {
+            fault: Fault::from(origin),
+            access,
+            wnr: esr.get_masked(EsrEl2::WNR) != 0,
+            s1ptw: esr.get_masked(EsrEl2::S1PTW) != 0,
+            esr: origin,
+        }
+    }
+}
+
+impl DataAbortIss {
+    pub fn is_valid(&self) -> bool {
+        self.access.is_some()
+    }
+
+    /// ESR of the abort forwarded to the host,
+    /// which leaves out ISS[23:14] unless the syndrome is valid.
+    pub fn esr(&self) -> u64 {
+        const ACCESS: u64 = EsrEl2::SAS | EsrEl2::SSE | EsrEl2::SRT | EsrEl2::SF | EsrEl2::AR;
+        match self.is_valid() {
+            true => self.esr as u64,
+            false => self.esr as u64 & !ACCESS,
+        }
+    }
+}
+
+/// Decoded ISS of a trapped MSR, MRS or System instruction
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct MsrMrsIss {
+    pub op0: u8,
+    pub op1: u8,
+    pub crn: u8,
+    pub crm: u8,
+    pub op2: u8,
+    /// General-purpose register used for the transfer, 31 means xzr
+    pub rt: u8,
+    /// true for MRS (read), false for MSR (write)
+    pub is_read: bool,
+}
+
+impl From<u32> for MsrMrsIss {
+    fn from(origin: u32) -> Self {
+        let iss = ISS::new(origin as u64);
+        MsrMrsIss {
+            op0: iss.get_masked_value(ISS::Op0) as u8,
+            op1: iss.get_masked_value(ISS::Op1) as u8,
+            crn: iss.get_masked_value(ISS::CRn) as u8,
+            crm: iss.get_masked_value(ISS::CRm) as u8,
+            op2: iss.get_masked_value(ISS::Op2) as u8,
+            rt: iss.get_masked_value(ISS::Rt) as u8,
+            is_read: iss.get_masked(ISS::Direction) != 0,
+        }
+    }
+}
+
+impl MsrMrsIss {
+    /// Encoding of the accessed register, comparable with armv9a::regs::ISS_ID_*
+    pub fn encoding(&self) -> u32 {
+        (bits_in_reg(ISS::Op0, self.op0 as u64)
+            | bits_in_reg(ISS::Op1, self.op1 as u64)
+            | bits_in_reg(ISS::CRn, self.crn as u64)
+            | bits_in_reg(ISS::CRm, self.crm as u64)
+            | bits_in_reg(ISS::Op2, self.op2 as u64)) as u32
+    }
+
+    /// ID registers (ID_AA64*_EL1, ...) trapped by HCR_EL2.TID3
+    pub fn is_id_reg(&self) -> bool {
+        self.op0 == 3 && self.op1 == 0 && self.crn == 0
+    }
+
+    /// System instructions (e.g., DC, IC, AT) rather than register accesses
+    pub fn is_sys_inst(&self) -> bool {
+        self.op0 == 1
+    }
+}
+
+/// Cache maintenance instructions trapped by HCR_EL2.TPC/TPU/TSW.
+/// DC ZVA isn't one of them, it is a store.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum CacheOp {
+    DcIvac,
+    DcIsw,
+    DcCsw,
+    DcCisw,
+    DcCvac,
+    DcCvau,
+    DcCvap,
+    DcCvadp,
+    DcCivac,
+    IcIalluis,
+    IcIallu,
+    IcIvau,
+}
+
+impl CacheOp {
+    /// All of them are encoded as SYS with op0 == 1 and CRn == 7
+    pub fn decode(iss: &MsrMrsIss) -> Option<Self> {
+        if !iss.is_sys_inst() || iss.crn != 7 || iss.is_read {
+            return None;
+        }
+        let op = match (iss.op1, iss.crm, iss.op2) {
+            (0, 1, 0) => CacheOp::IcIalluis,
+            (0, 5, 0) => CacheOp::IcIallu,
+            (3, 5, 1) => CacheOp::IcIvau,
+            (0, 6, 1) => CacheOp::DcIvac,
+            (0, 6, 2) => CacheOp::DcIsw,
+            (0, 10, 2) => CacheOp::DcCsw,
+            (0, 14, 2) => CacheOp::DcCisw,
+            (3, 10, 1) => CacheOp::DcCvac,
+            (3, 11, 1) => CacheOp::DcCvau,
+            (3, 12, 1) => CacheOp::DcCvap,
+            (3, 13, 1) => CacheOp::DcCvadp,
+            (3, 14, 1) => CacheOp::DcCivac,
+            _ => return None,
+        };
+        Some(op)
+    }
+
+    /// Whether Xt holds a virtual address rather than set/way or nothing
+    pub fn by_va(&self) -> bool {
+        !matches!(
+            self,
+            CacheOp::DcIsw
+                | CacheOp::DcCsw
+                | CacheOp::DcCisw
+                | CacheOp::IcIalluis
+                | CacheOp::IcIallu
+        )
+    }
+
+    pub fn is_icache(&self) -> bool {
+        matches!(
+            self,
+            CacheOp::IcIalluis | CacheOp::IcIallu | CacheOp::IcIvau
+        )
+    }
+}
+
+/// Asynchronous Error Type of an SError
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SErrorType {
+    Uncontainable,
+    UnrecoverableState,
+    Restartable,
+    Recoverable,
+    Corrected,
+    Reserved(u8),
+}
+
+impl From<u64> for SErrorType {
+    fn from(aet: u64) -> Self {
+        match aet {
+            0b000 => SErrorType::Uncontainable,
+            0b001 => SErrorType::UnrecoverableState,
+            0b010 => SErrorType::Restartable,
+            0b011 => SErrorType::Recoverable,
+            0b110 => SErrorType::Corrected,
+            other => SErrorType::Reserved(other as u8),
+        }
+    }
+}
+
+/// Decoded ISS of an SError
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct SErrorIss {
+    /// The rest of the syndrome is IMPLEMENTATION DEFINED
+    pub ids: bool,
+    /// Only valid for an asynchronous SError with a syndrome not IMPLEMENTATION DEFINED
+    pub aet: SErrorType,
+    pub ea: bool,
+    pub dfsc: u8,
+}
+
+impl From<u32> for SErrorIss {
+    fn from(origin: u32) -> Self {
+        let esr = EsrEl2::new(origin as u64);
+        SErrorIss {
+            ids: esr.get_masked(EsrEl2::IDS) != 0,
+            aet: SErrorType::from(esr.get_masked_value(EsrEl2::AET)),
+            ea: esr.get_masked(EsrEl2::EA) != 0,
+            dfsc: esr.get_masked_value(EsrEl2::DFSC) as u8,
+        }
+    }
+}
+
+impl SErrorIss {
+    const DFSC_ASYNC_SERROR: u8 = 0b01_0001;
+
+    pub fn is_async(&self) -> bool {
+        !self.ids && self.dfsc == Self::DFSC_ASYNC_SERROR
+    }
+
+    /// The error might have been propagated, so the state of the PE can't be trusted
+    pub fn is_uncontainable(&self) -> bool {
+        self.is_async() && self.aet == SErrorType::Uncontainable
+    }
+}
+
+/// Trapped WFx instruction, decoded from the TI field of ISS
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[repr(u8)]
+pub enum WFxType {
+    WFI = 0b00,
+    WFE = 0b01,
+    WFIT = 0b10,
+    WFET = 0b11,
+}
+
+impl From<u32> for WFxType {
+    fn from(origin: u32) -> Self {
+        match origin & 0b11 {
+            0b00 => WFxType::WFI,
+            0b01 => WFxType::WFE,
+            0b10 => WFxType::WFIT,
+            _ => WFxType::WFET,
+        }
+    }
+}
+
 #[derive(Debug, Copy, Clone)]
 pub enum Syndrome {
     Unknown,
@@ -35,10 +297,16 @@ pub enum Syndrome {
     InstructionAbort(Fault),
     SPAlignmentFault,
     Brk(u16),
+    /// Breakpoint, software step or watchpoint exception with its EC
+    Debug(u32),
     HVC,
     SMC,
-    SysRegInst,
-    WFX,
+    MsrMrs(MsrMrsIss),
+    WFx(WFxType),
+    /// Access to FP/SIMD registers trapped by CPTR_EL2.TFP
+    SimdFp,
+    /// Access to SVE registers trapped by CPTR_EL2.TZ
+    Sve,
     Other(u32),
 }
 
@@ -46,12 +314,14 @@ impl From<u32> for Syndrome {
     fn from(origin: u32) -> Self {
         match (origin & ESR_EL2::EC as u32) >> ESR_EL2::EC.trailing_zeros() {
             0b00_0000 => Syndrome::Unknown,
-            0b00_0001 => Syndrome::WFX,
+            0b00_0001 => Syndrome::WFx(WFxType::from(origin)),
+            0b00_0111 => Syndrome::SimdFp,
             0b01_0010 => Syndrome::HVC,
             0b01_0110 => Syndrome::HVC,
             0b01_0011 => Syndrome::SMC,
             0b01_0111 => Syndrome::SMC,
-            0b01_1000 => Syndrome::SysRegInst,
+            0b01_1000 => Syndrome::MsrMrs(MsrMrsIss::from(origin)),
+            0b01_1001 => Syndrome::Sve,
             0b10_0000 => {
                 debug!("Instruction Abort from a lower Exception level");
                 Syndrome::InstructionAbort(Fault::from(origin))
@@ -70,12 +340,115 @@ impl From<u32> for Syndrome {
                 Syndrome::DataAbort(Fault::from(origin))
             }
             0b10_0110 => Syndrome::SPAlignmentFault,
+            ec @ 0b11_0000..=0b11_0101 => Syndrome::Debug(ec),
             0b11_1100 => Syndrome::Brk((origin & ESR_EL2::ISS_BRK_CMT as u32) as u16),
             ec => Syndrome::Other(ec as u32),
         }
     }
 }
 
+impl Syndrome {
+    /// Returns the decoded ISS if `esr` holds a data abort, otherwise `None`.
+    pub fn data_abort_iss(esr: u32) -> Option<DataAbortIss> {
+        match Syndrome::from(esr) {
+            Syndrome::DataAbort(_) => Some(DataAbortIss::from(esr)),
+            _ => None,
+        }
+    }
+
+    /// Returns the immediate of the instruction if `esr` holds an HVC, otherwise `None`.
+    pub fn hvc_imm(esr: u32) -> Option<u16> {
+        match Syndrome::from(esr) {
+            Syndrome::HVC => Some((esr & ESR_EL2::ISS_BRK_CMT as u32) as u16),
+            _ => None,
+        }
+    }
+
+    /// Length in bytes of the trapped instruction by the IL bit of `esr`,
+    /// which is clear only for the 16-bit T32 instructions.
+    pub fn instruction_len(esr: u32) -> u64 {
+        match esr & ESR_EL2::IL as u32 {
+            0 => 2,
+            _ => 4,
+        }
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Fault::AddressSize { level } => write!(f, "level {} address size fault", level),
+            Fault::Translation { level } => write!(f, "level {} translation fault", level),
+            Fault::AccessFlag { level } => write!(f, "level {} access flag fault", level),
+            Fault::Permission { level } => write!(f, "level {} permission fault", level),
+            Fault::Alignment => write!(f, "alignment fault"),
+            Fault::TLBConflict => write!(f, "TLB conflict abort"),
+            Fault::Other(fsc) => write!(f, "fault status {:#04x}", fsc),
+        }
+    }
+}
+
+/// Human-readable form of a syndrome, e.g., "DataAbort, level 3 translation fault, write, S1PTW=0"
+#[derive(Copy, Clone)]
+pub struct Description(u32);
+
+/// Describes the exception held by `esr` for logging
+pub fn describe(esr: u32) -> Description {
+    Description(esr)
+}
+
+impl fmt::Display for Description {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let esr = self.0;
+        match Syndrome::from(esr) {
+            Syndrome::DataAbort(fault) => {
+                let iss = DataAbortIss::from(esr);
+                let access = match iss.wnr {
+                    true => "write",
+                    false => "read",
+                };
+                write!(
+                    f,
+                    "DataAbort, {}, {}, S1PTW={}",
+                    fault, access, iss.s1ptw as u8
+                )?;
+                if let Some(access) = iss.access {
+                    write!(f, ", SAS={} SRT={}", access.sas, access.srt)?;
+                }
+                Ok(())
+            }
+            Syndrome::InstructionAbort(fault) => {
+                let s1ptw = EsrEl2::new(esr as u64).get_masked(EsrEl2::S1PTW) != 0;
+                write!(f, "InstructionAbort, {}, S1PTW={}", fault, s1ptw as u8)
+            }
+            Syndrome::MsrMrs(iss) => write!(
+                f,
+                "{}, op0={} op1={} CRn={} CRm={} op2={}, x{}",
+                if iss.is_read { "MRS" } else { "MSR" },
+                iss.op0,
+                iss.op1,
+                iss.crn,
+                iss.crm,
+                iss.op2,
+                iss.rt
+            ),
+            Syndrome::HVC => write!(f, "HVC #{:#x}", esr & ESR_EL2::ISS_BRK_CMT as u32),
+            Syndrome::SMC => write!(f, "SMC #{:#x}", esr & ESR_EL2::ISS_BRK_CMT as u32),
+            Syndrome::Brk(comment) => write!(f, "BRK #{:#x}", comment),
+            Syndrome::WFx(wfx) => write!(f, "{:?}", wfx),
+            Syndrome::Unknown => write!(f, "Unknown reason"),
+            Syndrome::Other(ec) => write!(f, "unknown EC {:#04x}", ec),
+            other => write!(f, "{:?}", other),
+        }
+    }
+}
+
+impl fmt::Debug for Description {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (ESR: {:#x})", self, self.0)
+    }
+}
+
 impl Into<u64> for Syndrome {
     fn into(self) -> u64 {
         match self {
@@ -101,3 +474,177 @@ impl Into<u64> for Fault {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_msr_mrs_decode() {
+        // mrs x0, id_aa64pfr0_el1
+        const ESR: u32 = 0x6230_0009;
+
+        let iss = match Syndrome::from(ESR) {
+            Syndrome::MsrMrs(iss) => iss,
+            other => panic!("unexpected syndrome: {:?}", other),
+        };
+        assert_eq!(
+            iss,
+            MsrMrsIss {
+                op0: 3,
+                op1: 0,
+                crn: 0,
+                crm: 4,
+                op2: 0,
+                rt: 0,
+                is_read: true,
+            }
+        );
+        assert!(iss.is_id_reg());
+        assert_eq!(iss.encoding(), armv9a::regs::ISS_ID_AA64PFR0_EL1);
+    }
+
+    #[test]
+    fn test_data_abort_decode() {
+        // str w1, [x2] to a level 3 translation fault
+        const ESR: u32 = 0x9381_0047;
+
+        let iss = Syndrome::data_abort_iss(ESR).unwrap();
+        assert!(matches!(iss.fault, Fault::Translation { level: 3 }));
+        assert_eq!(
+            iss.access,
+            Some(AccessSyndrome {
+                sas: 2,
+                sse: false,
+                srt: 1,
+                sf: false,
+            })
+        );
+        assert!(iss.wnr);
+        assert!(!iss.s1ptw);
+        assert_eq!(iss.esr(), ESR as u64);
+        assert_eq!(EsrEl2::new(iss.esr()).get_access_size_mask(), 0xffff_ffff);
+
+        // the same without ISV, whose access fields aren't forwarded
+        let iss = Syndrome::data_abort_iss(0x9281_00c7).unwrap();
+        assert!(!iss.is_valid());
+        assert!(iss.s1ptw);
+        assert_eq!(iss.esr(), 0x9200_00c7);
+
+        assert!(Syndrome::data_abort_iss(0x8200_0007).is_none());
+        assert!(Syndrome::data_abort_iss(0x5a00_0000).is_none());
+    }
+
+    #[test]
+    fn test_cache_op_decode() {
+        // dc cvac, x3
+        const ESR: u32 = 0x6212_dc74;
+
+        let iss = match Syndrome::from(ESR) {
+            Syndrome::MsrMrs(iss) => iss,
+            other => panic!("unexpected syndrome: {:?}", other),
+        };
+        assert!(iss.is_sys_inst());
+        assert_eq!(iss.rt, 3);
+        assert!(!iss.is_read);
+
+        let op = CacheOp::decode(&iss).unwrap();
+        assert_eq!(op, CacheOp::DcCvac);
+        assert!(op.by_va());
+        assert!(!op.is_icache());
+
+        // dc cisw, x3 is by set/way
+        let iss = MsrMrsIss::from(0x6214_1c7c);
+        assert_eq!(CacheOp::decode(&iss), Some(CacheOp::DcCisw));
+        assert!(!CacheOp::DcCisw.by_va());
+
+        // dc zva, x3 and mrs x0, id_aa64pfr0_el1 aren't cache maintenance
+        assert_eq!(CacheOp::decode(&MsrMrsIss::from(0x6212_dc68)), None);
+        assert_eq!(CacheOp::decode(&MsrMrsIss::from(0x6230_0009)), None);
+    }
+
+    #[test]
+    fn test_simd_fp_decode() {
+        // EC 0b000111 with IL set
+        assert!(matches!(Syndrome::from(0x1e00_0000), Syndrome::SimdFp));
+        assert!(matches!(Syndrome::from(0x0600_0000), Syndrome::WFx(_)));
+        // EC 0b011001
+        assert!(matches!(Syndrome::from(0x6600_0000), Syndrome::Sve));
+    }
+
+    #[test]
+    fn test_debug_decode() {
+        // software step and watchpoint from a lower EL
+        assert!(matches!(Syndrome::from(0xca00_0000), Syndrome::Debug(0x32)));
+        assert!(matches!(Syndrome::from(0xd200_0000), Syndrome::Debug(0x34)));
+        assert!(matches!(Syndrome::from(0xf200_0001), Syndrome::Brk(1)));
+    }
+
+    #[test]
+    fn test_serror_decode() {
+        // asynchronous SError, uncontainable
+        let iss = SErrorIss::from(0xbe00_0011);
+        assert_eq!(
+            iss,
+            SErrorIss {
+                ids: false,
+                aet: SErrorType::Uncontainable,
+                ea: false,
+                dfsc: 0x11,
+            }
+        );
+        assert!(iss.is_uncontainable());
+
+        // asynchronous SError, recoverable, external abort
+        let iss = SErrorIss::from(0xbe00_0e11);
+        assert_eq!(iss.aet, SErrorType::Recoverable);
+        assert!(iss.ea);
+        assert!(iss.is_async());
+        assert!(!iss.is_uncontainable());
+
+        // IMPLEMENTATION DEFINED syndrome
+        let iss = SErrorIss::from(0xbf00_0000);
+        assert!(iss.ids);
+        assert!(!iss.is_uncontainable());
+    }
+
+    #[test]
+    fn test_describe() {
+        extern crate alloc;
+        use alloc::string::ToString;
+
+        let cases = [
+            // ldr from a level 3 translation fault without a valid syndrome
+            (
+                0x9200_0007,
+                "DataAbort, level 3 translation fault, read, S1PTW=0",
+            ),
+            (
+                0x9200_0047,
+                "DataAbort, level 3 translation fault, write, S1PTW=0",
+            ),
+            // str x1 to a page with a permission fault on the stage 1 walk
+            (
+                0x93c1_00cf,
+                "DataAbort, level 3 permission fault, write, S1PTW=1, SAS=3 SRT=1",
+            ),
+            (0x9200_0021, "DataAbort, alignment fault, read, S1PTW=0"),
+            (
+                0x8200_0006,
+                "InstructionAbort, level 2 translation fault, S1PTW=0",
+            ),
+            (0x6230_0009, "MRS, op0=3 op1=0 CRn=0 CRm=4 op2=0, x0"),
+            (0x5e00_0000, "SMC #0x0"),
+            (0x5a00_0001, "HVC #0x1"),
+            (0x0600_0000, "WFI"),
+            (0x0200_0000, "Unknown reason"),
+            (0x1e00_0000, "SimdFp"),
+            // EC 0b101111 (SError) isn't handled as a synchronous exception
+            (0xbe00_0011, "unknown EC 0x2f"),
+            (0xfc00_0000, "unknown EC 0x3f"),
+        ];
+        for (esr, expected) in cases {
+            assert_eq!(describe(esr).to_string(), expected, "ESR: {:#x}", esr);
+        }
+    }
+}
@@ -3,12 +3,15 @@ use vmsa::error::Error;
 use vmsa::guard::EntryGuard;
 use vmsa::page_table::{self, Level};
 
-use super::translation::{add_l1_table, addr_to_idx, get_l1_table_addr, L0_TABLE_ENTRY_SIZE_RANGE};
+use super::translation::{
+    add_l1_table, addr_to_idx, get_l1_table_addr, GranuleStatusTable, L0_TABLE_ENTRY_SIZE_RANGE,
+};
 use super::{GranuleState, GRANULE_SIZE};
 use spinning_top::Spinlock;
 
 extern crate alloc;
 use alloc::rc::Rc;
+use core::sync::atomic::{AtomicU64, Ordering};
 
 // Safety: concurrency safety
 //  - For a granule status table that manages granules, it doesn't use a big lock for efficiency. So, we need to associate "lock" with each granule entry.
@@ -16,71 +19,101 @@ use alloc::rc::Rc;
 //  - There are two points that requires locking,
 //    - (1) subtable creation: `set_with_page_table_flags_via_alloc()` is in charge of it. In this function, validity_check-memory_alloc-set_state should be done under the lock.
 //    - (2) state change on entry: `set()` should be done under the lock.
+//      On top of that, the state word is only changed by `transition()` from the state observed by the caller,
+//      so that a granule which is first tracked by `set()` can't be overwritten by another CPU in between.
 //  - Each entry can be either table (L1 table) or granule. This is determined by `Inner.table`.
 
+/// Word holding the state of a granule
+pub trait StateWord {
+    fn load(&self) -> u64;
+    fn compare_exchange(&self, current: u64, new: u64) -> Result<u64, u64>;
+}
+
+impl StateWord for AtomicU64 {
+    fn load(&self) -> u64 {
+        AtomicU64::load(self, Ordering::Acquire)
+    }
+
+    fn compare_exchange(&self, current: u64, new: u64) -> Result<u64, u64> {
+        AtomicU64::compare_exchange(self, current, new, Ordering::AcqRel, Ordering::Acquire)
+    }
+}
+
+/// Moves the state in `word` from `from` to `to` with compare-and-swap,
+/// which fails with MmStateError on an invalid transition
+/// or if the state is no longer the one observed.
+pub fn transition(word: &impl StateWord, from: u64, to: u64) -> Result<(), Error> {
+    if let Err(e) = GranuleStatusTable::transition(from, to) {
+        error!(
+            "Granule state transition failed: prev[{:?}] -> next[{:?}]",
+            from, to
+        );
+        return Err(e);
+    }
+    word.compare_exchange(from, to)
+        .map(|_| ())
+        .map_err(|_| Error::MmStateError)
+}
+
 pub struct Granule {
     /// granule state
-    state: u64,
+    state: AtomicU64,
     /// physical address which is aligned with GRANULE_SIZE
     addr: usize,
     /// parent that this granule points to
-    /// the only case at this point is "Rd(parent) - Rec(child)"
+    /// the cases at this point are "Rd(parent) - Rec(child)" and "Rec(parent) - RecAux(child)"
     /// Notice: do not put self-reference into this field, which may cause undefined behaviors.
     parent: Option<Inner>,
+    /// number of live entries in this granule which refer to other granules
+    /// the only case at this point is "RTT - Data"
+    refcount: usize,
+    /// id of the realm the granule is assigned to, which is cleared on its way back to Delegated
+    /// the only case at this point is "Data"
+    owner: Option<usize>,
 }
 
 impl Granule {
-    fn set_state<F>(&mut self, addr: usize, state: u64, destroy_callback: F) -> Result<(), Error>
+    fn set_state<F>(
+        &mut self,
+        addr: usize,
+        prev: u64,
+        state: u64,
+        destroy_callback: F,
+    ) -> Result<(), Error>
     where
         F: Fn() -> Result<(), Error>,
     {
-        // check if this state transition is valid
-        let prev = self.state;
-        let valid = match prev {
-            GranuleState::Undelegated => {
-                state == GranuleState::Delegated || state == GranuleState::Undelegated
-            }
-            GranuleState::Delegated => state != GranuleState::Delegated,
-            _ => state == GranuleState::Delegated,
-        };
-        if !valid {
-            error!(
-                "Granule state transition failed: prev[{:?}] -> next[{:?}]",
-                prev, state
-            );
-            return Err(Error::MmStateError);
+        // transition from something to Delegatated means "destroyed". (e.g., Rd --> Delegated when REALM_DESTROY)
+        // so, it checks its refcount to determine whether it's safe to get destroyed.
+        let destroy = state == GranuleState::Delegated;
+        if destroy && prev != GranuleState::RTT {
+            destroy_callback()?;
+        }
+
+        transition(&self.state, prev, state)?;
+
+        if destroy {
+            // and releases its parent once it is destroyed.
+            self.parent.take();
+            self.refcount = 0;
+            self.owner = None;
         }
 
         // check if it needs to be wiped out
-        match state {
-            GranuleState::Delegated => {
-                // transition from something to Delegatated means "destroyed". (e.g., Rd --> Delegated when REALM_DESTROY)
-                // so, it releases its parent and check its refcount to determine whether it's safe to get destroyed.
-                // Note: currently, as we don't map RTT, rule out the case where prev == RTT.
-                if prev != GranuleState::RTT {
-                    self.parent.take();
-                    destroy_callback()?;
-                    self.zeroize();
-                }
-            }
-            GranuleState::Undelegated => {
-                if prev == GranuleState::Delegated {
-                    self.zeroize();
-                }
-            }
-            _ => {}
+        if GranuleState::needs_scrub(prev, state) {
+            self.zeroize();
         }
 
         self.addr = addr;
-        self.state = state;
         Ok(())
     }
 
     fn set_parent(&mut self, parent: Inner) -> Result<(), Error> {
         // parent-child state validation check
-        // (Parent, Child): (Rd, Rec) --> only one case at this moment.
-        if self.state() != GranuleState::Rec || parent.granule.state() != GranuleState::RD {
-            return Err(Error::MmWrongParentChild);
+        // (Parent, Child): (Rd, Rec), (Rec, RecAux)
+        match (parent.granule.state(), self.state()) {
+            (GranuleState::RD, GranuleState::Rec) | (GranuleState::Rec, GranuleState::RecAux) => {}
+            _ => return Err(Error::MmWrongParentChild),
         }
         self.parent = Some(parent);
         Ok(())
@@ -95,15 +128,13 @@ impl Granule {
     }
 
     fn state(&self) -> u64 {
-        self.state
+        self.state.load(Ordering::Acquire)
     }
 
     #[cfg(not(test))]
     fn zeroize(&mut self) {
-        let buf = self.addr;
-        unsafe {
-            core::ptr::write_bytes(buf as *mut usize, 0x0, GRANULE_SIZE / 8);
-        }
+        // Safety: the granule is mapped to RMM during its state transition
+        unsafe { super::scrub(self.addr) };
     }
 
     #[cfg(test)]
@@ -120,9 +151,11 @@ impl Inner {
     fn new() -> Self {
         Self {
             granule: Rc::new(Granule {
-                state: GranuleState::Undelegated,
+                state: AtomicU64::new(GranuleState::Undelegated),
                 addr: 0,
                 parent: None,
+                refcount: 0,
+                owner: None,
             }),
             table: false,
             valid: false,
@@ -138,12 +171,18 @@ impl Inner {
     }
 
     pub fn set_state(&mut self, addr: PhysAddr, state: u64) -> Result<(), Error> {
+        self.transition(addr, self.state(), state)
+    }
+
+    /// Moves the granule from `prev` to `state`,
+    /// which fails with MmStateError if it is no longer at `prev`.
+    pub fn transition(&mut self, addr: PhysAddr, prev: u64, state: u64) -> Result<(), Error> {
         let refcount = Rc::strong_count(&self.granule);
 
         Rc::get_mut(&mut self.granule).map_or_else(
             || Err(Error::MmRefcountError),
             |g| {
-                g.set_state(addr.as_usize(), state, || {
+                g.set_state(addr.as_usize(), prev, state, || {
                     if refcount > 1 {
                         // if this is a command to destroy granule (e.g., Rd -> Delegated),
                         // there has to be no one who points to it.
@@ -160,11 +199,59 @@ impl Inner {
         Ok(())
     }
 
+    pub fn refcount(&self) -> usize {
+        self.granule.refcount
+    }
+
+    pub fn inc_refcount(&mut self) -> Result<(), Error> {
+        let g = Rc::get_mut(&mut self.granule).ok_or(Error::MmRefcountError)?;
+        g.refcount += 1;
+        Ok(())
+    }
+
+    pub fn add_refcount(&mut self, count: usize) -> Result<(), Error> {
+        let g = Rc::get_mut(&mut self.granule).ok_or(Error::MmRefcountError)?;
+        g.refcount = g
+            .refcount
+            .checked_add(count)
+            .ok_or(Error::MmRefcountError)?;
+        Ok(())
+    }
+
+    pub fn dec_refcount(&mut self) -> Result<(), Error> {
+        let g = Rc::get_mut(&mut self.granule).ok_or(Error::MmRefcountError)?;
+        g.refcount = g.refcount.checked_sub(1).ok_or(Error::MmRefcountError)?;
+        Ok(())
+    }
+
+    /// Whether other granules refer to this one as their parent
+    pub fn is_referenced(&self) -> bool {
+        Rc::strong_count(&self.granule) > 1
+    }
+
     pub fn set_parent(&mut self, parent: Inner) -> Result<(), Error> {
         Rc::get_mut(&mut self.granule)
             .map_or_else(|| Err(Error::MmRefcountError), |g| g.set_parent(parent))
     }
 
+    pub fn owner(&self) -> Option<usize> {
+        self.granule.owner
+    }
+
+    pub fn set_owner(&mut self, owner: usize) -> Result<(), Error> {
+        let g = Rc::get_mut(&mut self.granule).ok_or(Error::MmRefcountError)?;
+        g.owner = Some(owner);
+        Ok(())
+    }
+
+    /// Fails unless the granule is unowned or assigned to the realm `owner`
+    pub fn check_owner(&self, owner: usize) -> Result<(), Error> {
+        match self.granule.owner {
+            Some(id) if id != owner => Err(Error::MmStateError),
+            _ => Ok(()),
+        }
+    }
+
     pub fn check_parent(&self, parent: &Inner) -> Result<(), Error> {
         if let Some(src_parent) = &self.granule.parent {
             if src_parent as *const Inner == parent as *const Inner {
@@ -239,8 +326,12 @@ impl page_table::Entry for Entry {
         Some(PhysAddr::from(self.0.lock().addr()))
     }
 
+    /// Starts tracking the granule at `addr` in the state `flags`,
+    /// which fails if another CPU has moved it out of Undelegated in the meantime.
     fn set(&mut self, addr: PhysAddr, flags: u64, _is_raw: bool) -> Result<(), Error> {
-        self.0.lock().set_state(addr, flags)
+        self.0
+            .lock()
+            .transition(addr, GranuleState::Undelegated, flags)
     }
 
     fn set_with_page_table_flags(&mut self, _addr: PhysAddr) -> Result<(), Error> {
@@ -61,6 +61,19 @@ impl GranuleState {
     pub fn new(state: u64) -> Self {
         Self { inner: state }
     }
+
+    /// Checks if the contents must be wiped out on the transition.
+    /// A granule destroyed back to Delegated may hold realm data or metadata,
+    /// while a newly delegated one only holds what the host already owns.
+    pub fn needs_scrub(prev: u64, next: u64) -> bool {
+        matches!(
+            (prev, next),
+            (
+                Self::RD | Self::Rec | Self::RecAux | Self::Data | Self::RTT,
+                Self::Delegated
+            ) | (Self::Delegated, Self::Undelegated)
+        )
+    }
 }
 
 /// Safety / Usage: "granule transaction" a set of APIs that define how to access "granule" and contents inside it.
@@ -191,6 +204,22 @@ pub fn set_granule(granule: &mut Inner, state: u64) -> Result<(), RmiError> {
     Ok(())
 }
 
+/// Moves the granule to `state` as assigned to the realm `owner`,
+/// which fails if another realm owns it.
+pub fn set_granule_with_owner(
+    granule: &mut Inner,
+    state: u64,
+    owner: usize,
+) -> Result<(), RmiError> {
+    to_rmi_result(granule.check_owner(owner))?;
+    set_granule(granule, state)?;
+    to_rmi_result(granule.set_owner(owner))
+}
+
+pub fn check_granule_owner(granule: &Inner, owner: usize) -> Result<(), RmiError> {
+    to_rmi_result(granule.check_owner(owner))
+}
+
 pub fn check_granule_parent(parent: &Inner, child: &Inner) -> Result<(), RmiError> {
     to_rmi_result(child.check_parent(parent))
 }
@@ -256,17 +285,36 @@ pub fn is_granule_aligned(addr: usize) -> bool {
     addr % GRANULE_SIZE == 0
 }
 
+/// Zeroes the granule at `pa` with volatile writes not to be optimized away.
+///
+/// # Safety
+///
+/// `pa` must be a granule aligned address mapped to RMM as writable.
+pub unsafe fn scrub(pa: usize) {
+    let buf = pa as *mut u64;
+    for i in 0..GRANULE_SIZE / core::mem::size_of::<u64>() {
+        core::ptr::write_volatile(buf.add(i), 0);
+    }
+}
+
 #[cfg(test)]
-mod test {
+pub(crate) mod test {
+    use crate::granule::entry::{transition, StateWord};
+    use crate::granule::set_granule_raw;
     use crate::granule::translation::{GranuleStatusTable, GRANULE_STATUS_TABLE};
-    use crate::granule::{set_granule, GranuleState};
-    use crate::set_state_and_get_granule;
+    use crate::granule::{scrub, set_granule, set_granule_with_owner, set_granule_with_parent};
+    use crate::granule::{GranuleState, GRANULE_SIZE};
+    use crate::{get_granule, set_state_and_get_granule};
+    use core::cell::Cell;
+    use core::sync::atomic::AtomicU64;
+    use vmsa::address::PhysAddr;
     use vmsa::error::Error;
 
-    const TEST_ADDR: usize = 0x880c_0000;
+    pub(crate) const TEST_ADDR: usize = 0x880c_0000;
+    pub(crate) const TEST_ADDR2: usize = 0x880c_1000;
     const TEST_WRONG_ADDR: usize = 0x7900_0000;
 
-    fn recreate_granule_status_table() {
+    pub(crate) fn recreate_granule_status_table() {
         unsafe {
             if GRANULE_STATUS_TABLE.is_none() {
                 GRANULE_STATUS_TABLE = Some(GranuleStatusTable::new());
@@ -338,4 +386,255 @@ mod test {
         };
         assert!(test_fn(TEST_ADDR).is_ok());
     }
+
+    #[test]
+    fn test_legal_transitions() {
+        let assigned = [
+            GranuleState::RD,
+            GranuleState::Rec,
+            GranuleState::RecAux,
+            GranuleState::Data,
+            GranuleState::RTT,
+        ];
+        let transition = GranuleStatusTable::transition;
+
+        assert!(transition(GranuleState::Undelegated, GranuleState::Delegated).is_ok());
+        assert!(transition(GranuleState::Undelegated, GranuleState::Undelegated).is_ok());
+        assert!(transition(GranuleState::Delegated, GranuleState::Undelegated).is_ok());
+        for state in assigned {
+            assert!(transition(GranuleState::Delegated, state).is_ok());
+            assert!(transition(state, GranuleState::Delegated).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_illegal_transitions() {
+        let cases = [
+            (GranuleState::Delegated, GranuleState::Delegated),
+            (GranuleState::Undelegated, GranuleState::Data),
+            (GranuleState::RD, GranuleState::Undelegated),
+            (GranuleState::Rec, GranuleState::RTT),
+            (GranuleState::RTT, GranuleState::RTT),
+            (GranuleState::Delegated, GranuleState::RTT + 1),
+            (GranuleState::RTT + 1, GranuleState::Delegated),
+        ];
+        for (prev, next) in cases {
+            assert_eq!(
+                GranuleStatusTable::transition(prev, next),
+                Err(Error::MmStateError),
+                "{} -> {}",
+                prev,
+                next
+            );
+        }
+    }
+
+    #[test]
+    fn test_rtt_refcount() {
+        recreate_granule_status_table();
+
+        let test_fn = || -> Result<(), Error> {
+            let mut rtt = set_state_and_get_granule!(TEST_ADDR, GranuleState::Delegated)?;
+            assert!(set_granule(&mut rtt, GranuleState::RTT).is_ok());
+            assert_eq!(rtt.refcount(), 0);
+
+            rtt.inc_refcount()?;
+            rtt.inc_refcount()?;
+            rtt.dec_refcount()?;
+            assert_eq!(rtt.refcount(), 1);
+            rtt.dec_refcount()?;
+            assert_eq!(rtt.dec_refcount(), Err(Error::MmRefcountError));
+            assert_eq!(rtt.refcount(), 0);
+            Ok(())
+        };
+        assert!(test_fn().is_ok());
+    }
+
+    #[test]
+    fn test_destroy_with_refcount() {
+        recreate_granule_status_table();
+
+        let test_fn = || -> Result<(), Error> {
+            let mut rd = set_state_and_get_granule!(TEST_ADDR, GranuleState::Delegated)?;
+            assert!(set_granule(&mut rd, GranuleState::RD).is_ok());
+
+            let mut rec = set_state_and_get_granule!(TEST_ADDR2, GranuleState::Delegated)?;
+            assert!(set_granule_with_parent(rd.clone(), &mut rec, GranuleState::Rec).is_ok());
+
+            // Rd is still referenced by Rec
+            assert_eq!(
+                rd.set_state(PhysAddr::from(TEST_ADDR), GranuleState::Delegated),
+                Err(Error::MmRefcountError)
+            );
+
+            assert!(set_granule(&mut rec, GranuleState::Delegated).is_ok());
+            assert!(set_granule(&mut rd, GranuleState::Delegated).is_ok());
+            Ok(())
+        };
+        assert!(test_fn().is_ok());
+    }
+
+    #[test]
+    fn test_destroy_with_aux() {
+        const TEST_ADDR3: usize = 0x880c_2000;
+        recreate_granule_status_table();
+
+        let test_fn = || -> Result<(), Error> {
+            let mut rd = set_state_and_get_granule!(TEST_ADDR, GranuleState::Delegated)?;
+            assert!(set_granule(&mut rd, GranuleState::RD).is_ok());
+            let mut rec = set_state_and_get_granule!(TEST_ADDR2, GranuleState::Delegated)?;
+            assert!(set_granule_with_parent(rd.clone(), &mut rec, GranuleState::Rec).is_ok());
+
+            // only a REC takes auxiliary granules
+            let mut aux = set_state_and_get_granule!(TEST_ADDR3, GranuleState::Delegated)?;
+            assert!(set_granule_with_parent(rd.clone(), &mut aux, GranuleState::RecAux).is_err());
+            assert_eq!(aux.state(), GranuleState::Delegated);
+            assert!(set_granule_with_parent(rec.clone(), &mut aux, GranuleState::RecAux).is_ok());
+            assert_eq!(aux.state(), GranuleState::RecAux);
+
+            // Rec is still referenced by its auxiliary granule
+            assert_eq!(
+                rec.set_state(PhysAddr::from(TEST_ADDR2), GranuleState::Delegated),
+                Err(Error::MmRefcountError)
+            );
+            assert_eq!(rec.state(), GranuleState::Rec);
+
+            assert!(set_granule(&mut aux, GranuleState::Delegated).is_ok());
+            assert!(set_granule(&mut rec, GranuleState::Delegated).is_ok());
+            assert!(set_granule(&mut rd, GranuleState::Delegated).is_ok());
+            assert!(set_granule(&mut aux, GranuleState::Undelegated).is_ok());
+            Ok(())
+        };
+        assert!(test_fn().is_ok());
+    }
+
+    #[test]
+    fn data_granule_of_another_realm() {
+        const REALM_A: usize = 1;
+        const REALM_B: usize = 2;
+        recreate_granule_status_table();
+
+        let test_fn = || -> Result<(), Error> {
+            let mut data = set_state_and_get_granule!(TEST_ADDR, GranuleState::Delegated)?;
+            assert_eq!(data.owner(), None);
+            assert!(data.check_owner(REALM_B).is_ok());
+
+            assert!(set_granule_with_owner(&mut data, GranuleState::Data, REALM_A).is_ok());
+            assert_eq!(data.owner(), Some(REALM_A));
+            assert_eq!(data.check_owner(REALM_B), Err(Error::MmStateError));
+            assert!(data.check_owner(REALM_A).is_ok());
+            assert!(set_granule_with_owner(&mut data, GranuleState::Data, REALM_B).is_err());
+            assert_eq!(data.owner(), Some(REALM_A));
+
+            // released by realm A
+            assert!(set_granule(&mut data, GranuleState::Delegated).is_ok());
+            assert_eq!(data.owner(), None);
+            assert!(set_granule_with_owner(&mut data, GranuleState::Data, REALM_B).is_ok());
+            assert_eq!(data.owner(), Some(REALM_B));
+            Ok(())
+        };
+        assert!(test_fn().is_ok());
+    }
+
+    #[test]
+    fn scrub_granule() {
+        #[repr(C, align(4096))]
+        struct Page([u8; GRANULE_SIZE]);
+
+        let mut page = Page([0xab; GRANULE_SIZE]);
+        unsafe { scrub(page.0.as_mut_ptr() as usize) };
+        assert!(page.0.iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn scrub_on_destroy_only() {
+        for prev in [
+            GranuleState::RD,
+            GranuleState::Rec,
+            GranuleState::RecAux,
+            GranuleState::Data,
+            GranuleState::RTT,
+        ] {
+            assert!(GranuleState::needs_scrub(prev, GranuleState::Delegated));
+            assert!(!GranuleState::needs_scrub(GranuleState::Delegated, prev));
+        }
+        assert!(GranuleState::needs_scrub(
+            GranuleState::Delegated,
+            GranuleState::Undelegated
+        ));
+        // a newly delegated granule is still owned by the host
+        assert!(!GranuleState::needs_scrub(
+            GranuleState::Undelegated,
+            GranuleState::Delegated
+        ));
+        assert!(!GranuleState::needs_scrub(
+            GranuleState::Undelegated,
+            GranuleState::Undelegated
+        ));
+    }
+
+    /// State word of which another CPU wins the first compare-and-swap with `rival`
+    struct Racing {
+        word: AtomicU64,
+        rival: Cell<Option<u64>>,
+    }
+
+    impl StateWord for Racing {
+        fn load(&self) -> u64 {
+            StateWord::load(&self.word)
+        }
+
+        fn compare_exchange(&self, current: u64, new: u64) -> Result<u64, u64> {
+            if let Some(rival) = self.rival.take() {
+                let _ = StateWord::compare_exchange(&self.word, current, rival);
+            }
+            StateWord::compare_exchange(&self.word, current, new)
+        }
+    }
+
+    #[test]
+    fn racing_delegates() {
+        let granule = Racing {
+            word: AtomicU64::new(GranuleState::Undelegated),
+            rival: Cell::new(Some(GranuleState::Delegated)),
+        };
+        // both observe Undelegated, and the other CPU delegates it first
+        let observed = granule.load();
+        assert_eq!(
+            transition(&granule, observed, GranuleState::Delegated),
+            Err(Error::MmStateError)
+        );
+        assert_eq!(granule.load(), GranuleState::Delegated);
+
+        // nothing changes on the way back either
+        let observed = granule.load();
+        assert_eq!(
+            transition(&granule, observed, GranuleState::Delegated),
+            Err(Error::MmStateError)
+        );
+        assert_eq!(
+            transition(&granule, observed, GranuleState::Undelegated),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn track_delegated_granule() {
+        recreate_granule_status_table();
+
+        let test_fn = |addr: usize| -> Result<(), Error> {
+            let mut granule = set_state_and_get_granule!(addr, GranuleState::Undelegated)?;
+            set_granule(&mut granule, GranuleState::Delegated).unwrap();
+            drop(granule);
+
+            // a delegate which found the granule untracked comes too late
+            assert_eq!(
+                set_granule_raw(addr, GranuleState::Undelegated),
+                Err(Error::MmStateError)
+            );
+            assert_eq!(get_granule!(addr)?.state(), GranuleState::Delegated);
+            Ok(())
+        };
+        assert!(test_fn(TEST_ADDR).is_ok());
+    }
 }
@@ -45,6 +45,35 @@ impl GranuleStatusTable {
         self.l1_tables.insert(index, L1PageTable::new());
     }
 
+    /// Checks the granule lifecycle, which fails with MmStateError on any
+    /// transition other than:
+    /// - Undelegated <-> Delegated
+    /// - Delegated <-> RD, Rec, RecAux, Data, RTT
+    /// Staying Undelegated is allowed as the GST lazily tracks granules.
+    pub fn transition(prev: u64, next: u64) -> Result<(), Error> {
+        match (prev, next) {
+            (GranuleState::Undelegated, GranuleState::Undelegated | GranuleState::Delegated)
+            | (
+                GranuleState::Delegated,
+                GranuleState::Undelegated
+                | GranuleState::RD
+                | GranuleState::Rec
+                | GranuleState::RecAux
+                | GranuleState::Data
+                | GranuleState::RTT,
+            )
+            | (
+                GranuleState::RD
+                | GranuleState::Rec
+                | GranuleState::RecAux
+                | GranuleState::Data
+                | GranuleState::RTT,
+                GranuleState::Delegated,
+            ) => Ok(()),
+            _ => Err(Error::MmStateError),
+        }
+    }
+
     pub fn set_granule(&mut self, addr: usize, state: u64) -> Result<(), Error> {
         if !validate_addr(addr) {
             return Err(Error::MmInvalidAddr);
@@ -52,6 +52,11 @@ impl DataPage {
     pub fn as_slice(&self) -> &[u8] {
         self.0.as_slice()
     }
+
+    /// Wipes out the contents not to leak realm data
+    pub fn scrub(&mut self) {
+        self.0.fill(0);
+    }
 }
 
 impl Default for DataPage {
@@ -67,3 +72,15 @@ impl Accessor for DataPage {}
 impl Content for DataPage {
     const FLAGS: u64 = GranuleState::Data;
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn scrub_data_page() {
+        let mut page = DataPage([0xab; GRANULE_SIZE]);
+        page.scrub();
+        assert!(page.as_slice().iter().all(|b| *b == 0));
+    }
+}
@@ -12,10 +12,10 @@ pub mod allocator;
 pub mod asm;
 pub mod config;
 pub mod cpu;
+pub mod crypto;
 pub mod error;
 pub mod event;
 pub mod exception;
-pub mod gic;
 #[macro_use]
 pub mod granule;
 #[macro_use]
@@ -88,7 +88,7 @@ unsafe fn setup_el2() {
     );
     VBAR_EL2.set(&vectors as *const u64 as u64);
     SCTLR_EL2.set(SCTLR_EL2::C | SCTLR_EL2::I | SCTLR_EL2::M | SCTLR_EL2::EOS);
-    CPTR_EL2.set(CPTR_EL2::TAM);
+    CPTR_EL2.set(CPTR_EL2::TAM | CPTR_EL2::TFP | CPTR_EL2::TZ);
     ICC_SRE_EL2.set(ICC_SRE_EL2::ENABLE | ICC_SRE_EL2::DIB | ICC_SRE_EL2::DFB | ICC_SRE_EL2::SRE);
 }
 
@@ -1,40 +1,122 @@
 use crate::io::Write;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use log::{Level, LevelFilter, Metadata, Record};
 
-struct SimpleLogger;
 extern crate alloc;
 
-impl log::Log for SimpleLogger {
+/// Where the records passing the threshold of a `SimpleLogger` go
+trait Sink: Send + Sync {
+    fn write(&self, record: &Record<'_>);
+}
+
+struct Console;
+
+impl Sink for Console {
+    fn write(&self, record: &Record<'_>) {
+        if record.metadata().level() <= Level::Warn {
+            crate::eprintln!(
+                "[{}]{} -- {}",
+                record.level(),
+                record.target(),
+                record.args()
+            );
+        } else {
+            crate::println!(
+                "[{}]{} -- {}",
+                record.level(),
+                record.target(),
+                record.args()
+            );
+        }
+    }
+}
+
+struct SimpleLogger<S: Sink> {
+    // LevelFilter as usize, so that any CPU can change it at runtime
+    level: AtomicUsize,
+    sink: S,
+}
+
+impl<S: Sink> SimpleLogger<S> {
+    const fn new(sink: S) -> Self {
+        Self {
+            level: AtomicUsize::new(LevelFilter::Trace as usize),
+            sink,
+        }
+    }
+
+    fn set_level(&self, level: LevelFilter) {
+        self.level.store(level as usize, Ordering::Relaxed);
+    }
+}
+
+impl<S: Sink> log::Log for SimpleLogger<S> {
     fn enabled(&self, metadata: &Metadata<'_>) -> bool {
-        metadata.level() <= Level::Trace
+        metadata.level() as usize <= self.level.load(Ordering::Relaxed)
     }
 
     fn log(&self, record: &Record<'_>) {
         if self.enabled(record.metadata()) {
-            if record.metadata().level() <= Level::Warn {
-                crate::eprintln!(
-                    "[{}]{} -- {}",
-                    record.level(),
-                    record.target(),
-                    record.args()
-                );
-            } else {
-                crate::println!(
-                    "[{}]{} -- {}",
-                    record.level(),
-                    record.target(),
-                    record.args()
-                );
-            }
+            self.sink.write(record);
         }
     }
 
     fn flush(&self) {}
 }
 
-static LOGGER: SimpleLogger = SimpleLogger;
+static LOGGER: SimpleLogger<Console> = SimpleLogger::new(Console);
 
 pub fn register_global_logger(maxlevel: LevelFilter) {
     log::set_logger(&LOGGER).unwrap();
-    log::set_max_level(maxlevel);
+    set_level(maxlevel);
+}
+
+/// Changes the threshold of the messages to be logged, e.g., to
+/// `LevelFilter::Error` to keep realm entries and exits quiet.
+pub fn set_level(level: LevelFilter) {
+    LOGGER.set_level(level);
+    // lets the macros skip formatting below the threshold
+    log::set_max_level(level);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::format;
+    use alloc::string::String;
+    use alloc::vec::Vec;
+    use log::Log;
+    use spin::mutex::Mutex;
+
+    struct Capture(Mutex<Vec<String>>);
+
+    impl Sink for Capture {
+        fn write(&self, record: &Record<'_>) {
+            self.0.lock().push(format!("{}", record.args()));
+        }
+    }
+
+    fn emit(logger: &SimpleLogger<Capture>, level: Level, msg: &str) {
+        logger.log(
+            &Record::builder()
+                .level(level)
+                .args(format_args!("{}", msg))
+                .build(),
+        );
+    }
+
+    #[test]
+    fn suppress_below_threshold() {
+        let logger = SimpleLogger::new(Capture(Mutex::new(Vec::new())));
+
+        emit(&logger, Level::Debug, "entry");
+        logger.set_level(LevelFilter::Error);
+        emit(&logger, Level::Debug, "exit");
+        emit(&logger, Level::Warn, "warn");
+        emit(&logger, Level::Error, "error");
+        logger.set_level(LevelFilter::Off);
+        emit(&logger, Level::Error, "off");
+
+        assert_eq!(*logger.sink.0.lock(), ["entry", "error"]);
+    }
 }
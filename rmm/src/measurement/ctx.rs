@@ -1,7 +1,5 @@
-use super::{
-    Hasher, Measurement, MeasurementError, MEASUREMENTS_SLOT_RIM, MEASURE_DESC_TYPE_DATA,
-    MEASURE_DESC_TYPE_REC, MEASURE_DESC_TYPE_RIPAS, RMI_MEASURE_CONTENT,
-};
+use super::rim::{self, Record};
+use super::{Hasher, Measurement, MeasurementError, MEASUREMENTS_SLOT_RIM, RMI_MEASURE_CONTENT};
 use crate::rmi::rec::params::Params as RecParams;
 use crate::{
     event::RsiHandle,
@@ -34,12 +32,7 @@ impl<'a> HashContext<'a> {
 
     pub fn extend_measurement(&self, buffer: &[u8], index: usize) -> Result<(), rsi::error::Error> {
         self.rsi.measurement_extend(self.rd.id(), index, |current| {
-            let old_value = current.clone();
-
-            self.hasher.hash_fields_into(current, |h| {
-                h.hash(&old_value.as_ref()[0..self.hasher.output_size()]);
-                h.hash(buffer);
-            })
+            self.hasher.extend_into(current, buffer)
         })
     }
 
@@ -57,21 +50,11 @@ impl<'a> HashContext<'a> {
             })?;
         }
 
-        self.rsi
-            .measurement_extend(self.rd.id(), MEASUREMENTS_SLOT_RIM, |current| {
-                let oldrim = current.clone();
-
-                self.hasher.hash_fields_into(current, |h| {
-                    h.hash_u8(MEASURE_DESC_TYPE_DATA); // desc type
-                    h.hash([0u8; 7]); // padding
-                    h.hash_u64(0x100); // desc struct size
-                    h.hash(oldrim); // old RIM value
-                    h.hash_usize(ipa); // ipa
-                    h.hash_usize(flags); // flags
-                    h.hash(data_measurement); // data granule hash
-                    h.hash([0u8; 0x100 - 0xa0]); // padding
-                })
-            })
+        self.record(Record::Data {
+            ipa,
+            flags,
+            content: data_measurement,
+        })
     }
 
     pub fn measure_rec_params(&self, params: &RecParams) -> Result<(), rsi::error::Error> {
@@ -79,36 +62,19 @@ impl<'a> HashContext<'a> {
         self.hasher
             .hash_object_into(params, &mut params_measurement)?;
 
-        self.rsi
-            .measurement_extend(self.rd.id(), MEASUREMENTS_SLOT_RIM, |current| {
-                let oldrim = current.clone();
+        self.record(Record::Rec {
+            params: params_measurement,
+        })
+    }
 
-                self.hasher.hash_fields_into(current, |h| {
-                    h.hash_u8(MEASURE_DESC_TYPE_REC); // desc type
-                    h.hash([0u8; 7]); // padding
-                    h.hash_u64(0x100); // desc struct size
-                    h.hash(oldrim); // old RIM value
-                    h.hash(params_measurement); // REC params hash
-                    h.hash([0u8; 0x100 - 0x90]); // padding
-                })
-            })
+    pub fn measure_ripas_range(&self, base: usize, top: usize) -> Result<(), rsi::error::Error> {
+        self.record(Record::Ripas { base, top })
     }
 
-    pub fn measure_ripas_granule(&self, ipa: usize, level: u8) -> Result<(), rsi::error::Error> {
+    fn record(&self, op: Record) -> Result<(), rsi::error::Error> {
         self.rsi
-            .measurement_extend(self.rd.id(), MEASUREMENTS_SLOT_RIM, |current| {
-                let oldrim = current.clone();
-
-                self.hasher.hash_fields_into(current, |h| {
-                    h.hash_u8(MEASURE_DESC_TYPE_RIPAS); // desc type
-                    h.hash([0u8; 7]); // padding
-                    h.hash_u64(0x100); // desc struct size
-                    h.hash(oldrim); // old RIM value
-                    h.hash_usize(ipa); // ipa
-                    h.hash_u8(level); // level
-                    h.hash([0u8; 7]); // level's padding
-                    h.hash([0u8; 0xa0]); // padding to 0x100 size
-                })
+            .measurement_extend(self.rd.id(), MEASUREMENTS_SLOT_RIM, |rim| {
+                rim::record(&self.hasher, rim, &op)
             })
     }
 }
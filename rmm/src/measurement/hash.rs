@@ -1,14 +1,12 @@
 use alloc::boxed::Box;
-use sha2::Digest;
-use sha2::{digest::DynDigest, Sha256, Sha512};
 
 use crate::{
-    measurement::MeasurementError,
-    rmi::{HASH_ALGO_SHA256, HASH_ALGO_SHA512},
+    crypto::hash::{self, HashAlgo},
+    measurement::{Measurement, MeasurementError},
 };
 
 pub struct HashWrapper {
-    pub hash_func: Box<dyn DynDigest>,
+    pub hash_func: Box<dyn hash::Hasher>,
 }
 
 impl HashWrapper {
@@ -32,10 +30,6 @@ impl HashWrapper {
         self.hash_func.update(data.to_le_bytes().as_slice());
     }
 
-    pub fn hash_usize(&mut self, data: usize) {
-        self.hash_func.update(data.to_le_bytes().as_slice());
-    }
-
     pub fn hash_u64_array(&mut self, array: &[u64]) {
         for el in array.iter() {
             self.hash_func.update(el.to_le_bytes().as_slice());
@@ -44,34 +38,20 @@ impl HashWrapper {
 
     fn finish(&mut self, mut out: impl AsMut<[u8]>) -> Result<(), MeasurementError> {
         self.hash_func
-            .finalize_into_reset(&mut out.as_mut()[0..self.hash_func.output_size()])
+            .finalize(out.as_mut())
             .map_err(|_| MeasurementError::OutputBufferTooSmall)
     }
 }
 
 pub struct Hasher {
-    factory: Box<dyn Fn() -> Box<dyn DynDigest>>,
-    block_size: usize,
+    algo: HashAlgo,
 }
 
 impl Hasher {
     pub fn from_hash_algo(hash_algo: u8) -> Result<Self, MeasurementError> {
-        let factory: Box<dyn Fn() -> Box<dyn DynDigest>> = match hash_algo {
-            HASH_ALGO_SHA256 => Box::new(|| Box::new(Sha256::new())),
-            HASH_ALGO_SHA512 => Box::new(|| Box::new(Sha512::new())),
-            _ => return Err(MeasurementError::InvalidHashAlgorithmValue(hash_algo)),
-        };
-
-        let block_size = match hash_algo {
-            HASH_ALGO_SHA256 => <Sha256 as Digest>::output_size(),
-            HASH_ALGO_SHA512 => <Sha512 as Digest>::output_size(),
-            _ => return Err(MeasurementError::InvalidHashAlgorithmValue(hash_algo)),
-        };
-
-        Ok(Self {
-            factory,
-            block_size,
-        })
+        let algo =
+            HashAlgo::try_from(hash_algo).map_err(MeasurementError::InvalidHashAlgorithmValue)?;
+        Ok(Self { algo })
     }
 
     pub fn hash_fields_into(
@@ -80,7 +60,7 @@ impl Hasher {
         f: impl Fn(&mut HashWrapper),
     ) -> Result<(), MeasurementError> {
         let mut wrapper = HashWrapper {
-            hash_func: (self.factory)(),
+            hash_func: self.algo.hasher(),
         };
         f(&mut wrapper);
         wrapper.finish(out)
@@ -94,8 +74,25 @@ impl Hasher {
         obj.hash(&self, out.as_mut())
     }
 
+    /// Extends `current` with `data` as Hash(current || data)
+    pub fn extend_into(
+        &self,
+        current: &mut Measurement,
+        data: &[u8],
+    ) -> Result<(), MeasurementError> {
+        if current.len() < self.output_size() {
+            return Err(MeasurementError::OutputBufferTooSmall);
+        }
+        let old_value = *current;
+
+        self.hash_fields_into(current, |h| {
+            h.hash(&old_value.as_ref()[0..self.output_size()]);
+            h.hash(data);
+        })
+    }
+
     pub fn output_size(&self) -> usize {
-        self.block_size
+        self.algo.output_len()
     }
 }
 
@@ -1,35 +1,83 @@
 mod ctx;
 mod error;
 mod hash;
+pub mod rim;
 
 pub use ctx::HashContext;
 pub use error::MeasurementError;
 pub use hash::Hashable;
 pub use hash::Hasher;
 
+use crate::crypto::hash::HashAlgo;
+
 pub const MEASUREMENTS_SLOT_MAX_SIZE: usize = 512 / 8;
 pub const MEASUREMENTS_SLOT_NR: usize = 5;
 pub const MEASUREMENTS_SLOT_RIM: usize = 0;
 
+/// Number of registers carrying a measurement, which are X1-X8
+pub const MEASUREMENT_NR_GPRS: usize = MEASUREMENTS_SLOT_MAX_SIZE / core::mem::size_of::<usize>();
+
 pub const RMI_MEASURE_CONTENT: usize = 1;
 
 pub const MEASURE_DESC_TYPE_DATA: u8 = 0;
 pub const MEASURE_DESC_TYPE_REC: u8 = 1;
 pub const MEASURE_DESC_TYPE_RIPAS: u8 = 2;
 
+/// Digest of the size of the hash algorithm it is for,
+/// stored zero-padded to the largest one
 #[derive(Copy, Clone, Debug)]
-pub struct Measurement([u8; MEASUREMENTS_SLOT_MAX_SIZE]);
+pub struct Measurement([u8; MEASUREMENTS_SLOT_MAX_SIZE], usize);
 
 impl Measurement {
+    /// Measurement with room for the digest of any algorithm
     pub const fn empty() -> Self {
-        Self([0u8; MEASUREMENTS_SLOT_MAX_SIZE])
+        Self(
+            [0u8; MEASUREMENTS_SLOT_MAX_SIZE],
+            MEASUREMENTS_SLOT_MAX_SIZE,
+        )
+    }
+
+    /// Measurement holding a digest of `algo`
+    pub fn new(algo: HashAlgo) -> Self {
+        Self([0u8; MEASUREMENTS_SLOT_MAX_SIZE], algo.output_len())
+    }
+
+    pub fn len(&self) -> usize {
+        self.1
     }
 
     pub fn as_slice(&self) -> &[u8] {
-        &self.0
+        &self.0[..self.1]
     }
     pub fn as_mut_slice(&mut self) -> &mut [u8] {
-        &mut self.0
+        &mut self.0[..self.1]
+    }
+
+    /// The whole slot, which is zero beyond the digest
+    pub fn as_padded(&self) -> &[u8; MEASUREMENTS_SLOT_MAX_SIZE] {
+        &self.0
+    }
+
+    /// Reads the digest into `out`, which fails if it can't hold the digest
+    pub fn read_into(&self, out: &mut Measurement) -> Result<(), MeasurementError> {
+        if out.len() < self.len() {
+            return Err(MeasurementError::OutputBufferTooSmall);
+        }
+        out.0 = self.0;
+        Ok(())
+    }
+
+    /// Splits the measurement into little-endian register values,
+    /// zero-padded beyond the size of the digest.
+    pub fn regs(&self) -> [usize; MEASUREMENT_NR_GPRS] {
+        let mut regs = [0; MEASUREMENT_NR_GPRS];
+        for (reg, chunk) in regs
+            .iter_mut()
+            .zip(self.0.chunks_exact(core::mem::size_of::<usize>()))
+        {
+            *reg = usize::from_le_bytes(chunk.try_into().unwrap());
+        }
+        regs
     }
 }
 
@@ -47,6 +95,100 @@ impl AsRef<[u8]> for Measurement {
 
 impl Default for Measurement {
     fn default() -> Self {
-        Measurement([0; MEASUREMENTS_SLOT_MAX_SIZE])
+        Self::empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rmi::HASH_ALGO_SHA256;
+
+    #[test]
+    fn read_rem_after_extend() {
+        let hasher = Hasher::from_hash_algo(HASH_ALGO_SHA256).unwrap();
+        let mut rem = Measurement::empty();
+        hasher.extend_into(&mut rem, b"abc").unwrap();
+
+        let mut expected = Measurement::empty();
+        hasher
+            .hash_fields_into(&mut expected, |h| {
+                h.hash([0u8; 32]);
+                h.hash(b"abc");
+            })
+            .unwrap();
+
+        let regs = rem.regs();
+        assert_eq!(regs, expected.regs());
+        assert_eq!(
+            regs[0],
+            usize::from_le_bytes(rem.0[0..8].try_into().unwrap())
+        );
+        // sha-256 fills only the first half of the slot
+        assert_ne!(regs[0..4], [0; 4]);
+        assert_eq!(regs[4..], [0; 4]);
+    }
+
+    #[test]
+    fn extend_rem_sha256() {
+        let hasher = Hasher::from_hash_algo(HASH_ALGO_SHA256).unwrap();
+        let mut rem = Measurement::empty();
+
+        let data: [u8; 64] = core::array::from_fn(|i| i as u8);
+        hasher.extend_into(&mut rem, &data[0..16]).unwrap();
+        assert_eq!(
+            rem.0[0..32],
+            [
+                0x80, 0x67, 0x4c, 0xf0, 0xb7, 0x91, 0xa5, 0xb8, 0xe7, 0xc5, 0x6d, 0x2d, 0xe5, 0xbd,
+                0x13, 0x6d, 0x16, 0x7f, 0x9a, 0x60, 0x71, 0x82, 0x29, 0x07, 0x62, 0xf0, 0x0a, 0xd8,
+                0x51, 0x0c, 0x20, 0x73
+            ]
+        );
+
+        // only the digest of the current value is taken into the next extension
+        hasher.extend_into(&mut rem, &data).unwrap();
+        assert_eq!(
+            rem.0[0..32],
+            [
+                0xa9, 0x35, 0x42, 0x29, 0xda, 0x20, 0x87, 0x6a, 0x6f, 0x40, 0xc7, 0xec, 0x24, 0x79,
+                0x22, 0x38, 0x1c, 0x85, 0x20, 0x2e, 0x95, 0x15, 0xf6, 0x6a, 0x55, 0x91, 0xe0, 0x4d,
+                0x3d, 0x40, 0x1f, 0x86
+            ]
+        );
+        assert_eq!(rem.0[32..], [0; 32]);
+    }
+
+    #[test]
+    fn sized_by_hash_algo() {
+        use crate::rmi::HASH_ALGO_SHA512;
+
+        let rem = Measurement::new(HashAlgo::Sha256);
+        assert_eq!((rem.len(), rem.as_slice().len()), (32, 32));
+        let mut rem = Measurement::new(HashAlgo::Sha512);
+        assert_eq!((rem.len(), rem.as_slice().len()), (64, 64));
+        assert_eq!(Measurement::empty().len(), MEASUREMENTS_SLOT_MAX_SIZE);
+
+        let sha256 = Hasher::from_hash_algo(HASH_ALGO_SHA256).unwrap();
+        let sha512 = Hasher::from_hash_algo(HASH_ALGO_SHA512).unwrap();
+        sha512.extend_into(&mut rem, b"abc").unwrap();
+        assert_ne!(rem.0[32..], [0; 32]);
+
+        // a SHA-512 digest doesn't fit in a SHA-256 slot
+        let mut small = Measurement::new(HashAlgo::Sha256);
+        assert!(sha512.extend_into(&mut small, b"abc").is_err());
+        assert!(matches!(
+            rem.read_into(&mut small),
+            Err(MeasurementError::OutputBufferTooSmall)
+        ));
+
+        let mut out = Measurement::empty();
+        rem.read_into(&mut out).unwrap();
+        assert_eq!(out.regs(), rem.regs());
+
+        // the slot beyond a SHA-256 digest stays zero
+        sha256.extend_into(&mut small, b"abc").unwrap();
+        small.read_into(&mut out).unwrap();
+        assert_eq!(out.as_slice()[..32], small.as_slice()[..]);
+        assert_eq!(out.regs()[4..], [0; 4]);
     }
 }
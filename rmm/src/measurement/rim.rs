@@ -0,0 +1,136 @@
+use super::{
+    Hasher, Measurement, MeasurementError, MEASUREMENTS_SLOT_MAX_SIZE, MEASURE_DESC_TYPE_DATA,
+    MEASURE_DESC_TYPE_REC, MEASURE_DESC_TYPE_RIPAS,
+};
+
+/// Size of a measurement descriptor, which is also stored in its header
+pub const RECORD_SIZE: usize = 0x100;
+
+const TYPE_OFFSET: usize = 0x0;
+const SIZE_OFFSET: usize = 0x8;
+const RIM_OFFSET: usize = 0x10;
+const BODY_OFFSET: usize = RIM_OFFSET + MEASUREMENTS_SLOT_MAX_SIZE;
+
+/// RMI operations measured into the RIM
+#[derive(Clone, Copy, Debug)]
+pub enum Record {
+    /// RMI_DATA_CREATE, `content` is the hash of the granule or zero if unmeasured
+    Data {
+        ipa: usize,
+        flags: usize,
+        content: Measurement,
+    },
+    /// RMI_REC_CREATE, `params` is the hash of the REC parameters
+    Rec { params: Measurement },
+    /// RMI_RTT_INIT_RIPAS for the processed range
+    Ripas { base: usize, top: usize },
+}
+
+impl Record {
+    fn desc_type(&self) -> u8 {
+        match self {
+            Record::Data { .. } => MEASURE_DESC_TYPE_DATA,
+            Record::Rec { .. } => MEASURE_DESC_TYPE_REC,
+            Record::Ripas { .. } => MEASURE_DESC_TYPE_RIPAS,
+        }
+    }
+
+    /// Serializes the record into the measurement descriptor extending `rim`.
+    /// All fields are little-endian and the unused bytes are zero, so the result
+    /// only depends on the operation and the RIM before it.
+    pub fn serialize(&self, rim: &Measurement) -> [u8; RECORD_SIZE] {
+        let mut desc = [0u8; RECORD_SIZE];
+        desc[TYPE_OFFSET] = self.desc_type();
+        desc[SIZE_OFFSET..SIZE_OFFSET + 8].copy_from_slice(&(RECORD_SIZE as u64).to_le_bytes());
+        desc[RIM_OFFSET..BODY_OFFSET].copy_from_slice(rim.as_padded());
+
+        let body = &mut desc[BODY_OFFSET..];
+        match self {
+            Record::Data {
+                ipa,
+                flags,
+                content,
+            } => {
+                body[0x0..0x8].copy_from_slice(&ipa.to_le_bytes());
+                body[0x8..0x10].copy_from_slice(&flags.to_le_bytes());
+                body[0x10..0x10 + MEASUREMENTS_SLOT_MAX_SIZE].copy_from_slice(content.as_padded());
+            }
+            Record::Rec { params } => {
+                body[..MEASUREMENTS_SLOT_MAX_SIZE].copy_from_slice(params.as_padded());
+            }
+            Record::Ripas { base, top } => {
+                body[0x0..0x8].copy_from_slice(&base.to_le_bytes());
+                body[0x8..0x10].copy_from_slice(&top.to_le_bytes());
+            }
+        }
+        desc
+    }
+}
+
+/// Extends `rim` with the measurement descriptor of `op` as RIM = Hash(descriptor)
+pub fn record(hasher: &Hasher, rim: &mut Measurement, op: &Record) -> Result<(), MeasurementError> {
+    let desc = op.serialize(rim);
+    hasher.hash_fields_into(rim, |h| h.hash(desc))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::granule::GRANULE_SIZE;
+    use crate::rmi::HASH_ALGO_SHA256;
+
+    #[test]
+    fn record_layout() {
+        let mut rim = Measurement::empty();
+        rim.as_mut_slice().fill(0x5a);
+        let desc = Record::Ripas {
+            base: 0x8000_0000,
+            top: 0x8020_0000,
+        }
+        .serialize(&rim);
+
+        assert_eq!(desc[0], MEASURE_DESC_TYPE_RIPAS);
+        assert_eq!(desc[1..8], [0; 7]);
+        assert_eq!(desc[8..16], 0x100u64.to_le_bytes());
+        assert_eq!(desc[16..80], rim.0);
+        assert_eq!(desc[80..88], 0x8000_0000usize.to_le_bytes());
+        assert_eq!(desc[88..96], 0x8020_0000usize.to_le_bytes());
+        assert!(desc[96..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn fixed_rim_digest() {
+        let hasher = Hasher::from_hash_algo(HASH_ALGO_SHA256).unwrap();
+        let mut content = Measurement::empty();
+        hasher
+            .hash_fields_into(&mut content, |h| h.hash([0xaa; GRANULE_SIZE]))
+            .unwrap();
+        let mut params = Measurement::empty();
+        params.0[..32].fill(0x11);
+
+        let ops = [
+            Record::Ripas {
+                base: 0x8000_0000,
+                top: 0x8020_0000,
+            },
+            Record::Data {
+                ipa: 0x8000_0000,
+                flags: 1,
+                content,
+            },
+            Record::Rec { params },
+        ];
+        let mut rim = Measurement::empty();
+        for op in ops.iter() {
+            record(&hasher, &mut rim, op).unwrap();
+        }
+
+        let expected: [u8; 32] = [
+            0x18, 0x6b, 0x7f, 0x96, 0x7f, 0x1a, 0x4c, 0x7c, 0xd8, 0xf2, 0x4e, 0x8d, 0xe2, 0x4e,
+            0xfa, 0xa3, 0x00, 0xfd, 0xaa, 0x3b, 0xac, 0x49, 0x8d, 0x80, 0x95, 0x5c, 0x3f, 0x32,
+            0x66, 0x5b, 0xf0, 0x1e,
+        ];
+        assert_eq!(rim.0[..32], expected);
+        assert_eq!(rim.0[32..], [0; 32]);
+    }
+}
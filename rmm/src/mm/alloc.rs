@@ -0,0 +1,141 @@
+use crate::config::RMM_GRANULE_POOL_SIZE;
+use crate::granule::GRANULE_SIZE;
+
+use lazy_static::lazy_static;
+use spin::mutex::Mutex;
+use vmsa::error::Error;
+
+/// Granules reserved for RMM-internal buffers, e.g., attestation tokens.
+/// They are part of the RMM image, so they are never seen by the host
+/// and can't be delegated or assigned to a realm.
+#[repr(C, align(4096))]
+struct Granules([[u8; GRANULE_SIZE]; RMM_GRANULE_POOL_SIZE]);
+
+static mut GRANULES: Granules = Granules([[0; GRANULE_SIZE]; RMM_GRANULE_POOL_SIZE]);
+
+lazy_static! {
+    static ref POOL: Mutex<GranulePool<RMM_GRANULE_POOL_SIZE>> =
+        Mutex::new(GranulePool::new(unsafe { GRANULES.0.as_ptr() as usize }));
+}
+
+/// Tracks which of the `N` contiguous granules starting at `base` are handed out
+pub struct GranulePool<const N: usize> {
+    base: usize,
+    used: [bool; N],
+}
+
+impl<const N: usize> GranulePool<N> {
+    pub const fn new(base: usize) -> Self {
+        Self {
+            base,
+            used: [false; N],
+        }
+    }
+
+    /// Whether `pa` is within a granule of the pool
+    pub fn contains(&self, pa: usize) -> bool {
+        (self.base..self.base + N * GRANULE_SIZE).contains(&pa)
+    }
+
+    pub fn available(&self) -> usize {
+        self.used.iter().filter(|used| !**used).count()
+    }
+
+    /// Hands out `count` contiguous granules and returns the address of the first one.
+    pub fn alloc(&mut self, count: usize) -> Result<usize, Error> {
+        if count == 0 || count > N {
+            return Err(Error::MmAllocFail);
+        }
+        let first = (0..=N - count)
+            .find(|&i| self.used[i..i + count].iter().all(|used| !used))
+            .ok_or(Error::MmAllocFail)?;
+        self.used[first..first + count].fill(true);
+        Ok(self.base + first * GRANULE_SIZE)
+    }
+
+    /// Returns `count` granules starting at `pa` to the pool.
+    /// All of them must have been handed out by the pool.
+    pub fn free(&mut self, pa: usize, count: usize) -> Result<(), Error> {
+        if count == 0 || pa % GRANULE_SIZE != 0 || !self.contains(pa) {
+            return Err(Error::MmErrorOthers);
+        }
+        let first = (pa - self.base) / GRANULE_SIZE;
+        let granules = self
+            .used
+            .get_mut(first..first + count)
+            .ok_or(Error::MmErrorOthers)?;
+        if !granules.iter().all(|used| *used) {
+            return Err(Error::MmErrorOthers);
+        }
+        granules.fill(false);
+        Ok(())
+    }
+}
+
+pub fn alloc(count: usize) -> Result<usize, Error> {
+    POOL.lock().alloc(count)
+}
+
+pub fn free(pa: usize, count: usize) -> Result<(), Error> {
+    POOL.lock().free(pa, count)
+}
+
+pub fn is_pool_granule(pa: usize) -> bool {
+    POOL.lock().contains(pa)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const BASE: usize = 0x8800_0000;
+
+    #[test]
+    fn pool_exhaustion() {
+        let mut pool = GranulePool::<4>::new(BASE);
+
+        assert_eq!(pool.alloc(2), Ok(BASE));
+        assert_eq!(pool.alloc(1), Ok(BASE + 2 * GRANULE_SIZE));
+        assert_eq!(pool.alloc(2), Err(Error::MmAllocFail));
+        assert_eq!(pool.alloc(1), Ok(BASE + 3 * GRANULE_SIZE));
+        assert_eq!(pool.available(), 0);
+        assert_eq!(pool.alloc(1), Err(Error::MmAllocFail));
+
+        // a hole of a single granule doesn't fit two
+        pool.free(BASE + 2 * GRANULE_SIZE, 1).unwrap();
+        assert_eq!(pool.alloc(2), Err(Error::MmAllocFail));
+        pool.free(BASE, 2).unwrap();
+        assert_eq!(pool.alloc(3), Ok(BASE));
+
+        assert_eq!(pool.alloc(0), Err(Error::MmAllocFail));
+        assert_eq!(pool.alloc(5), Err(Error::MmAllocFail));
+    }
+
+    #[test]
+    fn pool_double_free() {
+        let mut pool = GranulePool::<4>::new(BASE);
+
+        let pa = pool.alloc(2).unwrap();
+        pool.free(pa, 2).unwrap();
+        assert_eq!(pool.free(pa, 2), Err(Error::MmErrorOthers));
+        assert_eq!(pool.available(), 4);
+
+        // partially handed out
+        let pa = pool.alloc(1).unwrap();
+        assert_eq!(pool.free(pa, 2), Err(Error::MmErrorOthers));
+        pool.free(pa, 1).unwrap();
+
+        // not owned by the pool
+        assert_eq!(pool.free(BASE - GRANULE_SIZE, 1), Err(Error::MmErrorOthers));
+        assert_eq!(
+            pool.free(BASE + 4 * GRANULE_SIZE, 1),
+            Err(Error::MmErrorOthers)
+        );
+        assert_eq!(pool.free(BASE + 0x800, 1), Err(Error::MmErrorOthers));
+        assert_eq!(
+            pool.free(BASE + 3 * GRANULE_SIZE, 2),
+            Err(Error::MmErrorOthers)
+        );
+        assert!(!pool.contains(BASE + 4 * GRANULE_SIZE));
+    }
+}
@@ -1,3 +1,78 @@
+pub mod alloc;
 pub mod page;
 pub mod page_table;
+pub mod rtt;
+pub mod tlb;
 pub mod translation;
+
+use crate::rmi::realm::Rd;
+use crate::rmi::rtt::RTT_PAGE_LEVEL;
+use rtt::level_size;
+
+use vmsa::error::Error;
+
+/// Checks that `ipa` is within the IPA space of the realm
+/// and aligned to the size of the address range translated at `level`.
+pub fn validate_ipa(rd: &Rd, ipa: usize, level: usize) -> Result<(), Error> {
+    check_ipa(ipa, level, rd.ipa_bits(), rd.s2_starting_level() as usize)
+}
+
+fn check_ipa(ipa: usize, level: usize, ipa_bits: usize, start_level: usize) -> Result<(), Error> {
+    if !(start_level..=RTT_PAGE_LEVEL).contains(&level) {
+        return Err(Error::MmInvalidLevel);
+    }
+    if ipa >= 1 << ipa_bits || ipa & (level_size(level) - 1) != 0 {
+        return Err(Error::MmInvalidAddr);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn ipa_validation() {
+        const OK: Result<(), Error> = Ok(());
+        const ADDR: Result<(), Error> = Err(Error::MmInvalidAddr);
+        const LEVEL: Result<(), Error> = Err(Error::MmInvalidLevel);
+
+        // (ipa_bits, start_level, ipa, level, expected)
+        let cases = [
+            (48, 0, 0x0, 0, OK),
+            (48, 0, 0x80_0000_0000, 0, OK),
+            (48, 0, 0x4000_0000, 0, ADDR),
+            (48, 0, 0x4000_0000, 1, OK),
+            (48, 0, 0x20_0000, 2, OK),
+            (48, 0, 0x20_1000, 2, ADDR),
+            (48, 0, 0xffff_ffff_f000, 3, OK),
+            (48, 0, 0x1_0000_0000_0000, 3, ADDR),
+            (48, 0, 0x1800, 3, ADDR),
+            (48, 0, 0x1000, 4, LEVEL),
+            (40, 1, 0x0, 0, LEVEL),
+            (40, 1, 0x80_4000_0000, 1, OK),
+            (40, 1, 0xc0_0000_0000, 1, OK),
+            (40, 1, 0xc0_0010_0000, 1, ADDR),
+            (40, 1, 0x7f_c000_0000, 1, OK),
+            (40, 1, 0x7f_ffe0_0000, 2, OK),
+            (40, 1, 0xff_ffff_f000, 3, OK),
+            (40, 1, 0x100_0000_0000, 3, ADDR),
+            (40, 1, 0x100_0000_0000, 4, LEVEL),
+            // with LPA2
+            (52, 0, 0xf_ff80_0000_0000, 0, OK),
+            (52, 0, 0xf_ffff_ffff_f000, 3, OK),
+            (52, 0, 0x10_0000_0000_0000, 3, ADDR),
+            (52, 0, 0x10_0000_0000_0000, 0, ADDR),
+        ];
+        for (ipa_bits, start_level, ipa, level, expected) in cases {
+            assert_eq!(
+                check_ipa(ipa, level, ipa_bits, start_level),
+                expected,
+                "ipa_bits: {}, ipa: {:#x}, level: {}",
+                ipa_bits,
+                ipa,
+                level
+            );
+        }
+    }
+}
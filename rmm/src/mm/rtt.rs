@@ -0,0 +1,865 @@
+use crate::granule::{GRANULE_MASK, GRANULE_SHIFT};
+use crate::realm::mm::page_table::pte::{attribute, permission, shareable};
+use crate::realm::mm::stage2_tte::{desc_type, invalid_hipas, invalid_ripas};
+use crate::realm::mm::stage2_tte::{oa_mask, pack_oa, unpack_oa, S2TTE};
+use crate::rmi::rtt::{RTT_MIN_BLOCK_LEVEL, RTT_PAGE_LEVEL, S2TTE_STRIDE};
+
+use armv9a::bits_in_reg;
+use armv9a::regs::{vtcr_sl0, VTCR_EL2};
+use core::fmt;
+use core::ops::BitOr;
+use vmsa::error::Error;
+
+const ENTRIES_PER_TABLE: usize = 1 << S2TTE_STRIDE;
+
+/// Range of the IPA width supported for a realm, which extends to 52 bits with LPA2
+const IPA_BITS_MIN: usize = 32;
+const IPA_BITS_MAX: usize = 48;
+const IPA_BITS_MAX_LPA2: usize = 52;
+
+/// Number of tables which can be concatenated at the starting level
+const MAX_START_TABLES: usize = 16;
+
+/// Number of page entries sharing the contiguous hint
+pub const CONTIG_ENTRIES: usize = 16;
+
+/// Stage 2 permissions and memory type of a mapping
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct S2Prot(u8);
+
+impl S2Prot {
+    pub const READ: Self = Self(1 << 0);
+    pub const WRITE: Self = Self(1 << 1);
+    pub const EXEC: Self = Self(1 << 2);
+    pub const DEVICE: Self = Self(1 << 3);
+    pub const NORMAL_CACHEABLE: Self = Self(1 << 4);
+
+    /// Normal non-cacheable memory with no access
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub const fn contains(&self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Normal cacheable memory with all the permissions, for DATA granules
+    pub fn data() -> Self {
+        Self::READ | Self::WRITE | Self::EXEC | Self::NORMAL_CACHEABLE
+    }
+
+    /// AP, XN, MemAttr and SH bits of a descriptor with the permissions,
+    /// which fails for write-only, executable device
+    /// or both device and normal memory.
+    pub fn desc_bits(&self) -> Result<u64, Error> {
+        let device = self.contains(Self::DEVICE);
+        if (self.contains(Self::WRITE) && !self.contains(Self::READ))
+            || (device && self.contains(Self::NORMAL_CACHEABLE))
+            || (device && self.contains(Self::EXEC))
+        {
+            return Err(Error::MmErrorOthers);
+        }
+
+        let ap = match (self.contains(Self::READ), self.contains(Self::WRITE)) {
+            (true, true) => permission::RW,
+            (true, false) => permission::RO,
+            _ => permission::NONE,
+        };
+        let memattr = match (device, self.contains(Self::NORMAL_CACHEABLE)) {
+            (true, _) => attribute::DEVICE_NGNRE,
+            (_, true) => attribute::NORMAL_FWB,
+            _ => attribute::NORMAL_NC,
+        };
+        Ok(bits_in_reg(S2TTE::AP, ap)
+            | bits_in_reg(S2TTE::XN, !self.contains(Self::EXEC) as u64)
+            | bits_in_reg(S2TTE::MEMATTR, memattr)
+            | bits_in_reg(S2TTE::SH, shareable::INNER))
+    }
+}
+
+impl BitOr for S2Prot {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// Size of the address range translated by a single entry at the given level
+pub fn level_size(level: usize) -> usize {
+    1 << (GRANULE_SHIFT + S2TTE_STRIDE * (RTT_PAGE_LEVEL - level))
+}
+
+/// Size of the address range translated by a single table at the given level
+pub fn table_size(level: usize) -> usize {
+    level_size(level) * ENTRIES_PER_TABLE
+}
+
+/// Number of concatenated tables needed at the starting level to cover `ipa_bits`
+pub fn num_start_tables(ipa_bits: usize, start_level: usize) -> usize {
+    let shift = GRANULE_SHIFT + S2TTE_STRIDE * (RTT_PAGE_LEVEL - start_level);
+    match ipa_bits > shift + S2TTE_STRIDE {
+        true => 1 << (ipa_bits - shift - S2TTE_STRIDE),
+        false => 1,
+    }
+}
+
+/// The deepest starting level covering `ipa_bits` with concatenated tables
+pub fn start_level(ipa_bits: usize, lpa2: bool) -> Result<usize, Error> {
+    let max = match lpa2 {
+        true => IPA_BITS_MAX_LPA2,
+        false => IPA_BITS_MAX,
+    };
+    if !(IPA_BITS_MIN..=max).contains(&ipa_bits) {
+        return Err(Error::MmInvalidAddr);
+    }
+    (0..=RTT_PAGE_LEVEL)
+        .rev()
+        .find(|&level| num_start_tables(ipa_bits, level) <= MAX_START_TABLES)
+        .ok_or(Error::MmInvalidAddr)
+}
+
+/// T0SZ, SL0 and DS of VTCR_EL2 for a stage 2 translation of `ipa_bits`,
+/// which starts at the level given by `start_level()`
+pub fn vtcr_for(ipa_bits: usize, lpa2: bool) -> Result<u64, Error> {
+    let sl0 = match start_level(ipa_bits, lpa2)? {
+        0 => vtcr_sl0::SL0_4K_L0,
+        1 => vtcr_sl0::SL0_4K_L1,
+        2 => vtcr_sl0::SL0_4K_L2,
+        _ => vtcr_sl0::SL0_4K_L3,
+    };
+    let vtcr = bits_in_reg(VTCR_EL2::T0SZ, (64 - ipa_bits) as u64)
+        | bits_in_reg(VTCR_EL2::SL0, sl0)
+        | bits_in_reg(VTCR_EL2::DS, lpa2 as u64);
+    Ok(vtcr)
+}
+
+/// State of the entry found at the end of a walk
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RttEntryState {
+    Unassigned,
+    Assigned,
+    Destroyed,
+    Valid,
+    ValidNs,
+    Table,
+    Unknown,
+}
+
+/// Result of walking the stage 2 translation tables down to a level
+#[derive(Clone, Copy)]
+pub struct RttWalk {
+    pub desc: S2TTE,
+    /// The level of `desc`, which can be above the requested level
+    /// if the walk ends at a non-table entry.
+    pub level: usize,
+    entry: usize,
+    lpa2: bool,
+}
+
+impl RttEntryState {
+    /// State of `desc` found at `level`
+    pub fn of(desc: S2TTE, level: usize) -> Self {
+        if desc.is_table(level) {
+            RttEntryState::Table
+        } else if desc.is_valid(level, false) {
+            RttEntryState::Valid
+        } else if desc.is_valid(level, true) {
+            RttEntryState::ValidNs
+        } else if desc.is_unassigned() {
+            RttEntryState::Unassigned
+        } else if desc.is_assigned() {
+            RttEntryState::Assigned
+        } else if desc.is_destroyed() {
+            RttEntryState::Destroyed
+        } else {
+            RttEntryState::Unknown
+        }
+    }
+}
+
+impl RttWalk {
+    pub fn state(&self) -> RttEntryState {
+        RttEntryState::of(self.desc, self.level)
+    }
+
+    pub fn is_valid(&self) -> bool {
+        matches!(self.state(), RttEntryState::Valid | RttEntryState::ValidNs)
+    }
+
+    pub fn is_unassigned(&self) -> bool {
+        self.state() == RttEntryState::Unassigned
+    }
+
+    pub fn is_assigned(&self) -> bool {
+        self.state() == RttEntryState::Assigned
+    }
+
+    /// Physical address of the table holding the entry
+    pub fn table(&self) -> usize {
+        self.entry & GRANULE_MASK
+    }
+
+    /// Output address of the entry aligned to the granularity of its level
+    pub fn output_address(&self) -> usize {
+        let addr = unpack_oa(self.desc.get(), self.lpa2) as usize;
+        match self.state() {
+            RttEntryState::Table => addr,
+            _ => addr & !(level_size(self.level) - 1),
+        }
+    }
+
+    fn set(&self, val: u64) {
+        // Safety: `entry` points to an entry within a table reached by the walk
+        unsafe { *(self.entry as *mut u64) = val };
+    }
+
+    /// Whether the entry is a page of a run with the contiguous hint
+    pub fn is_contiguous(&self) -> bool {
+        self.level == RTT_PAGE_LEVEL && self.desc.get_masked_value(S2TTE::CONTIG) != 0
+    }
+
+    /// Whether the entry holds a 52-bit output address
+    pub fn lpa2(&self) -> bool {
+        self.lpa2
+    }
+}
+
+impl fmt::Debug for RttWalk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RttWalk")
+            .field("desc", &format_args!("{:#X}", self.desc.get()))
+            .field("level", &self.level)
+            .finish()
+    }
+}
+
+/// Stage 2 translation tables of a realm, accessed through their physical addresses.
+///
+/// The tables are expected to be accessible with the identity mapping of RMM.
+/// Invalidating TLB entries for the updated entries is up to the caller.
+#[derive(Debug)]
+pub struct Rtt {
+    root: usize,
+    start_level: usize,
+    num_start: usize,
+    /// Descriptors hold 52-bit output addresses
+    lpa2: bool,
+}
+
+impl Rtt {
+    pub fn new(root: usize, start_level: usize, num_start: usize) -> Self {
+        Self {
+            root,
+            start_level,
+            num_start,
+            lpa2: false,
+        }
+    }
+
+    pub fn with_lpa2(mut self, lpa2: bool) -> Self {
+        self.lpa2 = lpa2;
+        self
+    }
+
+    pub fn lpa2(&self) -> bool {
+        self.lpa2
+    }
+
+    fn index(&self, ipa: usize, level: usize) -> usize {
+        let index = ipa >> (GRANULE_SHIFT + S2TTE_STRIDE * (RTT_PAGE_LEVEL - level));
+        match level == self.start_level {
+            // the starting level may consist of concatenated tables
+            true => index,
+            false => index & (ENTRIES_PER_TABLE - 1),
+        }
+    }
+
+    /// Walks the tables for `ipa` until `level` or the first non-table entry is reached.
+    pub fn walk(&self, ipa: usize, level: usize) -> Result<RttWalk, Error> {
+        if !(self.start_level..=RTT_PAGE_LEVEL).contains(&level) {
+            return Err(Error::MmInvalidLevel);
+        }
+        if self.index(ipa, self.start_level) >= ENTRIES_PER_TABLE * self.num_start {
+            return Err(Error::MmInvalidAddr);
+        }
+
+        let mut table = self.root;
+        let mut cur = self.start_level;
+        loop {
+            let entry = table + self.index(ipa, cur) * core::mem::size_of::<u64>();
+            // Safety: `table` is either the root or reached through a table descriptor
+            let desc = S2TTE::new(unsafe { *(entry as *const u64) });
+
+            if cur == level || !desc.is_table(cur) {
+                return Ok(RttWalk {
+                    desc,
+                    level: cur,
+                    entry,
+                    lpa2: self.lpa2,
+                });
+            }
+            table = unpack_oa(desc.get(), self.lpa2) as usize;
+            cur += 1;
+        }
+    }
+
+    /// Maps `ipa` to `pa` with a block or page descriptor at `level`.
+    /// `prot` holds the attribute bits of the new descriptor,
+    /// of which the shareability is taken from VTCR_EL2 with LPA2.
+    pub fn map(&mut self, ipa: usize, pa: usize, level: usize, prot: u64) -> Result<(), Error> {
+        if !(RTT_MIN_BLOCK_LEVEL..=RTT_PAGE_LEVEL).contains(&level) {
+            return Err(Error::MmInvalidLevel);
+        }
+        let align = level_size(level) - 1;
+        if ipa & align != 0 || pa & align != 0 {
+            return Err(Error::MmInvalidAddr);
+        }
+
+        let walk = self.walk(ipa, level)?;
+        if walk.level != level {
+            return Err(Error::MmInvalidLevel);
+        }
+        if !walk.is_unassigned() && !walk.is_assigned() {
+            return Err(Error::MmStateError);
+        }
+
+        let desc_type = match level {
+            RTT_PAGE_LEVEL => desc_type::L3_PAGE,
+            _ => desc_type::L012_BLOCK,
+        };
+        let prot = match self.lpa2 {
+            true => prot & !S2TTE::SH,
+            false => prot,
+        };
+        walk.set(
+            pack_oa(pa as u64, self.lpa2)
+                | prot
+                | bits_in_reg(S2TTE::AF, 1)
+                | bits_in_reg(S2TTE::DESC_TYPE, desc_type),
+        );
+        Ok(())
+    }
+
+    /// Maps `ipa` to `pa` at `level` with the attribute bits of `prot`.
+    pub fn map_prot(
+        &mut self,
+        ipa: usize,
+        pa: usize,
+        level: usize,
+        prot: S2Prot,
+    ) -> Result<(), Error> {
+        self.map(ipa, pa, level, prot.desc_bits()?)
+    }
+
+    /// Overwrites the entry for `ipa` at `level` with `desc`.
+    pub fn set(&mut self, ipa: usize, level: usize, desc: u64) -> Result<(), Error> {
+        let walk = self.walk(ipa, level)?;
+        if walk.level != level {
+            return Err(Error::MmInvalidLevel);
+        }
+        if walk.is_contiguous() {
+            return Err(Error::MmStateError);
+        }
+        walk.set(desc);
+        Ok(())
+    }
+
+    /// Removes the block or page descriptor mapping `ipa`
+    /// and returns the output address it had.
+    pub fn unmap(&mut self, ipa: usize) -> Result<usize, Error> {
+        let walk = self.walk(ipa, RTT_PAGE_LEVEL)?;
+        if !walk.is_valid() {
+            return Err(Error::MmNoEntry);
+        }
+
+        if walk.is_contiguous() {
+            return Err(Error::MmStateError);
+        }
+
+        let pa = walk.output_address();
+        walk.set(0);
+        Ok(pa)
+    }
+
+    /// Sets the contiguous hint on the `count` valid pages mapping `base_ipa`
+    /// to `pa` onwards, in runs of CONTIG_ENTRIES. All the pages must have the same
+    /// attributes. The hint can't change on live entries, so the pages are made
+    /// invalid first and `invalidate` is called for the IPA range before they are
+    /// mapped again with the hint.
+    pub fn map_contiguous(
+        &mut self,
+        base_ipa: usize,
+        pa: usize,
+        count: usize,
+        invalidate: impl FnOnce(core::ops::Range<usize>),
+    ) -> Result<(), Error> {
+        let size = level_size(RTT_PAGE_LEVEL);
+        let align = CONTIG_ENTRIES * size - 1;
+        if count == 0 || count % CONTIG_ENTRIES != 0 || base_ipa & align != 0 || pa & align != 0 {
+            return Err(Error::MmInvalidAddr);
+        }
+
+        let mut attrs = None;
+        for i in 0..count {
+            let walk = self.walk(base_ipa + i * size, RTT_PAGE_LEVEL)?;
+            if walk.level != RTT_PAGE_LEVEL || walk.state() != RttEntryState::Valid {
+                return Err(Error::MmStateError);
+            }
+            let desc = walk.desc.get() & !(oa_mask(self.lpa2) | S2TTE::CONTIG);
+            if walk.output_address() != pa + i * size || *attrs.get_or_insert(desc) != desc {
+                return Err(Error::MmErrorOthers);
+            }
+        }
+        let attrs = attrs.ok_or(Error::MmInvalidAddr)? | bits_in_reg(S2TTE::CONTIG, 1);
+        self.remap_run(base_ipa, pa, count, attrs, invalidate)
+    }
+
+    /// Removes the contiguous hint from the run of pages holding `ipa`,
+    /// which must be done before any of its pages can be changed. The pages
+    /// go through the same invalidation as in map_contiguous().
+    pub fn clear_contiguous(
+        &mut self,
+        ipa: usize,
+        invalidate: impl FnOnce(core::ops::Range<usize>),
+    ) -> Result<(), Error> {
+        let size = level_size(RTT_PAGE_LEVEL);
+        let base_ipa = ipa & !(CONTIG_ENTRIES * size - 1);
+        let walk = self.walk(base_ipa, RTT_PAGE_LEVEL)?;
+        if !walk.is_contiguous() {
+            return Err(Error::MmStateError);
+        }
+        // the pages of a run only change together, so they map a single range
+        let attrs = walk.desc.get() & !(oa_mask(self.lpa2) | S2TTE::CONTIG);
+        let pa = walk.output_address();
+        self.remap_run(base_ipa, pa, CONTIG_ENTRIES, attrs, invalidate)
+    }
+
+    /// Maps the `count` pages from `base_ipa` to `pa` onwards again with `attrs`,
+    /// after making them invalid and calling `invalidate` for their IPA range.
+    fn remap_run(
+        &mut self,
+        base_ipa: usize,
+        pa: usize,
+        count: usize,
+        attrs: u64,
+        invalidate: impl FnOnce(core::ops::Range<usize>),
+    ) -> Result<(), Error> {
+        let size = level_size(RTT_PAGE_LEVEL);
+        for i in 0..count {
+            let walk = self.walk(base_ipa + i * size, RTT_PAGE_LEVEL)?;
+            walk.set(
+                pack_oa((pa + i * size) as u64, self.lpa2)
+                    | bits_in_reg(S2TTE::INVALID_HIPAS, invalid_hipas::ASSIGNED)
+                    | bits_in_reg(S2TTE::INVALID_RIPAS, invalid_ripas::RAM),
+            );
+        }
+        invalidate(base_ipa..base_ipa + count * size);
+        for i in 0..count {
+            let walk = self.walk(base_ipa + i * size, RTT_PAGE_LEVEL)?;
+            walk.set(pack_oa((pa + i * size) as u64, self.lpa2) | attrs);
+        }
+        Ok(())
+    }
+
+    /// Iterates over the entries translating `range`
+    pub fn entries(&self, range: core::ops::Range<usize>) -> EntryIterator<'_> {
+        EntryIterator {
+            rtt: self,
+            ipa: range.start,
+            end: range.end,
+        }
+    }
+}
+
+/// Entries of an RTT over a range, yielded as `(ipa, level, state, output_addr)`
+/// for each of the last entries walked, i.e., once for a block or for a page.
+/// `ipa` is the start of the range translated by the entry, which can be below
+/// the start of the range. `output_addr` is 0 without an output address.
+pub struct EntryIterator<'a> {
+    rtt: &'a Rtt,
+    ipa: usize,
+    end: usize,
+}
+
+impl Iterator for EntryIterator<'_> {
+    type Item = (usize, usize, RttEntryState, usize);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.ipa >= self.end {
+            return None;
+        }
+        // stops outside of the IPA space
+        let walk = self.rtt.walk(self.ipa, RTT_PAGE_LEVEL).ok()?;
+        let base = self.ipa & !(level_size(walk.level) - 1);
+        self.ipa = base + level_size(walk.level);
+
+        let state = walk.state();
+        let output_addr = match state {
+            RttEntryState::Assigned | RttEntryState::Valid | RttEntryState::ValidNs => {
+                walk.output_address()
+            }
+            _ => 0,
+        };
+        Some((base, walk.level, state, output_addr))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+
+    extern crate alloc;
+    use alloc::boxed::Box;
+
+    #[repr(C, align(4096))]
+    pub(crate) struct Table(pub(crate) [u64; ENTRIES_PER_TABLE]);
+
+    impl Table {
+        pub(crate) fn new() -> Box<Self> {
+            Box::new(Self([0; ENTRIES_PER_TABLE]))
+        }
+
+        pub(crate) fn addr(&self) -> usize {
+            self as *const Self as usize
+        }
+
+        pub(crate) fn link(&mut self, index: usize, child: &Table) {
+            self.0[index] =
+                child.addr() as u64 | bits_in_reg(S2TTE::DESC_TYPE, desc_type::L012_TABLE);
+        }
+    }
+
+    pub(crate) const IPA: usize = 0x8000_0000 | (1 << 30) | (2 << 21) | (3 << 12);
+
+    #[test]
+    fn vtcr_start_level() {
+        // 40 bits: T0SZ 24, two concatenated tables at level 1 (SL0 1)
+        assert_eq!(start_level(40, false), Ok(1));
+        assert_eq!(num_start_tables(40, 1), 2);
+        assert_eq!(vtcr_for(40, false), Ok(0x58));
+
+        // 48 bits: T0SZ 16, a single table at level 0 (SL0 2)
+        assert_eq!(start_level(48, false), Ok(0));
+        assert_eq!(num_start_tables(48, 0), 1);
+        assert_eq!(vtcr_for(48, false), Ok(0x90));
+
+        // 32 bits: four tables at level 2 (SL0 0)
+        assert_eq!(vtcr_for(32, false), Ok(0x20));
+        // 52 bits: sixteen tables at level 0 with DS
+        assert_eq!(num_start_tables(52, 0), 16);
+        assert_eq!(vtcr_for(52, true), Ok((1 << 32) | 0x8c));
+
+        assert_eq!(vtcr_for(31, false), Err(Error::MmInvalidAddr));
+        assert_eq!(vtcr_for(49, false), Err(Error::MmInvalidAddr));
+        assert_eq!(vtcr_for(53, true), Err(Error::MmInvalidAddr));
+    }
+
+    #[test]
+    fn walk_levels() {
+        let mut l1 = Table::new();
+        let mut l2 = Table::new();
+        let mut l3 = Table::new();
+        l1.link(3, &l2);
+        l2.link(2, &l3);
+        l3.0[3] = 0x8800_0000 | bits_in_reg(S2TTE::DESC_TYPE, desc_type::L3_PAGE);
+
+        let rtt = Rtt::new(l1.addr(), 1, 1);
+
+        let walk = rtt.walk(IPA, 1).unwrap();
+        assert_eq!((walk.level, walk.state()), (1, RttEntryState::Table));
+        assert_eq!(walk.output_address(), l2.addr());
+
+        let walk = rtt.walk(IPA, 2).unwrap();
+        assert_eq!((walk.level, walk.state()), (2, RttEntryState::Table));
+        assert_eq!(walk.output_address(), l3.addr());
+
+        let walk = rtt.walk(IPA, 3).unwrap();
+        assert_eq!((walk.level, walk.state()), (3, RttEntryState::Valid));
+        assert_eq!(walk.output_address(), 0x8800_0000);
+        assert_eq!(walk.table(), l3.addr());
+
+        // the walk stops at the unassigned entry of level 2
+        let walk = rtt.walk(IPA + (1 << 21), 3).unwrap();
+        assert_eq!((walk.level, walk.state()), (2, RttEntryState::Unassigned));
+
+        assert_eq!(rtt.walk(IPA, 0).unwrap_err(), Error::MmInvalidLevel);
+        assert_eq!(rtt.walk(IPA, 4).unwrap_err(), Error::MmInvalidLevel);
+        assert_eq!(rtt.walk(1 << 39, 3).unwrap_err(), Error::MmInvalidAddr);
+    }
+
+    #[test]
+    fn map_and_unmap() {
+        let mut l1 = Table::new();
+        let mut l2 = Table::new();
+        let l3 = Table::new();
+        l1.link(3, &l2);
+        l2.link(2, &l3);
+
+        let mut rtt = Rtt::new(l1.addr(), 1, 1);
+        let prot = bits_in_reg(S2TTE::AP, permission::RW);
+
+        rtt.map(IPA, 0x8800_0000, 3, prot).unwrap();
+        let walk = rtt.walk(IPA, 3).unwrap();
+        assert_eq!((walk.level, walk.state()), (3, RttEntryState::Valid));
+        assert_eq!(walk.desc.get_masked_value(S2TTE::AP), permission::RW);
+        assert_eq!(rtt.map(IPA, 0x8800_0000, 3, prot), Err(Error::MmStateError));
+
+        // a table is in place at level 2
+        assert_eq!(
+            rtt.map(IPA & !0x1f_ffff, 0x8820_0000, 2, prot),
+            Err(Error::MmStateError)
+        );
+        // level 3 isn't reachable outside of the linked table
+        let block_ipa = IPA + (1 << 21);
+        assert_eq!(
+            rtt.map(block_ipa & !0xfff, 0x8800_0000, 3, prot),
+            Err(Error::MmInvalidLevel)
+        );
+        assert_eq!(
+            rtt.map(block_ipa, 0x8820_0000, 2, prot),
+            Err(Error::MmInvalidAddr)
+        );
+        let block_ipa = block_ipa & !0x1f_ffff;
+        rtt.map(block_ipa, 0x8820_0000, 2, prot).unwrap();
+        let walk = rtt.walk(block_ipa + 0x1000, 3).unwrap();
+        assert_eq!((walk.level, walk.state()), (2, RttEntryState::Valid));
+        assert_eq!(walk.output_address(), 0x8820_0000);
+
+        assert_eq!(rtt.unmap(IPA), Ok(0x8800_0000));
+        assert!(rtt.walk(IPA, 3).unwrap().is_unassigned());
+        assert_eq!(rtt.unmap(IPA), Err(Error::MmNoEntry));
+        assert_eq!(rtt.unmap(block_ipa + 0x1000), Ok(0x8820_0000));
+        assert_eq!(
+            rtt.map(IPA, 0x8800_0000, 1, prot),
+            Err(Error::MmInvalidLevel)
+        );
+    }
+
+    #[test]
+    fn map_lpa2() {
+        let mut l1 = Table::new();
+        let mut l2 = Table::new();
+        let l3 = Table::new();
+        l1.link(3, &l2);
+        l2.link(2, &l3);
+
+        let mut rtt = Rtt::new(l1.addr(), 1, 1).with_lpa2(true);
+        let prot = bits_in_reg(S2TTE::AP, permission::RW) | bits_in_reg(S2TTE::SH, 0b11);
+        let pa = 0xc_0000_8800_0000;
+
+        rtt.map(IPA, pa, 3, prot).unwrap();
+        let walk = rtt.walk(IPA, 3).unwrap();
+        assert_eq!((walk.level, walk.state()), (3, RttEntryState::Valid));
+        assert_eq!(walk.output_address(), pa);
+        assert_eq!(walk.desc.get_masked_value(S2TTE::ADDR_LPA2_HI), 0b11);
+        assert_eq!(walk.desc.get_masked(S2TTE::ADDR_L3_PAGE), 0x8800_0000);
+        assert_eq!(rtt.unmap(IPA), Ok(pa));
+
+        // the same descriptor holds a 48-bit address and SH without LPA2
+        let mut rtt = Rtt::new(l1.addr(), 1, 1);
+        rtt.map(IPA, 0x8800_0000, 3, prot).unwrap();
+        let walk = rtt.walk(IPA, 3).unwrap();
+        assert_eq!(walk.output_address(), 0x8800_0000);
+        assert_eq!(walk.desc.get_masked_value(S2TTE::SH), 0b11);
+    }
+
+    #[test]
+    fn s2prot_bits() {
+        let none = S2Prot::empty().desc_bits().unwrap();
+        assert_eq!(none & S2TTE::AP, 0);
+        assert_eq!(none & S2TTE::XN, S2TTE::XN);
+        assert_eq!(none & S2TTE::MEMATTR, attribute::NORMAL_NC << 2);
+        assert_eq!(none & S2TTE::SH, shareable::INNER << 8);
+
+        let read = S2Prot::READ.desc_bits().unwrap();
+        assert_eq!(read ^ none, permission::RO << 6);
+        let write = (S2Prot::READ | S2Prot::WRITE).desc_bits().unwrap();
+        assert_eq!(write ^ none, permission::RW << 6);
+        let exec = S2Prot::EXEC.desc_bits().unwrap();
+        assert_eq!(exec ^ none, S2TTE::XN);
+        let device = S2Prot::DEVICE.desc_bits().unwrap();
+        assert_eq!(device & S2TTE::MEMATTR, attribute::DEVICE_NGNRE << 2);
+        let cacheable = S2Prot::NORMAL_CACHEABLE.desc_bits().unwrap();
+        assert_eq!(cacheable & S2TTE::MEMATTR, attribute::NORMAL_FWB << 2);
+
+        // the attributes DATA_CREATE has always mapped with
+        assert_eq!(
+            S2Prot::data().desc_bits(),
+            Ok(bits_in_reg(S2TTE::MEMATTR, attribute::NORMAL_FWB)
+                | bits_in_reg(S2TTE::AP, permission::RW)
+                | bits_in_reg(S2TTE::SH, shareable::INNER))
+        );
+    }
+
+    #[test]
+    fn s2prot_invalid() {
+        for prot in [
+            S2Prot::WRITE,
+            S2Prot::WRITE | S2Prot::EXEC,
+            S2Prot::READ | S2Prot::DEVICE | S2Prot::NORMAL_CACHEABLE,
+            S2Prot::READ | S2Prot::EXEC | S2Prot::DEVICE,
+        ] {
+            assert_eq!(prot.desc_bits(), Err(Error::MmErrorOthers));
+        }
+
+        let root = Table::new();
+        let mut rtt = Rtt::new(root.addr(), 1, 1);
+        assert_eq!(
+            rtt.map_prot(IPA, 0x8800_0000, 3, S2Prot::WRITE),
+            Err(Error::MmErrorOthers)
+        );
+    }
+
+    #[test]
+    fn entries_of_blocks_and_pages() {
+        let mut l1 = Table::new();
+        let mut l2 = Table::new();
+        let l3 = Table::new();
+        l1.link(3, &l2);
+        l2.link(2, &l3);
+        let mut rtt = Rtt::new(l1.addr(), 1, 1);
+
+        let prot = bits_in_reg(S2TTE::AP, permission::RW);
+        let pages = IPA & !0x1f_ffff;
+        let block = pages + level_size(2);
+        rtt.map(pages + 510 * 0x1000, 0x8800_0000, 3, prot).unwrap();
+        rtt.map(block, 0x8820_0000, 2, prot).unwrap();
+
+        let mut entries = rtt.entries(pages + 509 * 0x1000..block + 2 * level_size(2));
+        assert_eq!(
+            entries.next(),
+            Some((pages + 509 * 0x1000, 3, RttEntryState::Unassigned, 0))
+        );
+        assert_eq!(
+            entries.next(),
+            Some((pages + 510 * 0x1000, 3, RttEntryState::Valid, 0x8800_0000))
+        );
+        assert_eq!(
+            entries.next(),
+            Some((pages + 511 * 0x1000, 3, RttEntryState::Unassigned, 0))
+        );
+        // the block is yielded once, as well as the unassigned entry at level 2
+        assert_eq!(
+            entries.next(),
+            Some((block, 2, RttEntryState::Valid, 0x8820_0000))
+        );
+        assert_eq!(
+            entries.next(),
+            Some((block + level_size(2), 2, RttEntryState::Unassigned, 0))
+        );
+        assert_eq!(entries.next(), None);
+
+        // starting within the block
+        let mut entries = rtt.entries(block + 0x5000..block + 0x6000);
+        assert_eq!(
+            entries.next(),
+            Some((block, 2, RttEntryState::Valid, 0x8820_0000))
+        );
+        assert_eq!(entries.next(), None);
+        assert_eq!(rtt.entries(block..block).count(), 0);
+        assert_eq!(rtt.entries(pages..block).count(), 512);
+    }
+
+    fn contiguous_run() -> (Box<Table>, Box<Table>, Box<Table>, Rtt, usize) {
+        let mut l1 = Table::new();
+        let mut l2 = Table::new();
+        let l3 = Table::new();
+        l1.link(3, &l2);
+        l2.link(2, &l3);
+        let mut rtt = Rtt::new(l1.addr(), 1, 1);
+
+        let base = IPA & !0x1f_ffff;
+        for i in 0..2 * CONTIG_ENTRIES {
+            let (ipa, pa) = (base + i * 0x1000, 0x8800_0000 + i * 0x1000);
+            rtt.map_prot(ipa, pa, 3, S2Prot::data()).unwrap();
+        }
+        (l1, l2, l3, rtt, base)
+    }
+
+    #[test]
+    fn contiguous_alignment() {
+        let (_l1, _l2, l3, mut rtt, base) = contiguous_run();
+        let pa = 0x8800_0000;
+
+        for (ipa, pa, count) in [
+            (base + 0x1000, pa + 0x1000, 16),
+            (base, pa + 0x1000, 16),
+            (base, pa, 8),
+            (base, pa, 0),
+        ] {
+            assert_eq!(
+                rtt.map_contiguous(ipa, pa, count, |_| unreachable!()),
+                Err(Error::MmInvalidAddr)
+            );
+        }
+        // the run maps discontiguous output addresses
+        assert_eq!(
+            rtt.map_contiguous(base, pa + 0x1_0000, 16, |_| unreachable!()),
+            Err(Error::MmErrorOthers)
+        );
+        // beyond the mapped pages
+        assert_eq!(
+            rtt.map_contiguous(base, pa, 48, |_| unreachable!()),
+            Err(Error::MmStateError)
+        );
+
+        rtt.unmap(base + 0x1_5000).unwrap();
+        rtt.map_prot(base + 0x1_5000, pa + 0x1_5000, 3, S2Prot::READ)
+            .unwrap();
+        assert_eq!(
+            rtt.map_contiguous(base, pa, 32, |_| unreachable!()),
+            Err(Error::MmErrorOthers)
+        );
+        assert!(l3
+            .0
+            .iter()
+            .all(|&e| S2TTE::new(e).get_masked_value(S2TTE::CONTIG) == 0));
+    }
+
+    #[test]
+    fn contiguous_hint() {
+        let (_l1, _l2, l3, mut rtt, base) = contiguous_run();
+        let contig = |e: &u64| S2TTE::new(*e).get_masked_value(S2TTE::CONTIG) == 1;
+
+        let mut invalidated = None;
+        let mut broken = false;
+        rtt.map_contiguous(base, 0x8800_0000, 16, |range| {
+            broken = l3.0[..16].iter().all(|&e| e & S2TTE::DESC_TYPE == 0);
+            invalidated = Some(range);
+        })
+        .unwrap();
+        assert!(broken);
+        assert_eq!(invalidated, Some(base..base + 0x1_0000));
+
+        assert!(l3.0[..16].iter().all(contig));
+        assert!(!l3.0[16..].iter().any(contig));
+        for i in 0..16 {
+            let walk = rtt.walk(base + i * 0x1000, 3).unwrap();
+            assert_eq!(walk.state(), RttEntryState::Valid);
+            assert_eq!(walk.output_address(), 0x8800_0000 + i * 0x1000);
+        }
+
+        // no page of the run changes until the hint is removed from all of them
+        assert_eq!(rtt.unmap(base + 0x3000), Err(Error::MmStateError));
+        assert_eq!(rtt.set(base + 0x3000, 3, 0), Err(Error::MmStateError));
+
+        let mut invalidated = None;
+        let mut broken = false;
+        rtt.clear_contiguous(base + 0x3000, |range| {
+            broken = l3.0[..16].iter().all(|&e| e & S2TTE::DESC_TYPE == 0);
+            invalidated = Some(range);
+        })
+        .unwrap();
+        assert!(broken);
+        assert_eq!(invalidated, Some(base..base + 0x1_0000));
+        assert!(!l3.0.iter().any(contig));
+        assert_eq!(
+            rtt.clear_contiguous(base, |_| unreachable!()),
+            Err(Error::MmStateError)
+        );
+
+        assert_eq!(rtt.unmap(base + 0x3000), Ok(0x8800_3000));
+        assert!(rtt.walk(base + 0x4000, 3).unwrap().is_valid());
+    }
+}
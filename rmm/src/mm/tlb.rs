@@ -0,0 +1,123 @@
+use armv9a::regs::VTTBR_EL2;
+use armv9a::{bits_in_reg, define_bitfield, define_bits, define_mask};
+
+define_bits!(TLBI_IPA, TTL[47 - 44], IPA[39 - 0]);
+
+/// Stage 2 TLB maintenance, broadcast to the Inner Shareable domain.
+/// Each operation carries the VTTBR_EL2 of the realm it is done for.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Invalidation {
+    /// Entries translating a single IPA, along with the combined
+    /// stage 1 and 2 entries of the VMID as they may be derived from it
+    Ipa { vttbr: u64, ipa: usize },
+    /// All stage 1 and 2 entries of the VMID
+    Vmid(u64),
+}
+
+impl Invalidation {
+    fn vttbr(&self) -> u64 {
+        match *self {
+            Invalidation::Ipa { vttbr, .. } | Invalidation::Vmid(vttbr) => vttbr,
+        }
+    }
+
+    pub fn vmid(&self) -> u16 {
+        ((self.vttbr() & VTTBR_EL2::VMID) >> VTTBR_EL2::VMID.trailing_zeros()) as u16
+    }
+}
+
+/// Invalidates stale entries after an IPA of a realm has been unmapped.
+pub fn invalidate_ipa(vttbr: u64, ipa: usize) {
+    issue(Invalidation::Ipa { vttbr, ipa });
+}
+
+/// Invalidates everything cached for a realm, e.g., when it's destroyed.
+pub fn invalidate_vmid(vttbr: u64) {
+    issue(Invalidation::Vmid(vttbr));
+}
+
+/// Operand of TLBI IPAS2E1IS. No level hint is given (TTL == 0),
+/// so entries of all levels, including walk caches, are invalidated.
+fn ipa_operand(ipa: usize) -> u64 {
+    bits_in_reg(TLBI_IPA::TTL, 0) | bits_in_reg(TLBI_IPA::IPA, (ipa >> 12) as u64)
+}
+
+/// TLBI instructions act on the VMID in VTTBR_EL2, so the VTTBR_EL2 of the
+/// target realm is installed for the duration of the operation. Only changing
+/// the VMID would let speculative walks of the tables in VTTBR_EL2.BADDR
+/// fill the TLB of the target VMID with entries of another realm.
+#[cfg(not(test))]
+fn issue(op: Invalidation) {
+    use core::arch::asm;
+
+    unsafe {
+        let vttbr = VTTBR_EL2.get();
+        VTTBR_EL2.set(op.vttbr());
+        asm!("isb");
+
+        match op {
+            // ref. DDI0487 D8.16.1, the stage 2 invalidation has to complete
+            // before the stage 1 entries derived from it are invalidated
+            Invalidation::Ipa { ipa, .. } => asm!(
+                "dsb ishst",
+                "tlbi ipas2e1is, {}",
+                "dsb ish",
+                "tlbi vmalle1is",
+                "dsb ish",
+                "isb",
+                in(reg) ipa_operand(ipa),
+            ),
+            Invalidation::Vmid(_) => asm!("dsb ishst", "tlbi vmalls12e1is", "dsb ish", "isb"),
+        }
+
+        VTTBR_EL2.set(vttbr);
+        asm!("isb");
+    }
+}
+
+#[cfg(test)]
+fn issue(op: Invalidation) {
+    test::ISSUED.lock().push(op);
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    use alloc::vec::Vec;
+    use spin::mutex::Mutex;
+
+    extern crate alloc;
+
+    /// Invalidations issued so far, in place of the TLBI instructions
+    pub static ISSUED: Mutex<Vec<Invalidation>> = Mutex::new(Vec::new());
+
+    /// Takes the invalidations issued for `vmid`
+    pub fn issued(vmid: u16) -> Vec<Invalidation> {
+        let mut issued = ISSUED.lock();
+        let (mine, others) = issued.drain(..).partition(|op| op.vmid() == vmid);
+        *issued = others;
+        mine
+    }
+
+    #[test]
+    fn invalidation_for_unmap_and_teardown() {
+        const VMID: u16 = 0xabc;
+        const VTTBR: u64 = 0x0abc_0000_8800_0000;
+        const IPA: usize = 0x8804_3000;
+
+        invalidate_ipa(VTTBR, IPA);
+        assert_eq!(
+            issued(VMID),
+            [Invalidation::Ipa {
+                vttbr: VTTBR,
+                ipa: IPA
+            }]
+        );
+        assert_eq!(ipa_operand(IPA), 0x8_8043);
+
+        invalidate_vmid(VTTBR);
+        assert_eq!(issued(VMID), [Invalidation::Vmid(VTTBR)]);
+        assert!(issued(VMID).is_empty());
+    }
+}
@@ -1,3 +1,4 @@
+use crate::exception::trap::syndrome::Syndrome;
 use crate::realm::registry::get_realm;
 use crate::rmi::error::Error;
 use crate::rmi::error::InternalError::*;
@@ -42,6 +43,6 @@ pub fn emulate_mmio(id: usize, vcpu: usize, run: &Run) -> Result<(), Error> {
         }
         context.gp_regs[rt] = val;
     }
-    context.elr += 4;
+    context.elr += Syndrome::instruction_len(esr_el2 as u32);
     Ok(())
 }
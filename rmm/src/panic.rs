@@ -1,3 +1,5 @@
+use crate::cpu::dump::PanicDump;
+
 #[alloc_error_handler]
 fn alloc_error_handler(_layout: core::alloc::Layout) -> ! {
     panic!("OOM! memory allocation of {} bytes failed", _layout.size())
@@ -6,6 +8,7 @@ fn alloc_error_handler(_layout: core::alloc::Layout) -> ! {
 #[panic_handler]
 pub fn panic_handler(_info: &core::panic::PanicInfo<'_>) -> ! {
     error!("RMM: {}", _info);
+    error!("RMM: {}", PanicDump::collect());
     halt()
 }
 
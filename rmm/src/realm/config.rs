@@ -1,16 +1,20 @@
-use crate::realm::mm::address::GuestPhysAddr;
-use crate::realm::registry::get_realm;
+use crate::granule::GRANULE_SIZE;
 use crate::rmi::error::Error;
-use crate::rmi::error::InternalError::*;
-use crate::rmi::rtt::RTT_PAGE_LEVEL;
+use crate::rmi::realm::Rd;
+use crate::rtt::{check_buffer, Access};
 
+const REALM_CONFIG_RESERVED: usize = GRANULE_SIZE - 0x9;
+
+/// RsiRealmConfig, which holds the attributes of the realm
+/// returned by RSI_REALM_CONFIG.
 #[repr(C)]
 pub struct RealmConfig {
-    ipa_width: usize,
+    ipa_width: u64,
+    hash_algo: u8,
+    reserved: [u8; REALM_CONFIG_RESERVED],
 }
 
 impl RealmConfig {
-    #[allow(dead_code)]
     // The below `init()` fills the object allocated in the Realm kernel with the proper
     // value (ipa_width), which helps to redirect the accesses to decrypted pages.
     //
@@ -18,24 +22,45 @@ impl RealmConfig {
     // in parsing the following kernel cmdline argument:
     // `console=ttyS0 root=/dev/vda rw  console=pl011,mmio,0x1c0a0000 console=ttyAMA0 printk.devkmsg=on`.
     // So, we get back to use the same kernel argument with TF-RMM's one (uart0 & uart3).
-    pub unsafe fn init(config_addr: usize, ipa_width: usize) {
+    pub unsafe fn init(config_addr: usize, ipa_width: usize, hash_algo: u8) {
         let config: &mut RealmConfig = &mut *(config_addr as *mut RealmConfig);
-        config.ipa_width = ipa_width;
+        config.ipa_width = ipa_width as u64;
+        config.hash_algo = hash_algo;
+        config.reserved.fill(0);
     }
 }
 
-pub fn realm_config(id: usize, config_ipa: usize, ipa_bits: usize) -> Result<(), Error> {
-    let res = get_realm(id)
-        .ok_or(Error::RmiErrorOthers(NotExistRealm))?
-        .lock()
-        .page_table
-        .lock()
-        .ipa_to_pa(GuestPhysAddr::from(config_ipa), RTT_PAGE_LEVEL);
-    if let Some(pa) = res {
-        let pa: usize = pa.into();
-        unsafe { RealmConfig::init(pa, ipa_bits) };
-        Ok(())
-    } else {
-        Err(Error::RmiErrorInput)
+pub fn realm_config(rd: &Rd, config_ipa: usize) -> Result<(), Error> {
+    let size = core::mem::size_of::<RealmConfig>();
+    let pa = check_buffer(&rd.rtt(), config_ipa, size, Access::Write)?;
+    unsafe { RealmConfig::init(pa, rd.ipa_bits(), rd.hash_algo()) };
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::offset_of;
+    use crate::rmi::HASH_ALGO_SHA512;
+
+    #[repr(C, align(4096))]
+    struct Page([u8; GRANULE_SIZE]);
+
+    #[test]
+    fn spec_realm_config() {
+        assert_eq!(core::mem::size_of::<RealmConfig>(), GRANULE_SIZE);
+        assert_eq!(offset_of!(RealmConfig, ipa_width), 0x0);
+        assert_eq!(offset_of!(RealmConfig, hash_algo), 0x8);
+        assert_eq!(offset_of!(RealmConfig, reserved), 0x9);
+    }
+
+    #[test]
+    fn fill_realm_config() {
+        let mut page = Page([0xff; GRANULE_SIZE]);
+        unsafe { RealmConfig::init(page.0.as_mut_ptr() as usize, 40, HASH_ALGO_SHA512) };
+
+        assert_eq!(page.0[0..8], 40u64.to_le_bytes());
+        assert_eq!(page.0[8], HASH_ALGO_SHA512);
+        assert!(page.0[9..].iter().all(|&b| b == 0));
     }
 }
@@ -1,6 +1,11 @@
+use super::debug::{self, DebugState};
+use super::fpu;
+use super::gic::{self, GicState};
+use super::inject::InjectedFault;
+use super::pmu::{self, PmuState};
+use super::sve::SveState;
 use super::timer;
-use crate::cpu::get_cpu_id;
-use crate::gic;
+use crate::cpu::{get_cpu_id, this_cpu};
 use crate::realm::registry::get_realm;
 use crate::realm::vcpu::VCPU;
 use crate::rmi::error::Error;
@@ -15,9 +20,18 @@ pub struct Context {
     pub elr: u64,
     pub spsr: u64,
     pub sys_regs: SystemRegister,
-    pub gic_state: GICRegister,
+    pub gic_state: GicState,
     pub timer: TimerRegister,
-    pub fp_regs: [u128; 32],
+    pub fpsimd: [u128; 32],
+    pub fpsr: u64,
+    pub fpcr: u64,
+    /// Used instead of `fpsimd` if the realm is configured with SVE
+    pub sve: Option<SveState>,
+    pub debug: DebugState,
+    /// Set if the realm is configured with the PMU
+    pub pmu: Option<PmuState>,
+    /// The last exception injected to the realm
+    pub injected: Option<InjectedFault>,
 }
 
 pub fn set_reg(id: usize, vcpu: usize, register: usize, value: usize) -> Result<(), Error> {
@@ -93,6 +107,19 @@ pub fn get_reg(id: usize, vcpu: usize, register: usize) -> Result<usize, Error>
     }
 }
 
+/// Copy of the general purpose registers of the vcpu
+pub fn get_gp_regs(id: usize, vcpu: usize) -> Result<[u64; 31], Error> {
+    Ok(get_realm(id)
+        .ok_or(Error::RmiErrorOthers(NotExistRealm))?
+        .lock()
+        .vcpus
+        .get(vcpu)
+        .ok_or(Error::RmiErrorOthers(NotExistVCPU))?
+        .lock()
+        .context
+        .gp_regs)
+}
+
 impl crate::realm::vcpu::Context for Context {
     fn new() -> Self {
         let mut context: Self = Default::default();
@@ -101,8 +128,7 @@ impl crate::realm::vcpu::Context for Context {
         context.spsr =
             SPSR_EL2::D | SPSR_EL2::A | SPSR_EL2::I | SPSR_EL2::F | (SPSR_EL2::M & 0b0101);
 
-        // TODO: enable floating point
-        // CPTR_EL2, CPACR_EL1, update vectors.s, etc..
+        // FP/SIMD registers are loaded lazily on the first access (ref. realm::fpu)
 
         context
     }
@@ -111,37 +137,26 @@ impl crate::realm::vcpu::Context for Context {
         vcpu.pcpu = Some(get_cpu_id());
         vcpu.context.sys_regs.vmpidr = vcpu.pcpu.unwrap() as u64;
         TPIDR_EL2.set(vcpu as *const _ as u64);
+        this_cpu().set_vcpu(Some(vcpu as *const _ as usize));
         gic::restore_state(vcpu);
         timer::restore_state(vcpu);
+        debug::restore_state(vcpu);
+        pmu::restore_state(vcpu);
     }
 
     unsafe fn from_current(vcpu: &mut VCPU<Self>) {
+        fpu::put_realm(&mut vcpu.context);
         gic::save_state(vcpu);
         timer::save_state(vcpu);
+        debug::save_state(vcpu);
+        pmu::save_state(vcpu);
         vcpu.pcpu = None;
+        this_cpu().set_vcpu(None);
         //vcpu.context.sys_regs.vmpidr = 0u64;
         //TPIDR_EL2.set(0u64);
     }
 }
 
-/// Generic Interrupt Controller Registers
-#[repr(C)]
-#[derive(Default, Debug)]
-pub struct GICRegister {
-    // Interrupt Controller Hyp Active Priorities Group 0 Registers
-    pub ich_ap0r_el2: [u64; 4],
-    // Interrupt Controller Hyp Active Priorities Group 1 Registers
-    pub ich_ap1r_el2: [u64; 4],
-    // GICv3 Virtual Machine Control Register
-    pub ich_vmcr_el2: u64,
-    // Interrupt Controller Hyp Control Register
-    pub ich_hcr_el2: u64,
-    // GICv3 List Registers
-    pub ich_lr_el2: [u64; 16],
-    // GICv3 Maintenance Interrupt State Register
-    pub ich_misr_el2: u64,
-}
-
 #[repr(C)]
 #[derive(Default, Debug)]
 pub struct SystemRegister {
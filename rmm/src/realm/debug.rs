@@ -0,0 +1,201 @@
+//! Breakpoints and watchpoints programmed by realms.
+//!
+//! The banks are switched on every entry and exit, whether or not the realm
+//! has debug enabled in MDSCR_EL1, as the realm can program them at any time.
+//! The MDSCR_EL1 of the RMM is put back on exits.
+
+use super::context::Context;
+use super::vcpu::VCPU;
+use crate::cpu::features::cpu_features;
+use crate::cpu::this_cpu;
+
+use armv9a::regs::*;
+
+/// Largest number of breakpoints and watchpoints of the architecture
+pub const NR_DBG_REGS: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Bank {
+    Bcr,
+    Bvr,
+    Wcr,
+    Wvr,
+}
+
+/// Access to the breakpoint and watchpoint registers
+trait Registers {
+    fn read(&self, bank: Bank, n: usize) -> u64;
+    fn write(&mut self, bank: Bank, n: usize, val: u64);
+}
+
+macro_rules! hw_registers {
+    ($($n:literal),*) => {
+        impl Registers for Hardware {
+            fn read(&self, bank: Bank, n: usize) -> u64 {
+                let mut val: u64 = 0;
+                unsafe {
+                    match (bank, n) {
+                        $(
+                            (Bank::Bcr, $n) => core::arch::asm!(concat!("mrs {}, dbgbcr", $n, "_el1"), out(reg) val),
+                            (Bank::Bvr, $n) => core::arch::asm!(concat!("mrs {}, dbgbvr", $n, "_el1"), out(reg) val),
+                            (Bank::Wcr, $n) => core::arch::asm!(concat!("mrs {}, dbgwcr", $n, "_el1"), out(reg) val),
+                            (Bank::Wvr, $n) => core::arch::asm!(concat!("mrs {}, dbgwvr", $n, "_el1"), out(reg) val),
+                        )*
+                        _ => {}
+                    }
+                }
+                val
+            }
+
+            fn write(&mut self, bank: Bank, n: usize, val: u64) {
+                unsafe {
+                    match (bank, n) {
+                        $(
+                            (Bank::Bcr, $n) => core::arch::asm!(concat!("msr dbgbcr", $n, "_el1, {}"), in(reg) val),
+                            (Bank::Bvr, $n) => core::arch::asm!(concat!("msr dbgbvr", $n, "_el1, {}"), in(reg) val),
+                            (Bank::Wcr, $n) => core::arch::asm!(concat!("msr dbgwcr", $n, "_el1, {}"), in(reg) val),
+                            (Bank::Wvr, $n) => core::arch::asm!(concat!("msr dbgwvr", $n, "_el1, {}"), in(reg) val),
+                        )*
+                        _ => {}
+                    }
+                }
+            }
+        }
+    };
+}
+
+struct Hardware;
+hw_registers!(0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15);
+
+/// Debug registers of a REC
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DebugState {
+    mdscr: u64,
+    bcr: [u64; NR_DBG_REGS],
+    bvr: [u64; NR_DBG_REGS],
+    wcr: [u64; NR_DBG_REGS],
+    wvr: [u64; NR_DBG_REGS],
+}
+
+impl DebugState {
+    fn banks(&mut self) -> [(Bank, &mut [u64; NR_DBG_REGS]); 4] {
+        [
+            (Bank::Bcr, &mut self.bcr),
+            (Bank::Bvr, &mut self.bvr),
+            (Bank::Wcr, &mut self.wcr),
+            (Bank::Wvr, &mut self.wvr),
+        ]
+    }
+
+    /// Saves the first `nr_bps` breakpoints and `nr_wps` watchpoints
+    /// and disables them, so that they don't fire outside of the realm.
+    fn save_from(&mut self, regs: &mut impl Registers, nr_bps: usize, nr_wps: usize) {
+        for (bank, vals) in self.banks() {
+            let nr = match bank {
+                Bank::Bcr | Bank::Bvr => nr_bps,
+                Bank::Wcr | Bank::Wvr => nr_wps,
+            };
+            for (n, val) in vals.iter_mut().enumerate().take(nr) {
+                *val = regs.read(bank, n);
+                if matches!(bank, Bank::Bcr | Bank::Wcr) {
+                    regs.write(bank, n, 0);
+                }
+            }
+        }
+    }
+
+    fn restore_to(&mut self, regs: &mut impl Registers, nr_bps: usize, nr_wps: usize) {
+        for (bank, vals) in self.banks() {
+            let nr = match bank {
+                Bank::Bcr | Bank::Bvr => nr_bps,
+                Bank::Wcr | Bank::Wvr => nr_wps,
+            };
+            for (n, val) in vals.iter().enumerate().take(nr) {
+                regs.write(bank, n, *val);
+            }
+        }
+    }
+}
+
+fn implemented() -> (usize, usize) {
+    let features = cpu_features();
+    (
+        features.brps().min(NR_DBG_REGS),
+        features.wrps().min(NR_DBG_REGS),
+    )
+}
+
+pub fn restore_state(vcpu: &mut VCPU<Context>) {
+    let debug = &mut vcpu.context.debug;
+    let (nr_bps, nr_wps) = implemented();
+
+    unsafe {
+        *this_cpu().host_mdscr_mut() = MDSCR_EL1.get();
+        MDSCR_EL1.set(debug.mdscr);
+    }
+    debug.restore_to(&mut Hardware, nr_bps, nr_wps);
+}
+
+pub fn save_state(vcpu: &mut VCPU<Context>) {
+    let debug = &mut vcpu.context.debug;
+    let (nr_bps, nr_wps) = implemented();
+
+    debug.save_from(&mut Hardware, nr_bps, nr_wps);
+    unsafe {
+        debug.mdscr = MDSCR_EL1.get();
+        MDSCR_EL1.set(*this_cpu().host_mdscr_mut());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Default)]
+    struct Fake(DebugState);
+
+    impl Registers for Fake {
+        fn read(&self, bank: Bank, n: usize) -> u64 {
+            match bank {
+                Bank::Bcr => self.0.bcr[n],
+                Bank::Bvr => self.0.bvr[n],
+                Bank::Wcr => self.0.wcr[n],
+                Bank::Wvr => self.0.wvr[n],
+            }
+        }
+
+        fn write(&mut self, bank: Bank, n: usize, val: u64) {
+            match bank {
+                Bank::Bcr => self.0.bcr[n] = val,
+                Bank::Bvr => self.0.bvr[n] = val,
+                Bank::Wcr => self.0.wcr[n] = val,
+                Bank::Wvr => self.0.wvr[n] = val,
+            }
+        }
+    }
+
+    #[test]
+    fn breakpoint_switch() {
+        const BCR: u64 = 0x1e7; // enabled for EL1 and EL0, any byte
+        const BVR: u64 = 0xffff_8000_1000_0000;
+
+        let mut hw = Fake::default();
+        hw.write(Bank::Bcr, 1, BCR);
+        hw.write(Bank::Bvr, 1, BVR);
+        // beyond the implemented ones
+        hw.write(Bank::Bvr, 2, 0xdead);
+
+        // saved even with debug disabled in MDSCR_EL1
+        let mut state = DebugState::default();
+        state.save_from(&mut hw, 2, 1);
+        assert_eq!((state.bcr[1], state.bvr[1]), (BCR, BVR));
+        assert_eq!(state.bvr[2], 0);
+        // disabled while the realm isn't running
+        assert_eq!(hw.read(Bank::Bcr, 1), 0);
+        assert_eq!(hw.read(Bank::Bvr, 1), BVR);
+
+        let mut hw = Fake::default();
+        state.restore_to(&mut hw, 2, 1);
+        assert_eq!((hw.read(Bank::Bcr, 1), hw.read(Bank::Bvr, 1)), (BCR, BVR));
+    }
+}
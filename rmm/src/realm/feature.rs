@@ -0,0 +1,161 @@
+use armv9a::regs::*;
+
+/// ID registers exposed to a realm.
+/// Values are sanitized and cached per realm on its creation.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct IdRegs {
+    pub pfr0: u64,
+    pub pfr1: u64,
+    pub dfr0: u64,
+    pub dfr1: u64,
+    pub afr0: u64,
+    pub afr1: u64,
+    pub isar0: u64,
+    pub isar1: u64,
+    pub mmfr0: u64,
+    pub mmfr1: u64,
+    pub mmfr2: u64,
+}
+
+impl IdRegs {
+    /// Reads the raw values from the hardware.
+    pub fn read() -> Self {
+        unsafe {
+            Self {
+                pfr0: ID_AA64PFR0_EL1.get(),
+                pfr1: ID_AA64PFR1_EL1.get(),
+                dfr0: ID_AA64DFR0_EL1.get(),
+                dfr1: ID_AA64DFR1_EL1.get(),
+                afr0: ID_AA64AFR0_EL1.get(),
+                afr1: ID_AA64AFR1_EL1.get(),
+                isar0: ID_AA64ISAR0_EL1.get(),
+                isar1: ID_AA64ISAR1_EL1.get(),
+                mmfr0: ID_AA64MMFR0_EL1.get(),
+                mmfr1: ID_AA64MMFR1_EL1.get(),
+                mmfr2: ID_AA64MMFR2_EL1.get(),
+            }
+        }
+    }
+
+    /// Returns the value of the register given as ISS encoding (ISS_ID_*).
+    pub fn get(&self, iss_id: u32) -> Option<u64> {
+        let val = match iss_id {
+            ISS_ID_AA64PFR0_EL1 => self.pfr0,
+            ISS_ID_AA64PFR1_EL1 => self.pfr1,
+            ISS_ID_AA64DFR0_EL1 => self.dfr0,
+            ISS_ID_AA64DFR1_EL1 => self.dfr1,
+            ISS_ID_AA64AFR0_EL1 => self.afr0,
+            ISS_ID_AA64AFR1_EL1 => self.afr1,
+            ISS_ID_AA64ISAR0_EL1 => self.isar0,
+            ISS_ID_AA64ISAR1_EL1 => self.isar1,
+            ISS_ID_AA64MMFR0_EL1 => self.mmfr0,
+            ISS_ID_AA64MMFR1_EL1 => self.mmfr1,
+            ISS_ID_AA64MMFR2_EL1 => self.mmfr2,
+            _ => return None,
+        };
+        Some(val)
+    }
+}
+
+// Features that RMM doesn't support for realms
+const PFR0_MASK: u64 = AA64PFR0::AMU | AA64PFR0::MPAM | AA64PFR0::SVE | AA64PFR0::RAS;
+const PFR1_MASK: u64 = AA64PFR1::MPAM_frac | AA64PFR1::RAS_frac | AA64PFR1::MTE;
+// Pointer authentication
+const ISAR1_MASK: u64 = AA64ISAR1::GPI | AA64ISAR1::GPA | AA64ISAR1::APA | AA64ISAR1::API;
+const DFR0_MASK: u64 = AA64DFR0::BRBE
+    | AA64DFR0::MTPMU
+    | AA64DFR0::TraceBuffer
+    | AA64DFR0::TraceFilt
+    | AA64DFR0::PMSVer
+    | AA64DFR0::CTX_CMPs
+    | AA64DFR0::WRPs
+    | AA64DFR0::BRPs
+    | AA64DFR0::PMUVer
+    | AA64DFR0::TraceVer
+    | AA64DFR0::DebugVer;
+
+/// Masks out the feature fields which must not be visible to realms.
+pub fn sanitize_id_regs(raw: &IdRegs) -> IdRegs {
+    // Armv8.4 debug with one breakpoint and one watchpoint
+    let mut dfr0 = AA64DFR0(raw.dfr0 & !DFR0_MASK);
+    dfr0.set_masked_value(AA64DFR0::DebugVer, 6);
+    dfr0.set_masked_value(AA64DFR0::BRPs, 1);
+    dfr0.set_masked_value(AA64DFR0::WRPs, 1);
+
+    IdRegs {
+        pfr0: raw.pfr0 & !PFR0_MASK,
+        pfr1: raw.pfr1 & !PFR1_MASK,
+        dfr0: dfr0.get(),
+        isar1: raw.isar1 & !ISAR1_MASK,
+        ..*raw
+    }
+}
+
+/// Makes SVE visible to a realm configured with it.
+pub fn expose_sve(regs: &mut IdRegs, raw: &IdRegs) {
+    regs.pfr0 |= raw.pfr0 & AA64PFR0::SVE;
+}
+
+/// Makes the PMU visible to a realm configured with it.
+pub fn expose_pmu(regs: &mut IdRegs, raw: &IdRegs) {
+    regs.dfr0 |= raw.dfr0 & AA64DFR0::PMUVer;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sanitize_id_regs() {
+        let raw = IdRegs {
+            pfr0: u64::MAX,
+            pfr1: u64::MAX,
+            dfr0: u64::MAX,
+            isar0: 0x1234_5678,
+            isar1: u64::MAX,
+            mmfr0: 0x8765_4321,
+            ..Default::default()
+        };
+        let sanitized = sanitize_id_regs(&raw);
+
+        assert_eq!(sanitized.pfr0 & AA64PFR0::RAS, 0);
+        assert_eq!(sanitized.pfr0 & AA64PFR0::AMU, 0);
+        assert_eq!(sanitized.pfr0 & AA64PFR0::MPAM, 0);
+        assert_eq!(sanitized.pfr0 & AA64PFR0::SVE, 0);
+        assert_eq!(sanitized.pfr0 | PFR0_MASK, u64::MAX);
+
+        assert_eq!(sanitized.pfr1 & AA64PFR1::MTE, 0);
+        assert_eq!(sanitized.pfr1 | PFR1_MASK, u64::MAX);
+
+        assert_eq!(sanitized.isar1 & (AA64ISAR1::APA | AA64ISAR1::API), 0);
+        assert_eq!(sanitized.isar1 & (AA64ISAR1::GPA | AA64ISAR1::GPI), 0);
+
+        let dfr0 = AA64DFR0(sanitized.dfr0);
+        assert_eq!(dfr0.get_masked_value(AA64DFR0::DebugVer), 6);
+        assert_eq!(dfr0.get_masked_value(AA64DFR0::BRPs), 1);
+        assert_eq!(dfr0.get_masked_value(AA64DFR0::PMUVer), 0);
+
+        assert_eq!(sanitized.isar0, raw.isar0);
+        assert_eq!(sanitized.mmfr0, raw.mmfr0);
+
+        let mut sve = sanitized;
+        expose_sve(&mut sve, &raw);
+        assert_eq!(sve.pfr0, sanitized.pfr0 | AA64PFR0::SVE);
+
+        let mut pmu = sanitized;
+        expose_pmu(&mut pmu, &raw);
+        assert_eq!(pmu.dfr0, sanitized.dfr0 | AA64DFR0::PMUVer);
+    }
+
+    #[test]
+    fn test_get_by_encoding() {
+        let regs = IdRegs {
+            pfr0: 1,
+            mmfr2: 2,
+            ..Default::default()
+        };
+        assert_eq!(regs.get(ISS_ID_AA64PFR0_EL1), Some(1));
+        assert_eq!(regs.get(ISS_ID_AA64MMFR2_EL1), Some(2));
+        assert_eq!(regs.get(0), None);
+    }
+}
@@ -0,0 +1,239 @@
+//! Lazy switching of the FP/SIMD registers.
+//!
+//! FP/SIMD and SVE accesses of the realm are trapped by CPTR_EL2.TFP and TZ.
+//! The registers of the realm are loaded on its first access after REC entry
+//! and put back on REC exit, so the ones of the host are saved and restored
+//! only if the realm uses them. On CPUs with SVE, the whole SVE state of the
+//! host is saved as well, whether or not the realm uses SVE, so that its P, FFR,
+//! ZCR and the upper bits of its Z registers are neither lost nor left holding
+//! the values of the realm. Writes to the Q registers clear the upper bits.
+
+use super::context::Context;
+use super::sve::HostSve;
+use crate::cpu::features::cpu_features;
+use crate::cpu::this_cpu;
+
+use armv9a::regs::*;
+
+/// FP/SIMD registers saved in RMM
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FpRegs {
+    pub q: [u128; 32],
+    pub fpsr: u64,
+    pub fpcr: u64,
+}
+
+/// Tracks whose FP/SIMD registers are live on a CPU
+#[derive(Clone, Copy, Debug)]
+pub struct LazyFp {
+    realm_loaded: bool,
+    /// Registers of the host while the ones of the realm are loaded
+    host: FpRegs,
+    /// SVE registers of the host while the ones of the realm are loaded
+    host_sve: HostSve,
+}
+
+impl LazyFp {
+    pub const fn new() -> Self {
+        Self {
+            realm_loaded: false,
+            host: FpRegs {
+                q: [0; 32],
+                fpsr: 0,
+                fpcr: 0,
+            },
+            host_sve: HostSve::new(),
+        }
+    }
+
+    pub fn realm_loaded(&self) -> bool {
+        self.realm_loaded
+    }
+
+    /// Returns true if the registers need switching to the ones of the realm
+    fn load_realm(&mut self) -> bool {
+        !core::mem::replace(&mut self.realm_loaded, true)
+    }
+
+    /// Returns true if the registers need switching back to the ones of the host
+    fn put_realm(&mut self) -> bool {
+        core::mem::replace(&mut self.realm_loaded, false)
+    }
+}
+
+impl Default for LazyFp {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Handles the first FP/SIMD or SVE access of the running realm.
+/// The trapped instruction is executed again once the registers are loaded.
+pub fn handle_trap(context: &Context) {
+    let mut cpu = this_cpu();
+    let lazy = cpu.lazy_fp_mut();
+    if !lazy.load_realm() {
+        return;
+    }
+
+    let host_sve = cpu_features().sve();
+    unsafe {
+        set_traps(false, false);
+        save(&mut lazy.host);
+        if host_sve {
+            lazy.host_sve.save();
+        }
+        match &context.sve {
+            Some(sve) => {
+                sve.restore();
+                set_fp_ctrl(context.fpsr, context.fpcr);
+            }
+            None => {
+                restore(&FpRegs {
+                    q: context.fpsimd,
+                    fpsr: context.fpsr,
+                    fpcr: context.fpcr,
+                });
+                // SVE is undefined for the realm
+                set_traps(false, true);
+            }
+        }
+    }
+}
+
+/// Puts back the registers of the host if the realm has used FP/SIMD since the entry.
+pub fn put_realm(context: &mut Context) {
+    let mut cpu = this_cpu();
+    let lazy = cpu.lazy_fp_mut();
+    if !lazy.put_realm() {
+        return;
+    }
+
+    let host_sve = cpu_features().sve();
+    unsafe {
+        let mut regs = FpRegs::default();
+        set_traps(false, false);
+        if let Some(sve) = &mut context.sve {
+            sve.save();
+        }
+        save(&mut regs);
+        restore(&lazy.host);
+        // after the Q registers, whose writes clear the upper bits of the Z ones
+        if host_sve {
+            lazy.host_sve.restore();
+        }
+        set_traps(true, true);
+
+        context.fpsimd = regs.q;
+        context.fpsr = regs.fpsr;
+        context.fpcr = regs.fpcr;
+    }
+}
+
+/// Traps the FP/SIMD accesses if `fp` and the SVE ones if `sve`,
+/// from the realm as well as from RMM itself.
+unsafe fn set_traps(fp: bool, sve: bool) {
+    let mut cptr = CPTR_EL2.get() & !CPTR_EL2::TFP;
+    if fp {
+        cptr |= CPTR_EL2::TFP;
+    }
+    // TZ is RES1 without SVE, so it's only cleared on CPUs with SVE
+    if cpu_features().sve() && !sve {
+        cptr &= !CPTR_EL2::TZ;
+    } else {
+        cptr |= CPTR_EL2::TZ;
+    }
+    CPTR_EL2.set(cptr);
+    core::arch::asm!("isb");
+}
+
+unsafe fn set_fp_ctrl(fpsr: u64, fpcr: u64) {
+    core::arch::asm!(
+        ".arch_extension fp",
+        "msr fpsr, {fpsr}",
+        "msr fpcr, {fpcr}",
+        fpsr = in(reg) fpsr,
+        fpcr = in(reg) fpcr,
+    );
+}
+
+unsafe fn save(regs: &mut FpRegs) {
+    let q = regs.q.as_mut_ptr();
+    core::arch::asm!(
+        ".arch_extension fp",
+        ".arch_extension simd",
+        "stp q0, q1, [{q}, #32 * 0]",
+        "stp q2, q3, [{q}, #32 * 1]",
+        "stp q4, q5, [{q}, #32 * 2]",
+        "stp q6, q7, [{q}, #32 * 3]",
+        "stp q8, q9, [{q}, #32 * 4]",
+        "stp q10, q11, [{q}, #32 * 5]",
+        "stp q12, q13, [{q}, #32 * 6]",
+        "stp q14, q15, [{q}, #32 * 7]",
+        "stp q16, q17, [{q}, #32 * 8]",
+        "stp q18, q19, [{q}, #32 * 9]",
+        "stp q20, q21, [{q}, #32 * 10]",
+        "stp q22, q23, [{q}, #32 * 11]",
+        "stp q24, q25, [{q}, #32 * 12]",
+        "stp q26, q27, [{q}, #32 * 13]",
+        "stp q28, q29, [{q}, #32 * 14]",
+        "stp q30, q31, [{q}, #32 * 15]",
+        "mrs {fpsr}, fpsr",
+        "mrs {fpcr}, fpcr",
+        q = in(reg) q,
+        fpsr = out(reg) regs.fpsr,
+        fpcr = out(reg) regs.fpcr,
+    );
+}
+
+unsafe fn restore(regs: &FpRegs) {
+    let q = regs.q.as_ptr();
+    core::arch::asm!(
+        ".arch_extension fp",
+        ".arch_extension simd",
+        "ldp q0, q1, [{q}, #32 * 0]",
+        "ldp q2, q3, [{q}, #32 * 1]",
+        "ldp q4, q5, [{q}, #32 * 2]",
+        "ldp q6, q7, [{q}, #32 * 3]",
+        "ldp q8, q9, [{q}, #32 * 4]",
+        "ldp q10, q11, [{q}, #32 * 5]",
+        "ldp q12, q13, [{q}, #32 * 6]",
+        "ldp q14, q15, [{q}, #32 * 7]",
+        "ldp q16, q17, [{q}, #32 * 8]",
+        "ldp q18, q19, [{q}, #32 * 9]",
+        "ldp q20, q21, [{q}, #32 * 10]",
+        "ldp q22, q23, [{q}, #32 * 11]",
+        "ldp q24, q25, [{q}, #32 * 12]",
+        "ldp q26, q27, [{q}, #32 * 13]",
+        "ldp q28, q29, [{q}, #32 * 14]",
+        "ldp q30, q31, [{q}, #32 * 15]",
+        "msr fpsr, {fpsr}",
+        "msr fpcr, {fpcr}",
+        q = in(reg) q,
+        fpsr = in(reg) regs.fpsr,
+        fpcr = in(reg) regs.fpcr,
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lazy_fp_bookkeeping() {
+        let mut lazy = LazyFp::default();
+        assert!(!lazy.realm_loaded());
+        // nothing to put back if the realm hasn't used FP/SIMD
+        assert!(!lazy.put_realm());
+
+        assert!(lazy.load_realm());
+        assert!(lazy.realm_loaded());
+        // the registers of the host are saved only once
+        assert!(!lazy.load_realm());
+
+        assert!(lazy.put_realm());
+        assert!(!lazy.realm_loaded());
+        assert!(!lazy.put_realm());
+    }
+}
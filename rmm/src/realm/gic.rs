@@ -0,0 +1,482 @@
+use crate::realm::context::Context;
+use crate::realm::registry::get_realm;
+use crate::realm::vcpu::VCPU;
+use crate::rmi::error::Error;
+use crate::rmi::error::InternalError::*;
+use crate::rmi::rec::run::Run;
+
+use armv9a::regs::*;
+use lazy_static::lazy_static;
+
+// Interrupt Controller List Registers (ICH_LR)
+const ICH_LR_PRIORITY_WIDTH: u64 = 8;
+const ICH_LR_PRIORITY_SHIFT: u64 = 48;
+const ICH_LR_STATE_SHIFT: u64 = 62;
+const ICH_LR_VINTID_MASK: u64 = 0xffff_ffff;
+// INTIDs which are never signaled as interrupts
+const SPECIAL_INTIDS: core::ops::RangeInclusive<u64> = 1020..=1023;
+
+// Interrupt Controller Hyp Control Register (ICH_HCR)
+// Global enable bit for the virtual CPU interface.
+const ICH_HCR_EL2_EN_BIT: u64 = 1 << 0;
+// Underflow Interrupt Enable
+const ICH_HCR_EL2_UIE_BIT: u64 = 1 << 1;
+// List Register Entry Not Present Interrupt Enable
+const ICH_HCR_EL2_LRENPIE_BIT: u64 = 1 << 2;
+// No Pending Interrupt Enable
+const ICH_HCR_EL2_NPIE_BIT: u64 = 1 << 3;
+// VM Group 0 Enabled Interrupt Enable
+const ICH_HCR_EL2_VGRP0EIE_BIT: u64 = 1 << 4;
+// VM Group 0 Disabled Interrupt Enable
+const ICH_HCR_EL2_VGRP0DIE_BIT: u64 = 1 << 5;
+// VM Group 1 Enabled Interrupt Enable
+const ICH_HCR_EL2_VGRP1EIE_BIT: u64 = 1 << 6;
+// VM Group 1 Disabled Interrupt Enable
+const ICH_HCR_EL2_VGRP1DIE_BIT: u64 = 1 << 7;
+// Deactivation of virtual SGIs can increment ICH_HCR_EL2.EOIcount
+const ICH_HCR_EL2_VSGIEEOICOUNT_BIT: u64 = 1 << 8;
+// When FEAT_GICv3_TDIR is implemented, Trap EL1 writes to ICC_DIR_EL1 and ICV_DIR_EL1.
+const ICH_HCR_EL2_TDIR_BIT: u64 = 1 << 14;
+// ICH_HCR_EL2_DVIM_BIT
+const ICH_HCR_EL2_DVIM_BIT: u64 = 1 << 15;
+
+pub const ICH_HCR_EL2_NS_MASK: u64 = ICH_HCR_EL2_UIE_BIT
+    | ICH_HCR_EL2_LRENPIE_BIT
+    | ICH_HCR_EL2_NPIE_BIT
+    | ICH_HCR_EL2_VGRP0EIE_BIT
+    | ICH_HCR_EL2_VGRP0DIE_BIT
+    | ICH_HCR_EL2_VGRP1EIE_BIT
+    | ICH_HCR_EL2_VGRP1DIE_BIT
+    | ICH_HCR_EL2_TDIR_BIT;
+
+const ICH_HCR_EL2_EOI_COUNT_SHIFT: usize = 27;
+const ICH_HCR_EL2_EOI_COUNT_WIDTH: usize = 5;
+pub const ICH_HCR_EL2_EOI_COUNT_MASK: u64 =
+    ((!0u64) >> (64 - ICH_HCR_EL2_EOI_COUNT_WIDTH)) << ICH_HCR_EL2_EOI_COUNT_SHIFT;
+
+/// State of the virtual CPU interface of a REC
+#[repr(C)]
+#[derive(Default, Debug)]
+pub struct GicState {
+    // Interrupt Controller Hyp Active Priorities Group 0 Registers
+    pub ich_ap0r_el2: [u64; 4],
+    // Interrupt Controller Hyp Active Priorities Group 1 Registers
+    pub ich_ap1r_el2: [u64; 4],
+    // GICv3 Virtual Machine Control Register
+    pub ich_vmcr_el2: u64,
+    // Interrupt Controller Hyp Control Register
+    pub ich_hcr_el2: u64,
+    // GICv3 List Registers
+    pub ich_lr_el2: [u64; 16],
+    // GICv3 Maintenance Interrupt State Register
+    pub ich_misr_el2: u64,
+}
+
+impl GicState {
+    /// Takes the list registers and the control bits the host manages from `run`.
+    /// `nr_lrs` is the number of implemented list registers.
+    pub fn receive_from_host(&mut self, run: &Run, nr_lrs: usize) {
+        self.ich_lr_el2[..nr_lrs].copy_from_slice(&unsafe { run.entry_gic_lrs() }[..nr_lrs]);
+        self.ich_hcr_el2 &= !ICH_HCR_EL2_NS_MASK;
+        self.ich_hcr_el2 |= unsafe { run.entry_gic_hcr() } & ICH_HCR_EL2_NS_MASK;
+    }
+
+    /// Exposes the list registers and the maintenance state to the host through `run`.
+    /// The host finds the virtual interrupts still pending and the EOIs to be handled
+    /// here on every exit, including the IRQ exits.
+    pub fn send_to_host(&self, run: &mut Run, nr_lrs: usize) {
+        unsafe {
+            run.exit_gic_lrs_mut()[..nr_lrs].copy_from_slice(&self.ich_lr_el2[..nr_lrs]);
+            run.set_gic_misr(self.ich_misr_el2);
+            run.set_gic_vmcr(self.ich_vmcr_el2);
+            run.set_gic_hcr(self.ich_hcr_el2 & (ICH_HCR_EL2_EOI_COUNT_MASK | ICH_HCR_EL2_NS_MASK));
+        }
+    }
+
+    /// A maintenance interrupt is asserted for the REC
+    pub fn maintenance_pending(&self) -> bool {
+        self.ich_misr_el2 != 0
+    }
+}
+
+#[allow(dead_code)]
+pub struct GicFeatures {
+    /// Number of implemented list registers minus one (ICH_VTR_EL2.ListRegs)
+    pub nr_lrs: usize,
+    pub nr_aprs: usize,
+    pub pri_res0_mask: u64,
+    pub max_vintid: u64,
+}
+
+lazy_static! {
+    pub static ref GIC_FEATURES: GicFeatures = {
+        trace!("read gic features");
+        let nr_lrs = unsafe { ICH_VTR_EL2.get_masked_value(ICH_VTR_EL2::LIST) } as usize;
+        trace!("nr_lrs (LIST) {}", nr_lrs);
+        let id = unsafe { ICH_VTR_EL2.get_masked_value(ICH_VTR_EL2::ID) };
+        let max_vintid = if id == 0 {
+            (1u64 << 16) - 1
+        } else {
+            (1u64 << 24) - 1
+        };
+        trace!("id {} max_vintid {}", id, max_vintid);
+        let pre = unsafe { ICH_VTR_EL2.get_masked_value(ICH_VTR_EL2::PRE) } + 1;
+        let nr_aprs = (1 << (pre - 5)) - 1;
+        trace!("pre {}, nr_aprs {}", pre, nr_aprs);
+        let pri = unsafe { ICH_VTR_EL2.get_masked_value(ICH_VTR_EL2::PRI) } + 1;
+        let pri_res0_mask = (1u64 << (ICH_LR_PRIORITY_WIDTH - pri)) - 1;
+        trace!("pri {} pri_res0_mask {}", pri, pri_res0_mask);
+        GicFeatures {
+            nr_lrs,
+            nr_aprs,
+            pri_res0_mask,
+            max_vintid,
+        }
+    };
+}
+
+/// Checks the virtual interrupts the host injects with the list registers in `lrs`.
+/// Each of them needs a valid vINTID, which is in at most one list register,
+/// and a priority the implemented bits can hold. Only the implemented list
+/// registers can be in use.
+pub fn check_lrs(lrs: &[u64; 16], features: &GicFeatures) -> Result<(), Error> {
+    let in_use = |lr: u64| lr >> ICH_LR_STATE_SHIFT != 0;
+    let nr_lrs = features.nr_lrs + 1;
+
+    for (i, &lr) in lrs.iter().enumerate().filter(|(_, &lr)| in_use(lr)) {
+        if i >= nr_lrs {
+            warn!("LR{} is injected but {} LRs are implemented", i, nr_lrs);
+            return Err(Error::RmiErrorRec);
+        }
+        let vintid = lr & ICH_LR_VINTID_MASK;
+        let priority = (lr >> ICH_LR_PRIORITY_SHIFT) & ((1 << ICH_LR_PRIORITY_WIDTH) - 1);
+        if vintid > features.max_vintid
+            || SPECIAL_INTIDS.contains(&vintid)
+            || priority & features.pri_res0_mask != 0
+        {
+            warn!("Invalid LR{}: {:#x}", i, lr);
+            return Err(Error::RmiErrorRec);
+        }
+        if lrs[..i]
+            .iter()
+            .any(|&other| in_use(other) && other & ICH_LR_VINTID_MASK == vintid)
+        {
+            warn!("vINTID {} is injected twice", vintid);
+            return Err(Error::RmiErrorRec);
+        }
+    }
+    Ok(())
+}
+
+/// Checks the list registers of `run` before anything is taken from it on REC entry
+pub fn check_entry_lrs(run: &Run) -> Result<(), Error> {
+    check_lrs(unsafe { run.entry_gic_lrs() }, &GIC_FEATURES)
+}
+
+pub fn init_gic(vcpu: &mut VCPU<Context>) {
+    let gic_state = &mut vcpu.context.gic_state;
+    gic_state.ich_hcr_el2 =
+        ICH_HCR_EL2_EN_BIT | ICH_HCR_EL2_VSGIEEOICOUNT_BIT | ICH_HCR_EL2_DVIM_BIT
+}
+
+fn set_lr(i: usize, val: u64) {
+    match i {
+        0 => unsafe { ICH_LR0_EL2.set(val) },
+        1 => unsafe { ICH_LR1_EL2.set(val) },
+        2 => unsafe { ICH_LR2_EL2.set(val) },
+        3 => unsafe { ICH_LR3_EL2.set(val) },
+        4 => unsafe { ICH_LR4_EL2.set(val) },
+        5 => unsafe { ICH_LR5_EL2.set(val) },
+        6 => unsafe { ICH_LR6_EL2.set(val) },
+        7 => unsafe { ICH_LR7_EL2.set(val) },
+        8 => unsafe { ICH_LR8_EL2.set(val) },
+        9 => unsafe { ICH_LR9_EL2.set(val) },
+        10 => unsafe { ICH_LR10_EL2.set(val) },
+        11 => unsafe { ICH_LR11_EL2.set(val) },
+        12 => unsafe { ICH_LR12_EL2.set(val) },
+        13 => unsafe { ICH_LR13_EL2.set(val) },
+        14 => unsafe { ICH_LR14_EL2.set(val) },
+        15 => unsafe { ICH_LR15_EL2.set(val) },
+        _ => {}
+    }
+}
+
+fn set_ap0r(i: usize, val: u64) {
+    match i {
+        0 => unsafe {
+            ICH_AP0R0_EL2.set(val);
+        },
+        1 => unsafe {
+            ICH_AP0R1_EL2.set(val);
+        },
+        2 => unsafe {
+            ICH_AP0R2_EL2.set(val);
+        },
+        3 => unsafe {
+            ICH_AP0R3_EL2.set(val);
+        },
+        _ => {}
+    }
+}
+
+fn set_ap1r(i: usize, val: u64) {
+    match i {
+        0 => unsafe {
+            ICH_AP1R0_EL2.set(val);
+        },
+        1 => unsafe {
+            ICH_AP1R1_EL2.set(val);
+        },
+        2 => unsafe {
+            ICH_AP1R2_EL2.set(val);
+        },
+        3 => unsafe {
+            ICH_AP1R3_EL2.set(val);
+        },
+        _ => {}
+    }
+}
+
+fn get_lr(i: usize) -> u64 {
+    match i {
+        0 => unsafe { ICH_LR0_EL2.get() },
+        1 => unsafe { ICH_LR1_EL2.get() },
+        2 => unsafe { ICH_LR2_EL2.get() },
+        3 => unsafe { ICH_LR3_EL2.get() },
+        4 => unsafe { ICH_LR4_EL2.get() },
+        5 => unsafe { ICH_LR5_EL2.get() },
+        6 => unsafe { ICH_LR6_EL2.get() },
+        7 => unsafe { ICH_LR7_EL2.get() },
+        8 => unsafe { ICH_LR8_EL2.get() },
+        9 => unsafe { ICH_LR9_EL2.get() },
+        10 => unsafe { ICH_LR10_EL2.get() },
+        11 => unsafe { ICH_LR11_EL2.get() },
+        12 => unsafe { ICH_LR12_EL2.get() },
+        13 => unsafe { ICH_LR13_EL2.get() },
+        14 => unsafe { ICH_LR14_EL2.get() },
+        15 => unsafe { ICH_LR15_EL2.get() },
+        _ => {
+            unreachable!();
+        }
+    }
+}
+
+fn get_ap0r(i: usize) -> u64 {
+    match i {
+        0 => unsafe { ICH_AP0R0_EL2.get() },
+        1 => unsafe { ICH_AP0R1_EL2.get() },
+        2 => unsafe { ICH_AP0R2_EL2.get() },
+        3 => unsafe { ICH_AP0R3_EL2.get() },
+        _ => {
+            unreachable!();
+        }
+    }
+}
+
+fn get_ap1r(i: usize) -> u64 {
+    match i {
+        0 => unsafe { ICH_AP1R0_EL2.get() },
+        1 => unsafe { ICH_AP1R1_EL2.get() },
+        2 => unsafe { ICH_AP1R2_EL2.get() },
+        3 => unsafe { ICH_AP1R3_EL2.get() },
+        _ => {
+            unreachable!();
+        }
+    }
+}
+
+pub fn restore_state(vcpu: &VCPU<Context>) {
+    let gic_state = &vcpu.context.gic_state;
+    let nr_lrs = GIC_FEATURES.nr_lrs;
+    let nr_aprs = GIC_FEATURES.nr_aprs;
+
+    for i in 0..=nr_lrs {
+        set_lr(i, gic_state.ich_lr_el2[i]);
+    }
+    for i in 0..=nr_aprs {
+        set_ap0r(i, gic_state.ich_ap0r_el2[i]);
+        set_ap1r(i, gic_state.ich_ap1r_el2[i]);
+    }
+    unsafe { ICH_VMCR_EL2.set(gic_state.ich_vmcr_el2) };
+    unsafe { ICH_HCR_EL2.set(gic_state.ich_hcr_el2) };
+}
+
+pub fn save_state(vcpu: &mut VCPU<Context>) {
+    let gic_state = &mut vcpu.context.gic_state;
+    let nr_lrs = GIC_FEATURES.nr_lrs;
+    let nr_aprs = GIC_FEATURES.nr_aprs;
+
+    for i in 0..=nr_lrs {
+        *&mut gic_state.ich_lr_el2[i] = get_lr(i);
+    }
+    for i in 0..=nr_aprs {
+        *&mut gic_state.ich_ap0r_el2[i] = get_ap0r(i);
+        *&mut gic_state.ich_ap1r_el2[i] = get_ap1r(i);
+    }
+
+    *&mut gic_state.ich_vmcr_el2 = unsafe { ICH_VMCR_EL2.get() };
+    *&mut gic_state.ich_hcr_el2 = unsafe { ICH_HCR_EL2.get() };
+    *&mut gic_state.ich_misr_el2 = unsafe { ICH_MISR_EL2.get() };
+
+    unsafe { ICH_HCR_EL2.set(gic_state.ich_hcr_el2 & !ICH_HCR_EL2_EN_BIT) };
+}
+
+pub fn receive_state_from_host(id: usize, vcpu: usize, run: &Run) -> Result<(), Error> {
+    let realm = get_realm(id).ok_or(Error::RmiErrorOthers(NotExistRealm))?;
+    let locked_realm = realm.lock();
+    let vcpu = locked_realm
+        .vcpus
+        .get(vcpu)
+        .ok_or(Error::RmiErrorOthers(NotExistVCPU))?;
+
+    vcpu.lock()
+        .context
+        .gic_state
+        .receive_from_host(run, GIC_FEATURES.nr_lrs + 1);
+    Ok(())
+}
+
+pub fn send_state_to_host(id: usize, vcpu: usize, run: &mut Run) -> Result<(), Error> {
+    let realm = get_realm(id).ok_or(Error::RmiErrorOthers(NotExistRealm))?;
+    let locked_realm = realm.lock();
+    let vcpu = locked_realm
+        .vcpus
+        .get(vcpu)
+        .ok_or(Error::RmiErrorOthers(NotExistVCPU))?;
+
+    vcpu.lock()
+        .context
+        .gic_state
+        .send_to_host(run, GIC_FEATURES.nr_lrs + 1);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lr_marshalling() {
+        const NR_LRS: usize = 4;
+        let mut run = Run::default();
+        let mut lrs = [0u64; 16];
+        for (i, lr) in lrs.iter_mut().enumerate() {
+            *lr = 0xa000_0000_0000_0000 | i as u64;
+        }
+        run.set_entry_gic(&lrs, u64::MAX);
+
+        let mut gic = GicState {
+            ich_hcr_el2: ICH_HCR_EL2_EN_BIT,
+            ..Default::default()
+        };
+        gic.receive_from_host(&run, NR_LRS);
+        assert_eq!(gic.ich_lr_el2[..NR_LRS], lrs[..NR_LRS]);
+        // list registers which aren't implemented are left alone
+        assert!(gic.ich_lr_el2[NR_LRS..].iter().all(|&lr| lr == 0));
+        // the host can't touch the bits which RMM owns
+        assert_eq!(gic.ich_hcr_el2, ICH_HCR_EL2_EN_BIT | ICH_HCR_EL2_NS_MASK);
+
+        gic.ich_lr_el2[0] = 0x1234;
+        gic.ich_misr_el2 = 0x1;
+        gic.ich_hcr_el2 |= 3 << ICH_HCR_EL2_EOI_COUNT_SHIFT;
+        assert!(gic.maintenance_pending());
+
+        let mut run = Run::default();
+        gic.send_to_host(&mut run, NR_LRS);
+        let exit_lrs = unsafe { *run.exit_gic_lrs_mut() };
+        assert_eq!(exit_lrs[0], 0x1234);
+        assert_eq!(exit_lrs[1..NR_LRS], lrs[1..NR_LRS]);
+        assert!(exit_lrs[NR_LRS..].iter().all(|&lr| lr == 0));
+        assert_eq!(run.exit_gic_misr(), 0x1);
+        assert_eq!(
+            run.exit_gic_hcr(),
+            ICH_HCR_EL2_NS_MASK | (3 << ICH_HCR_EL2_EOI_COUNT_SHIFT)
+        );
+    }
+
+    #[test]
+    fn pending_state_on_irq_exit() {
+        const NR_LRS: usize = 4;
+        const LR_PENDING: u64 = 1 << 62;
+        const LR_ACTIVE: u64 = 1 << 63;
+        // maintenance interrupt on EOI of a software interrupt
+        const LR_EOI: u64 = 1 << 41;
+        const MISR_EOI: u64 = 1 << 0;
+
+        let mut gic = GicState {
+            ich_misr_el2: MISR_EOI,
+            ich_hcr_el2: ICH_HCR_EL2_EN_BIT | (1 << ICH_HCR_EL2_EOI_COUNT_SHIFT),
+            ..Default::default()
+        };
+        gic.ich_lr_el2[..5].copy_from_slice(&[
+            LR_PENDING | 27,
+            LR_ACTIVE | LR_EOI | 33,
+            LR_PENDING | LR_ACTIVE | 34,
+            0,
+            LR_PENDING | 40, // not implemented
+        ]);
+        assert!(gic.maintenance_pending());
+
+        let mut run = Run::default();
+        gic.send_to_host(&mut run, NR_LRS);
+        let exit_lrs = unsafe { *run.exit_gic_lrs_mut() };
+        let pending = exit_lrs
+            .iter()
+            .filter(|&lr| lr & LR_PENDING != 0)
+            .map(|lr| lr & 0xffff_ffff)
+            .collect::<alloc::vec::Vec<_>>();
+        assert_eq!(pending, [27, 34]);
+        assert_eq!(exit_lrs[1], LR_ACTIVE | LR_EOI | 33);
+        assert_eq!(run.exit_gic_misr(), MISR_EOI);
+        assert_eq!(run.exit_gic_hcr(), 1 << ICH_HCR_EL2_EOI_COUNT_SHIFT);
+    }
+
+    #[test]
+    fn injected_lrs() {
+        const LR_PENDING: u64 = 1 << 62;
+        const LR_GROUP1: u64 = 1 << 60;
+        const PRIORITY_A0: u64 = 0xa0 << 48;
+        const PRIORITY_80: u64 = 0x80 << 48;
+
+        // 4 LRs, 16-bit vINTIDs and 5 bits of priority
+        let features = GicFeatures {
+            nr_lrs: 3,
+            nr_aprs: 0,
+            pri_res0_mask: 0b111,
+            max_vintid: (1 << 16) - 1,
+        };
+        let mut lrs = [0u64; 16];
+        lrs[0] = LR_PENDING | LR_GROUP1 | PRIORITY_A0 | 27;
+        lrs[2] = LR_PENDING | LR_GROUP1 | PRIORITY_80 | 8192;
+        assert!(check_lrs(&lrs, &features).is_ok());
+
+        let mut run = Run::default();
+        run.set_entry_gic(&lrs, 0);
+        let mut gic = GicState::default();
+        gic.receive_from_host(&run, features.nr_lrs + 1);
+        assert_eq!(gic.ich_lr_el2[0], LR_PENDING | LR_GROUP1 | PRIORITY_A0 | 27);
+        assert_eq!(gic.ich_lr_el2[1], 0);
+        assert_eq!(
+            gic.ich_lr_el2[2],
+            LR_PENDING | LR_GROUP1 | PRIORITY_80 | 8192
+        );
+        assert!(gic.ich_lr_el2[3..].iter().all(|&lr| lr == 0));
+
+        let rejected = |slot: usize, lr: u64| {
+            let mut lrs = lrs;
+            lrs[slot] = lr;
+            matches!(check_lrs(&lrs, &features), Err(Error::RmiErrorRec))
+        };
+        // over-full: beyond the implemented LRs
+        assert!(rejected(4, LR_PENDING | PRIORITY_80 | 40));
+        // an unused LR beyond them is fine
+        assert!(!rejected(4, PRIORITY_80 | 40));
+        // injected twice
+        assert!(rejected(1, LR_PENDING | PRIORITY_80 | 27));
+        assert!(rejected(1, LR_PENDING | PRIORITY_80 | 1023));
+        assert!(rejected(1, LR_PENDING | PRIORITY_80 | (1 << 16)));
+        // a priority bit which isn't implemented
+        assert!(rejected(1, LR_PENDING | (0x84 << 48) | 40));
+        assert!(!rejected(1, LR_PENDING | (0x88 << 48) | 40));
+    }
+}
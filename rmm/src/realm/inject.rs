@@ -0,0 +1,192 @@
+use crate::exception::trap::helper::{write_el1_exception, write_far_el1, SysRegs};
+use crate::realm::context::Context;
+use crate::realm::registry::get_realm;
+use crate::rmi::error::Error;
+use crate::rmi::error::InternalError::*;
+
+use armv9a::regs::*;
+
+// SPSR.M[4]: the exception is taken from AArch32
+const SPSR_M_AARCH32: u64 = 1 << 4;
+const SPSR_M_EL1T: u64 = 0b0100;
+const SPSR_M_EL1H: u64 = 0b0101;
+
+// Offsets of the synchronous exception vectors from VBAR_EL1
+const VECTOR_CURRENT_SP0: u64 = 0x000;
+const VECTOR_CURRENT_SPX: u64 = 0x200;
+const VECTOR_LOWER_AARCH64: u64 = 0x400;
+const VECTOR_LOWER_AARCH32: u64 = 0x600;
+
+/// Data abort to be taken by the realm at EL1
+#[derive(Clone, Copy, Debug)]
+pub struct DataAbort {
+    /// ISS of ESR_EL1, e.g., DFSC and WnR
+    pub iss: u64,
+    pub far: u64,
+}
+
+/// Syndrome of an exception injected to the realm as the realm observes it,
+/// which is also the layout of the buffer filled by RSI_INJECTED_FAULT_READ
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct InjectedFault {
+    pub esr: u64,
+    pub far: u64,
+}
+
+impl InjectedFault {
+    /// Copies the syndrome to the buffer at `pa`.
+    ///
+    /// # Safety
+    /// `pa` must be mapped and aligned for `InjectedFault`.
+    pub unsafe fn write(&self, pa: usize) {
+        *(pa as *mut Self) = *self;
+    }
+}
+
+/// Offset of the vector the synchronous exception is taken to when
+/// it's taken to EL1 from the mode of `spsr`.
+pub fn vector_offset(spsr: u64) -> u64 {
+    if spsr & SPSR_M_AARCH32 != 0 {
+        return VECTOR_LOWER_AARCH32;
+    }
+    match spsr & SPSR_EL2::M {
+        SPSR_M_EL1T => VECTOR_CURRENT_SP0,
+        SPSR_M_EL1H => VECTOR_CURRENT_SPX,
+        _ => VECTOR_LOWER_AARCH64,
+    }
+}
+
+fn from_lower_el(spsr: u64) -> bool {
+    vector_offset(spsr) >= VECTOR_LOWER_AARCH64
+}
+
+fn data_abort_syndrome(spsr: u64, fault: &DataAbort) -> u64 {
+    let ec = match from_lower_el(spsr) {
+        true => ESR_EL1_EC_DATA_ABORT_LOWER_EL,
+        false => ESR_EL1_EC_DATA_ABORT_SAME_EL,
+    };
+    EsrEl1::new(0)
+        .set_masked_value(EsrEl1::EC, ec)
+        .set_bits(EsrEl1::IL)
+        .set_masked_value(EsrEl1::ISS, fault.iss)
+        .get()
+}
+
+/// Makes the realm take a synchronous exception with `esr` at EL1 on the next entry,
+/// which returns to the exception vector of the realm with the exceptions masked.
+/// `far` is the fault address recorded with the exception, 0 if the class has none.
+fn take(context: &mut Context, regs: &impl SysRegs, esr: u64, far: u64) {
+    write_el1_exception(regs, context.spsr, context.elr, esr);
+    enter_vector(context, esr, far);
+}
+
+/// Updates the context of the realm as `take()` does, but without the registers in `regs`
+pub(crate) fn enter_vector(context: &mut Context, esr: u64, far: u64) {
+    // restored on the next entry if the context is saved before that
+    context.sys_regs.esr_el1 = esr;
+    context.injected = Some(InjectedFault { esr, far });
+
+    context.elr = context.sys_regs.vbar + vector_offset(context.spsr);
+    context.spsr = SPSR_EL2::D | SPSR_EL2::A | SPSR_EL2::I | SPSR_EL2::F | SPSR_M_EL1H;
+}
+
+/// Injects an undefined exception to the realm, which leaves FAR_EL1 as it is.
+pub fn undefined(context: &mut Context, regs: &impl SysRegs) {
+    take(context, regs, undefined_syndrome(), 0);
+}
+
+pub(crate) fn undefined_syndrome() -> u64 {
+    EsrEl1::new(0)
+        .set_masked_value(EsrEl1::EC, ESR_EL1_EC_UNKNOWN)
+        .set_bits(EsrEl1::IL)
+        .get()
+}
+
+/// Injects a data abort to the realm, so that it takes its own abort on the next entry.
+pub fn data_abort(context: &mut Context, regs: &impl SysRegs, fault: &DataAbort) {
+    write_far_el1(regs, fault.far);
+    context.sys_regs.far = fault.far;
+    take(
+        context,
+        regs,
+        data_abort_syndrome(context.spsr, fault),
+        fault.far,
+    );
+}
+
+/// The last exception injected to `vcpu`, if any
+pub fn last_injected(id: usize, vcpu: usize) -> Result<Option<InjectedFault>, Error> {
+    let realm = get_realm(id).ok_or(Error::RmiErrorOthers(NotExistRealm))?;
+    let locked_realm = realm.lock();
+    let vcpu = locked_realm
+        .vcpus
+        .get(vcpu)
+        .ok_or(Error::RmiErrorOthers(NotExistVCPU))?;
+    let injected = vcpu.lock().context.injected;
+    Ok(injected)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::exception::trap::fixture::{El1, Regs};
+
+    #[test]
+    fn exception_vector_offset() {
+        assert_eq!(vector_offset(0x3c4), 0x000); // EL1t
+        assert_eq!(vector_offset(0x3c5), 0x200); // EL1h
+        assert_eq!(vector_offset(0x0), 0x400); // EL0t
+        assert_eq!(vector_offset(0x10), 0x600); // AArch32 User
+        assert_eq!(vector_offset(0x13), 0x600); // AArch32 Supervisor
+    }
+
+    #[test]
+    fn data_abort_class() {
+        let fault = DataAbort {
+            iss: 1 << 6 | 0b01_0000,
+            far: 0x1000,
+        };
+        assert_eq!(data_abort_syndrome(0x3c5, &fault), 0x9600_0050);
+        assert_eq!(data_abort_syndrome(0x0, &fault), 0x9200_0050);
+        assert_eq!(data_abort_syndrome(0x10, &fault), 0x9200_0050);
+    }
+
+    #[test]
+    fn injected_data_abort_readback() {
+        let mut context = Context {
+            spsr: 0x3c5,
+            ..Default::default()
+        };
+        context.sys_regs.vbar = 0x8000_0000;
+        context.elr = 0x8000_1000;
+        let fault = DataAbort {
+            iss: 1 << 6 | 0b01_0000,
+            far: 0x8000_1234,
+        };
+        let regs = Regs::default();
+        data_abort(&mut context, &regs, &fault);
+
+        let injected = InjectedFault {
+            esr: 0x9600_0050,
+            far: 0x8000_1234,
+        };
+        assert_eq!(context.injected, Some(injected));
+        assert_eq!(context.elr, 0x8000_0200);
+        assert_eq!(context.sys_regs.far, 0x8000_1234);
+        // the handler of the realm returns to the access
+        assert_eq!(
+            regs.el1.get(),
+            El1 {
+                spsr: 0x3c5,
+                elr: 0x8000_1000,
+                esr: 0x9600_0050,
+                far: 0x8000_1234,
+            }
+        );
+
+        let mut buf = InjectedFault::default();
+        unsafe { injected.write(&mut buf as *mut InjectedFault as usize) };
+        assert_eq!(buf, injected);
+    }
+}
@@ -1,13 +1,13 @@
 use core::mem::size_of;
 use vmsa::address::PhysAddr;
 
-use super::address::GuestPhysAddr;
 use crate::granule::{GranuleState, GRANULE_SIZE};
+use crate::mm::rtt::level_size;
 use crate::realm::mm::page_table::pte::{attribute, shareable};
-use crate::realm::registry::get_realm;
-use crate::rmi::error::{Error, InternalError::NotExistRealm};
+use crate::rmi::error::Error;
+use crate::rmi::realm::Rd;
 use crate::rmi::rtt::{RTT_MIN_BLOCK_LEVEL, RTT_PAGE_LEVEL};
-use armv9a::{define_bitfield, define_bits, define_mask};
+use armv9a::{bits_in_reg, define_bitfield, define_bits, define_mask};
 use vmsa::guard::Content;
 
 pub const INVALID_UNPROTECTED: u64 = 0x0;
@@ -50,19 +50,31 @@ impl RttPage {
     pub fn get_mut(&mut self, index: usize) -> Option<&mut u64> {
         self.0.get_mut(index)
     }
+
+    pub fn entries(&self) -> &[u64] {
+        &self.0
+    }
+
+    pub fn entries_mut(&mut self) -> &mut [u64] {
+        &mut self.0
+    }
 }
 
 define_bits!(
     S2TTE,
     NS[55 - 55],
     XN[54 - 54],
+    CONTIG[52 - 52],
     ADDR_L0_PAGE[47 - 39], // XXX: check this again
     ADDR_L1_PAGE[47 - 30], // XXX: check this again
     ADDR_L2_PAGE[47 - 21], // XXX: check this again
     ADDR_L3_PAGE[47 - 12], // XXX: check this again
+    ADDR_LPA2[49 - 12],
     ADDR_FULL[55 - 12],
     AF[10 - 10],
     SH[9 - 8],
+    // OA[51:50] takes the place of SH with LPA2
+    ADDR_LPA2_HI[9 - 8],
     AP[7 - 6],
     INVALID_RIPAS[6 - 6],
     INVALID_HIPAS[5 - 2],
@@ -71,6 +83,44 @@ define_bits!(
     PAGE_FLAGS[11 - 0]
 );
 
+/// Width of the output address without and with LPA2
+pub const OA_BITS: usize = 48;
+pub const OA_BITS_LPA2: usize = 52;
+
+const OA_LPA2_HI_SHIFT: usize = 50;
+
+/// Bits of a descriptor holding the output address
+pub fn oa_mask(lpa2: bool) -> u64 {
+    match lpa2 {
+        true => S2TTE::ADDR_LPA2 | S2TTE::ADDR_LPA2_HI,
+        false => S2TTE::ADDR_L3_PAGE,
+    }
+}
+
+/// Packs the output address `pa` into the address bits of a descriptor.
+/// With LPA2, OA[49:12] stays in place and OA[51:50] moves to bits [9:8].
+pub fn pack_oa(pa: u64, lpa2: bool) -> u64 {
+    match lpa2 {
+        true => {
+            (pa & S2TTE::ADDR_LPA2)
+                | bits_in_reg(S2TTE::ADDR_LPA2_HI, (pa >> OA_LPA2_HI_SHIFT) & 0b11)
+        }
+        false => pa & S2TTE::ADDR_L3_PAGE,
+    }
+}
+
+/// Extracts the output address from the descriptor `desc`
+pub fn unpack_oa(desc: u64, lpa2: bool) -> u64 {
+    let desc = S2TTE::new(desc);
+    match lpa2 {
+        true => {
+            desc.get_masked(S2TTE::ADDR_LPA2)
+                | (desc.get_masked_value(S2TTE::ADDR_LPA2_HI) << OA_LPA2_HI_SHIFT)
+        }
+        false => desc.get_masked(S2TTE::ADDR_L3_PAGE),
+    }
+}
+
 impl From<usize> for S2TTE {
     fn from(val: usize) -> Self {
         Self(val as u64)
@@ -79,20 +129,13 @@ impl From<usize> for S2TTE {
 
 impl S2TTE {
     pub fn get_s2tte(
-        realm_id: usize,
+        rd: &Rd,
         ipa: usize,
         level: usize,
         error_code: Error,
     ) -> Result<(S2TTE, usize), Error> {
-        let (s2tte, last_level) = get_realm(realm_id)
-            .ok_or(Error::RmiErrorOthers(NotExistRealm))?
-            .lock()
-            .page_table
-            .lock()
-            .ipa_to_pte(GuestPhysAddr::from(ipa), level)
-            .ok_or(error_code)?;
-
-        Ok((S2TTE::from(s2tte as usize), last_level))
+        let walk = rd.rtt().walk(ipa, level).map_err(|_| error_code)?;
+        Ok((walk.desc, walk.level))
     }
 
     pub fn is_valid(self, level: usize, is_ns: bool) -> bool {
@@ -105,18 +148,19 @@ impl S2TTE {
                     && self.get_masked_value(S2TTE::DESC_TYPE) == desc_type::L012_BLOCK))
     }
 
-    pub fn is_host_ns_valid(self, level: usize) -> bool {
+    /// Whether the host can map an unprotected IPA at `level` with the entry.
+    /// With LPA2 the SH bits hold OA[51:50] instead of the shareability.
+    pub fn is_host_ns_valid(self, level: usize, lpa2: bool) -> bool {
         let tmp = S2TTE::new(!0);
         let addr_mask = match level {
-            1 => tmp.get_masked(S2TTE::ADDR_L1_PAGE),
-            2 => tmp.get_masked(S2TTE::ADDR_L2_PAGE),
-            3 => tmp.get_masked(S2TTE::ADDR_L3_PAGE),
+            // OA below the size of the level, not OA[51:50] in place of SH
+            1..=3 => oa_mask(lpa2) & !(S2TTE::ADDR_LPA2 & (level_size(level) as u64 - 1)),
             _ => return false,
         };
-        let mask = addr_mask
-            | tmp.get_masked(S2TTE::MEMATTR)
-            | tmp.get_masked(S2TTE::AP)
-            | tmp.get_masked(S2TTE::SH);
+        let mut mask = addr_mask | tmp.get_masked(S2TTE::MEMATTR) | tmp.get_masked(S2TTE::AP);
+        if !lpa2 {
+            mask |= tmp.get_masked(S2TTE::SH);
+        }
 
         if (self.get() & !mask) != 0 {
             return false;
@@ -126,7 +170,7 @@ impl S2TTE {
             return false;
         }
 
-        if self.get_masked_value(S2TTE::SH) == shareable::RESERVED {
+        if !lpa2 && self.get_masked_value(S2TTE::SH) == shareable::RESERVED {
             return false;
         }
 
@@ -172,3 +216,60 @@ impl S2TTE {
         self.get_masked_value(S2TTE::INVALID_RIPAS)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn output_address_packing() {
+        const ATTRS: u64 = 0x400 | desc_type::L3_PAGE;
+
+        // 48-bit PA
+        let pa = 0xffff_ffff_f000;
+        let desc = pack_oa(pa, false) | ATTRS;
+        assert_eq!(desc, 0xffff_ffff_f403);
+        assert_eq!(unpack_oa(desc, false), pa);
+        // the upper bits don't fit
+        assert_eq!(pack_oa(0xf_0000_0000_0000 | pa, false), pa);
+        // the shareability field isn't part of the address
+        assert_eq!(unpack_oa(desc | 0x300, false), pa);
+
+        // 52-bit PA with LPA2
+        let pa = 0xd_8000_8800_0000;
+        let desc = pack_oa(pa, true) | ATTRS;
+        assert_eq!(desc, 0x1_8000_8800_0703);
+        assert_eq!(unpack_oa(desc, true), pa);
+        assert_eq!(
+            unpack_oa(pack_oa(0xf_ffff_ffff_f000, true), true),
+            0xf_ffff_ffff_f000
+        );
+        assert_eq!(oa_mask(true) & ATTRS, 0);
+        assert_eq!(oa_mask(false) & 0x300, 0);
+
+        // a 48-bit PA is packed the same in both modes
+        let pa = 0x8800_0000;
+        assert_eq!(pack_oa(pa, true), pack_oa(pa, false));
+    }
+
+    #[test]
+    fn host_ns_entry() {
+        let attrs =
+            bits_in_reg(S2TTE::MEMATTR, attribute::NORMAL_FWB) | bits_in_reg(S2TTE::AP, 0b11);
+
+        let desc = S2TTE::new(0x9000_0000 | attrs | bits_in_reg(S2TTE::SH, shareable::INNER));
+        assert!(desc.is_host_ns_valid(3, false));
+        assert!(desc.is_host_ns_valid(2, false));
+        assert!(!desc.is_host_ns_valid(0, false));
+        assert!(!S2TTE::new(desc.get() | 0x1000).is_host_ns_valid(2, false));
+
+        // OA[50] set, which reads as the reserved shareability without LPA2
+        let desc = S2TTE::new(pack_oa(0x4_0000_9000_0000, true) | attrs);
+        assert_eq!(desc.get_masked_value(S2TTE::SH), shareable::RESERVED);
+        assert!(!desc.is_host_ns_valid(3, false));
+        assert!(desc.is_host_ns_valid(3, true));
+        assert!(desc.is_host_ns_valid(2, true));
+        assert!(!S2TTE::new(desc.get() | 0x1000).is_host_ns_valid(2, true));
+        assert!(!S2TTE::new(desc.get() | 1 << 50).is_host_ns_valid(3, true));
+    }
+}
@@ -1,11 +1,21 @@
 pub mod config;
 pub mod context;
+pub mod debug;
+pub mod feature;
+pub mod fpu;
+pub mod gic;
+pub mod inject;
 pub mod mm;
+pub mod pmu;
 pub mod registry;
+pub mod sve;
 pub mod timer;
 pub mod vcpu;
+pub mod vmid;
 
+use crate::crypto::hash::HashAlgo;
 use crate::measurement::{Measurement, MEASUREMENTS_SLOT_NR};
+use crate::realm::feature::{sanitize_id_regs, IdRegs};
 use crate::realm::mm::IPATranslation;
 use crate::realm::vcpu::{Context, VCPU};
 
@@ -23,8 +33,11 @@ pub struct Realm<T: Context> {
     pub vmid: u16,
     pub state: State,
     pub vcpus: Vec<Arc<Mutex<VCPU<T>>>>,
+    // MPIDR of the live REC backing each VCPU, indexed by vcpuid
+    rec_mpidrs: Vec<Option<u64>>,
     pub page_table: Arc<Mutex<Box<dyn IPATranslation>>>,
     pub measurements: [Measurement; MEASUREMENTS_SLOT_NR],
+    pub id_regs: IdRegs,
 }
 
 impl<T: Context + Default> Realm<T> {
@@ -40,14 +53,50 @@ impl<T: Context + Default> Realm<T> {
                 vmid,
                 state: State::New,
                 vcpus: vcpus,
+                rec_mpidrs: Vec::new(),
                 page_table: page_table,
                 measurements: [Measurement::empty(); MEASUREMENTS_SLOT_NR],
+                id_regs: sanitize_id_regs(&IdRegs::read()),
             });
             realm
         })
     }
 }
 
+impl<T: Context> Realm<T> {
+    /// Sizes the RIM and the REMs to the digests of `algo`
+    pub fn init_measurements(&mut self, algo: HashAlgo) {
+        self.measurements = [Measurement::new(algo); MEASUREMENTS_SLOT_NR];
+    }
+
+    /// Sizes the tables of the RECs to the number the realm can have,
+    /// beyond which REC_CREATE fails
+    pub fn reserve_recs(&mut self, max_recs: usize) {
+        self.vcpus.reserve_exact(max_recs);
+        self.rec_mpidrs.reserve_exact(max_recs);
+    }
+
+    /// Records that the REC created for `vcpuid` answers to `mpidr`.
+    pub fn set_rec_mpidr(&mut self, vcpuid: usize, mpidr: u64) {
+        if self.rec_mpidrs.len() <= vcpuid {
+            self.rec_mpidrs.resize(vcpuid + 1, None);
+        }
+        self.rec_mpidrs[vcpuid] = Some(mpidr);
+    }
+
+    /// Forgets the REC of `vcpuid` once it is destroyed.
+    pub fn clear_rec_mpidr(&mut self, vcpuid: usize) {
+        if let Some(mpidr) = self.rec_mpidrs.get_mut(vcpuid) {
+            *mpidr = None;
+        }
+    }
+
+    /// Returns the vcpuid of the live REC whose MPIDR is `mpidr`.
+    pub fn find_rec_by_mpidr(&self, mpidr: u64) -> Option<usize> {
+        self.rec_mpidrs.iter().position(|rec| *rec == Some(mpidr))
+    }
+}
+
 impl<T: Context> Drop for Realm<T> {
     fn drop(&mut self) {
         info!("Realm #{} was destroyed!", self.id);
@@ -61,3 +110,58 @@ pub enum State {
     Active,
     SystemOff,
 }
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use crate::realm::context::Context as RealmContext;
+    use crate::realm::mm::address::{GuestPhysAddr, PhysAddr};
+    use core::ffi::c_void;
+
+    #[derive(Debug)]
+    struct NoTranslation;
+
+    impl IPATranslation for NoTranslation {
+        fn get_base_address(&self) -> *const c_void {
+            core::ptr::null()
+        }
+        fn ipa_to_pa(&mut self, _guest: GuestPhysAddr, _level: usize) -> Option<PhysAddr> {
+            None
+        }
+        fn ipa_to_pte(&mut self, _guest: GuestPhysAddr, _level: usize) -> Option<(u64, usize)> {
+            None
+        }
+        fn ipa_to_pte_set(
+            &mut self,
+            _guest: GuestPhysAddr,
+            _level: usize,
+            _val: u64,
+        ) -> Result<(), crate::rmi::error::Error> {
+            Ok(())
+        }
+        fn clean(&mut self) {}
+    }
+
+    pub(crate) fn realm() -> Arc<Mutex<Realm<RealmContext>>> {
+        let page_table: Box<dyn IPATranslation> = Box::new(NoTranslation);
+        Realm::new(0, 0, Arc::new(Mutex::new(page_table)))
+    }
+
+    #[test]
+    fn find_rec_by_mpidr() {
+        let realm = realm();
+        let mut realm = realm.lock();
+
+        realm.set_rec_mpidr(0, 0x0);
+        realm.set_rec_mpidr(1, 0x1);
+        realm.set_rec_mpidr(2, 0x100);
+
+        assert_eq!(realm.find_rec_by_mpidr(0x0), Some(0));
+        assert_eq!(realm.find_rec_by_mpidr(0x100), Some(2));
+        assert_eq!(realm.find_rec_by_mpidr(0x2), None);
+
+        realm.clear_rec_mpidr(1);
+        assert_eq!(realm.find_rec_by_mpidr(0x1), None);
+        assert_eq!(realm.find_rec_by_mpidr(0x100), Some(2));
+    }
+}
@@ -0,0 +1,221 @@
+//! PMU state of realms configured with the PMU at REALM_CREATE.
+//!
+//! MDCR_EL2.HPMN gives the realm the event counters it has been created with.
+//! The registers of the host are saved on REC entry and restored on REC exit
+//! only for such realms. The PMU accesses of the other realms are trapped.
+
+use super::context::Context;
+use super::vcpu::VCPU;
+use crate::cpu::features::cpu_features;
+use crate::cpu::this_cpu;
+use crate::realm::registry::get_realm;
+use crate::rmi::error::Error;
+use crate::rmi::error::InternalError::*;
+use crate::rmi::rec::run::Run;
+
+use armv9a::regs::*;
+
+/// Largest number of event counters of the architecture
+pub const NR_EVENT_CTRS: usize = 31;
+
+// PMCNTENSET_EL0, PMINTENSET_EL1 and PMOVSSET_EL0
+const CYCLE_CTR: u64 = 1 << 31;
+
+/// Number of event counters implemented, or `None` without an architected PMU
+pub fn max_counters() -> Option<u8> {
+    cpu_features().pmu()?;
+    Some(unsafe { PMCR_EL0.get_masked_value(PMCR_EL0::N) } as u8)
+}
+
+/// PMU registers of a REC, or of the host while a realm is running
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PmuState {
+    nr: u8,
+    pmcr: u64,
+    pmccfiltr: u64,
+    pmccntr: u64,
+    pmcntenset: u64,
+    pmintenset: u64,
+    pmovsset: u64,
+    pmselr: u64,
+    pmuserenr: u64,
+    evcntr: [u64; NR_EVENT_CTRS],
+    evtyper: [u64; NR_EVENT_CTRS],
+}
+
+impl PmuState {
+    /// State with the first `nr` event counters and the cycle counter
+    pub const fn new(nr: u8) -> Self {
+        Self {
+            nr,
+            pmcr: 0,
+            pmccfiltr: 0,
+            pmccntr: 0,
+            pmcntenset: 0,
+            pmintenset: 0,
+            pmovsset: 0,
+            pmselr: 0,
+            pmuserenr: 0,
+            evcntr: [0; NR_EVENT_CTRS],
+            evtyper: [0; NR_EVENT_CTRS],
+        }
+    }
+
+    pub fn nr(&self) -> u8 {
+        self.nr
+    }
+
+    /// Bits of the counters in the SET and CLR registers
+    fn counters(&self) -> u64 {
+        CYCLE_CTR | ((1 << self.nr.min(NR_EVENT_CTRS as u8)) - 1)
+    }
+
+    /// Whether an overflow interrupt is pending
+    pub fn overflow(&self) -> bool {
+        self.pmcr & PMCR_EL0::E != 0 && self.pmovsset & self.pmintenset & self.counters() != 0
+    }
+
+    /// Saves the registers of the CPU and stops the counters.
+    ///
+    /// # Safety
+    /// The first `nr` event counters must be implemented.
+    pub unsafe fn save(&mut self) {
+        self.pmcr = PMCR_EL0.get();
+        PMCR_EL0.set(self.pmcr & !PMCR_EL0::E);
+        core::arch::asm!("isb");
+
+        let counters = self.counters();
+        self.pmccfiltr = PMCCFILTR_EL0.get();
+        self.pmccntr = PMCCNTR_EL0.get();
+        self.pmcntenset = PMCNTENSET_EL0.get() & counters;
+        self.pmintenset = PMINTENSET_EL1.get() & counters;
+        self.pmovsset = PMOVSSET_EL0.get() & counters;
+        self.pmselr = PMSELR_EL0.get();
+        self.pmuserenr = PMUSERENR_EL0.get();
+        for n in 0..self.nr as usize {
+            PMSELR_EL0.set(n as u64);
+            core::arch::asm!("isb");
+            self.evcntr[n] = PMXEVCNTR_EL0.get();
+            self.evtyper[n] = PMXEVTYPER_EL0.get();
+        }
+    }
+
+    /// Loads the registers into the CPU and starts counting if enabled.
+    ///
+    /// # Safety
+    /// The first `nr` event counters must be implemented.
+    pub unsafe fn restore(&self) {
+        PMCR_EL0.set(self.pmcr & !PMCR_EL0::E);
+        core::arch::asm!("isb");
+
+        for n in 0..self.nr as usize {
+            PMSELR_EL0.set(n as u64);
+            core::arch::asm!("isb");
+            PMXEVCNTR_EL0.set(self.evcntr[n]);
+            PMXEVTYPER_EL0.set(self.evtyper[n]);
+        }
+        let counters = self.counters();
+        PMCNTENCLR_EL0.set(!self.pmcntenset & counters);
+        PMCNTENSET_EL0.set(self.pmcntenset);
+        PMINTENCLR_EL1.set(!self.pmintenset & counters);
+        PMINTENSET_EL1.set(self.pmintenset);
+        PMOVSCLR_EL0.set(!self.pmovsset & counters);
+        PMOVSSET_EL0.set(self.pmovsset);
+        PMSELR_EL0.set(self.pmselr);
+        PMUSERENR_EL0.set(self.pmuserenr);
+        PMCCFILTR_EL0.set(self.pmccfiltr);
+        PMCCNTR_EL0.set(self.pmccntr);
+        core::arch::asm!("isb");
+
+        PMCR_EL0.set(self.pmcr);
+    }
+}
+
+/// MDCR_EL2 for running a realm with `nr` event counters,
+/// or with the PMU accesses trapped if the realm has no PMU.
+fn mdcr_for(mdcr: u64, nr: Option<u8>) -> u64 {
+    let mdcr = mdcr & !(MDCR_EL2::HPME | MDCR_EL2::TPM | MDCR_EL2::TPMCR);
+    match nr {
+        Some(nr) => (mdcr & !MDCR_EL2::HPMN) | (nr as u64 & MDCR_EL2::HPMN),
+        None => mdcr | MDCR_EL2::TPM | MDCR_EL2::TPMCR,
+    }
+}
+
+pub fn restore_state(vcpu: &VCPU<Context>) {
+    let pmu = &vcpu.context.pmu;
+
+    unsafe {
+        MDCR_EL2.set(mdcr_for(MDCR_EL2.get(), pmu.as_ref().map(PmuState::nr)));
+        if let (Some(pmu), Some(nr)) = (pmu, max_counters()) {
+            let mut cpu = this_cpu();
+            let host = cpu.host_pmu_mut();
+            *host = PmuState::new(nr);
+            host.save();
+            pmu.restore();
+        }
+    }
+}
+
+pub fn save_state(vcpu: &mut VCPU<Context>) {
+    if let Some(pmu) = &mut vcpu.context.pmu {
+        unsafe {
+            pmu.save();
+            this_cpu().host_pmu_mut().restore();
+        }
+    }
+}
+
+pub fn send_state_to_host(id: usize, vcpu: usize, run: &mut Run) -> Result<(), Error> {
+    let realm = get_realm(id).ok_or(Error::RmiErrorOthers(NotExistRealm))?;
+    let mut locked_realm = realm.lock();
+    let vcpu = locked_realm
+        .vcpus
+        .get_mut(vcpu)
+        .ok_or(Error::RmiErrorOthers(NotExistVCPU))?;
+    let overflow = vcpu
+        .lock()
+        .context
+        .pmu
+        .as_ref()
+        .map_or(false, PmuState::overflow);
+    run.set_pmu_overflow(overflow);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn overflow_status() {
+        let mut pmu = PmuState::new(2);
+        assert_eq!(pmu.counters(), CYCLE_CTR | 0b11);
+
+        pmu.pmovsset = 1 << 1;
+        pmu.pmintenset = 1 << 1;
+        assert!(!pmu.overflow());
+        pmu.pmcr = PMCR_EL0::E;
+        assert!(pmu.overflow());
+
+        // the counter beyond the ones of the realm doesn't count
+        pmu.pmovsset = 1 << 2;
+        pmu.pmintenset = 1 << 2;
+        assert!(!pmu.overflow());
+        pmu.pmovsset = CYCLE_CTR;
+        pmu.pmintenset = CYCLE_CTR | 1;
+        assert!(pmu.overflow());
+
+        assert_eq!(PmuState::new(31).counters(), u32::MAX as u64);
+    }
+
+    #[test]
+    fn counters_reserved_for_host() {
+        const TDA: u64 = 1 << 9;
+
+        let mdcr = mdcr_for(TDA | MDCR_EL2::TPM | 6, Some(2));
+        assert_eq!(mdcr, TDA | 2);
+
+        let mdcr = mdcr_for(mdcr | MDCR_EL2::HPME, None);
+        assert_eq!(mdcr, TDA | 2 | MDCR_EL2::TPM | MDCR_EL2::TPMCR);
+    }
+}
@@ -1,9 +1,11 @@
-use crate::measurement::{Measurement, MeasurementError};
+use crate::measurement::MeasurementError;
 use crate::realm::Realm;
 
 use crate::event::RsiHandle;
 use crate::realm::context::Context;
-use crate::rmm_el3::{plat_token, realm_attest_key};
+use crate::rmm_el3::plat_token;
+use crate::rsi::attestation::key::attest_key;
+use crate::rsi::attestation::session::TokenSession;
 use crate::rsi::attestation::Attestation;
 use crate::rsi::error::Error as RsiError;
 
@@ -37,7 +39,7 @@ impl crate::rsi::Interface for RsiHandle {
             .nth(index)
             .ok_or(RsiError::InvalidMeasurementIndex)?;
 
-        out.as_mut_slice().copy_from_slice(measurement.as_slice());
+        measurement.read_into(out)?;
         Ok(())
     }
 
@@ -61,26 +63,10 @@ impl crate::rsi::Interface for RsiHandle {
         Ok(())
     }
 
-    fn get_attestation_token(
-        &self,
-        attest_pa: usize,
-        challenge: &[u8],
-        measurements: &[Measurement],
-        hash_algo: u8,
-    ) -> usize {
+    fn get_attestation_token(&self, session: &TokenSession, buf: &mut [u8]) -> usize {
         // TODO: consider storing attestation object somewhere,
         // as RAK and token do not change during rmm lifetime.
-        let token = Attestation::new(&plat_token(), &realm_attest_key()).create_attestation_token(
-            challenge,
-            measurements,
-            hash_algo,
-        );
-
-        unsafe {
-            let pa_ptr = attest_pa as *mut u8;
-            core::ptr::copy(token.as_ptr(), pa_ptr, token.len());
-        }
-
-        token.len()
+        let rak = attest_key().expect("Realm attestation key isn't provisioned");
+        Attestation::new(&plat_token(), rak).create_attestation_token(session, buf)
     }
 }
@@ -0,0 +1,321 @@
+//! SVE state of realms configured with SVE at REALM_CREATE.
+//!
+//! The state is switched together with FP/SIMD (ref. realm::fpu),
+//! as the FP/SIMD registers are the lower bits of the Z registers.
+
+use crate::cpu::features::cpu_features;
+
+use armv9a::regs::*;
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+extern crate alloc;
+
+/// Largest vector length encoding, which is (VL / 128) - 1
+pub const SVE_VL_MAX: u8 = 0xf;
+
+const NR_ZREGS: usize = 32;
+const NR_PREGS: usize = 16;
+const VL_BYTES_MAX: usize = vl_bytes(SVE_VL_MAX);
+
+/// Vector length in bytes for the encoded vector length `vl`
+pub const fn vl_bytes(vl: u8) -> usize {
+    (vl as usize + 1) * 16
+}
+
+/// Largest vector length supported by the hardware, or `None` without SVE
+pub fn max_vl() -> Option<u8> {
+    unsafe {
+        if !cpu_features().sve() {
+            return None;
+        }
+
+        let cptr = CPTR_EL2.get();
+        CPTR_EL2.set(cptr & !(CPTR_EL2::TZ | CPTR_EL2::TFP));
+        core::arch::asm!("isb");
+
+        // the effective length is capped to the largest one implemented
+        S3_4_C1_C2_0.set(SVE_VL_MAX as u64); // ZCR_EL2
+        core::arch::asm!("isb");
+        let bytes: usize;
+        core::arch::asm!(
+            ".arch_extension sve",
+            "rdvl {}, #1",
+            out(reg) bytes,
+        );
+
+        CPTR_EL2.set(cptr);
+        core::arch::asm!("isb");
+        Some((bytes / 16 - 1) as u8)
+    }
+}
+
+/// Z, P, FFR and ZCR_EL1 registers of a REC, sized by the vector length of its realm
+#[derive(Clone, Default)]
+pub struct SveState {
+    vl: u8,
+    zcr_el1: u64,
+    z: Vec<u8>,
+    p: Vec<u8>,
+    ffr: Vec<u8>,
+}
+
+impl SveState {
+    pub fn new(vl: u8) -> Self {
+        let bytes = vl_bytes(vl);
+        Self {
+            vl,
+            zcr_el1: vl as u64,
+            z: vec![0; NR_ZREGS * bytes],
+            p: vec![0; NR_PREGS * bytes / 8],
+            ffr: vec![0; bytes / 8],
+        }
+    }
+
+    pub fn vl(&self) -> u8 {
+        self.vl
+    }
+
+    /// Saves the registers of the CPU.
+    ///
+    /// # Safety
+    /// SVE must be accessible with the vector length of the state.
+    pub unsafe fn save(&mut self) {
+        self.zcr_el1 = S3_0_C1_C2_0.get();
+        save_regs(
+            self.z.as_mut_ptr(),
+            self.p.as_mut_ptr(),
+            self.ffr.as_mut_ptr(),
+        );
+    }
+
+    /// Loads the registers to the CPU after setting up the vector length.
+    ///
+    /// # Safety
+    /// SVE must be accessible.
+    pub unsafe fn restore(&self) {
+        S3_4_C1_C2_0.set(self.vl as u64); // ZCR_EL2
+        S3_0_C1_C2_0.set(self.zcr_el1); // ZCR_EL1
+        core::arch::asm!("isb");
+        load_regs(self.z.as_ptr(), self.p.as_ptr(), self.ffr.as_ptr());
+    }
+}
+
+/// SVE registers of the host along with its ZCR_EL2 and ZCR_EL1, which are
+/// saved at the largest vector length so that none of the bits the host may
+/// be using is lost, whatever length it has set.
+#[derive(Clone, Copy)]
+pub struct HostSve {
+    zcr_el2: u64,
+    zcr_el1: u64,
+    z: [u8; NR_ZREGS * VL_BYTES_MAX],
+    p: [u8; NR_PREGS * VL_BYTES_MAX / 8],
+    ffr: [u8; VL_BYTES_MAX / 8],
+}
+
+impl HostSve {
+    pub const fn new() -> Self {
+        Self {
+            zcr_el2: 0,
+            zcr_el1: 0,
+            z: [0; NR_ZREGS * VL_BYTES_MAX],
+            p: [0; NR_PREGS * VL_BYTES_MAX / 8],
+            ffr: [0; VL_BYTES_MAX / 8],
+        }
+    }
+
+    /// Saves the registers of the CPU.
+    ///
+    /// # Safety
+    /// SVE must be accessible.
+    pub unsafe fn save(&mut self) {
+        self.zcr_el2 = S3_4_C1_C2_0.get();
+        self.zcr_el1 = S3_0_C1_C2_0.get();
+        S3_4_C1_C2_0.set(SVE_VL_MAX as u64);
+        core::arch::asm!("isb");
+        save_regs(
+            self.z.as_mut_ptr(),
+            self.p.as_mut_ptr(),
+            self.ffr.as_mut_ptr(),
+        );
+    }
+
+    /// Loads the registers to the CPU and puts back the vector length of the host.
+    ///
+    /// # Safety
+    /// SVE must be accessible.
+    pub unsafe fn restore(&self) {
+        S3_4_C1_C2_0.set(SVE_VL_MAX as u64);
+        core::arch::asm!("isb");
+        load_regs(self.z.as_ptr(), self.p.as_ptr(), self.ffr.as_ptr());
+        S3_4_C1_C2_0.set(self.zcr_el2);
+        S3_0_C1_C2_0.set(self.zcr_el1);
+        core::arch::asm!("isb");
+    }
+}
+
+impl Default for HostSve {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl core::fmt::Debug for HostSve {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("HostSve")
+            .field("zcr_el2", &self.zcr_el2)
+            .field("zcr_el1", &self.zcr_el1)
+            .finish()
+    }
+}
+
+/// Stores Z0-Z31, P0-P15 and FFR at the current vector length
+unsafe fn save_regs(z: *mut u8, p: *mut u8, ffr: *mut u8) {
+    core::arch::asm!(
+        ".arch_extension sve",
+        "str z0, [{z}, #0, mul vl]",
+        "str z1, [{z}, #1, mul vl]",
+        "str z2, [{z}, #2, mul vl]",
+        "str z3, [{z}, #3, mul vl]",
+        "str z4, [{z}, #4, mul vl]",
+        "str z5, [{z}, #5, mul vl]",
+        "str z6, [{z}, #6, mul vl]",
+        "str z7, [{z}, #7, mul vl]",
+        "str z8, [{z}, #8, mul vl]",
+        "str z9, [{z}, #9, mul vl]",
+        "str z10, [{z}, #10, mul vl]",
+        "str z11, [{z}, #11, mul vl]",
+        "str z12, [{z}, #12, mul vl]",
+        "str z13, [{z}, #13, mul vl]",
+        "str z14, [{z}, #14, mul vl]",
+        "str z15, [{z}, #15, mul vl]",
+        "str z16, [{z}, #16, mul vl]",
+        "str z17, [{z}, #17, mul vl]",
+        "str z18, [{z}, #18, mul vl]",
+        "str z19, [{z}, #19, mul vl]",
+        "str z20, [{z}, #20, mul vl]",
+        "str z21, [{z}, #21, mul vl]",
+        "str z22, [{z}, #22, mul vl]",
+        "str z23, [{z}, #23, mul vl]",
+        "str z24, [{z}, #24, mul vl]",
+        "str z25, [{z}, #25, mul vl]",
+        "str z26, [{z}, #26, mul vl]",
+        "str z27, [{z}, #27, mul vl]",
+        "str z28, [{z}, #28, mul vl]",
+        "str z29, [{z}, #29, mul vl]",
+        "str z30, [{z}, #30, mul vl]",
+        "str z31, [{z}, #31, mul vl]",
+        "str p0, [{p}, #0, mul vl]",
+        "str p1, [{p}, #1, mul vl]",
+        "str p2, [{p}, #2, mul vl]",
+        "str p3, [{p}, #3, mul vl]",
+        "str p4, [{p}, #4, mul vl]",
+        "str p5, [{p}, #5, mul vl]",
+        "str p6, [{p}, #6, mul vl]",
+        "str p7, [{p}, #7, mul vl]",
+        "str p8, [{p}, #8, mul vl]",
+        "str p9, [{p}, #9, mul vl]",
+        "str p10, [{p}, #10, mul vl]",
+        "str p11, [{p}, #11, mul vl]",
+        "str p12, [{p}, #12, mul vl]",
+        "str p13, [{p}, #13, mul vl]",
+        "str p14, [{p}, #14, mul vl]",
+        "str p15, [{p}, #15, mul vl]",
+        "rdffr p0.b",
+        "str p0, [{ffr}]",
+        "ldr p0, [{p}]",
+        z = in(reg) z,
+        p = in(reg) p,
+        ffr = in(reg) ffr,
+    );
+}
+
+/// Loads Z0-Z31, P0-P15 and FFR at the current vector length
+unsafe fn load_regs(z: *const u8, p: *const u8, ffr: *const u8) {
+    core::arch::asm!(
+        ".arch_extension sve",
+        "ldr p0, [{ffr}]",
+        "wrffr p0.b",
+        "ldr p0, [{p}, #0, mul vl]",
+        "ldr p1, [{p}, #1, mul vl]",
+        "ldr p2, [{p}, #2, mul vl]",
+        "ldr p3, [{p}, #3, mul vl]",
+        "ldr p4, [{p}, #4, mul vl]",
+        "ldr p5, [{p}, #5, mul vl]",
+        "ldr p6, [{p}, #6, mul vl]",
+        "ldr p7, [{p}, #7, mul vl]",
+        "ldr p8, [{p}, #8, mul vl]",
+        "ldr p9, [{p}, #9, mul vl]",
+        "ldr p10, [{p}, #10, mul vl]",
+        "ldr p11, [{p}, #11, mul vl]",
+        "ldr p12, [{p}, #12, mul vl]",
+        "ldr p13, [{p}, #13, mul vl]",
+        "ldr p14, [{p}, #14, mul vl]",
+        "ldr p15, [{p}, #15, mul vl]",
+        "ldr z0, [{z}, #0, mul vl]",
+        "ldr z1, [{z}, #1, mul vl]",
+        "ldr z2, [{z}, #2, mul vl]",
+        "ldr z3, [{z}, #3, mul vl]",
+        "ldr z4, [{z}, #4, mul vl]",
+        "ldr z5, [{z}, #5, mul vl]",
+        "ldr z6, [{z}, #6, mul vl]",
+        "ldr z7, [{z}, #7, mul vl]",
+        "ldr z8, [{z}, #8, mul vl]",
+        "ldr z9, [{z}, #9, mul vl]",
+        "ldr z10, [{z}, #10, mul vl]",
+        "ldr z11, [{z}, #11, mul vl]",
+        "ldr z12, [{z}, #12, mul vl]",
+        "ldr z13, [{z}, #13, mul vl]",
+        "ldr z14, [{z}, #14, mul vl]",
+        "ldr z15, [{z}, #15, mul vl]",
+        "ldr z16, [{z}, #16, mul vl]",
+        "ldr z17, [{z}, #17, mul vl]",
+        "ldr z18, [{z}, #18, mul vl]",
+        "ldr z19, [{z}, #19, mul vl]",
+        "ldr z20, [{z}, #20, mul vl]",
+        "ldr z21, [{z}, #21, mul vl]",
+        "ldr z22, [{z}, #22, mul vl]",
+        "ldr z23, [{z}, #23, mul vl]",
+        "ldr z24, [{z}, #24, mul vl]",
+        "ldr z25, [{z}, #25, mul vl]",
+        "ldr z26, [{z}, #26, mul vl]",
+        "ldr z27, [{z}, #27, mul vl]",
+        "ldr z28, [{z}, #28, mul vl]",
+        "ldr z29, [{z}, #29, mul vl]",
+        "ldr z30, [{z}, #30, mul vl]",
+        "ldr z31, [{z}, #31, mul vl]",
+        z = in(reg) z,
+        p = in(reg) p,
+        ffr = in(reg) ffr,
+    );
+}
+
+impl core::fmt::Debug for SveState {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("SveState").field("vl", &self.vl).finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sve_state_size() {
+        assert_eq!(vl_bytes(0), 16);
+        assert_eq!(vl_bytes(SVE_VL_MAX), 256);
+
+        let sve = SveState::new(3);
+        assert_eq!(sve.vl(), 3);
+        assert_eq!(sve.z.len(), 32 * 64);
+        assert_eq!(sve.p.len(), 16 * 8);
+        assert_eq!(sve.ffr.len(), 8);
+
+        // the host is saved at the largest vector length
+        let host = HostSve::new();
+        assert_eq!(host.z.len(), 32 * 256);
+        assert_eq!(host.p.len(), 16 * 32);
+        assert_eq!(host.ffr.len(), 32);
+    }
+}
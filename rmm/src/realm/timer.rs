@@ -1,4 +1,5 @@
-use super::context::Context;
+use super::context::{Context, TimerRegister};
+use crate::event::realmexit::RecExitReason;
 use crate::realm::registry::get_realm;
 use crate::realm::vcpu::VCPU;
 use crate::rmi::error::Error;
@@ -7,6 +8,46 @@ use crate::rmi::rec::run::Run;
 
 use armv9a::regs::*;
 
+// CNTV_CTL_EL0 and CNTP_CTL_EL0
+const CNT_CTL_ENABLE: u64 = 1 << 0;
+const CNT_CTL_IMASK: u64 = 1 << 1;
+const CNT_CTL_ISTATUS: u64 = 1 << 2;
+
+/// The timer is enabled, unmasked and its condition is met
+pub fn is_asserted(ctl: u64) -> bool {
+    ctl & (CNT_CTL_ENABLE | CNT_CTL_IMASK | CNT_CTL_ISTATUS) == CNT_CTL_ENABLE | CNT_CTL_ISTATUS
+}
+
+/// Tells an IRQ caused by a timer of the realm apart from the others,
+/// given the timer controls at the time the IRQ was taken.
+pub fn irq_exit_reason(cntv_ctl: u64, cntp_ctl: u64) -> RecExitReason {
+    if is_asserted(cntv_ctl) {
+        RecExitReason::VirtualTimer
+    } else if is_asserted(cntp_ctl) {
+        RecExitReason::PhysicalTimer
+    } else {
+        RecExitReason::IRQ
+    }
+}
+
+impl TimerRegister {
+    /// Reports the timers programmed by the realm to the host,
+    /// with the deadlines in terms of the counts the host sees.
+    pub fn send_to_host(&self, run: &mut Run) {
+        unsafe {
+            run.set_cntv_ctl(self.cntv_ctl_el0);
+            run.set_cntv_cval(self.cntv_cval_el0.wrapping_sub(self.cntvoff_el2));
+            run.set_cntp_ctl(self.cntp_ctl_el0);
+            run.set_cntp_cval(self.cntp_cval_el0.wrapping_sub(self.cntpoff_el2));
+        }
+    }
+
+    /// Takes the virtual counter offset of the realm, which is the same for all its RECs
+    pub fn set_cntvoff(&mut self, cntvoff: u64) {
+        self.cntvoff_el2 = cntvoff;
+    }
+}
+
 pub fn init_timer(vcpu: &mut VCPU<Context>) {
     let timer = &mut vcpu.context.timer;
     timer.cnthctl_el2 = S3_4_C14_C1_0::EL1PCTEN | S3_4_C14_C1_0::EL1PTEN;
@@ -41,6 +82,18 @@ pub fn save_state(vcpu: &mut VCPU<Context>) {
     *&mut timer.cnthctl_el2 = unsafe { S3_4_C14_C1_0.get() };
 }
 
+/// Programs the virtual counter offset of the realm on the next entry to `vcpu`
+pub fn receive_offset(id: usize, vcpu: usize, cntvoff: u64) -> Result<(), Error> {
+    let realm = get_realm(id).ok_or(Error::RmiErrorOthers(NotExistRealm))?;
+    let locked_realm = realm.lock();
+    let vcpu = locked_realm
+        .vcpus
+        .get(vcpu)
+        .ok_or(Error::RmiErrorOthers(NotExistVCPU))?;
+    vcpu.lock().context.timer.set_cntvoff(cntvoff);
+    Ok(())
+}
+
 pub fn send_state_to_host(id: usize, vcpu: usize, run: &mut Run) -> Result<(), Error> {
     let realm = get_realm(id).ok_or(Error::RmiErrorOthers(NotExistRealm))?;
     let mut locked_realm = realm.lock();
@@ -48,13 +101,89 @@ pub fn send_state_to_host(id: usize, vcpu: usize, run: &mut Run) -> Result<(), E
         .vcpus
         .get_mut(vcpu)
         .ok_or(Error::RmiErrorOthers(NotExistVCPU))?;
-    let timer = &vcpu.lock().context.timer;
+    vcpu.lock().context.timer.send_to_host(run);
+    Ok(())
+}
 
-    unsafe {
-        run.set_cntv_ctl(timer.cntv_ctl_el0);
-        run.set_cntv_cval(timer.cntv_cval_el0 - timer.cntvoff_el2);
-        run.set_cntp_ctl(timer.cntp_ctl_el0);
-        run.set_cntp_cval(timer.cntp_cval_el0 - timer.cntpoff_el2);
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn timer_exit() {
+        let context = Context {
+            timer: TimerRegister {
+                cntvoff_el2: 0x100,
+                cntv_cval_el0: 0x1100,
+                cntv_ctl_el0: CNT_CTL_ENABLE | CNT_CTL_ISTATUS,
+                cntp_cval_el0: 0x2000,
+                cntp_ctl_el0: CNT_CTL_ENABLE | CNT_CTL_IMASK | CNT_CTL_ISTATUS,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let timer = &context.timer;
+
+        assert!(matches!(
+            irq_exit_reason(timer.cntv_ctl_el0, timer.cntp_ctl_el0),
+            RecExitReason::VirtualTimer
+        ));
+        // the physical timer is masked
+        assert!(matches!(
+            irq_exit_reason(0, timer.cntp_ctl_el0),
+            RecExitReason::IRQ
+        ));
+        assert!(matches!(
+            irq_exit_reason(CNT_CTL_ENABLE, CNT_CTL_ENABLE | CNT_CTL_ISTATUS),
+            RecExitReason::PhysicalTimer
+        ));
+
+        let mut run = Run::default();
+        timer.send_to_host(&mut run);
+        assert_eq!(run.exit_cntv(), (CNT_CTL_ENABLE | CNT_CTL_ISTATUS, 0x1000));
+        assert_eq!(run.exit_cntp(), (timer.cntp_ctl_el0, 0x2000));
+    }
+
+    #[test]
+    fn same_offset_across_recs() {
+        use crate::mm::rtt::test::Table;
+        use crate::realm::vcpu::{create_vcpu, remove};
+        use crate::rmi::realm::create_realm;
+
+        const CNTVOFF: u64 = 0x1234_0000;
+        let rtt = Table::new();
+        let id = create_realm(0xe7, rtt.addr()).unwrap();
+        let recs = [create_vcpu(id).unwrap(), create_vcpu(id).unwrap()];
+        let realm = get_realm(id).unwrap();
+        let cntvoff = |vcpu: usize| realm.lock().vcpus[vcpu].lock().context.timer.cntvoff_el2;
+
+        // the offsets saved on the last exits of the RECs
+        for (vcpu, cntvoff) in recs.iter().zip([0x100, 0x200]) {
+            let realm = realm.lock();
+            let context = &mut realm.vcpus[*vcpu].lock().context;
+            context.timer.cntvoff_el2 = cntvoff;
+            context.timer.cntv_cval_el0 = 0x5000;
+        }
+
+        // as on REC_ENTER of each REC, with the offset of the RD
+        for vcpu in recs {
+            receive_offset(id, vcpu, CNTVOFF).unwrap();
+        }
+        assert_eq!(cntvoff(recs[0]), CNTVOFF);
+        assert_eq!(cntvoff(recs[1]), CNTVOFF);
+        assert!(matches!(
+            receive_offset(id, 2, CNTVOFF),
+            Err(Error::RmiErrorOthers(NotExistVCPU))
+        ));
+
+        // the same deadline of the realm is the same deadline of the host
+        let mut run = [Run::default(), Run::default()];
+        send_state_to_host(id, recs[0], &mut run[0]).unwrap();
+        send_state_to_host(id, recs[1], &mut run[1]).unwrap();
+        assert_eq!(run[0].exit_cntv(), (0, 0x5000u64.wrapping_sub(CNTVOFF)));
+        assert_eq!(run[0].exit_cntv(), run[1].exit_cntv());
+
+        drop(realm);
+        remove(id).unwrap();
     }
-    Ok(())
 }
@@ -1,9 +1,10 @@
 use super::Realm;
 
-use crate::gic;
+use crate::realm::gic;
 use crate::realm::registry::get_realm;
 use crate::realm::registry::RMS;
 use crate::realm::timer;
+use crate::realm::vmid;
 use crate::rmi::error::Error;
 use alloc::sync::{Arc, Weak};
 use armv9a::bits_in_reg;
@@ -98,8 +99,10 @@ pub fn create_vcpu(id: usize) -> Result<usize, Error> {
     let realm = get_realm(id).ok_or(Error::RmiErrorInput)?;
 
     let page_table = realm.lock().page_table.lock().get_base_address();
-    let vttbr =
-        bits_in_reg(VTTBR_EL2::VMID, id as u64) | bits_in_reg(VTTBR_EL2::BADDR, page_table as u64);
+    // the VMID the TLB maintenance of the realm is done for
+    let vmid = realm.lock().vmid;
+    let vttbr = bits_in_reg(VTTBR_EL2::VMID, vmid as u64)
+        | bits_in_reg(VTTBR_EL2::BADDR, page_table as u64);
 
     let vcpu = VCPU::new(realm.clone());
     vcpu.lock().context.sys_regs.vttbr = vttbr;
@@ -112,6 +115,8 @@ pub fn create_vcpu(id: usize) -> Result<usize, Error> {
 }
 
 pub fn remove(id: usize) -> Result<(), Error> {
-    RMS.lock().1.remove(&id).ok_or(Error::RmiErrorInput)?;
-    Ok(())
+    let mut rms = RMS.lock();
+    let realm = rms.1.remove(&id).ok_or(Error::RmiErrorInput)?;
+    let vmid = realm.lock().vmid;
+    vmid::release(vmid)
 }
@@ -0,0 +1,143 @@
+use crate::rmi::error::{Error, InternalError};
+
+use armv9a::regs::*;
+use lazy_static::lazy_static;
+use spin::mutex::Mutex;
+
+const VMID_MAX_BITS: usize = 16;
+const BITMAP_LEN: usize = (1 << VMID_MAX_BITS) / u64::BITS as usize;
+
+lazy_static! {
+    static ref VMIDS: Mutex<VmidAllocator> = Mutex::new(VmidAllocator::new(allocator_bits()));
+}
+
+/// Width of VMIDs, which follows VTCR_EL2.VS set by vtcr::prepare_vtcr()
+pub fn vmid_bits() -> usize {
+    let vmid = unsafe { ID_AA64MMFR1_EL1.get_masked_value(ID_AA64MMFR1_EL1::VMID) };
+    match vmid {
+        mmfr1_vmid::VMIDBITS_16 => 16,
+        _ => 8,
+    }
+}
+
+#[cfg(not(test))]
+fn allocator_bits() -> usize {
+    vmid_bits()
+}
+
+/// Tests don't depend on the ID registers of the host,
+/// and 8-bit VMIDs are implemented by every PE.
+#[cfg(test)]
+fn allocator_bits() -> usize {
+    8
+}
+
+/// Bitmap of the VMIDs taken by realms.
+/// VMIDs are chosen by the host in RMI_REALM_CREATE, so they are reserved
+/// rather than handed out.
+pub struct VmidAllocator {
+    bits: usize,
+    used: [u64; BITMAP_LEN],
+}
+
+impl VmidAllocator {
+    pub const fn new(bits: usize) -> Self {
+        Self {
+            bits,
+            used: [0; BITMAP_LEN],
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        1 << self.bits
+    }
+
+    fn slot(vmid: u16) -> (usize, u64) {
+        let vmid = vmid as usize;
+        (vmid / u64::BITS as usize, 1 << (vmid % u64::BITS as usize))
+    }
+
+    pub fn is_used(&self, vmid: u16) -> bool {
+        let (idx, bit) = Self::slot(vmid);
+        self.used[idx] & bit != 0
+    }
+
+    /// Takes `vmid` for a new realm. It fails if the VMID is already in use
+    /// or doesn't fit in the VMID width.
+    pub fn reserve(&mut self, vmid: u16) -> Result<(), Error> {
+        if vmid as usize >= self.capacity() || self.is_used(vmid) {
+            return Err(Error::RmiErrorInput);
+        }
+        let (idx, bit) = Self::slot(vmid);
+        self.used[idx] |= bit;
+        Ok(())
+    }
+
+    /// Gives back the VMID of a destroyed realm.
+    /// Releasing a VMID which isn't in use is a bug of RMM.
+    pub fn release(&mut self, vmid: u16) -> Result<(), Error> {
+        if vmid as usize >= self.capacity() || !self.is_used(vmid) {
+            return Err(Error::RmiErrorOthers(InternalError::NotReservedVmid));
+        }
+        let (idx, bit) = Self::slot(vmid);
+        self.used[idx] &= !bit;
+        Ok(())
+    }
+}
+
+pub fn reserve(vmid: u16) -> Result<(), Error> {
+    VMIDS.lock().reserve(vmid)
+}
+
+pub fn release(vmid: u16) -> Result<(), Error> {
+    VMIDS.lock().release(vmid)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn vmid_reserve_and_release() {
+        let mut vmids = VmidAllocator::new(16);
+
+        assert_eq!(vmids.capacity(), 0x1_0000);
+        assert!(vmids.reserve(0).is_ok());
+        assert!(vmids.reserve(0xffff).is_ok());
+        assert!(vmids.is_used(0xffff));
+        assert!(!vmids.is_used(1));
+        assert!(matches!(vmids.reserve(0), Err(Error::RmiErrorInput)));
+
+        // recycle
+        assert!(vmids.release(0).is_ok());
+        assert!(vmids.reserve(0).is_ok());
+
+        assert!(matches!(
+            vmids.release(1),
+            Err(Error::RmiErrorOthers(InternalError::NotReservedVmid))
+        ));
+        assert!(vmids.release(0).is_ok());
+        assert!(matches!(
+            vmids.release(0),
+            Err(Error::RmiErrorOthers(InternalError::NotReservedVmid))
+        ));
+    }
+
+    #[test]
+    fn vmid_exhaustion() {
+        let mut vmids = VmidAllocator::new(8);
+
+        assert!(matches!(vmids.reserve(0x100), Err(Error::RmiErrorInput)));
+        for vmid in 0..=0xff {
+            assert!(vmids.reserve(vmid).is_ok());
+        }
+        assert!((0..=0xff).all(|vmid| vmids.reserve(vmid).is_err()));
+
+        assert!(vmids.release(0x80).is_ok());
+        assert!(vmids.reserve(0x80).is_ok());
+        assert!(matches!(
+            vmids.release(0x100),
+            Err(Error::RmiErrorOthers(InternalError::NotReservedVmid))
+        ));
+    }
+}
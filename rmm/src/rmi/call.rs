@@ -0,0 +1,126 @@
+//! Registers of an SMC made by the realm.
+//!
+//! The function ID is passed in x0 and the arguments follow in x1 onwards.
+//! On return, x0 holds the status code and the output values follow in x1 onwards.
+
+/// Number of output values which can be returned besides the status code
+pub const MAX_RESULT_VALUES: usize = 4;
+
+/// Reads the arguments of a call from the registers following x0
+pub struct RmiArgs<'a> {
+    regs: &'a [u64],
+}
+
+impl<'a> RmiArgs<'a> {
+    /// `regs` holds the general purpose registers starting from x0
+    pub fn new(regs: &'a [u64]) -> Self {
+        Self {
+            regs: regs.get(1..).unwrap_or(&[]),
+        }
+    }
+
+    /// The `n`th argument, which is held in x(n + 1),
+    /// or 0 if it is out of the registers.
+    pub fn get(&self, n: usize) -> usize {
+        self.regs.get(n).copied().unwrap_or(0) as usize
+    }
+}
+
+/// Status code and output values of a call
+#[derive(Debug, PartialEq)]
+pub struct RmiResult {
+    status: usize,
+    vals: [u64; MAX_RESULT_VALUES],
+    nr_vals: usize,
+}
+
+impl RmiResult {
+    pub fn new(status: usize) -> Self {
+        Self {
+            status,
+            vals: [0; MAX_RESULT_VALUES],
+            nr_vals: 0,
+        }
+    }
+
+    /// Appends an output value, which is placed in the register after the previous one.
+    pub fn with(mut self, val: usize) -> Self {
+        if self.nr_vals < MAX_RESULT_VALUES {
+            self.vals[self.nr_vals] = val as u64;
+            self.nr_vals += 1;
+        } else {
+            error!("too many results: {:#X} dropped", val);
+        }
+        self
+    }
+
+    pub fn status(&self) -> usize {
+        self.status
+    }
+
+    /// Writes the status code into `regs[0]` and the output values after it.
+    /// The registers not covered by the result are left as they are.
+    pub fn write(&self, regs: &mut [u64]) {
+        let len = (self.nr_vals + 1).min(regs.len());
+        if let Some((x0, vals)) = regs[..len].split_first_mut() {
+            *x0 = self.status as u64;
+            vals.copy_from_slice(&self.vals[..len - 1]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::exception::lower::synchronous;
+    use crate::exception::trap;
+    use crate::realm::context::Context;
+    use crate::rsi;
+
+    #[test]
+    fn args_and_results() {
+        let mut regs = [0u64; 31];
+        regs[0] = rsi::HOST_CALL as u64;
+        regs[1] = 0x8800_0100;
+        regs[2] = 0x1234;
+
+        let args = RmiArgs::new(&regs);
+        assert_eq!(args.get(0), 0x8800_0100);
+        assert_eq!(args.get(1), 0x1234);
+        assert_eq!(args.get(30), 0);
+        assert_eq!(RmiArgs::new(&[]).get(0), 0);
+
+        RmiResult::new(rsi::SUCCESS).write(&mut regs);
+        assert_eq!(regs[..3], [rsi::SUCCESS as u64, 0x8800_0100, 0x1234]);
+
+        RmiResult::new(rsi::SUCCESS)
+            .with(1)
+            .with(0x2000)
+            .write(&mut regs);
+        assert_eq!(regs[..4], [rsi::SUCCESS as u64, 1, 0x2000, 0]);
+
+        // the values beyond the registers are dropped
+        let mut short = [0u64; 2];
+        RmiResult::new(rsi::ERROR_INPUT)
+            .with(1)
+            .with(2)
+            .write(&mut short);
+        assert_eq!(short, [rsi::ERROR_INPUT as u64, 1]);
+        let result = (0..=MAX_RESULT_VALUES).fold(RmiResult::new(0), |r, v| r.with(v));
+        assert_eq!(result.nr_vals, MAX_RESULT_VALUES);
+    }
+
+    #[test]
+    fn in_place_commands() {
+        let mut context = Context::default();
+        context.gp_regs[0] = rsi::ABI_VERSION as u64;
+        context.gp_regs[1] = 0xdead;
+        assert_eq!(synchronous::rsi::handle(&mut context), trap::RET_TO_REC);
+        assert_eq!(context.gp_regs[0], rsi::VERSION as u64);
+        assert_eq!(context.gp_regs[1], 0xdead);
+
+        context.gp_regs[0] = rsi::HOST_CALL as u64;
+        assert_eq!(synchronous::rsi::handle(&mut context), trap::RET_TO_RMM);
+        assert_eq!(context.gp_regs[0], rsi::HOST_CALL as u64);
+    }
+}
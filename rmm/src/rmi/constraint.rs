@@ -26,7 +26,7 @@ impl Constraint {
 lazy_static! {
     static ref CONSTRAINTS: BTreeMap<Command, Constraint> = {
         let mut m = BTreeMap::new();
-        m.insert(rmi::VERSION, Constraint::new(rmi::VERSION, 1, 1));
+        m.insert(rmi::VERSION, Constraint::new(rmi::VERSION, 2, 3));
         m.insert(
             rmi::GRANULE_DELEGATE,
             Constraint::new(rmi::GRANULE_DELEGATE, 2, 1),
@@ -79,6 +79,7 @@ lazy_static! {
             rmi::RTT_DESTROY,
             Constraint::new(rmi::RTT_DESTROY, 5, 1),
         );
+        m.insert(rmi::RTT_FOLD, Constraint::new(rmi::RTT_FOLD, 5, 1));
         m.insert(
             rmi::RTT_INIT_RIPAS,
             Constraint::new(rmi::RTT_INIT_RIPAS, 4, 2),
@@ -87,6 +88,20 @@ lazy_static! {
             rmi::RTT_SET_RIPAS,
             Constraint::new(rmi::RTT_SET_RIPAS, 6, 2),
         );
+        m.insert(
+            rmi::PSCI_COMPLETE,
+            Constraint::new(rmi::PSCI_COMPLETE, 3, 1),
+        );
+        m.insert(
+            rmi::REALM_SET_CNTVOFF,
+            Constraint::new(rmi::REALM_SET_CNTVOFF, 3, 1),
+        );
+        m.insert(
+            rmi::REALM_GET_CNTVOFF,
+            Constraint::new(rmi::REALM_GET_CNTVOFF, 2, 2),
+        );
+        #[cfg(debug_assertions)]
+        m.insert(rmi::SELF_TEST, Constraint::new(rmi::SELF_TEST, 2, 2));
         m.insert(rmi::REQ_COMPLETE, Constraint::new(rmi::REQ_COMPLETE, 4, 2));
         m
     };
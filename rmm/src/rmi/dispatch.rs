@@ -0,0 +1,79 @@
+extern crate alloc;
+
+use crate::rmi::error::Error;
+use crate::Monitor;
+
+use alloc::boxed::Box;
+use alloc::collections::btree_map::BTreeMap;
+
+pub type Handler = Box<dyn Fn(&[usize], &mut [usize], &Monitor) -> Result<(), Error>>;
+
+/// Handlers of the RMI commands keyed by their function IDs,
+/// which each module registers with `listen!()` at boot.
+#[derive(Default)]
+pub struct Dispatcher {
+    handlers: BTreeMap<usize, Handler>,
+}
+
+impl Dispatcher {
+    pub fn new() -> Self {
+        Self {
+            handlers: BTreeMap::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.handlers.is_empty()
+    }
+
+    pub fn register(&mut self, cmd: usize, handler: Handler) {
+        self.handlers.insert(cmd, handler);
+    }
+
+    /// Runs the handler of `cmd`, which fails with RmiErrorNotSupported if none is registered.
+    pub fn dispatch(
+        &self,
+        cmd: usize,
+        arg: &[usize],
+        ret: &mut [usize],
+        monitor: &Monitor,
+    ) -> Result<(), Error> {
+        match self.handlers.get(&cmd) {
+            Some(handler) => handler(arg, ret, monitor),
+            None => {
+                error!("Not registered event: {:X}", cmd);
+                Err(Error::RmiErrorNotSupported)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rmi;
+
+    #[test]
+    fn dispatch_by_command() {
+        let mut dispatcher = Dispatcher::new();
+        assert!(dispatcher.is_empty());
+        dispatcher.register(
+            rmi::VERSION,
+            Box::new(|arg, ret, _| {
+                ret[1] = arg[0] + 1;
+                Ok(())
+            }),
+        );
+
+        let monitor = Monitor::new();
+        let mut ret = [0; 2];
+        assert!(dispatcher
+            .dispatch(rmi::VERSION, &[0x41], &mut ret, &monitor)
+            .is_ok());
+        assert_eq!(ret[1], 0x42);
+
+        let res = dispatcher.dispatch(rmi::FEATURES, &[0], &mut ret, &monitor);
+        assert!(matches!(res, Err(Error::RmiErrorNotSupported)));
+        assert_eq!(usize::from(res.unwrap_err()), !0);
+    }
+}
@@ -10,6 +10,8 @@ pub enum Error {
     RmiErrorRtt(usize),
     RmiErrorInUse,
     RmiErrorCount,
+    /// SMCCC_NOT_SUPPORTED for a command RMM doesn't implement
+    RmiErrorNotSupported,
     //// The below are our-defined errors not in TF-RMM
     RmiErrorOthers(InternalError),
 }
@@ -20,6 +22,8 @@ pub enum InternalError {
     NotExistVCPU,
     MeasurementError,
     InvalidMeasurementIndex,
+    NotReservedVmid,
+    OutOfMemory,
 }
 
 impl From<Error> for usize {
@@ -31,15 +35,27 @@ impl From<Error> for usize {
             Error::RmiErrorRtt(level) => 4 | (level << 8),
             Error::RmiErrorInUse => 5,
             Error::RmiErrorCount => 6,
+            Error::RmiErrorNotSupported => !0,
             Error::RmiErrorOthers(_) => 7,
         }
     }
 }
 
 impl From<vmsa::error::Error> for Error {
-    fn from(_e: vmsa::error::Error) -> Self {
+    fn from(e: vmsa::error::Error) -> Self {
+        use vmsa::error::Error as MmError;
+
         //error!("MmError occured: {}", <Error as Into<usize>>::into(e));
-        Error::RmiErrorInput
+        match e {
+            MmError::MmIsInUse | MmError::MmRefcountError => Error::RmiErrorInUse,
+            MmError::MmAllocFail => Error::RmiErrorOthers(InternalError::OutOfMemory),
+            // The level tells the host which RTT to create, destroy or fold first
+            MmError::MmRttLevel(level) => Error::RmiErrorRtt(level),
+            // Granules and RTT entries in an unexpected state are errors of the input
+            // of the command; checks of the realm state return RmiErrorRealm by themselves.
+            // RMI has no error code for permission faults either.
+            _ => Error::RmiErrorInput,
+        }
     }
 }
 
@@ -55,7 +71,52 @@ impl From<rsi::error::Error> for Error {
             rsi::error::Error::RealmDoesNotExists => {
                 Self::RmiErrorOthers(InternalError::NotExistRealm)
             }
+            rsi::error::Error::MeasurementError(MeasurementError::OutputBufferTooSmall) => {
+                Self::RmiErrorInput
+            }
             _ => Self::RmiErrorOthers(InternalError::InvalidMeasurementIndex),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use vmsa::error::Error as MmError;
+
+    #[test]
+    fn mm_error_categories() {
+        let rmi = |e: MmError| usize::from(Error::from(e));
+
+        assert_eq!(rmi(MmError::MmIsInUse), 5);
+        assert_eq!(rmi(MmError::MmRefcountError), 5);
+        assert!(matches!(
+            Error::from(MmError::MmAllocFail),
+            Error::RmiErrorOthers(InternalError::OutOfMemory)
+        ));
+        assert_eq!(rmi(MmError::MmRttLevel(2)), 4 | 2 << 8);
+        for input in [
+            MmError::MmStateError,
+            MmError::MmInvalidAddr,
+            MmError::MmInvalidLevel,
+            MmError::MmNoEntry,
+            MmError::MmRustError,
+            MmError::MmUnimplemented,
+            MmError::MmWrongParentChild,
+            MmError::MmSubtableError,
+            MmError::MmErrorOthers,
+        ] {
+            assert!(matches!(Error::from(input), Error::RmiErrorInput));
+        }
+    }
+
+    #[test]
+    fn permission_fault() {
+        assert_eq!(usize::from(MmError::MmPermissionFault), 20);
+        assert!(matches!(
+            Error::from(MmError::MmPermissionFault),
+            Error::RmiErrorInput
+        ));
+        assert_eq!(usize::from(Error::from(MmError::MmPermissionFault)), 1);
+    }
+}
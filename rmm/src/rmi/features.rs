@@ -1,5 +1,8 @@
+use crate::cpu::features::{cpu_features, CpuFeatures};
 use crate::event::Mainloop;
 use crate::listen;
+use crate::realm::pmu::max_counters;
+use crate::realm::sve::max_vl;
 use crate::rmi;
 use crate::rmi::error::Error;
 
@@ -8,19 +11,23 @@ extern crate alloc;
 const S2SZ_SHIFT: usize = 0;
 const S2SZ_WIDTH: usize = 8;
 const S2SZ_VALUE: usize = 48;
+const S2SZ_LPA2_VALUE: usize = 52;
 
 const LPA2_SHIFT: usize = 8;
-#[allow(unused)]
 const LPA2_WIDTH: usize = 1;
-const LPA2_VALUE: usize = 0;
+const LPA2_VALUE: usize = SUPPORTED;
+
+const SVE_EN_SHIFT: usize = 9;
+const SVE_EN_WIDTH: usize = 1;
+
+const SVE_VL_SHIFT: usize = 10;
+const SVE_VL_WIDTH: usize = 4;
 
 const PMU_EN_SHIFT: usize = 22;
 const PMU_EN_WIDTH: usize = 1;
-const PMU_EN_VALUE: usize = NOT_SUPPORTED;
 
 const PMU_NUM_CTRS_SHIFT: usize = 23;
 const PMU_NUM_CTRS_WIDTH: usize = 5;
-const PMU_NUM_CTRS_VALUE: usize = 0;
 
 const HASH_SHA_256_SHIFT: usize = 28;
 const HASH_SHA_256_VALUE: usize = SUPPORTED;
@@ -34,8 +41,7 @@ const SUPPORTED: usize = 1;
 const FEATURE_REGISTER_0_INDEX: usize = 0;
 
 fn extract(reg: usize, shift: usize, width: usize) -> usize {
-    let mask = mask(shift, width);
-    (reg << (mask.trailing_zeros())) & mask
+    (reg & mask(shift, width)) >> shift
 }
 
 fn mask(shift: usize, width: usize) -> usize {
@@ -44,47 +50,223 @@ fn mask(shift: usize, width: usize) -> usize {
 
 pub fn set_event_handler(mainloop: &mut Mainloop) {
     listen!(mainloop, rmi::FEATURES, |arg, ret, _| {
-        if arg[0] != FEATURE_REGISTER_0_INDEX {
-            return Err(Error::RmiErrorInput);
-        }
-
-        let mut feat_reg0: usize = 0;
-        feat_reg0 |= S2SZ_VALUE << S2SZ_SHIFT;
-        feat_reg0 |= LPA2_VALUE << LPA2_SHIFT;
-        feat_reg0 |= PMU_EN_VALUE << PMU_EN_SHIFT;
-        feat_reg0 |= PMU_NUM_CTRS_VALUE << PMU_NUM_CTRS_SHIFT;
-        feat_reg0 |= HASH_SHA_256_VALUE << HASH_SHA_256_SHIFT;
-        feat_reg0 |= HASH_SHA_512_VALUE << HASH_SHA_512_SHIFT;
-
-        ret[1] = feat_reg0;
-        debug!("rmi::FEATURES ret:{:X}", feat_reg0);
+        ret[1] = match arg[0] {
+            FEATURE_REGISTER_0_INDEX => {
+                feature_register_0(cpu_features(), max_vl(), max_counters())
+            }
+            _ => 0,
+        };
+        debug!("rmi::FEATURES index:{} ret:{:X}", arg[0], ret[1]);
         Ok(())
     });
 }
 
+/// The largest IPA width a realm can be created with on `features`,
+/// which is beyond 48 bits only with LPA2.
+fn max_ipa_bits(features: &CpuFeatures) -> usize {
+    match LPA2_VALUE == SUPPORTED && features.lpa2() {
+        true => features.pa_bits().min(S2SZ_LPA2_VALUE),
+        false => features.pa_bits().min(S2SZ_VALUE),
+    }
+}
+
+/// Feature Register 0 as it is reported to the host.
+/// `max_vl` is the largest SVE vector length of the PEs, if SVE is implemented,
+/// and `pmu_ctrs` the number of PMU event counters, if the PMU is implemented.
+fn feature_register_0(features: &CpuFeatures, max_vl: Option<u8>, pmu_ctrs: Option<u8>) -> usize {
+    let mut feat_reg0: usize = 0;
+    feat_reg0 |= max_ipa_bits(features) << S2SZ_SHIFT;
+    if LPA2_VALUE == SUPPORTED && features.lpa2() {
+        feat_reg0 |= SUPPORTED << LPA2_SHIFT;
+    }
+    if let Some(vl) = max_vl {
+        feat_reg0 |= SUPPORTED << SVE_EN_SHIFT;
+        feat_reg0 |= (vl as usize) << SVE_VL_SHIFT;
+    }
+    if let Some(ctrs) = pmu_ctrs {
+        feat_reg0 |= SUPPORTED << PMU_EN_SHIFT;
+        feat_reg0 |= (ctrs as usize) << PMU_NUM_CTRS_SHIFT;
+    }
+    feat_reg0 |= HASH_SHA_256_VALUE << HASH_SHA_256_SHIFT;
+    feat_reg0 |= HASH_SHA_512_VALUE << HASH_SHA_512_SHIFT;
+    feat_reg0
+}
+
 pub fn ipa_bits(feat_reg0: usize) -> usize {
     extract(feat_reg0, S2SZ_SHIFT, S2SZ_WIDTH)
 }
 
-//TODO: locate validate() in armv9a to check against AA64MMFR_EL1 register
-pub fn validate(feat_reg0: usize) -> bool {
+/// Whether the realm uses 52-bit output addresses in its RTT
+pub fn lpa2(feat_reg0: usize) -> bool {
+    extract(feat_reg0, LPA2_SHIFT, LPA2_WIDTH) == SUPPORTED
+}
+
+/// Requested SVE vector length, or `None` if SVE isn't enabled
+pub fn sve_vl(feat_reg0: usize) -> Option<u8> {
+    match extract(feat_reg0, SVE_EN_SHIFT, SVE_EN_WIDTH) {
+        SUPPORTED => Some(extract(feat_reg0, SVE_VL_SHIFT, SVE_VL_WIDTH) as u8),
+        _ => None,
+    }
+}
+
+/// Checks the requested SVE configuration against the largest vector length
+/// supported by the hardware, and returns the vector length for the realm.
+pub fn validate_sve(feat_reg0: usize, max_vl: Option<u8>) -> Result<Option<u8>, Error> {
+    match (sve_vl(feat_reg0), max_vl) {
+        (None, _) => Ok(None),
+        (Some(vl), Some(max)) if vl <= max => Ok(Some(vl)),
+        _ => Err(Error::RmiErrorInput),
+    }
+}
+
+/// Requested number of PMU event counters, or `None` if the PMU isn't enabled
+pub fn pmu_ctrs(feat_reg0: usize) -> Option<u8> {
+    match extract(feat_reg0, PMU_EN_SHIFT, PMU_EN_WIDTH) {
+        SUPPORTED => Some(extract(feat_reg0, PMU_NUM_CTRS_SHIFT, PMU_NUM_CTRS_WIDTH) as u8),
+        _ => None,
+    }
+}
+
+/// Checks the requested PMU configuration against the number of event counters
+/// implemented, and returns the number of counters for the realm.
+/// A realm with the PMU has at least one event counter, as MDCR_EL2.HPMN
+/// can only be 0 with FEAT_HPMN0, which RMM doesn't use.
+pub fn validate_pmu(feat_reg0: usize, max_ctrs: Option<u8>) -> Result<Option<u8>, Error> {
+    match (pmu_ctrs(feat_reg0), max_ctrs) {
+        (None, _) => Ok(None),
+        (Some(ctrs), Some(max)) if ctrs > 0 && ctrs <= max => Ok(Some(ctrs)),
+        _ => Err(Error::RmiErrorInput),
+    }
+}
+
+/// Checks the requested features against `features` of the PEs.
+pub fn validate(feat_reg0: usize, features: &CpuFeatures) -> bool {
     const MIN_IPA_SIZE: usize = 32;
     let s2sz = extract(feat_reg0, S2SZ_SHIFT, S2SZ_WIDTH);
-    if s2sz < MIN_IPA_SIZE || s2sz > S2SZ_VALUE {
+    if s2sz < MIN_IPA_SIZE || s2sz > max_ipa_bits(features) {
         return false;
     }
 
-    if extract(feat_reg0, S2SZ_SHIFT, S2SZ_WIDTH) > S2SZ_VALUE {
+    if lpa2(feat_reg0) && (LPA2_VALUE == NOT_SUPPORTED || !features.lpa2()) {
         return false;
     }
-
-    // TODO: Add a check for LPA2 flag with AA64MMFR_EL1 reigster after refactoring
-
-    if extract(feat_reg0, PMU_EN_SHIFT, PMU_EN_WIDTH) == SUPPORTED
-        && extract(feat_reg0, PMU_NUM_CTRS_SHIFT, PMU_NUM_CTRS_WIDTH) != PMU_NUM_CTRS_VALUE
-    {
+    if s2sz > S2SZ_VALUE && !lpa2(feat_reg0) {
         return false;
     }
 
     true
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::realm::feature::IdRegs;
+
+    fn sve(vl: usize) -> usize {
+        (SUPPORTED << SVE_EN_SHIFT) | (vl << SVE_VL_SHIFT) | 40
+    }
+
+    #[test]
+    fn sve_vl_validation() {
+        assert_eq!(sve_vl(40), None);
+        assert_eq!(sve_vl(sve(3)), Some(3));
+        assert_eq!(ipa_bits(sve(3)), 40);
+
+        assert!(matches!(validate_sve(40, None), Ok(None)));
+        assert!(matches!(validate_sve(sve(3), Some(3)), Ok(Some(3))));
+        assert!(matches!(validate_sve(sve(0), Some(15)), Ok(Some(0))));
+        // larger than the hardware supports
+        assert!(matches!(
+            validate_sve(sve(4), Some(3)),
+            Err(Error::RmiErrorInput)
+        ));
+        assert!(matches!(
+            validate_sve(sve(0), None),
+            Err(Error::RmiErrorInput)
+        ));
+    }
+
+    fn pmu(ctrs: usize) -> usize {
+        (SUPPORTED << PMU_EN_SHIFT) | (ctrs << PMU_NUM_CTRS_SHIFT) | 40
+    }
+
+    #[test]
+    fn pmu_counters_validation() {
+        assert_eq!(pmu_ctrs(40), None);
+        assert_eq!(pmu_ctrs(pmu(6)), Some(6));
+
+        assert!(matches!(validate_pmu(40, None), Ok(None)));
+        assert!(matches!(validate_pmu(pmu(6), Some(6)), Ok(Some(6))));
+        assert!(matches!(validate_pmu(pmu(1), Some(31)), Ok(Some(1))));
+        assert!(matches!(
+            validate_pmu(pmu(0), Some(31)),
+            Err(Error::RmiErrorInput)
+        ));
+        // more counters than the hardware implements
+        assert!(matches!(
+            validate_pmu(pmu(7), Some(6)),
+            Err(Error::RmiErrorInput)
+        ));
+        assert!(matches!(
+            validate_pmu(pmu(0), None),
+            Err(Error::RmiErrorInput)
+        ));
+    }
+
+    #[test]
+    fn feature_register_0_layout() {
+        // SVE, RAS, PMUv3 for Armv8.1, 48-bit PA
+        let features = CpuFeatures::parse(&IdRegs {
+            pfr0: 0x1101_0001_1011_1112,
+            dfr0: 0x0000_0000_1030_5408,
+            mmfr0: 0x0000_0000_0010_1125,
+            ..Default::default()
+        });
+        let feat_reg0 = feature_register_0(&features, Some(3), None);
+        assert_eq!(feat_reg0, 0x3000_0e30);
+        assert_eq!(ipa_bits(feat_reg0), 48);
+        assert_eq!(sve_vl(feat_reg0), Some(3));
+        assert_eq!(extract(feat_reg0, LPA2_SHIFT, LPA2_WIDTH), NOT_SUPPORTED);
+        assert_eq!(
+            extract(feat_reg0, PMU_EN_SHIFT, PMU_EN_WIDTH),
+            NOT_SUPPORTED
+        );
+        assert_eq!(extract(feat_reg0, HASH_SHA_256_SHIFT, 1), SUPPORTED);
+        assert_eq!(extract(feat_reg0, HASH_SHA_512_SHIFT, 1), SUPPORTED);
+
+        // with the 6 event counters of the PMU
+        let feat_reg0 = feature_register_0(&features, Some(3), Some(6));
+        assert_eq!(feat_reg0, 0x3340_0e30);
+        assert_eq!(pmu_ctrs(feat_reg0), Some(6));
+
+        // 40-bit PA without SVE
+        let features = CpuFeatures::parse(&IdRegs {
+            pfr0: 0x0000_0000_0000_0011,
+            mmfr0: 0x0000_0000_0000_0002,
+            ..Default::default()
+        });
+        assert_eq!(feature_register_0(&features, None, None), 0x3000_0028);
+    }
+
+    #[test]
+    fn feature_register_0_lpa2() {
+        // 52-bit PA with LPA2 for 4KB granules
+        let features = CpuFeatures::parse(&IdRegs {
+            pfr0: 0x0000_0000_0000_0011,
+            mmfr0: 0x0000_0000_1000_0006,
+            ..Default::default()
+        });
+        let feat_reg0 = feature_register_0(&features, None, None);
+        assert_eq!(feat_reg0, 0x3000_0134);
+        assert_eq!(ipa_bits(feat_reg0), 52);
+        assert!(lpa2(feat_reg0));
+
+        // 52-bit PA without LPA2 is reported as 48 bits
+        let features = CpuFeatures::parse(&IdRegs {
+            pfr0: 0x0000_0000_0000_0011,
+            mmfr0: 0x0000_0000_0000_0006,
+            ..Default::default()
+        });
+        assert_eq!(feature_register_0(&features, None, None), 0x3000_0030);
+    }
+}
@@ -1,26 +1,51 @@
 use crate::asm::{smc, SMC_SUCCESS};
 use crate::event::Mainloop;
-use crate::granule::{set_granule, GranuleState};
+use crate::granule::{is_granule_aligned, set_granule, GranuleState};
 use crate::listen;
+use crate::mm::alloc::is_pool_granule;
 use crate::rmi;
 use crate::rmi::error::Error;
-use crate::{get_granule, get_granule_if, set_state_and_get_granule};
+use crate::{get_granule, set_state_and_get_granule};
 
 use vmsa::error::Error as MmError;
 
 extern crate alloc;
 
 // defined in trusted-firmware-a/include/services/rmmd_svc.h
-const MARK_REALM: usize = 0xc400_01b0;
-const MARK_NONSECURE: usize = 0xc400_01b1;
+pub(crate) const MARK_REALM: usize = 0xc400_01b0;
+pub(crate) const MARK_NONSECURE: usize = 0xc400_01b1;
+
+/// Only an undelegated granule can be delegated.
+fn check_delegate(state: u64) -> Result<(), MmError> {
+    match state {
+        GranuleState::Undelegated => Ok(()),
+        _ => Err(MmError::MmStateError),
+    }
+}
+
+/// Only a delegated granule can be undelegated.
+/// A granule assigned to a realm (e.g., RD, REC, RTT, DATA) is in use
+/// and must be destroyed first.
+fn check_undelegate(state: u64) -> Result<(), MmError> {
+    match state {
+        GranuleState::Delegated => Ok(()),
+        GranuleState::Undelegated => Err(MmError::MmStateError),
+        _ => Err(MmError::MmIsInUse),
+    }
+}
 
 pub fn set_event_handler(mainloop: &mut Mainloop) {
     listen!(mainloop, rmi::GRANULE_DELEGATE, |arg, _, rmm| {
         let addr = arg[0];
-        let mut granule = match get_granule_if!(addr, GranuleState::Undelegated) {
+        if !is_granule_aligned(addr) || is_pool_granule(addr) {
+            return Err(Error::RmiErrorInput);
+        }
+
+        let mut granule = match get_granule!(addr) {
             Err(MmError::MmNoEntry) => set_state_and_get_granule!(addr, GranuleState::Undelegated),
             other => other,
         }?;
+        check_delegate(granule.state())?;
 
         if smc(MARK_REALM, &[addr])[0] != SMC_SUCCESS {
             return Err(Error::RmiErrorInput);
@@ -37,7 +62,12 @@ pub fn set_event_handler(mainloop: &mut Mainloop) {
 
     listen!(mainloop, rmi::GRANULE_UNDELEGATE, |arg, _, rmm| {
         let addr = arg[0];
-        let mut granule = get_granule_if!(addr, GranuleState::Delegated)?;
+        if !is_granule_aligned(addr) {
+            return Err(Error::RmiErrorInput);
+        }
+
+        let mut granule = get_granule!(addr)?;
+        check_undelegate(granule.state())?;
 
         if smc(MARK_NONSECURE, &[addr])[0] != SMC_SUCCESS {
             panic!(
@@ -55,3 +85,53 @@ pub fn set_event_handler(mainloop: &mut Mainloop) {
         Ok(())
     });
 }
+
+#[cfg(test)]
+mod test {
+    use super::{check_delegate, check_undelegate};
+    use crate::granule::test::{recreate_granule_status_table, TEST_ADDR2};
+    use crate::granule::{set_granule, GranuleState};
+    use crate::{get_granule, set_state_and_get_granule};
+    use vmsa::error::Error;
+
+    #[test]
+    fn test_double_delegation() {
+        recreate_granule_status_table();
+
+        let test_fn = |addr: usize| -> Result<(), Error> {
+            let mut granule = set_state_and_get_granule!(addr, GranuleState::Undelegated)?;
+            check_delegate(granule.state())?;
+            assert!(set_granule(&mut granule, GranuleState::Delegated).is_ok());
+            drop(granule);
+
+            let granule = get_granule!(addr)?;
+            assert_eq!(check_delegate(granule.state()), Err(Error::MmStateError));
+            Ok(())
+        };
+        assert!(test_fn(TEST_ADDR2).is_ok());
+    }
+
+    #[test]
+    fn test_undelegate_in_use() {
+        recreate_granule_status_table();
+
+        let test_fn = |addr: usize| -> Result<(), Error> {
+            let mut granule = set_state_and_get_granule!(addr, GranuleState::Delegated)?;
+            assert!(set_granule(&mut granule, GranuleState::Data).is_ok());
+            assert_eq!(check_undelegate(granule.state()), Err(Error::MmIsInUse));
+
+            assert!(set_granule(&mut granule, GranuleState::Delegated).is_ok());
+            assert!(check_undelegate(granule.state()).is_ok());
+            Ok(())
+        };
+        assert!(test_fn(TEST_ADDR2).is_ok());
+    }
+
+    #[test]
+    fn test_undelegate_undelegated() {
+        assert_eq!(
+            check_undelegate(GranuleState::Undelegated),
+            Err(Error::MmStateError)
+        );
+    }
+}
@@ -1,10 +1,14 @@
+pub mod call;
 pub mod constraint;
+pub mod dispatch;
 pub mod error;
 pub mod features;
 pub mod gpt;
 pub mod realm;
 pub mod rec;
 pub mod rtt;
+#[cfg(debug_assertions)]
+pub mod selftest;
 pub mod version;
 
 use crate::define_interface;
@@ -29,13 +33,22 @@ define_interface! {
          RTT_MAP_UNPROTECTED    = 0xc400_015f,
          RTT_UNMAP_UNPROTECTED  = 0xc400_0162,
          RTT_READ_ENTRY         = 0xc400_0161,
+         PSCI_COMPLETE          = 0xc400_0164,
          FEATURES               = 0xc400_0165,
+         RTT_FOLD               = 0xc400_0166,
          REC_AUX_COUNT          = 0xc400_0167,
          RTT_INIT_RIPAS         = 0xc400_0168,
          RTT_SET_RIPAS          = 0xc400_0169,
+         // Implementation defined: the offset of the virtual counter of a realm
+         REALM_SET_CNTVOFF      = 0xc400_0180,
+         REALM_GET_CNTVOFF      = 0xc400_0181,
     }
 }
 
+/// Implementation defined: sanity check of the core paths, in debug builds only
+#[cfg(debug_assertions)]
+pub const SELF_TEST: usize = 0xc400_018e;
+
 pub const REQ_COMPLETE: usize = 0xc400_018f;
 
 pub const GET_REALM_ATTEST_KEY: usize = 0xC400_01B2;
@@ -74,13 +87,32 @@ pub mod rtt_entry_state {
 
 pub const MAX_REC_AUX_GRANULES: usize = 16;
 
-pub const EXIT_SYNC: u8 = 0;
-pub const EXIT_IRQ: u8 = 1;
-pub const EXIT_FIQ: u8 = 2;
-pub const EXIT_PSCI: u8 = 3;
-pub const EXIT_RIPAS_CHANGE: u8 = 4;
-pub const EXIT_HOST_CALL: u8 = 5;
-pub const EXIT_SERROR: u8 = 6;
+/// RmiRecExitReason, the reason of a REC exit reported to the host in RmiRecRun.
+/// Traps such as WFx and system register accesses are reported as `Sync`
+/// along with the ESR.
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[repr(u8)]
+pub enum ExitReason {
+    Sync = 0,
+    Irq = 1,
+    Fiq = 2,
+    Psci = 3,
+    RipasChange = 4,
+    HostCall = 5,
+    SError = 6,
+}
+
+impl From<ExitReason> for u8 {
+    fn from(reason: ExitReason) -> Self {
+        reason as u8
+    }
+}
+
+impl From<ExitReason> for u64 {
+    fn from(reason: ExitReason) -> Self {
+        reason as u64
+    }
+}
 
 pub struct MapProt(usize);
 
@@ -110,3 +142,20 @@ impl MapProt {
 pub(crate) fn dummy() {
     trace!("Dummy implementation.");
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn spec_exit_reason() {
+        assert_eq!(u8::from(ExitReason::Sync), 0);
+        assert_eq!(u8::from(ExitReason::Irq), 1);
+        assert_eq!(u8::from(ExitReason::Fiq), 2);
+        assert_eq!(u8::from(ExitReason::Psci), 3);
+        assert_eq!(u8::from(ExitReason::RipasChange), 4);
+        assert_eq!(u8::from(ExitReason::HostCall), 5);
+        assert_eq!(u8::from(ExitReason::SError), 6);
+        assert_eq!(u64::from(ExitReason::SError), 6);
+    }
+}
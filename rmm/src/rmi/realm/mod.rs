@@ -3,23 +3,33 @@ pub(crate) mod rd;
 
 pub use self::rd::Rd;
 
-use self::params::Params;
 use self::rd::State;
-use super::error::Error;
+
+use self::params::Params;
+use super::error::{Error, InternalError::*};
+use super::features;
+use crate::crypto::hash::HashAlgo;
 use crate::event::Mainloop;
+use crate::granule::entry::Inner;
 use crate::granule::GRANULE_SIZE;
 use crate::granule::{set_granule, GranuleState};
 use crate::host::pointer::Pointer as HostPointer;
 use crate::listen;
 use crate::measurement::HashContext;
+use crate::mm::tlb;
 use crate::mm::translation::PageTable;
+use crate::realm::feature::{expose_pmu, expose_sve, IdRegs};
 use crate::realm::mm::stage2_translation::Stage2Translation;
 use crate::realm::mm::IPATranslation;
-use crate::realm::registry::RMS;
+use crate::realm::pmu::max_counters;
+use crate::realm::registry::{get_realm, RMS};
+use crate::realm::sve::max_vl;
 use crate::realm::vcpu::remove;
+use crate::realm::vmid;
 use crate::realm::Realm;
 use crate::rmi;
-use crate::{get_granule, get_granule_if};
+use crate::rmi::rec::AffinityPolicy;
+use crate::{get_granule, get_granule_if, require_state};
 use alloc::boxed::Box;
 use alloc::sync::Arc;
 use spin::mutex::Mutex;
@@ -31,14 +41,7 @@ pub fn set_event_handler(mainloop: &mut Mainloop) {
         let rd = arg[0];
 
         let mut rd_granule = get_granule_if!(rd, GranuleState::RD)?;
-        let rd = rd_granule.content_mut::<Rd>();
-
-        if !rd.at_state(State::New) {
-            return Err(Error::RmiErrorRealm(0));
-        }
-
-        rd.set_state(State::Active);
-        Ok(())
+        rd_granule.content_mut::<Rd>().activate()
     });
 
     listen!(mainloop, rmi::REALM_CREATE, |arg, _, rmm| {
@@ -54,6 +57,7 @@ pub fn set_event_handler(mainloop: &mut Mainloop) {
         rmm.page_table.map(rd, true);
 
         let params = copy_from_host_or_ret!(Params, params_ptr);
+        params.validate_ipa_width()?;
         if params.rtt_base as usize == rd {
             return Err(Error::RmiErrorInput);
         }
@@ -61,6 +65,11 @@ pub fn set_event_handler(mainloop: &mut Mainloop) {
             return Err(Error::RmiErrorInput);
         }
         let _ = get_granule_if!(params.rtt_base as usize, GranuleState::Delegated)?;
+        let sve_vl = features::validate_sve(params.features_0 as usize, max_vl())?;
+        let pmu_ctrs = features::validate_pmu(params.features_0 as usize, max_counters())?;
+        let host_call_filter = params.host_call_filter()?;
+        let rec_affinity = AffinityPolicy::try_from(params.rec_affinity)?;
+        let hash_algo = HashAlgo::try_from(params.hash_algo).map_err(|_| Error::RmiErrorInput)?;
 
         // revisit rmi.create_realm() (is it necessary?)
         create_realm(params.vmid, params.rtt_base as usize).map(|id| {
@@ -78,8 +87,42 @@ pub fn set_event_handler(mainloop: &mut Mainloop) {
         PageTable::get_ref().map(rtt_base, true);
 
         rd_obj.set_hash_algo(params.hash_algo);
+        rd_obj.set_rpv(&params.rpv);
+        rd_obj.set_sve_vl(sve_vl);
+        rd_obj.set_pmu_ctrs(pmu_ctrs);
+        rd_obj.set_lpa2(features::lpa2(params.features_0 as usize));
+        rd_obj.set_vmid(params.vmid);
+        rd_obj.set_host_call_filter(host_call_filter);
+        rd_obj.set_rec_affinity(rec_affinity);
+        rd_obj.set_max_recs(params.max_recs());
 
-        HashContext::new(&rmm.rsi, rd_obj)?.measure_realm_create(&params)?;
+        // the realm and its VMID are released if anything fails from here on
+        let cleanup = |e| {
+            rmm.page_table.unmap(rd);
+            remove(id).expect("Realm should be created before.");
+            e
+        };
+
+        let setup = || -> Result<(), Error> {
+            let realm = get_realm(id).ok_or(Error::RmiErrorOthers(NotExistRealm))?;
+            let mut realm = realm.lock();
+            realm.reserve_recs(params.max_recs());
+            realm.init_measurements(hash_algo);
+            if sve_vl.is_some() || pmu_ctrs.is_some() {
+                let raw = IdRegs::read();
+                if sve_vl.is_some() {
+                    expose_sve(&mut realm.id_regs, &raw);
+                }
+                if pmu_ctrs.is_some() {
+                    expose_pmu(&mut realm.id_regs, &raw);
+                }
+            }
+            drop(realm);
+
+            HashContext::new(&rmm.rsi, rd_obj)?.measure_realm_create(&params)?;
+            Ok(())
+        };
+        setup().map_err(cleanup)?;
 
         let mut eplilog = move || {
             let mut rtt_granule = get_granule_if!(rtt_base, GranuleState::Delegated)?;
@@ -87,11 +130,7 @@ pub fn set_event_handler(mainloop: &mut Mainloop) {
             set_granule(&mut rd_granule, GranuleState::RD)
         };
 
-        eplilog().map_err(|e| {
-            rmm.page_table.unmap(rd);
-            remove(id).expect("Realm should be created before.");
-            e
-        })
+        eplilog().map_err(cleanup)
     });
 
     listen!(mainloop, rmi::REC_AUX_COUNT, |_, ret, _| {
@@ -99,31 +138,54 @@ pub fn set_event_handler(mainloop: &mut Mainloop) {
         Ok(())
     });
 
+    listen!(mainloop, rmi::REALM_SET_CNTVOFF, |arg, _, _| {
+        let mut rd_granule = get_granule_if!(arg[0], GranuleState::RD)?;
+        let rd = rd_granule.content_mut::<Rd>();
+        require_state!(rd, State::New);
+
+        // programmed on the next entry to each REC
+        rd.set_cntvoff(arg[1] as u64);
+        Ok(())
+    });
+
+    listen!(mainloop, rmi::REALM_GET_CNTVOFF, |arg, ret, _| {
+        let rd_granule = get_granule_if!(arg[0], GranuleState::RD)?;
+        ret[1] = rd_granule.content::<Rd>().cntvoff() as usize;
+        Ok(())
+    });
+
     listen!(mainloop, rmi::REALM_DESTROY, |arg, _ret, rmm| {
         // get the lock for Rd
         let mut rd_granule = get_granule_if!(arg[0], GranuleState::RD)?;
         let rd = rd_granule.content::<Rd>();
-        remove(rd.id())?;
+        let id = rd.id();
 
         let mut rtt_granule = get_granule_if!(rd.rtt_base(), GranuleState::RTT)?;
+        check_destroy(&rd_granule, &rtt_granule)?;
         set_granule(&mut rtt_granule, GranuleState::Delegated)?;
+        tlb::invalidate_vmid(rd.vttbr());
 
         // change state when everything goes fine.
         set_granule(&mut rd_granule, GranuleState::Delegated)?;
         rmm.page_table.unmap(arg[0]);
+        remove(id)?;
 
         Ok(())
     });
 }
 
-fn create_realm(vmid: u16, rtt_base: usize) -> Result<usize, Error> {
-    let mut rms = RMS.lock();
-
-    for (_, realm) in &rms.1 {
-        if vmid == realm.lock().vmid {
-            return Err(Error::RmiErrorInput);
-        }
+/// A realm can be destroyed only after all of its RECs are destroyed
+/// and its starting level RTT has no child tables left.
+fn check_destroy(rd_granule: &Inner, rtt_granule: &Inner) -> Result<(), Error> {
+    if rd_granule.is_referenced() || rtt_granule.refcount() != 0 {
+        return Err(Error::RmiErrorInput);
     }
+    Ok(())
+}
+
+pub(crate) fn create_realm(vmid: u16, rtt_base: usize) -> Result<usize, Error> {
+    let mut rms = RMS.lock();
+    vmid::reserve(vmid)?;
 
     let id = rms.0;
     let s2_table = Arc::new(Mutex::new(
@@ -136,3 +198,116 @@ fn create_realm(vmid: u16, rtt_base: usize) -> Result<usize, Error> {
 
     Ok(id)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::granule::set_granule_with_parent;
+    use crate::granule::test::{recreate_granule_status_table, TEST_ADDR, TEST_ADDR2};
+    use crate::mm::rtt::test::Table;
+    use crate::set_state_and_get_granule;
+
+    #[test]
+    fn vmid_collision() {
+        let rtt = Table::new();
+        let other = Table::new();
+
+        let id = create_realm(0xf0, rtt.addr()).unwrap();
+        assert!(matches!(
+            create_realm(0xf0, other.addr()),
+            Err(Error::RmiErrorInput)
+        ));
+
+        let other_id = create_realm(0xf1, other.addr()).unwrap();
+        assert_ne!(id, other_id);
+
+        // the vmid can be taken again once the realm is gone
+        remove(id).unwrap();
+        let id = create_realm(0xf0, rtt.addr()).unwrap();
+        remove(id).unwrap();
+        remove(other_id).unwrap();
+    }
+
+    #[test]
+    fn activate_new_realm_only() {
+        use crate::Monitor;
+
+        recreate_granule_status_table();
+        let mut mainloop = Mainloop::new();
+        set_event_handler(&mut mainloop);
+        let monitor = Monitor::new();
+
+        let mut rd_granule =
+            set_state_and_get_granule!(TEST_ADDR, GranuleState::Delegated).unwrap();
+        set_granule(&mut rd_granule, GranuleState::RD).unwrap();
+        let mut rd = rd::test::rd();
+        rd.init(1, 0x8800_0000, 40, 1);
+        *rd_granule.content_mut::<Rd>() = rd;
+        drop(rd_granule);
+
+        let activate = || {
+            mainloop
+                .dispatcher
+                .dispatch(rmi::REALM_ACTIVATE, &[TEST_ADDR], &mut [0; 1], &monitor)
+        };
+        let state = || {
+            get_granule_if!(TEST_ADDR, GranuleState::RD)
+                .map(|g| g.content::<Rd>().at_state(State::Active))
+        };
+
+        assert!(activate().is_ok());
+        assert!(matches!(state(), Ok(true)));
+        // the realm is no longer new
+        assert!(matches!(activate(), Err(Error::RmiErrorRealm(0))));
+
+        let mut rd_granule = get_granule_if!(TEST_ADDR, GranuleState::RD).unwrap();
+        rd_granule.content_mut::<Rd>().set_state(State::SystemOff);
+        drop(rd_granule);
+        assert!(matches!(activate(), Err(Error::RmiErrorRealm(0))));
+        assert!(matches!(state(), Ok(false)));
+
+        // neither is a granule which isn't an RD
+        assert!(matches!(
+            mainloop
+                .dispatcher
+                .dispatch(rmi::REALM_ACTIVATE, &[TEST_ADDR2], &mut [0; 1], &monitor),
+            Err(Error::RmiErrorInput)
+        ));
+
+        let mut rd_granule = get_granule_if!(TEST_ADDR, GranuleState::RD).unwrap();
+        set_granule(&mut rd_granule, GranuleState::Delegated).unwrap();
+    }
+
+    #[test]
+    fn destroy_after_recs_and_rtts() {
+        recreate_granule_status_table();
+
+        let mut rd = set_state_and_get_granule!(TEST_ADDR, GranuleState::Delegated).unwrap();
+        set_granule(&mut rd, GranuleState::RD).unwrap();
+        let rtt_addr = TEST_ADDR + 2 * GRANULE_SIZE;
+        let mut rtt = set_state_and_get_granule!(rtt_addr, GranuleState::Delegated).unwrap();
+        set_granule(&mut rtt, GranuleState::RTT).unwrap();
+        assert!(check_destroy(&rd, &rtt).is_ok());
+
+        let mut rec = set_state_and_get_granule!(TEST_ADDR2, GranuleState::Delegated).unwrap();
+        set_granule_with_parent(rd.clone(), &mut rec, GranuleState::Rec).unwrap();
+        assert!(matches!(
+            check_destroy(&rd, &rtt),
+            Err(Error::RmiErrorInput)
+        ));
+
+        set_granule(&mut rec, GranuleState::Delegated).unwrap();
+        assert!(check_destroy(&rd, &rtt).is_ok());
+
+        // a level 1 table is still linked
+        rtt.inc_refcount().unwrap();
+        assert!(matches!(
+            check_destroy(&rd, &rtt),
+            Err(Error::RmiErrorInput)
+        ));
+        rtt.dec_refcount().unwrap();
+
+        set_granule(&mut rtt, GranuleState::Delegated).unwrap();
+        set_granule(&mut rd, GranuleState::Delegated).unwrap();
+    }
+}
@@ -1,12 +1,21 @@
+use crate::config::NUM_OF_CPU;
 use crate::const_assert_eq;
+use crate::cpu::features::{cpu_features, CpuFeatures};
 use crate::granule::{GRANULE_SHIFT, GRANULE_SIZE};
 use crate::host::Accessor as HostAccessor;
 use crate::measurement::Hashable;
+use crate::rmi::error::Error;
 use crate::rmi::features;
+use crate::rmi::rec::AffinityPolicy;
 use crate::rmi::rtt::{RTT_PAGE_LEVEL, S2TTE_STRIDE};
 use crate::rmi::{HASH_ALGO_SHA256, HASH_ALGO_SHA512};
+use crate::rsi::hostcall::{ImmFilter, HOST_CALL_IMM_MAX};
+use vmsa::error::Error as MmError;
 
-const PADDING: [usize; 5] = [248, 767, 960, 6, 2020];
+/// Size of the Realm Personalization Value
+pub const RPV_SIZE: usize = 64;
+
+const PADDING: [usize; 7] = [248, 767, 960, 6, 1764, 1, 218];
 
 #[repr(C)]
 pub struct Params {
@@ -14,7 +23,7 @@ pub struct Params {
     padding0: [u8; PADDING[0]],
     pub hash_algo: u8,
     padding1: [u8; PADDING[1]],
-    pub rpv: [u8; 64],
+    pub rpv: [u8; RPV_SIZE],
     padding2: [u8; PADDING[2]],
     pub vmid: u16,
     padding3: [u8; PADDING[3]],
@@ -22,6 +31,17 @@ pub struct Params {
     pub rtt_level_start: i64,
     pub rtt_num_start: u32,
     padding4: [u8; PADDING[4]],
+    /// Implementation defined: the immediates allowed in RSI_HOST_CALL,
+    /// of which none means any of them is allowed.
+    pub host_call_imm_nr: u16,
+    pub host_call_imms: [u16; HOST_CALL_IMM_MAX],
+    /// Implementation defined: the AffinityPolicy of the RECs
+    pub rec_affinity: u8,
+    padding5: [u8; PADDING[5]],
+    /// Implementation defined: the number of RECs the realm can have,
+    /// of which 0 means one per CPU
+    pub max_recs: u16,
+    padding6: [u8; PADDING[6]],
 }
 
 const_assert_eq!(core::mem::size_of::<Params>(), GRANULE_SIZE);
@@ -33,7 +53,7 @@ impl Default for Params {
             padding0: [0; PADDING[0]],
             hash_algo: 0,
             padding1: [0; PADDING[1]],
-            rpv: [0; 64],
+            rpv: [0; RPV_SIZE],
             padding2: [0; PADDING[2]],
             vmid: 0,
             padding3: [0; PADDING[3]],
@@ -41,6 +61,12 @@ impl Default for Params {
             rtt_level_start: 0,
             rtt_num_start: 0,
             padding4: [0; PADDING[4]],
+            host_call_imm_nr: 0,
+            host_call_imms: [0; HOST_CALL_IMM_MAX],
+            rec_affinity: 0,
+            padding5: [0; PADDING[5]],
+            max_recs: 0,
+            padding6: [0; PADDING[6]],
         }
     }
 }
@@ -55,6 +81,12 @@ impl core::fmt::Debug for Params {
             .field("rtt_base", &format_args!("{:#X}", &self.rtt_base))
             .field("rtt_level_start", &self.rtt_level_start)
             .field("rtt_num_start", &self.rtt_num_start)
+            .field(
+                "host_call_imms",
+                &self.host_call_imms.get(..self.host_call_imm_nr as usize),
+            )
+            .field("rec_affinity", &self.rec_affinity)
+            .field("max_recs", &self.max_recs)
             .finish()
     }
 }
@@ -70,7 +102,7 @@ impl Hashable for Params {
             alg.hash(self.padding0);
             alg.hash_u8(self.hash_algo);
             alg.hash(self.padding1);
-            alg.hash([0u8; 64]); // rpv is not used
+            alg.hash(self.rpv);
             alg.hash(self.padding2);
             alg.hash_u16(0); // vmid is not used
             alg.hash(self.padding3);
@@ -78,6 +110,14 @@ impl Hashable for Params {
             alg.hash_u64(0); // rtt_level_start is not used
             alg.hash_u32(0); // rtt_num_start is not used
             alg.hash(self.padding4);
+            alg.hash_u16(self.host_call_imm_nr);
+            for imm in self.host_call_imms {
+                alg.hash_u16(imm);
+            }
+            alg.hash_u8(0); // rec_affinity is not used
+            alg.hash(self.padding5);
+            alg.hash_u16(0); // max_recs is not used
+            alg.hash(self.padding6);
         })
     }
 }
@@ -85,19 +125,24 @@ impl Hashable for Params {
 impl HostAccessor for Params {
     fn validate(&self) -> bool {
         trace!("{:?}", self);
-        if !features::validate(self.features_0 as usize) {
+        self.validate_with(cpu_features())
+    }
+}
+
+impl Params {
+    /// Checks the parameters against `features` of the PEs.
+    pub fn validate_with(&self, features: &CpuFeatures) -> bool {
+        if !features::validate(self.features_0 as usize, features) {
             return false;
         }
 
-        // Check misconfigurations between IPA size and SL
-        let ipa_bits = self.ipa_bits();
-        let rtt_slvl = self.rtt_level_start as usize;
-
-        let level = RTT_PAGE_LEVEL - rtt_slvl;
-        let min_ipa_bits = level * S2TTE_STRIDE + GRANULE_SHIFT + 1;
-        let max_ipa_bits = min_ipa_bits + (S2TTE_STRIDE - 1) + 4;
+        if self.host_call_filter().is_err() {
+            warn!("Too many host call immediates: {}", self.host_call_imm_nr);
+            return false;
+        }
 
-        if (ipa_bits < min_ipa_bits) || (ipa_bits > max_ipa_bits) {
+        if AffinityPolicy::try_from(self.rec_affinity).is_err() {
+            warn!("Invalid REC affinity policy: {}", self.rec_affinity);
             return false;
         }
 
@@ -106,18 +151,52 @@ impl HostAccessor for Params {
             _ => false,
         }
     }
-}
 
-impl Params {
     pub fn ipa_bits(&self) -> usize {
         features::ipa_bits(self.features_0 as usize)
     }
+
+    /// Number of RECs the realm can have, which is at most the number of CPUs
+    pub fn max_recs(&self) -> usize {
+        match self.max_recs as usize {
+            0 => NUM_OF_CPU,
+            n => n.min(NUM_OF_CPU),
+        }
+    }
+
+    pub fn host_call_filter(&self) -> Result<ImmFilter, Error> {
+        let imms = self
+            .host_call_imms
+            .get(..self.host_call_imm_nr as usize)
+            .ok_or(Error::RmiErrorInput)?;
+        ImmFilter::new(imms)
+    }
+
+    /// Checks misconfigurations between IPA size and SL,
+    /// which can be covered by up to 16 concatenated tables at the starting level.
+    pub fn validate_ipa_width(&self) -> Result<(), MmError> {
+        if !(0..RTT_PAGE_LEVEL as i64).contains(&self.rtt_level_start) {
+            return Err(MmError::MmInvalidLevel);
+        }
+        let ipa_bits = self.ipa_bits();
+        let rtt_slvl = self.rtt_level_start as usize;
+
+        let level = RTT_PAGE_LEVEL - rtt_slvl;
+        let min_ipa_bits = level * S2TTE_STRIDE + GRANULE_SHIFT + 1;
+        let max_ipa_bits = min_ipa_bits + (S2TTE_STRIDE - 1) + 4;
+
+        if (ipa_bits < min_ipa_bits) || (ipa_bits > max_ipa_bits) {
+            return Err(MmError::MmInvalidAddr);
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 pub mod test {
     use super::*;
     use crate::offset_of;
+    use crate::realm::feature::IdRegs;
 
     #[test]
     fn spec_params() {
@@ -130,5 +209,79 @@ pub mod test {
         assert_eq!(offset_of!(Params, rtt_base), 0x808);
         assert_eq!(offset_of!(Params, rtt_level_start), 0x810);
         assert_eq!(offset_of!(Params, rtt_num_start), 0x818);
+        assert_eq!(offset_of!(Params, host_call_imm_nr), 0xf00);
+        assert_eq!(offset_of!(Params, rec_affinity), 0xf22);
+        assert_eq!(offset_of!(Params, max_recs), 0xf24);
+    }
+
+    #[test]
+    fn max_recs_clamped() {
+        let mut params = Params::default();
+        assert_eq!(params.max_recs(), NUM_OF_CPU);
+        params.max_recs = 2;
+        assert_eq!(params.max_recs(), 2);
+        params.max_recs = NUM_OF_CPU as u16 + 1;
+        assert_eq!(params.max_recs(), NUM_OF_CPU);
+    }
+
+    fn params(ipa_bits: u64, rtt_level_start: i64) -> Params {
+        Params {
+            features_0: ipa_bits,
+            rtt_level_start,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn ipa_width_and_level() {
+        assert_eq!(params(48, 0).validate_ipa_width(), Ok(()));
+        assert_eq!(params(40, 1).validate_ipa_width(), Ok(()));
+        assert_eq!(params(33, 2).validate_ipa_width(), Ok(()));
+        // level 0 covers more than 39 bits only
+        assert_eq!(
+            params(39, 0).validate_ipa_width(),
+            Err(MmError::MmInvalidAddr)
+        );
+        // 16 concatenated tables at most
+        assert_eq!(
+            params(44, 1).validate_ipa_width(),
+            Err(MmError::MmInvalidAddr)
+        );
+        assert_eq!(
+            params(40, 3).validate_ipa_width(),
+            Err(MmError::MmInvalidLevel)
+        );
+        assert_eq!(
+            params(40, -1).validate_ipa_width(),
+            Err(MmError::MmInvalidLevel)
+        );
+    }
+
+    #[test]
+    fn validate_params() {
+        // 40-bit PA without LPA2
+        let features = CpuFeatures::parse(&IdRegs {
+            mmfr0: 0x2,
+            ..Default::default()
+        });
+        let mut p = params(40, 1);
+        assert!(p.validate_with(&features));
+
+        p.hash_algo = 2;
+        assert!(!p.validate_with(&features));
+        p.hash_algo = HASH_ALGO_SHA512;
+        assert!(p.validate_with(&features));
+
+        // smaller than the minimum IPA size, or larger than the PA size
+        p.features_0 = 31;
+        assert!(!p.validate_with(&features));
+        p.features_0 = 44;
+        assert!(!p.validate_with(&features));
+
+        p.features_0 = 40;
+        p.host_call_imm_nr = HOST_CALL_IMM_MAX as u16;
+        assert!(p.validate_with(&features));
+        p.host_call_imm_nr += 1;
+        assert!(!p.validate_with(&features));
     }
 }
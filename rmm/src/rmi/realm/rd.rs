@@ -1,18 +1,59 @@
+use crate::config::NUM_OF_CPU;
 use crate::granule::GranuleState;
+use crate::mm::rtt::{num_start_tables, Rtt};
+use crate::rmi::error::Error;
+use crate::rmi::realm::params::RPV_SIZE;
+use crate::rmi::rec::AffinityPolicy;
 use crate::rmi::rtt::realm_par_size;
+use crate::rsi::hostcall::ImmFilter;
 
+use armv9a::bits_in_reg;
+use armv9a::regs::VTTBR_EL2;
 use vmsa::guard::Content;
 
+/// require_state!(rd: an Rd, state: the state the realm must be in)
+/// - returns RmiErrorRealm(0) from the handler if the realm is in another state.
+#[macro_export]
+macro_rules! require_state {
+    ($rd:expr, $state:expr) => {{
+        $rd.require_state($state)?;
+    }};
+}
+
 // TODO: Integrate with our `struct Realm`
+/// Realm Descriptor, which is the content of the RD granule.
+///
+/// The lock of the RD granule is the lock of the realm. The RMI handlers
+/// changing the RTT or the RD hold it through `get_granule_if!()` until they
+/// return, so that they are serialized against the other commands on the realm:
+/// RTT_CREATE, RTT_DESTROY, RTT_FOLD, RTT_INIT_RIPAS, RTT_SET_RIPAS, DATA_*,
+/// RTT_(UN)MAP_UNPROTECTED, REALM_ACTIVATE, REALM_DESTROY and REC_CREATE.
+/// The RSI handlers walking the RTT take it as well.
+///
+/// RTT_READ_ENTRY and the state check of REC_ENTER only read the RD, so they
+/// could do with a shared lock, but granule locks are exclusive.
+/// They spin rather than fail, as RMI has no error for a contended realm.
 #[derive(Debug)]
 pub struct Rd {
     realm_id: usize,
+    vmid: u16,
     state: State,
     rtt_base: usize,
     ipa_bits: usize,
     rec_index: usize,
+    max_recs: usize,
     s2_starting_level: isize,
     hash_algo: u8,
+    rpv: [u8; RPV_SIZE],
+    sve_vl: Option<u8>,
+    pmu_ctrs: Option<u8>,
+    lpa2: bool,
+    /// Bumped whenever a mapping of protected memory may be removed or moved
+    rtt_gen: u64,
+    /// CNTVOFF_EL2 shared by all the RECs, so that they read the same virtual count
+    cntvoff: u64,
+    host_call_filter: ImmFilter,
+    rec_affinity: AffinityPolicy,
 }
 
 impl Rd {
@@ -22,7 +63,17 @@ impl Rd {
         self.rtt_base = rtt_base;
         self.ipa_bits = ipa_bits;
         self.rec_index = 0;
+        self.max_recs = NUM_OF_CPU;
         self.s2_starting_level = s2_starting_level;
+        self.rpv = [0; RPV_SIZE];
+        self.sve_vl = None;
+        self.pmu_ctrs = None;
+        self.lpa2 = false;
+        self.rtt_gen = 0;
+        self.cntvoff = 0;
+        self.vmid = 0;
+        self.host_call_filter = ImmFilter::default();
+        self.rec_affinity = AffinityPolicy::Any;
     }
 
     pub fn id(&self) -> usize {
@@ -37,6 +88,22 @@ impl Rd {
         self.state = state;
     }
 
+    /// Moves a new realm to the active state,
+    /// after which its RIM can no longer be extended.
+    pub fn activate(&mut self) -> Result<(), Error> {
+        self.require_state(State::New)?;
+        self.state = State::Active;
+        Ok(())
+    }
+
+    /// Fails with RmiErrorRealm(0) unless the realm is in the `expected` state
+    pub fn require_state(&self, expected: State) -> Result<(), Error> {
+        match self.state == expected {
+            true => Ok(()),
+            false => Err(Error::RmiErrorRealm(0)),
+        }
+    }
+
     pub fn at_state(&self, compared: State) -> bool {
         self.state == compared
     }
@@ -49,6 +116,17 @@ impl Rd {
         self.ipa_bits
     }
 
+    /// Stage 2 translation tables of the realm starting from the RTT base
+    pub fn rtt(&self) -> Rtt {
+        let start_level = self.s2_starting_level as usize;
+        Rtt::new(
+            self.rtt_base,
+            start_level,
+            num_start_tables(self.ipa_bits, start_level),
+        )
+        .with_lpa2(self.lpa2)
+    }
+
     pub fn rec_index(&self) -> usize {
         self.rec_index
     }
@@ -61,6 +139,19 @@ impl Rd {
         self.rec_index += 1;
     }
 
+    pub fn max_recs(&self) -> usize {
+        self.max_recs
+    }
+
+    pub fn set_max_recs(&mut self, max_recs: usize) {
+        self.max_recs = max_recs;
+    }
+
+    /// Whether another REC can be created, as REC_CREATE stops at `max_recs`
+    pub fn can_create_rec(&self) -> bool {
+        self.rec_index < self.max_recs
+    }
+
     pub fn addr_in_par(&self, addr: usize) -> bool {
         let ipa_bits = self.ipa_bits();
         addr < realm_par_size(ipa_bits)
@@ -73,6 +164,90 @@ impl Rd {
     pub fn set_hash_algo(&mut self, alg: u8) {
         self.hash_algo = alg;
     }
+
+    /// Realm Personalization Value, which is claimed in the attestation token
+    pub fn rpv(&self) -> &[u8; RPV_SIZE] {
+        &self.rpv
+    }
+
+    pub fn set_rpv(&mut self, rpv: &[u8; RPV_SIZE]) {
+        self.rpv = *rpv;
+    }
+
+    /// SVE vector length of the realm, or `None` if SVE isn't enabled
+    pub fn sve_vl(&self) -> Option<u8> {
+        self.sve_vl
+    }
+
+    pub fn set_sve_vl(&mut self, vl: Option<u8>) {
+        self.sve_vl = vl;
+    }
+
+    /// Number of PMU event counters of the realm, or `None` if the PMU isn't enabled
+    pub fn pmu_ctrs(&self) -> Option<u8> {
+        self.pmu_ctrs
+    }
+
+    pub fn set_pmu_ctrs(&mut self, ctrs: Option<u8>) {
+        self.pmu_ctrs = ctrs;
+    }
+
+    /// Whether the RTT holds 52-bit output addresses
+    pub fn lpa2(&self) -> bool {
+        self.lpa2
+    }
+
+    pub fn set_lpa2(&mut self, lpa2: bool) {
+        self.lpa2 = lpa2;
+    }
+
+    /// Generation of the RTT, which tells whether a buffer checked earlier
+    /// may have been unmapped since
+    pub fn rtt_generation(&self) -> u64 {
+        self.rtt_gen
+    }
+
+    pub fn rtt_changed(&mut self) {
+        self.rtt_gen = self.rtt_gen.wrapping_add(1);
+    }
+
+    pub fn cntvoff(&self) -> u64 {
+        self.cntvoff
+    }
+
+    pub fn set_cntvoff(&mut self, cntvoff: u64) {
+        self.cntvoff = cntvoff;
+    }
+
+    pub fn vmid(&self) -> u16 {
+        self.vmid
+    }
+
+    pub fn set_vmid(&mut self, vmid: u16) {
+        self.vmid = vmid;
+    }
+
+    /// VTTBR_EL2 of the realm, with its VMID and the RTT base
+    pub fn vttbr(&self) -> u64 {
+        bits_in_reg(VTTBR_EL2::VMID, self.vmid as u64)
+            | bits_in_reg(VTTBR_EL2::BADDR, self.rtt_base as u64)
+    }
+
+    pub fn host_call_filter(&self) -> &ImmFilter {
+        &self.host_call_filter
+    }
+
+    pub fn set_host_call_filter(&mut self, filter: ImmFilter) {
+        self.host_call_filter = filter;
+    }
+
+    pub fn rec_affinity(&self) -> AffinityPolicy {
+        self.rec_affinity
+    }
+
+    pub fn set_rec_affinity(&mut self, policy: AffinityPolicy) {
+        self.rec_affinity = policy;
+    }
 }
 
 impl Content for Rd {
@@ -86,3 +261,66 @@ pub enum State {
     Active,
     SystemOff,
 }
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+
+    /// An Rd of a realm being created, without its RTT
+    pub(crate) fn rd() -> Rd {
+        Rd {
+            realm_id: 0,
+            vmid: 0,
+            state: State::Null,
+            rtt_base: 0,
+            ipa_bits: 0,
+            rec_index: 0,
+            max_recs: 0,
+            s2_starting_level: 0,
+            hash_algo: 0,
+            rpv: [0; RPV_SIZE],
+            sve_vl: None,
+            pmu_ctrs: None,
+            lpa2: false,
+            rtt_gen: 0,
+            cntvoff: 0,
+            host_call_filter: ImmFilter::default(),
+            rec_affinity: AffinityPolicy::Any,
+        }
+    }
+
+    #[test]
+    fn rd_state_machine() {
+        let mut rd = rd();
+        assert!(matches!(rd.activate(), Err(Error::RmiErrorRealm(0))));
+
+        rd.init(1, 0x8800_0000, 40, 1);
+        assert!(rd.at_state(State::New));
+        assert!(rd.require_state(State::New).is_ok());
+        assert!(matches!(
+            rd.require_state(State::Active),
+            Err(Error::RmiErrorRealm(0))
+        ));
+        assert!(rd.activate().is_ok());
+        assert!(rd.at_state(State::Active));
+        assert!(matches!(rd.activate(), Err(Error::RmiErrorRealm(0))));
+
+        rd.set_state(State::SystemOff);
+        assert!(matches!(rd.activate(), Err(Error::RmiErrorRealm(0))));
+    }
+
+    #[test]
+    fn rec_limit() {
+        let mut rd = rd();
+        rd.init(1, 0x8800_0000, 40, 1);
+        assert_eq!(rd.max_recs(), NUM_OF_CPU);
+
+        rd.set_max_recs(2);
+        assert!(rd.can_create_rec());
+        rd.inc_rec_index();
+        assert!(rd.can_create_rec());
+        rd.inc_rec_index();
+        // one beyond the configured max
+        assert!(!rd.can_create_rec());
+    }
+}
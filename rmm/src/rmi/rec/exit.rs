@@ -1,8 +1,9 @@
 use crate::event::realmexit::*;
 use crate::event::{Context, RsiHandle};
-use crate::granule::GRANULE_MASK;
+use crate::exception::trap::syndrome::{DataAbortIss, Fault, MsrMrsIss, SErrorIss};
+use crate::granule::{GRANULE_MASK, GRANULE_SIZE};
 use crate::realm::context::get_reg;
-use crate::realm::mm::stage2_tte::S2TTE;
+use crate::realm::mm::stage2_tte::{invalid_ripas, S2TTE};
 use crate::rmi::error::Error;
 use crate::rmi::rec::run::Run;
 use crate::rmi::rec::Rec;
@@ -10,7 +11,10 @@ use crate::rmi::rtt::is_protected_ipa;
 use crate::rmi::rtt::RTT_PAGE_LEVEL;
 use crate::Monitor;
 use crate::{rmi, rsi};
-use armv9a::{EsrEl2, EMULATABLE_ABORT_MASK, HPFAR_EL2, NON_EMULATABLE_ABORT_MASK};
+use armv9a::{
+    EsrEl2, EMULATABLE_ABORT_MASK, HPFAR_EL2, NON_EMULATABLE_ABORT_MASK, SERROR_EXIT_MASK,
+    SYSREG_EXIT_MASK, WFX_EXIT_MASK,
+};
 
 pub fn handle_realm_exit(
     realm_exit_res: [usize; 4],
@@ -46,15 +50,44 @@ pub fn handle_realm_exit(
             handle_data_abort(realm_exit_res, rec, run)?
         }
         RecExitReason::IRQ => unsafe {
-            run.set_exit_reason(rmi::EXIT_IRQ);
+            run.set_exit_reason(rmi::ExitReason::Irq);
             run.set_esr(realm_exit_res[1] as u64);
             run.set_hpfar(realm_exit_res[2] as u64);
             run.set_far(realm_exit_res[3] as u64);
             rmi::SUCCESS
         },
-        RecExitReason::Sync(ExitSyncType::InstAbort)
-        | RecExitReason::Sync(ExitSyncType::Undefined) => unsafe {
-            run.set_exit_reason(rmi::EXIT_SYNC);
+        // The host sees a maintenance interrupt or an asserted timer as an IRQ exit
+        // and finds the cause in gicv3_misr or the timer state of RecRun.
+        RecExitReason::Maintenance | RecExitReason::VirtualTimer | RecExitReason::PhysicalTimer => unsafe {
+            run.set_exit_reason(rmi::ExitReason::Irq);
+            run.set_esr(0);
+            run.set_hpfar(0);
+            run.set_far(0);
+            rmi::SUCCESS
+        },
+        RecExitReason::SError => handle_serror(realm_exit_res, rec, run),
+        RecExitReason::Sync(ExitSyncType::SysReg) => {
+            handle_sysreg_access(realm_exit_res, rec, run)?
+        }
+        RecExitReason::Sync(ExitSyncType::WFx) => unsafe {
+            run.set_exit_reason(rmi::ExitReason::Sync);
+            run.set_esr(realm_exit_res[1] as u64 & WFX_EXIT_MASK);
+            run.set_hpfar(0);
+            run.set_far(0);
+            rmi::SUCCESS
+        },
+        // The realm doesn't handle its debug exceptions itself while they are routed to EL2.
+        // FAR isn't reported as it holds a VA of the realm for watchpoints.
+        RecExitReason::Sync(ExitSyncType::Debug) => unsafe {
+            run.set_exit_reason(rmi::ExitReason::Sync);
+            run.set_esr(realm_exit_res[1] as u64);
+            run.set_hpfar(0);
+            run.set_far(0);
+            rmi::SUCCESS
+        },
+        RecExitReason::Sync(ExitSyncType::InstAbort) => handle_inst_abort(realm_exit_res, run),
+        RecExitReason::Sync(ExitSyncType::Undefined) => unsafe {
+            run.set_exit_reason(rmi::ExitReason::Sync);
             run.set_esr(realm_exit_res[1] as u64);
             run.set_hpfar(realm_exit_res[2] as u64);
             run.set_far(realm_exit_res[3] as u64);
@@ -66,29 +99,115 @@ pub fn handle_realm_exit(
     Ok((return_to_ns, ret))
 }
 
-fn is_non_emulatable_data_abort(
-    realm_id: usize,
-    ipa_bits: usize,
-    fault_ipa: usize,
-    esr_el2: u64,
-) -> Result<bool, Error> {
-    let (s2tte, _) = S2TTE::get_s2tte(realm_id, fault_ipa, RTT_PAGE_LEVEL, Error::RmiErrorRtt(0))?;
-    let is_protected_ipa = is_protected_ipa(fault_ipa, ipa_bits);
-
-    let ret = match is_protected_ipa {
-        true => s2tte.is_unassigned() || s2tte.is_destroyed(),
-        false => (s2tte.is_unassigned() && (esr_el2 & EsrEl2::ISV) == 0) || s2tte.is_assigned(),
-    };
+/// Returns the exit reason and the syndrome reported to the host for an SError,
+/// and whether the REC can be run again.
+fn serror_exit(esr: u32) -> (rmi::ExitReason, u64, bool) {
+    let runnable = !SErrorIss::from(esr).is_uncontainable();
+    (
+        rmi::ExitReason::SError,
+        esr as u64 & SERROR_EXIT_MASK,
+        runnable,
+    )
+}
+
+fn handle_serror(realm_exit_res: [usize; 4], rec: &mut Rec<'_>, run: &mut Run) -> usize {
+    let (exit_reason, esr, runnable) = serror_exit(realm_exit_res[1] as u32);
+    if !runnable {
+        error!("Uncontainable SError from the realm. esr: {:#X}", esr);
+        rec.set_runnable(false);
+    }
+    unsafe {
+        run.set_exit_reason(exit_reason);
+        run.set_esr(esr);
+        run.set_hpfar(0);
+        run.set_far(0);
+    }
+    rmi::SUCCESS
+}
+
+/// How a data abort taken on a stage 2 translation of the realm is reported
+#[derive(Debug, PartialEq)]
+enum DataAbortKind {
+    /// The protected IPA has RIPAS EMPTY, the host is asked to change it to RAM
+    RipasEmpty,
+    /// Reported to the host as a translation fault without the access to emulate
+    NonEmulatable,
+    /// Reported to the host with the access to emulate
+    Emulatable,
+}
+
+fn classify_data_abort(s2tte: S2TTE, protected: bool, iss: &DataAbortIss) -> DataAbortKind {
+    if protected {
+        if s2tte.is_unassigned() && s2tte.get_ripas() == invalid_ripas::EMPTY {
+            DataAbortKind::RipasEmpty
+        } else if s2tte.is_unassigned() || s2tte.is_destroyed() {
+            DataAbortKind::NonEmulatable
+        } else {
+            DataAbortKind::Emulatable
+        }
+    } else if (s2tte.is_unassigned() && !iss.is_valid()) || s2tte.is_assigned() {
+        DataAbortKind::NonEmulatable
+    } else {
+        DataAbortKind::Emulatable
+    }
+}
+
+/// Instruction abort taken on a stage 2 translation of the realm, decoded from IFSC
+#[derive(Debug, PartialEq)]
+enum InstAbortKind {
+    /// The IPA isn't mapped, which the host can resolve by mapping the realm code
+    Translation,
+    AccessFlag,
+    /// The IPA is mapped but not executable, which isn't resolved by mapping it again
+    Permission,
+    Other,
+}
+
+impl From<u64> for InstAbortKind {
+    fn from(esr: u64) -> Self {
+        match Fault::from(esr as u32) {
+            Fault::Translation { .. } => InstAbortKind::Translation,
+            Fault::AccessFlag { .. } => InstAbortKind::AccessFlag,
+            Fault::Permission { .. } => InstAbortKind::Permission,
+            _ => InstAbortKind::Other,
+        }
+    }
+}
 
-    Ok(ret)
+/// Returns the syndrome and the faulting IPA reported to the host for an instruction abort.
+/// The VA of the realm isn't exposed.
+fn inst_abort_exit(esr: u64, hpfar: u64) -> (u64, u64) {
+    (esr & NON_EMULATABLE_ABORT_MASK, hpfar & HPFAR_EL2::FIPA)
 }
 
-fn get_write_val(realm_id: usize, vcpu_id: usize, esr_el2: u64) -> Result<u64, Error> {
-    let esr_el2 = EsrEl2::new(esr_el2);
-    let rt = esr_el2.get_masked_value(EsrEl2::SRT) as usize;
+fn handle_inst_abort(realm_exit_res: [usize; 4], run: &mut Run) -> usize {
+    let (esr, hpfar) = inst_abort_exit(realm_exit_res[1] as u64, realm_exit_res[2] as u64);
+    if InstAbortKind::from(esr) == InstAbortKind::Permission {
+        warn!(
+            "Instruction abort on a non-executable mapping, ipa:{:#X}",
+            hpfar << 8
+        );
+    }
+    unsafe {
+        run.set_exit_reason(rmi::ExitReason::Sync);
+        run.set_esr(esr);
+        run.set_hpfar(hpfar);
+        run.set_far(0);
+    }
+    rmi::SUCCESS
+}
+
+fn get_write_val(realm_id: usize, vcpu_id: usize, iss: &DataAbortIss) -> Result<u64, Error> {
+    let access = match iss.access {
+        Some(access) => access,
+        None => return Err(Error::RmiErrorRec),
+    };
+    let rt = access.srt as usize;
     let write_val = match rt == 31 {
         true => 0, // xzr
-        false => get_reg(realm_id, vcpu_id, rt)? as u64 & esr_el2.get_access_size_mask(),
+        false => {
+            get_reg(realm_id, vcpu_id, rt)? as u64 & EsrEl2::new(iss.esr()).get_access_size_mask()
+        }
     };
     Ok(write_val)
 }
@@ -104,30 +223,52 @@ fn handle_data_abort(
     let esr_el2 = realm_exit_res[1] as u64;
     let hpfar_el2 = realm_exit_res[2] as u64;
     let far_el2 = realm_exit_res[3] as u64;
+    let iss = DataAbortIss::from(esr_el2 as u32);
 
     unsafe {
-        run.set_exit_reason(rmi::EXIT_SYNC);
+        run.set_exit_reason(rmi::ExitReason::Sync);
         run.set_hpfar(hpfar_el2);
     }
 
     let fault_ipa = ((HPFAR_EL2::FIPA & hpfar_el2) << 8) as usize;
 
-    let (exit_esr, exit_far) =
-        match is_non_emulatable_data_abort(realm_id, ipa_bits, fault_ipa, esr_el2)? {
-            true => (esr_el2 & NON_EMULATABLE_ABORT_MASK, 0),
-            false => {
-                if esr_el2 & EsrEl2::WNR != 0 {
-                    let write_val = get_write_val(realm_id, rec.vcpuid(), esr_el2)?;
-                    unsafe {
-                        run.set_gpr(0, write_val)?;
-                    }
+    let (s2tte, _) = S2TTE::get_s2tte(
+        rec.get_owner()?,
+        fault_ipa,
+        RTT_PAGE_LEVEL,
+        Error::RmiErrorRtt(0),
+    )?;
+    let protected = is_protected_ipa(fault_ipa, ipa_bits);
+
+    let (exit_esr, exit_far) = match classify_data_abort(s2tte, protected, &iss) {
+        DataAbortKind::RipasEmpty => {
+            // The faulting access is retried once the host has changed the RIPAS.
+            let top = fault_ipa + GRANULE_SIZE;
+            rec.set_ripas_from_fault(fault_ipa as u64, top as u64);
+            unsafe {
+                run.set_exit_reason(rmi::ExitReason::RipasChange);
+                run.set_ripas(
+                    fault_ipa as u64,
+                    GRANULE_SIZE as u64,
+                    invalid_ripas::RAM as u8,
+                );
+            }
+            (esr_el2 & NON_EMULATABLE_ABORT_MASK, 0)
+        }
+        DataAbortKind::NonEmulatable => (esr_el2 & NON_EMULATABLE_ABORT_MASK, 0),
+        DataAbortKind::Emulatable => {
+            if iss.wnr {
+                let write_val = get_write_val(realm_id, rec.vcpuid(), &iss)?;
+                unsafe {
+                    run.set_gpr(0, write_val)?;
                 }
-                (
-                    esr_el2 & EMULATABLE_ABORT_MASK,
-                    (far_el2 & !(GRANULE_MASK as u64)),
-                )
             }
-        };
+            (
+                esr_el2 & EMULATABLE_ABORT_MASK,
+                (far_el2 & !(GRANULE_MASK as u64)),
+            )
+        }
+    };
 
     unsafe {
         run.set_esr(exit_esr);
@@ -136,3 +277,127 @@ fn handle_data_abort(
 
     Ok(rmi::SUCCESS)
 }
+
+/// Forwards a trapped MSR/MRS to the host for the emulation.
+/// - MSR: the value of Rt is passed in gprs[0]
+/// - MRS: the value is taken from gprs[0] on the next REC entry
+fn handle_sysreg_access(
+    realm_exit_res: [usize; 4],
+    rec: &mut Rec<'_>,
+    run: &mut Run,
+) -> Result<usize, Error> {
+    let esr_el2 = realm_exit_res[1] as u64;
+    let iss = MsrMrsIss::from(esr_el2 as u32);
+
+    unsafe {
+        run.set_exit_reason(rmi::ExitReason::Sync);
+        run.set_esr(esr_el2 & SYSREG_EXIT_MASK);
+        run.set_hpfar(0);
+        run.set_far(0);
+    }
+
+    let rt = iss.rt as usize;
+    if iss.is_read {
+        if rt != 31 {
+            rec.set_pending_sysreg_read(Some(rt));
+        }
+    } else {
+        let val = match rt == 31 {
+            true => 0, // xzr
+            false => get_reg(rec.realmid()?, rec.vcpuid(), rt)? as u64,
+        };
+        unsafe {
+            run.set_gpr(0, val)?;
+        }
+    }
+    Ok(rmi::SUCCESS)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::realm::mm::stage2_tte::invalid_hipas;
+    use armv9a::bits_in_reg;
+
+    #[test]
+    fn serror_exit_to_host() {
+        // uncontainable asynchronous SError
+        let (exit_reason, esr, runnable) = serror_exit(0xbe00_0011);
+        assert_eq!(exit_reason, rmi::ExitReason::SError);
+        // IL isn't reported
+        assert_eq!(esr, 0xbc00_0011);
+        assert!(!runnable);
+
+        // recoverable one
+        let (exit_reason, esr, runnable) = serror_exit(0xbe00_0e11);
+        assert_eq!(exit_reason, rmi::ExitReason::SError);
+        assert_eq!(esr, 0xbc00_0e11);
+        assert!(runnable);
+    }
+
+    #[test]
+    fn data_abort_classes() {
+        let unassigned = |ripas| {
+            S2TTE::new(
+                bits_in_reg(S2TTE::INVALID_HIPAS, invalid_hipas::UNASSIGNED)
+                    | bits_in_reg(S2TTE::INVALID_RIPAS, ripas),
+            )
+        };
+        let destroyed = S2TTE::new(bits_in_reg(S2TTE::INVALID_HIPAS, invalid_hipas::DESTROYED));
+        // a translation fault with a valid syndrome
+        let iss = DataAbortIss::from(0x0100_0007);
+
+        assert_eq!(
+            classify_data_abort(unassigned(invalid_ripas::EMPTY), true, &iss),
+            DataAbortKind::RipasEmpty
+        );
+        // valid but not mapped yet
+        assert_eq!(
+            classify_data_abort(unassigned(invalid_ripas::RAM), true, &iss),
+            DataAbortKind::NonEmulatable
+        );
+        assert_eq!(
+            classify_data_abort(destroyed, true, &iss),
+            DataAbortKind::NonEmulatable
+        );
+
+        // RIPAS doesn't apply to the unprotected IPA space
+        assert_eq!(
+            classify_data_abort(unassigned(invalid_ripas::EMPTY), false, &iss),
+            DataAbortKind::Emulatable
+        );
+        assert_eq!(
+            classify_data_abort(
+                unassigned(invalid_ripas::EMPTY),
+                false,
+                &DataAbortIss::from(0x7)
+            ),
+            DataAbortKind::NonEmulatable
+        );
+    }
+
+    #[test]
+    fn inst_abort_ifsc() {
+        // (ESR, kind) with EC 0b100000 and IL
+        let cases = [
+            (0x8200_0004, InstAbortKind::Translation),
+            (0x8200_0007, InstAbortKind::Translation),
+            (0x8200_0009, InstAbortKind::AccessFlag),
+            (0x8200_000b, InstAbortKind::AccessFlag),
+            (0x8200_000d, InstAbortKind::Permission),
+            (0x8200_000f, InstAbortKind::Permission),
+            // address size fault
+            (0x8200_0001, InstAbortKind::Other),
+            // synchronous external abort
+            (0x8200_0010, InstAbortKind::Other),
+        ];
+        for (esr, kind) in cases {
+            assert_eq!(InstAbortKind::from(esr), kind, "ESR: {:#x}", esr);
+        }
+
+        // S1PTW and IL are masked out, the faulting IPA is kept
+        let (esr, hpfar) = inst_abort_exit(0x8200_0087, 0x8000_0000_0088_0000);
+        assert_eq!(esr, 0x8000_0007);
+        assert_eq!(hpfar << 8, 0x8800_0000);
+    }
+}
@@ -1,23 +1,32 @@
-use super::mpidr::MPIDR;
 use super::params::Params;
 use super::run::{Run, REC_ENTRY_FLAG_TRAP_WFE, REC_ENTRY_FLAG_TRAP_WFI};
 use super::vtcr::{activate_stage2_mmu, prepare_vtcr};
 use super::Rec;
+use crate::cpu::{get_cpu_id, take_realm_fault, this_cpu};
 use crate::event::Mainloop;
 use crate::granule::{set_granule, set_granule_with_parent, GranuleState};
 use crate::host::pointer::Pointer as HostPointer;
 use crate::host::pointer::PointerMut as HostPointerMut;
 use crate::listen;
 use crate::measurement::HashContext;
-use crate::realm::context::set_reg;
+use crate::realm::context::{set_reg, Context};
+use crate::realm::pmu::PmuState;
+use crate::realm::registry::get_realm;
+use crate::realm::sve::SveState;
 use crate::realm::vcpu::create_vcpu;
 use crate::rmi;
 use crate::rmi::error::Error;
+use crate::rmi::error::InternalError::*;
 use crate::rmi::realm::{rd::State, Rd};
 use crate::rmi::rec::exit::handle_realm_exit;
 use crate::rmi::rec::RecState;
-use crate::rsi::do_host_call;
-use crate::{get_granule, get_granule_if};
+use crate::rsi::complete_host_call;
+use crate::rsi::psci;
+use crate::{get_granule, get_granule_if, require_state};
+
+use armv9a::regs::HCR_EL2;
+
+use alloc::vec::Vec;
 
 extern crate alloc;
 
@@ -35,49 +44,80 @@ pub fn set_event_handler(mainloop: &mut Mainloop) {
         let params = copy_from_host_or_ret!(Params, params_ptr);
         params.validate_aux(rec, rd, params_ptr)?;
 
-        let rec_index = MPIDR::from(params.mpidr).index();
         let mut rd_granule = get_granule_if!(rd, GranuleState::RD)?;
         let rd = rd_granule.content_mut::<Rd>();
-        if !rd.at_state(State::New) {
-            return Err(Error::RmiErrorRealm(0));
-        }
+        require_state!(rd, State::New);
 
-        if rec_index != rd.rec_index() {
+        if !rd.can_create_rec() {
             return Err(Error::RmiErrorInput);
         }
+        params.check_mpidr(rd.rec_index())?;
 
         // set Rec_state and grab the lock for Rec granule
         let mut rec_granule = get_granule_if!(rec, GranuleState::Delegated)?;
+        // locked until they are linked to the REC
+        let mut aux_granules = Vec::with_capacity(params.aux().len());
+        for &aux in params.aux() {
+            aux_granules.push(get_granule_if!(aux as usize, GranuleState::Delegated)?);
+        }
         rmm.page_table.map(rec, true);
         let rec = rec_granule.content_mut::<Rec<'_>>();
 
         match create_vcpu(rd.id()) {
             Ok(vcpuid) => {
                 ret[1] = vcpuid;
-                rec.init(owner, vcpuid, params.flags)?;
+                rec.init(owner, vcpuid, params.mpidr, params.flags)?;
+                rec.set_aux(params.aux())?;
+                rec.set_affinity(params.affinity());
             }
             Err(_) => return Err(Error::RmiErrorInput),
         }
 
-        for (idx, gpr) in params.gprs.iter().enumerate() {
-            if set_reg(rd.id(), rec.vcpuid(), idx, *gpr as usize).is_err() {
-                return Err(Error::RmiErrorInput);
-            }
-        }
-        if set_reg(rd.id(), rec.vcpuid(), 31, params.pc as usize).is_err() {
-            return Err(Error::RmiErrorInput);
+        {
+            let realm = get_realm(rd.id()).ok_or(Error::RmiErrorOthers(NotExistRealm))?;
+            let mut realm = realm.lock();
+            realm.set_rec_mpidr(rec.vcpuid(), rec.mpidr());
+            let mut vcpu = realm
+                .vcpus
+                .get(rec.vcpuid())
+                .ok_or(Error::RmiErrorOthers(NotExistVCPU))?
+                .lock();
+            params.init_context(&mut vcpu.context);
+            vcpu.context.sve = rd.sve_vl().map(SveState::new);
+            vcpu.context.pmu = rd.pmu_ctrs().map(PmuState::new);
         }
         rec.set_vtcr(prepare_vtcr(rd)?);
 
         rd.inc_rec_index();
         HashContext::new(&rmm.rsi, &rd)?.measure_rec_params(&params)?;
 
-        set_granule_with_parent(rd_granule.clone(), &mut rec_granule, GranuleState::Rec)
+        set_granule_with_parent(rd_granule.clone(), &mut rec_granule, GranuleState::Rec)?;
+        // the REC can't be destroyed while its auxiliary granules refer to it
+        for aux in aux_granules.iter_mut() {
+            set_granule_with_parent(rec_granule.clone(), aux, GranuleState::RecAux)?;
+        }
+        Ok(())
     });
 
     listen!(mainloop, rmi::REC_DESTROY, |arg, _ret, rmm| {
         let mut rec_granule = get_granule_if!(arg[0], GranuleState::Rec)?;
+        let rec = rec_granule.content::<Rec<'_>>();
+        rec.check_destroy()?;
+        info!("REC {} exits: {:?}", rec.vcpuid(), rec.exit_stats());
+        if let Some(realm) = get_realm(rec.realmid()?) {
+            realm.lock().clear_rec_mpidr(rec.vcpuid());
+        }
 
+        for &aux in rec.aux() {
+            let mut aux_granule = get_granule_if!(aux, GranuleState::RecAux)?;
+            // scrubbed on its way back to the Delegated state
+            rmm.page_table.map(aux, true);
+            let res = set_granule(&mut aux_granule, GranuleState::Delegated);
+            rmm.page_table.unmap(aux);
+            res?;
+        }
+
+        // Releasing the parent link drops the REC from the live RECs of its realm.
         set_granule(&mut rec_granule, GranuleState::Delegated).map_err(|e| {
             rmm.page_table.unmap(arg[0]);
             e
@@ -94,54 +134,43 @@ pub fn set_event_handler(mainloop: &mut Mainloop) {
         let mut rec = rec_granule.content_mut::<Rec<'_>>();
         let realm_id = rec.realmid()?;
 
-        if !rec.runnable() {
-            return Err(Error::RmiErrorRec);
-        }
-
-        if let RecState::Running = rec.get_state() {
-            error!("Rec is already running: {:?}", rec);
-            return Err(Error::RmiErrorRec);
-        }
-
-        match get_granule_if!(rec.owner()?, GranuleState::RD)?
-            .content::<Rd>()
-            .state() // Rd dropped
-        {
-            State::Active => {}
-            State::New => {
-                return Err(Error::RmiErrorRealm(0));
-            }
-            State::SystemOff => {
-                return Err(Error::RmiErrorRealm(1));
-            }
-            _ => {
-                panic!("Unexpected realm state");
-            }
-        }
+        let (realm_state, cntvoff, affinity) = {
+            let rd = get_granule_if!(rec.owner()?, GranuleState::RD)?;
+            let rd = rd.content::<Rd>();
+            (rd.state(), rd.cntvoff(), rd.rec_affinity())
+        }; // Rd dropped
+        rec.check_enter(realm_state)?;
+        rec.check_affinity(affinity, get_cpu_id())?;
+        // the realm may have been destroyed under the REC
+        get_realm(realm_id).ok_or(Error::RmiErrorOthers(NotExistRealm))?;
 
         // read Run
         let mut run = copy_from_host_or_ret!(Run, run_pa, Error::RmiErrorRec);
         trace!("{:?}", run);
+        crate::realm::gic::check_entry_lrs(&run)?;
 
         if rec.host_call_pending() {
-            do_host_call(&arg, ret, rmm, rec, &mut run)?;
+            complete_host_call(rec, &run)?;
         }
 
-        crate::gic::receive_state_from_host(realm_id, rec.vcpuid(), &run)?;
+        crate::realm::gic::receive_state_from_host(realm_id, rec.vcpuid(), &run)?;
         crate::mmio::emulate_mmio(realm_id, rec.vcpuid(), &run)?;
+        crate::realm::timer::receive_offset(realm_id, rec.vcpuid(), cntvoff)?;
 
-        let ripas = rec.ripas_addr() as usize;
-        if ripas > 0 {
-            set_reg(realm_id, rec.vcpuid(), 0, 0)?;
-            set_reg(realm_id, rec.vcpuid(), 1, ripas)?;
-            rec.set_ripas(0, 0, 0, 0);
-        }
+        complete_exit(
+            rec,
+            &run,
+            &mut get_realm(realm_id)
+                .ok_or(Error::RmiErrorOthers(NotExistRealm))?
+                .lock()
+                .vcpus
+                .get(rec.vcpuid())
+                .ok_or(Error::RmiErrorOthers(NotExistVCPU))?
+                .lock()
+                .context,
+        )?;
 
-        let wfx_flag = unsafe { run.entry_flags() };
-        if wfx_flag & (REC_ENTRY_FLAG_TRAP_WFI | REC_ENTRY_FLAG_TRAP_WFE) != 0 {
-            warn!("ISLET does not support re-configuring the WFI(E) trap");
-            warn!("TWI(E) in HCR_EL2 is currently fixed to 'no trap'");
-        }
+        configure_wfx_trap(unsafe { run.entry_flags() });
 
         activate_stage2_mmu(rec);
 
@@ -151,23 +180,150 @@ pub fn set_event_handler(mainloop: &mut Mainloop) {
             unsafe { run.set_imm(0) };
 
             rec.set_state(RecState::Running);
-            match crate::rmi::rec::run(realm_id, rec.vcpuid(), 0) {
+            rec.exit_stats().record_entry();
+            this_cpu().enter(arg[0], realm_id, rec.vcpuid());
+            let res = crate::rmi::rec::run(realm_id, rec.vcpuid(), 0);
+            // cleared before handling the exit, which may bail out early
+            this_cpu().exit();
+            rec.set_state(RecState::Ready);
+            if take_realm_fault() {
+                let mut rd = get_granule_if!(rec.owner()?, GranuleState::RD)?;
+                rd.content_mut::<Rd>().set_state(State::SystemOff);
+                return Err(Error::RmiErrorRec);
+            }
+            match res {
                 Ok(realm_exit_res) => {
+                    rec.exit_stats().record_exit(realm_exit_res[0]);
                     (ret_ns, ret[0]) = handle_realm_exit(realm_exit_res, rmm, &mut rec, &mut run)?
                 }
                 Err(_) => ret[0] = rmi::ERROR_REC,
             }
-            rec.set_state(RecState::Ready);
 
             if ret_ns == true {
                 break;
             }
         }
-        crate::gic::send_state_to_host(realm_id, rec.vcpuid(), &mut run)?;
+        crate::realm::gic::send_state_to_host(realm_id, rec.vcpuid(), &mut run)?;
         crate::realm::timer::send_state_to_host(realm_id, rec.vcpuid(), &mut run)?;
+        crate::realm::pmu::send_state_to_host(realm_id, rec.vcpuid(), &mut run)?;
 
         // NOTICE: do not modify `run` after copy_to_host_or_ret!(). it won't have any effect.
         copy_to_host_or_ret!(Run, &run, run_pa);
         Ok(())
     });
+
+    listen!(mainloop, rmi::PSCI_COMPLETE, |arg, _ret, _rmm| {
+        let caller = arg[0];
+        let target = arg[1];
+
+        if caller == target {
+            return Err(Error::RmiErrorInput);
+        }
+
+        let mut caller_granule = get_granule_if!(caller, GranuleState::Rec)?;
+        let caller = caller_granule.content_mut::<Rec<'_>>();
+        let mut target_granule = get_granule_if!(target, GranuleState::Rec)?;
+        let target = target_granule.content_mut::<Rec<'_>>();
+
+        if caller.owner()? != target.owner()? {
+            return Err(Error::RmiErrorInput);
+        }
+
+        let req = caller.psci_pending();
+        let realm_id = target.realmid()?;
+        let found = get_realm(realm_id)
+            .ok_or(Error::RmiErrorOthers(NotExistRealm))?
+            .lock()
+            .find_rec_by_mpidr(target.mpidr());
+        // the REC found by the MPIDR the realm asked for has to be the one given by the host
+        let target_runnable = found
+            .filter(|vcpuid| *vcpuid == target.vcpuid())
+            .map(|_| target.runnable());
+        let (status, start_target) = psci::complete(req.as_ref(), target.mpidr(), target_runnable)?;
+
+        if start_target {
+            // a pending request always exists when the target is to be started
+            let req = req.ok_or(Error::RmiErrorInput)?;
+            set_reg(realm_id, target.vcpuid(), 0, req.context_id as usize)?;
+            set_reg(realm_id, target.vcpuid(), 31, req.entry_point as usize)?;
+            target.set_runnable(true);
+        }
+
+        set_reg(caller.realmid()?, caller.vcpuid(), 0, status)?;
+        caller.set_psci_pending(None);
+        Ok(())
+    });
+}
+
+/// Loads the values the host provides in the entry portion of `run`
+/// into the context of the REC to complete the exit it handled.
+fn complete_exit(rec: &mut Rec<'_>, run: &Run, context: &mut Context) -> Result<(), Error> {
+    if let Some(rt) = rec.take_pending_sysreg_read() {
+        let val = unsafe { run.entry_gpr(0)? };
+        // reads into XZR are discarded
+        if let Some(reg) = context.gp_regs.get_mut(rt) {
+            *reg = val;
+        }
+    }
+
+    let ripas = rec.ripas_addr();
+    if ripas > 0 {
+        // the realm retries the faulting access instead of getting a result
+        if !rec.ripas_from_fault() {
+            context.gp_regs[0] = 0;
+            context.gp_regs[1] = ripas;
+        }
+        rec.set_ripas(0, 0, 0, 0);
+    }
+    Ok(())
+}
+
+/// Configures TWI/TWE in HCR_EL2 as requested by the host on REC entry,
+/// so that WFI/WFE executed by the realm exits to the host.
+fn configure_wfx_trap(flags: u64) {
+    let mut hcr = unsafe { HCR_EL2.get() } & !(HCR_EL2::TWI | HCR_EL2::TWE);
+    if flags & REC_ENTRY_FLAG_TRAP_WFI != 0 {
+        hcr |= HCR_EL2::TWI;
+    }
+    if flags & REC_ENTRY_FLAG_TRAP_WFE != 0 {
+        hcr |= HCR_EL2::TWE;
+    }
+    unsafe { HCR_EL2.set(hcr) };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rmi::rec::test::rec;
+
+    #[test]
+    fn entry_gprs_to_context() {
+        let mut rec = rec();
+        let mut run = Run::default();
+        let mut context = Context::default();
+        run.set_entry_gpr(0, 0x1234);
+
+        // nothing is pending
+        complete_exit(&mut rec, &run, &mut context).unwrap();
+        assert!(context.gp_regs.iter().all(|&r| r == 0));
+
+        rec.set_pending_sysreg_read(Some(5));
+        complete_exit(&mut rec, &run, &mut context).unwrap();
+        assert_eq!(context.gp_regs[5], 0x1234);
+
+        // the read is completed only once
+        run.set_entry_gpr(0, 0x5678);
+        complete_exit(&mut rec, &run, &mut context).unwrap();
+        assert_eq!(context.gp_regs[5], 0x1234);
+
+        rec.set_pending_sysreg_read(Some(31));
+        complete_exit(&mut rec, &run, &mut context).unwrap();
+        assert_eq!(context.elr, 0);
+
+        rec.set_ripas(0x1000, 0x3000, 0x2000, 1);
+        context.gp_regs[0] = 0xff;
+        complete_exit(&mut rec, &run, &mut context).unwrap();
+        assert_eq!(context.gp_regs[..2], [0, 0x2000]);
+        assert_eq!(rec.ripas_addr(), 0);
+    }
 }
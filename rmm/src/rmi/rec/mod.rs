@@ -3,21 +3,30 @@ pub mod handlers;
 pub mod mpidr;
 pub mod params;
 pub mod run;
+pub mod stats;
 pub mod vtcr;
 use crate::realm;
+use crate::realm::mm::stage2_tte::invalid_ripas;
 use crate::realm::registry::get_realm;
 use crate::realm::vcpu::State as RecState;
 use crate::realm::vcpu::VCPU;
 use crate::rmi::error::Error;
 use crate::rmi::error::InternalError::*;
+use crate::rmi::realm::rd::State;
 use crate::rmi::Rd;
+use crate::rmi::MAX_REC_AUX_GRANULES;
 use crate::rmm_exit;
+use crate::rsi::attestation::session::TokenSession;
+use crate::rsi::hostcall::BufferCache;
+use crate::rsi::psci::PsciRequest;
 use core::cell::OnceCell;
+use stats::ExitStats;
 
 pub use self::handlers::set_event_handler;
 
 use crate::granule::GranuleState;
 
+use vmsa::error::Error as MmError;
 use vmsa::guard::Content;
 
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -26,18 +35,44 @@ pub enum RmmRecAttestState {
     NoAttestInProgress,
 }
 
+/// What REC_ENTER does on a CPU other than the one the REC is pinned to,
+/// which is set for each realm at REALM_CREATE
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum AffinityPolicy {
+    /// The REC runs on any CPU
+    #[default]
+    Any = 0,
+    Warn = 1,
+    Reject = 2,
+}
+
+impl TryFrom<u8> for AffinityPolicy {
+    type Error = Error;
+
+    fn try_from(policy: u8) -> Result<Self, Error> {
+        match policy {
+            0 => Ok(AffinityPolicy::Any),
+            1 => Ok(AffinityPolicy::Warn),
+            2 => Ok(AffinityPolicy::Reject),
+            _ => Err(Error::RmiErrorInput),
+        }
+    }
+}
+
 #[derive(Debug)]
 struct Ripas {
     start: u64,
     end: u64,
     addr: u64,
     state: u8,
+    /// The change is requested by a data abort rather than RSI_IPA_STATE_SET
+    from_fault: bool,
 }
 
 #[derive(Debug)]
 pub struct Rec<'a> {
     attest_state: RmmRecAttestState,
-    attest_challenge: [u8; 64],
+    attest_session: TokenSession,
     /// PA of RD of Realm which owns this REC
     ///
     /// Safety:
@@ -45,15 +80,36 @@ pub struct Rec<'a> {
     /// by making getter method for the safety
     owner: OnceCell<&'a Rd>,
     vcpuid: usize,
+    mpidr: u64,
     runnable: bool,
     state: RecState,
     ripas: Ripas,
     vtcr: u64,
     host_call_pending: bool,
+    /// Host call buffer last checked, which saves walking the RTT on every call
+    host_call_buffer: Option<BufferCache>,
+    /// Rt of the MRS forwarded to the host, which is completed on the next REC entry
+    pending_sysreg_read: Option<usize>,
+    /// PSCI request forwarded to the host, which is completed by RMI_PSCI_COMPLETE
+    psci_pending: Option<PsciRequest>,
+    /// Auxiliary granules linked to the REC, which are released along with it
+    aux: [usize; MAX_REC_AUX_GRANULES],
+    num_aux: usize,
+    exit_stats: ExitStats,
+    /// CPU the REC is pinned to by the host, as the index of `get_cpu_id()`
+    affinity: Option<usize>,
+    /// Whether entering the REC on another CPU has been warned about
+    affinity_warned: bool,
 }
 
 impl Rec<'_> {
-    pub fn init(&mut self, owner: usize, vcpuid: usize, flags: u64) -> Result<(), Error> {
+    pub fn init(
+        &mut self,
+        owner: usize,
+        vcpuid: usize,
+        mpidr: u64,
+        flags: u64,
+    ) -> Result<(), Error> {
         if owner == 0 {
             error!("owner should be non-zero");
             return Err(Error::RmiErrorInput);
@@ -69,9 +125,19 @@ impl Rec<'_> {
         }
 
         self.vcpuid = vcpuid;
+        self.mpidr = mpidr;
         self.set_ripas(0, 0, 0, 0);
-        self.set_runnable(flags);
+        const RUNNABLE_OFFSET: u64 = 1;
+        self.set_runnable(flags & RUNNABLE_OFFSET != 0);
         self.set_state(RecState::Ready);
+        self.set_pending_sysreg_read(None);
+        self.set_psci_pending(None);
+        self.host_call_buffer = None;
+        self.discard_attest_session();
+        self.num_aux = 0;
+        self.exit_stats.reset();
+        self.affinity = None;
+        self.affinity_warned = false;
 
         Ok(())
     }
@@ -80,8 +146,19 @@ impl Rec<'_> {
         self.attest_state
     }
 
-    pub fn attest_challenge(&self) -> &[u8] {
-        &self.attest_challenge
+    pub fn attest_session(&self) -> &TokenSession {
+        &self.attest_session
+    }
+
+    pub fn attest_session_mut(&mut self) -> &mut TokenSession {
+        &mut self.attest_session
+    }
+
+    /// Ends the attestation token request in progress, if any,
+    /// so that RSI_ATTEST_TOKEN_CONTINUE fails until the next init.
+    pub fn discard_attest_session(&mut self) {
+        self.attest_session.discard();
+        self.attest_state = RmmRecAttestState::NoAttestInProgress;
     }
 
     pub fn runnable(&self) -> bool {
@@ -92,6 +169,10 @@ impl Rec<'_> {
         self.vcpuid
     }
 
+    pub fn mpidr(&self) -> u64 {
+        self.mpidr
+    }
+
     fn get_owner(&self) -> Result<&Rd, Error> {
         match self.owner.get() {
             Some(owner) => Ok(owner),
@@ -112,31 +193,99 @@ impl Rec<'_> {
         self.attest_state = state;
     }
 
-    pub fn set_attest_challenge(&mut self, challenge: &[u8]) {
-        self.attest_challenge.copy_from_slice(challenge);
-    }
-
     pub fn set_host_call_pending(&mut self, val: bool) {
         self.host_call_pending = val;
     }
 
+    pub fn aux(&self) -> &[usize] {
+        &self.aux[..self.num_aux]
+    }
+
+    pub fn set_aux(&mut self, aux: &[u64]) -> Result<(), Error> {
+        let dst = self.aux.get_mut(..aux.len()).ok_or(Error::RmiErrorInput)?;
+        for (dst, src) in dst.iter_mut().zip(aux) {
+            *dst = *src as usize;
+        }
+        self.num_aux = aux.len();
+        Ok(())
+    }
+
+    pub fn set_affinity(&mut self, affinity: Option<usize>) {
+        self.affinity = affinity;
+        self.affinity_warned = false;
+    }
+
+    /// Checks the CPU the REC is about to run on against the one it is pinned to.
+    /// With AffinityPolicy::Warn, only the first entry on another CPU is logged.
+    pub fn check_affinity(&mut self, policy: AffinityPolicy, cpu: usize) -> Result<(), Error> {
+        match self.affinity {
+            Some(pinned) if pinned != cpu => match policy {
+                AffinityPolicy::Any => Ok(()),
+                AffinityPolicy::Warn => {
+                    if !self.affinity_warned {
+                        warn!(
+                            "REC {} pinned to CPU {} is entered on CPU {}",
+                            self.vcpuid, pinned, cpu
+                        );
+                        self.affinity_warned = true;
+                    }
+                    Ok(())
+                }
+                AffinityPolicy::Reject => Err(Error::RmiErrorRec),
+            },
+            _ => Ok(()),
+        }
+    }
+
+    pub fn exit_stats(&self) -> &ExitStats {
+        &self.exit_stats
+    }
+
+    pub fn host_call_buffer_mut(&mut self) -> &mut Option<BufferCache> {
+        &mut self.host_call_buffer
+    }
+
+    pub fn set_pending_sysreg_read(&mut self, rt: Option<usize>) {
+        self.pending_sysreg_read = rt;
+    }
+
+    pub fn take_pending_sysreg_read(&mut self) -> Option<usize> {
+        self.pending_sysreg_read.take()
+    }
+
+    pub fn psci_pending(&self) -> Option<PsciRequest> {
+        self.psci_pending
+    }
+
+    pub fn set_psci_pending(&mut self, req: Option<PsciRequest>) {
+        self.psci_pending = req;
+    }
+
     pub fn set_ripas(&mut self, start: u64, end: u64, addr: u64, state: u8) {
         self.ripas.start = start;
         self.ripas.end = end;
         self.ripas.addr = addr;
         self.ripas.state = state;
+        self.ripas.from_fault = false;
+    }
+
+    /// Asks the host to change the RIPAS of `[start, end)` to RAM
+    /// on behalf of the realm which faulted on it.
+    pub fn set_ripas_from_fault(&mut self, start: u64, end: u64) {
+        self.set_ripas(start, end, start, invalid_ripas::RAM as u8);
+        self.ripas.from_fault = true;
+    }
+
+    pub fn ripas_from_fault(&self) -> bool {
+        self.ripas.from_fault
     }
 
     pub fn set_vtcr(&mut self, vtcr: u64) {
         self.vtcr = vtcr;
     }
 
-    fn set_runnable(&mut self, flags: u64) {
-        const RUNNABLE_OFFSET: u64 = 1;
-        self.runnable = match flags & RUNNABLE_OFFSET {
-            0 => false,
-            _ => true,
-        }
+    pub fn set_runnable(&mut self, runnable: bool) {
+        self.runnable = runnable;
     }
 
     pub fn set_state(&mut self, state: RecState) {
@@ -147,6 +296,48 @@ impl Rec<'_> {
         self.state
     }
 
+    /// The REC is running from REC_ENTER until the realm exits back to the host.
+    pub fn is_running(&self) -> bool {
+        matches!(self.state, RecState::Running)
+    }
+
+    /// A REC can't be destroyed while it is running on any PE.
+    pub fn check_destroy(&self) -> Result<(), MmError> {
+        match self.is_running() {
+            true => Err(MmError::MmIsInUse),
+            false => Ok(()),
+        }
+    }
+
+    /// Checks that the REC can be entered while its realm is in `realm`.
+    /// A realm faulting fatally is turned off, so SystemOff also covers
+    /// a fatal error latched on an earlier entry.
+    pub fn check_enter(&self, realm: State) -> Result<(), Error> {
+        // the owner is set last by REC_CREATE
+        self.get_owner()?;
+
+        match realm {
+            State::Active => {}
+            State::New => return Err(Error::RmiErrorRealm(0)),
+            State::SystemOff => return Err(Error::RmiErrorRealm(1)),
+            // the realm is being destroyed
+            State::Null => return Err(MmError::MmStateError.into()),
+        }
+
+        if !self.runnable() {
+            return Err(Error::RmiErrorRec);
+        }
+        if self.is_running() {
+            error!("Rec is already running: {:?}", self);
+            return Err(Error::RmiErrorRec);
+        }
+        if self.psci_pending().is_some() {
+            error!("Rec has a PSCI request pending: {:?}", self);
+            return Err(Error::RmiErrorRec);
+        }
+        Ok(())
+    }
+
     pub fn inc_ripas_addr(&mut self, size: u64) {
         self.ripas.addr += size;
     }
@@ -242,3 +433,193 @@ pub fn run(id: usize, vcpu: usize, incr_pc: usize) -> Result<[usize; 4], Error>
     exit();
     Ok(ret)
 }
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+
+    pub(crate) fn rec<'a>() -> Rec<'a> {
+        Rec {
+            attest_state: RmmRecAttestState::NoAttestInProgress,
+            attest_session: TokenSession::default(),
+            owner: OnceCell::new(),
+            vcpuid: 0,
+            mpidr: 0,
+            runnable: true,
+            state: RecState::Ready,
+            ripas: Ripas {
+                start: 0,
+                end: 0,
+                addr: 0,
+                state: 0,
+                from_fault: false,
+            },
+            vtcr: 0,
+            host_call_pending: false,
+            host_call_buffer: None,
+            pending_sysreg_read: None,
+            psci_pending: None,
+            aux: [0; MAX_REC_AUX_GRANULES],
+            num_aux: 0,
+            exit_stats: ExitStats::default(),
+            affinity: None,
+            affinity_warned: false,
+        }
+    }
+
+    #[test]
+    fn destroy_running_rec() {
+        let mut rec = rec();
+        assert!(rec.check_destroy().is_ok());
+
+        rec.set_state(RecState::Running);
+        assert!(rec.is_running());
+        assert_eq!(rec.check_destroy(), Err(MmError::MmIsInUse));
+
+        rec.set_state(RecState::Ready);
+        assert!(!rec.is_running());
+        assert!(rec.check_destroy().is_ok());
+    }
+
+    #[test]
+    fn enter_preconditions() {
+        use crate::rsi::psci::{PsciFunction, PsciRequest};
+
+        // the realm isn't set up yet
+        let rec = rec();
+        assert!(matches!(
+            rec.check_enter(State::Active),
+            Err(Error::RmiErrorRec)
+        ));
+
+        let rd = crate::mm::rtt::test::Table::new();
+        let mut rec = rec;
+        rec.owner
+            .set(unsafe { &*(rd.addr() as *const Rd) })
+            .unwrap();
+        assert!(rec.check_enter(State::Active).is_ok());
+
+        assert!(matches!(
+            rec.check_enter(State::New),
+            Err(Error::RmiErrorRealm(0))
+        ));
+        assert!(matches!(
+            rec.check_enter(State::SystemOff),
+            Err(Error::RmiErrorRealm(1))
+        ));
+        assert!(matches!(
+            rec.check_enter(State::Null),
+            Err(Error::RmiErrorInput)
+        ));
+
+        rec.set_runnable(false);
+        assert!(matches!(
+            rec.check_enter(State::Active),
+            Err(Error::RmiErrorRec)
+        ));
+        rec.set_runnable(true);
+
+        rec.set_state(RecState::Running);
+        assert!(matches!(
+            rec.check_enter(State::Active),
+            Err(Error::RmiErrorRec)
+        ));
+        rec.set_state(RecState::Ready);
+
+        let args = [0, 1, 0, 0];
+        rec.set_psci_pending(Some(PsciRequest::new(PsciFunction::CpuOn, &args)));
+        assert!(matches!(
+            rec.check_enter(State::Active),
+            Err(Error::RmiErrorRec)
+        ));
+        rec.set_psci_pending(None);
+        assert!(rec.check_enter(State::Active).is_ok());
+    }
+
+    #[test]
+    fn affinity_check() {
+        let mut rec = rec();
+        // unpinned
+        for policy in [AffinityPolicy::Any, AffinityPolicy::Reject] {
+            assert!(rec.check_affinity(policy, 3).is_ok());
+        }
+
+        rec.set_affinity(Some(1));
+        for policy in [
+            AffinityPolicy::Any,
+            AffinityPolicy::Warn,
+            AffinityPolicy::Reject,
+        ] {
+            assert!(rec.check_affinity(policy, 1).is_ok());
+        }
+        assert!(rec.check_affinity(AffinityPolicy::Any, 2).is_ok());
+        assert!(!rec.affinity_warned);
+        assert!(rec.check_affinity(AffinityPolicy::Warn, 2).is_ok());
+        // warned once, not on every entry
+        assert!(rec.affinity_warned);
+        assert!(rec.check_affinity(AffinityPolicy::Warn, 3).is_ok());
+        assert!(rec.affinity_warned);
+        // until the REC is pinned again
+        rec.set_affinity(Some(1));
+        assert!(!rec.affinity_warned);
+        assert!(matches!(
+            rec.check_affinity(AffinityPolicy::Reject, 2),
+            Err(Error::RmiErrorRec)
+        ));
+
+        assert_eq!(AffinityPolicy::try_from(0).unwrap(), AffinityPolicy::Any);
+        assert!(AffinityPolicy::try_from(3).is_err());
+    }
+
+    #[test]
+    fn discard_then_continue() {
+        use crate::event::{Context, RsiHandle};
+        use crate::measurement::{Measurement, MEASUREMENTS_SLOT_NR};
+        use crate::mm::rtt::test::Table;
+        use crate::realm::context::get_reg;
+        use crate::realm::vcpu::{create_vcpu, remove};
+        use crate::rmi::realm::params::RPV_SIZE;
+        use crate::rmi::realm::{create_realm, rd};
+        use crate::rmi::rec::run::Run;
+        use crate::rsi::attestation::session::CHALLENGE_SIZE;
+        use crate::{rmi, rsi, Monitor};
+
+        let rtt = Table::new();
+        let id = create_realm(0xe9, rtt.addr()).unwrap();
+        let vcpu = create_vcpu(id).unwrap();
+        let mut rd = rd::test::rd();
+        rd.init(id, rtt.addr(), 40, 1);
+        let mut rec = rec();
+        rec.init(&rd as *const Rd as usize, vcpu, 0, 1).unwrap();
+
+        let measurements = [Measurement::empty(); MEASUREMENTS_SLOT_NR];
+        rec.attest_session_mut()
+            .init(&[0x11; CHALLENGE_SIZE], &measurements, 0, &[0; RPV_SIZE]);
+        rec.set_attest_state(RmmRecAttestState::AttestInProgress);
+
+        let token = [0x5a; 32];
+        let mut buf = [0u8; 16];
+        assert_eq!(
+            rec.attest_session_mut().continue_token(&token, &mut buf),
+            (16, false)
+        );
+
+        rec.discard_attest_session();
+        assert_eq!(rec.attest_session().challenge(), [0; CHALLENGE_SIZE]);
+        assert_eq!(rec.attest_session().offset(), 0);
+
+        // RSI_ATTEST_TOKEN_CONTINUE answers ERROR_STATE from now on
+        let monitor = Monitor::new();
+        let mut run = Run::default();
+        let mut ctx = Context::new(rsi::ATTEST_TOKEN_CONTINUE);
+        ctx.resize_ret(1);
+        assert_eq!(
+            monitor.rsi.dispatch(&mut ctx, &monitor, &mut rec, &mut run),
+            RsiHandle::RET_SUCCESS
+        );
+        assert_eq!(ctx.ret_slice()[0], rmi::SUCCESS_REC_ENTER);
+        assert_eq!(get_reg(id, vcpu, 0).unwrap(), rsi::ERROR_STATE);
+
+        remove(id).unwrap();
+    }
+}
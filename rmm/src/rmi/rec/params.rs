@@ -1,13 +1,15 @@
 use super::mpidr;
+use crate::config::NUM_OF_CPU;
 use crate::const_assert_eq;
 use crate::granule::{GranuleState, GRANULE_SIZE};
 use crate::host::Accessor as HostAccessor;
 use crate::measurement::Hashable;
+use crate::realm::context::Context;
 use crate::rmi::error::Error;
 use crate::{get_granule, get_granule_if};
 
 const MAX_AUX: usize = 16;
-const PADDING: [usize; 5] = [248, 248, 248, 1216, 1912];
+const PADDING: [usize; 6] = [248, 248, 248, 1216, 1656, 248];
 
 #[repr(C)]
 pub struct Params {
@@ -22,6 +24,10 @@ pub struct Params {
     pub num_aux: u64,
     pub aux: [u64; MAX_AUX],
     padding4: [u8; PADDING[4]],
+    /// Implementation defined: the CPU the REC is pinned to as its index plus one,
+    /// of which zero means any CPU.
+    pub affinity: u64,
+    padding5: [u8; PADDING[5]],
 }
 
 const_assert_eq!(core::mem::size_of::<Params>(), GRANULE_SIZE);
@@ -40,15 +46,42 @@ impl Default for Params {
             num_aux: 0,
             aux: [0; MAX_AUX],
             padding4: [0; PADDING[4]],
+            affinity: 0,
+            padding5: [0; PADDING[5]],
         }
     }
 }
 
 impl Params {
+    /// Loads the entry point and the initial GPRs into the context of a new REC.
+    pub fn init_context(&self, context: &mut Context) {
+        context.gp_regs[..self.gprs.len()].copy_from_slice(&self.gprs);
+        context.elr = self.pc;
+    }
+
+    /// RECs are created in the order of their RecIndex starting from zero,
+    /// so the MPIDR is unique within the realm if its index is the next one.
+    pub fn check_mpidr(&self, rec_index: usize) -> Result<(), Error> {
+        match mpidr::MPIDR::from(self.mpidr).index() == rec_index {
+            true => Ok(()),
+            false => Err(Error::RmiErrorInput),
+        }
+    }
+
+    pub fn affinity(&self) -> Option<usize> {
+        (self.affinity as usize).checked_sub(1)
+    }
+
+    /// Addresses of the auxiliary granules given by the host
+    pub fn aux(&self) -> &[u64] {
+        &self.aux[..(self.num_aux as usize).min(MAX_AUX)]
+    }
+
     pub fn validate_aux(&self, rec: usize, rd: usize, params_ptr: usize) -> Result<(), Error> {
         let mut aux = self.aux;
+        let aux = &mut aux[..self.aux().len()];
         aux.sort();
-        for idx in 0..self.num_aux as usize {
+        for idx in 0..aux.len() {
             let addr = aux[idx] as usize;
             if addr == rec || addr == rd || addr == params_ptr {
                 return Err(Error::RmiErrorInput);
@@ -74,6 +107,7 @@ impl core::fmt::Debug for Params {
             .field("gprs", &format_args!("{:#X?}", &self.gprs))
             .field("num_aux", &self.num_aux)
             .field("aux", &self.aux)
+            .field("affinity", &self.affinity)
             .finish()
     }
 }
@@ -89,6 +123,10 @@ impl HostAccessor for Params {
             return false;
         }
 
+        if self.affinity as usize > NUM_OF_CPU {
+            return false;
+        }
+
         return true;
     }
 }
@@ -111,6 +149,8 @@ impl Hashable for Params {
             h.hash_u64(0); // num_aux not used
             h.hash_u64_array([0u64; 16].as_slice()); // aux is not used
             h.hash(self.padding4);
+            h.hash_u64(0); // affinity is not used
+            h.hash(self.padding5);
         })
     }
 }
@@ -130,5 +170,56 @@ pub mod test {
         assert_eq!(offset_of!(Params, gprs), 0x300);
         assert_eq!(offset_of!(Params, num_aux), 0x800);
         assert_eq!(offset_of!(Params, aux), 0x808);
+        assert_eq!(offset_of!(Params, affinity), 0xf00);
+    }
+
+    #[test]
+    fn init_context() {
+        let params = Params {
+            pc: 0x8800_0000,
+            gprs: [1, 2, 3, 4, 5, 6, 7, 8],
+            ..Default::default()
+        };
+
+        let mut context = Context {
+            gp_regs: [0xdead; 31],
+            ..Default::default()
+        };
+        params.init_context(&mut context);
+
+        assert_eq!(context.elr, 0x8800_0000);
+        assert_eq!(context.gp_regs[..8], [1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(context.gp_regs[8], 0xdead);
+    }
+
+    #[test]
+    fn mpidr_uniqueness() {
+        let params = Params::default();
+        assert!(params.check_mpidr(0).is_ok());
+        // the first REC already took index 0
+        assert!(matches!(params.check_mpidr(1), Err(Error::RmiErrorInput)));
+
+        // Aff1 = 1, Aff0 = 2
+        let params = Params {
+            mpidr: 0x102,
+            ..Default::default()
+        };
+        assert!(params.check_mpidr(18).is_ok());
+        assert!(matches!(params.check_mpidr(19), Err(Error::RmiErrorInput)));
+        assert!(matches!(params.check_mpidr(17), Err(Error::RmiErrorInput)));
+    }
+
+    #[test]
+    fn aux_granules() {
+        let mut params = Params::default();
+        assert!(params.aux().is_empty());
+
+        params.num_aux = 2;
+        params.aux[..3].copy_from_slice(&[0x8800_2000, 0x8800_1000, 0x8800_3000]);
+        assert_eq!(params.aux(), [0x8800_2000, 0x8800_1000]);
+
+        // rejected by validate()
+        params.num_aux = MAX_AUX as u64 + 1;
+        assert_eq!(params.aux().len(), MAX_AUX);
     }
 }
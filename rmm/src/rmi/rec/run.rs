@@ -1,5 +1,7 @@
 use crate::host::Accessor as HostAccessor;
+use crate::rmi;
 use crate::rmi::error::Error;
+use crate::rsi::hostcall::HOST_CALL_NR_GPRS;
 use core::mem::ManuallyDrop;
 
 /// The structure holds data passsed between the Host and the RMM
@@ -23,6 +25,48 @@ impl Run {
         Ok(self.entry.inner.gprs.val[idx])
     }
 
+    #[cfg(test)]
+    pub fn set_entry_gpr(&mut self, idx: usize, val: u64) {
+        // Safety: the entry portion is always initialized
+        unsafe {
+            let entry: &mut EntryInner = &mut self.entry.inner;
+            entry.gprs.val[idx] = val;
+        }
+    }
+
+    #[cfg(test)]
+    pub fn set_entry_gic(&mut self, lrs: &[u64; 16], hcr: u64) {
+        // Safety: the entry portion is always initialized
+        unsafe {
+            let entry: &mut EntryInner = &mut self.entry.inner;
+            let gicv3: &mut EntryGICv3Inner = &mut entry.gicv3.inner;
+            gicv3.lrs = *lrs;
+            gicv3.hcr = hcr;
+        }
+    }
+
+    #[cfg(test)]
+    pub fn exit_gic_misr(&self) -> u64 {
+        unsafe { self.exit.inner.gicv3.inner.misr }
+    }
+
+    #[cfg(test)]
+    pub fn exit_gic_hcr(&self) -> u64 {
+        unsafe { self.exit.inner.gicv3.inner.hcr }
+    }
+
+    #[cfg(test)]
+    pub fn exit_cntv(&self) -> (u64, u64) {
+        let cnt = unsafe { &self.exit.inner.cnt.inner };
+        (cnt.v_ctl, cnt.v_cval)
+    }
+
+    #[cfg(test)]
+    pub fn exit_cntp(&self) -> (u64, u64) {
+        let cnt = unsafe { &self.exit.inner.cnt.inner };
+        (cnt.p_ctl, cnt.p_cval)
+    }
+
     pub unsafe fn entry_gic_lrs(&self) -> &[u64; 16] {
         &self.entry.inner.gicv3.inner.lrs
     }
@@ -39,8 +83,16 @@ impl Run {
         (*self.exit.inner).imm.val = imm;
     }
 
-    pub unsafe fn set_exit_reason(&mut self, exit_reason: u8) {
-        (*self.exit.inner).exit_reason.val = exit_reason;
+    pub fn set_pmu_overflow(&mut self, pending: bool) {
+        // Safety: the exit portion is always initialized
+        unsafe {
+            let exit: &mut ExitInner = &mut self.exit.inner;
+            exit.pmu_ovf.val = pending as u8;
+        }
+    }
+
+    pub unsafe fn set_exit_reason(&mut self, exit_reason: rmi::ExitReason) {
+        (*self.exit.inner).exit_reason.val = exit_reason.into();
     }
 
     pub unsafe fn set_esr(&mut self, esr: u64) {
@@ -64,6 +116,22 @@ impl Run {
         Ok(())
     }
 
+    #[cfg(test)]
+    pub fn exit_gpr(&self, idx: usize) -> u64 {
+        unsafe { self.exit.inner.gprs.val[idx] }
+    }
+
+    /// Surfaces the host call made by the realm to the host.
+    pub fn set_host_call(&mut self, host_call: &RecExitHostCall) {
+        // Safety: the exit portion is always initialized
+        unsafe {
+            let exit: &mut ExitInner = &mut self.exit.inner;
+            exit.exit_reason.val = rmi::ExitReason::HostCall.into();
+            exit.imm.val = host_call.imm;
+            exit.gprs.val[..HOST_CALL_NR_GPRS].copy_from_slice(&host_call.gprs);
+        }
+    }
+
     pub unsafe fn set_ripas(&mut self, base: u64, size: u64, state: u8) {
         (*(*self.exit.inner).ripas.inner).base = base;
         (*(*self.exit.inner).ripas.inner).size = size;
@@ -120,6 +188,7 @@ impl core::fmt::Debug for Run {
                 .field("entry::gicv3_lrs", &self.entry.inner.gicv3.inner.lrs)
                 .field("exit::exit_reason", &self.exit.inner.exit_reason.val)
                 .field("exit::imm", &self.exit.inner.imm.val)
+                .field("exit::pmu_ovf", &self.exit.inner.pmu_ovf.val)
                 .field("exit::cntp_ctl", &self.exit.inner.cnt.inner.p_ctl)
                 .field("exit::cntp_cval", &self.exit.inner.cnt.inner.p_cval)
                 .field("exit::cntv_ctl", &self.exit.inner.cnt.inner.v_ctl)
@@ -179,6 +248,7 @@ impl Default for Run {
                         }),
                     },
                     imm: Imm { val: 0 },
+                    pmu_ovf: PmuOvf { val: 0 },
                 }),
             },
         }
@@ -195,6 +265,13 @@ impl Drop for Run {
     }
 }
 
+/// Immediate and arguments of a host call, passed to the host on REC exit.
+#[derive(Debug, Default, PartialEq)]
+pub struct RecExitHostCall {
+    pub imm: u16,
+    pub gprs: [u64; HOST_CALL_NR_GPRS],
+}
+
 /// The structure holds data passsed from the Host to the RMM on REC entry.
 #[repr(C)]
 union Entry {
@@ -277,6 +354,7 @@ struct ExitInner {
     cnt: CounterTimer,
     ripas: RIPAS,
     imm: Imm,
+    pmu_ovf: PmuOvf,
 }
 
 #[repr(C)]
@@ -356,7 +434,16 @@ struct RIPASInner {
 #[repr(C)]
 union Imm {
     val: u16,
-    reserved: [u8; 0x800 - 0x600],
+    reserved: [u8; 0x700 - 0x600],
+}
+
+/// PMU overflow status
+///  val 0: No PMU overflow is pending.
+///  val 1: A PMU overflow is pending.
+#[repr(C)]
+union PmuOvf {
+    val: u8,
+    reserved: [u8; 0x800 - 0x700],
 }
 
 impl HostAccessor for Run {
@@ -0,0 +1,156 @@
+use crate::event::realmexit::{ExitSyncType, RecExitReason};
+
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+// Exit reasons take the low 4 bits and the types of synchronous exits the next 4 bits,
+// so the synchronous exits are counted by their type below SYNC_SLOTS and the others above
+const SYNC_SLOTS: usize = 16;
+const SLOTS: usize = 2 * SYNC_SLOTS;
+
+/// Slot of the exit, which is decoded first so that every exit reason has one slot
+fn slot(exit: usize) -> usize {
+    let exit: u64 = RecExitReason::from(exit).into();
+    let exit = exit as usize;
+    match exit % SYNC_SLOTS {
+        0 => exit / SYNC_SLOTS,
+        reason => SYNC_SLOTS + reason,
+    }
+}
+
+/// Exit counted in `slot`
+fn exit_of(slot: usize) -> usize {
+    match slot < SYNC_SLOTS {
+        true => slot * SYNC_SLOTS,
+        false => slot - SYNC_SLOTS,
+    }
+}
+
+fn name(exit: RecExitReason) -> &'static str {
+    match exit {
+        RecExitReason::Sync(ExitSyncType::RSI) => "rsi",
+        RecExitReason::Sync(ExitSyncType::DataAbort) => "data_abort",
+        RecExitReason::Sync(ExitSyncType::InstAbort) => "inst_abort",
+        RecExitReason::Sync(ExitSyncType::WFx) => "wfx",
+        RecExitReason::Sync(ExitSyncType::SysReg) => "sysreg",
+        RecExitReason::Sync(ExitSyncType::Debug) => "debug",
+        RecExitReason::Sync(ExitSyncType::Undefined) => "sync_undefined",
+        RecExitReason::IRQ => "irq",
+        RecExitReason::FIQ => "fiq",
+        RecExitReason::PSCI => "psci",
+        RecExitReason::SError => "serror",
+        RecExitReason::Maintenance => "maintenance",
+        RecExitReason::VirtualTimer => "vtimer",
+        RecExitReason::PhysicalTimer => "ptimer",
+        RecExitReason::Undefined => "undefined",
+    }
+}
+
+/// Number of entries into a REC and of its exits by reason,
+/// including the exits handled without returning to the host.
+/// The counters wrap around.
+#[derive(Default)]
+pub struct ExitStats {
+    entries: AtomicU64,
+    exits: [AtomicU64; SLOTS],
+}
+
+impl ExitStats {
+    pub fn reset(&self) {
+        self.entries.store(0, Ordering::Relaxed);
+        for count in &self.exits {
+            count.store(0, Ordering::Relaxed);
+        }
+    }
+
+    pub fn record_entry(&self) {
+        self.entries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts an exit by the reason the realm has been left with
+    pub fn record_exit(&self, exit: usize) {
+        self.exits[slot(exit)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn entries(&self) -> u64 {
+        self.entries.load(Ordering::Relaxed)
+    }
+
+    pub fn exits(&self, exit: usize) -> u64 {
+        self.exits[slot(exit)].load(Ordering::Relaxed)
+    }
+}
+
+impl fmt::Debug for ExitStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut map = f.debug_map();
+        map.entry(&"entries", &self.entries());
+        for (slot, count) in self.exits.iter().enumerate() {
+            let count = count.load(Ordering::Relaxed);
+            if count != 0 {
+                map.entry(&name(RecExitReason::from(exit_of(slot))), &count);
+            }
+        }
+        map.finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    extern crate alloc;
+    use alloc::format;
+
+    const RSI: usize = ExitSyncType::RSI as usize;
+    const WFX: usize = ExitSyncType::WFx as usize;
+    const IRQ: usize = 1;
+    const VTIMER: usize = 6;
+
+    #[test]
+    fn count_by_reason() {
+        let stats = ExitStats::default();
+        for exit in [RSI, IRQ, RSI, WFX, RSI, VTIMER, IRQ] {
+            stats.record_entry();
+            stats.record_exit(exit);
+        }
+
+        assert_eq!(stats.entries(), 7);
+        assert_eq!(stats.exits(RSI), 3);
+        assert_eq!(stats.exits(IRQ), 2);
+        assert_eq!(stats.exits(WFX), 1);
+        assert_eq!(stats.exits(VTIMER), 1);
+        assert_eq!(stats.exits(ExitSyncType::DataAbort as usize), 0);
+        assert_eq!(
+            format!("{:?}", stats),
+            r#"{"entries": 7, "rsi": 3, "wfx": 1, "irq": 2, "vtimer": 1}"#
+        );
+
+        stats.reset();
+        assert_eq!((stats.entries(), stats.exits(RSI)), (0, 0));
+    }
+
+    #[test]
+    fn undefined_exits() {
+        const SYNC_UNDEFINED: usize = ExitSyncType::Undefined as usize;
+        const UNDEFINED: usize = 0xf;
+        let stats = ExitStats::default();
+
+        // an unknown synchronous type is decoded as the undefined one
+        for exit in [SYNC_UNDEFINED, 0x70, UNDEFINED] {
+            stats.record_exit(exit);
+        }
+        assert_eq!(stats.exits(SYNC_UNDEFINED), 2);
+        assert_eq!(stats.exits(0x70), 2);
+        assert_eq!(stats.exits(UNDEFINED), 1);
+        assert_eq!(
+            format!("{:?}", stats),
+            r#"{"entries": 0, "sync_undefined": 2, "undefined": 1}"#
+        );
+
+        // every slot an exit is counted in is named after the exit
+        for exit in 0..=0xff {
+            assert_eq!(slot(exit_of(slot(exit))), slot(exit), "{:#x}", exit);
+        }
+    }
+}
@@ -1,11 +1,13 @@
 use super::Rec;
+use crate::mm::rtt::vtcr_for;
+use crate::realm::vmid::vmid_bits;
 use crate::rmi::error::Error;
 use crate::rmi::realm::Rd;
 use armv9a::bits_in_reg;
 use armv9a::regs::*;
 
 fn is_feat_vmid16_present() -> bool {
-    unsafe { ID_AA64MMFR1_EL1.get_masked_value(ID_AA64MMFR1_EL1::VMID) == mmfr1_vmid::VMIDBITS_16 }
+    vmid_bits() == 16
 }
 
 pub fn prepare_vtcr(rd: &Rd) -> Result<u64, Error> {
@@ -17,10 +19,8 @@ pub fn prepare_vtcr(rd: &Rd) -> Result<u64, Error> {
         return Err(Error::RmiErrorInput);
     }
 
-    // t0sz consists of 6 bits (2^6 == 64)
-    if !(ipa_bits > 0 && ipa_bits <= 64) {
-        return Err(Error::RmiErrorInput);
-    }
+    // T0SZ of a supported IPA width, while SL0 follows the level the host built the RTT from
+    let s2 = vtcr_for(ipa_bits, rd.lpa2())?;
 
     let mut vtcr_val = bits_in_reg(VTCR_EL2::PS, tcr_paddr_size::PS_1T)
         | bits_in_reg(VTCR_EL2::TG0, tcr_granule::G_4K)
@@ -30,6 +30,12 @@ pub fn prepare_vtcr(rd: &Rd) -> Result<u64, Error> {
         | bits_in_reg(VTCR_EL2::NSA, 1)
         | bits_in_reg(VTCR_EL2::RES1, 1); //XXX: not sure why RES1 is in default set in tf-rmm
 
+    // 52-bit output addresses, of which OA[51:50] take the place of SH in descriptors
+    if rd.lpa2() {
+        vtcr_val &= !VTCR_EL2::PS;
+        vtcr_val |= bits_in_reg(VTCR_EL2::PS, tcr_paddr_size::PS_4P);
+    }
+
     if is_feat_vmid16_present() {
         vtcr_val |= bits_in_reg(VTCR_EL2::VS, 1);
     }
@@ -41,9 +47,8 @@ pub fn prepare_vtcr(rd: &Rd) -> Result<u64, Error> {
         vtcr_sl0::SL0_4K_L3,
     ];
     let sl0_val = sl0_array[s2_starting_level as usize];
-    let t0sz_val = (64 - ipa_bits) as u64;
 
-    vtcr_val |= bits_in_reg(VTCR_EL2::SL0, sl0_val) | bits_in_reg(VTCR_EL2::T0SZ, t0sz_val);
+    vtcr_val |= (s2 & !VTCR_EL2::SL0) | bits_in_reg(VTCR_EL2::SL0, sl0_val);
 
     Ok(vtcr_val)
 }
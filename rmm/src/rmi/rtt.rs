@@ -3,17 +3,17 @@ extern crate alloc;
 use super::realm::{rd::State, Rd};
 use super::rec::Rec;
 use crate::event::Mainloop;
-use crate::granule::{
-    is_granule_aligned, is_not_in_realm, set_granule, GranuleState, GRANULE_SHIFT, GRANULE_SIZE,
-};
+use crate::granule::{check_granule_owner, is_not_in_realm};
+use crate::granule::{set_granule_with_owner, GranuleState, GRANULE_SHIFT};
 use crate::host::pointer::Pointer as HostPointer;
 use crate::host::DataPage;
 use crate::listen;
 use crate::measurement::HashContext;
+use crate::mm;
 use crate::realm::mm::stage2_tte::S2TTE;
 use crate::rmi;
 use crate::rmi::error::Error;
-use crate::{get_granule, get_granule_if, set_state_and_get_granule};
+use crate::{get_granule, get_granule_if, require_state};
 
 pub const RTT_MIN_BLOCK_LEVEL: usize = 2;
 pub const RTT_PAGE_LEVEL: usize = 3;
@@ -33,23 +33,6 @@ fn level_to_size(level: usize) -> u64 {
     }
 }
 
-fn is_valid_rtt_cmd(ipa: usize, level: usize) -> bool {
-    if level > RTT_PAGE_LEVEL {
-        return false;
-    }
-    let mask = match level {
-        0 => S2TTE::ADDR_L0_PAGE,
-        1 => S2TTE::ADDR_L1_PAGE,
-        2 => S2TTE::ADDR_L2_PAGE,
-        3 => S2TTE::ADDR_L3_PAGE,
-        _ => unreachable!(),
-    };
-    if ipa & mask as usize != ipa {
-        return false;
-    }
-    return true;
-}
-
 pub fn set_event_handler(mainloop: &mut Mainloop) {
     listen!(mainloop, rmi::RTT_CREATE, |arg, _ret, _rmm| {
         let rtt_addr = arg[0];
@@ -58,46 +41,62 @@ pub fn set_event_handler(mainloop: &mut Mainloop) {
         let ipa = arg[2];
         let level = arg[3];
 
-        if !is_valid_rtt_cmd(ipa, level) {
-            return Err(Error::RmiErrorInput);
-        }
+        mm::validate_ipa(rd, ipa, level)?;
         if rtt_addr == arg[1] {
             return Err(Error::RmiErrorInput);
         }
-        crate::rtt::create(rd.id(), rtt_addr, ipa, level)?;
+        crate::rtt::create(rd, rtt_addr, ipa, level)?;
         Ok(())
     });
 
-    listen!(mainloop, rmi::RTT_DESTROY, |arg, _ret, _rmm| {
+    listen!(mainloop, rmi::RTT_DESTROY, |arg, _ret, rmm| {
         let rtt_addr = arg[0];
-        let rd_granule = get_granule_if!(arg[1], GranuleState::RD)?;
-        let rd = rd_granule.content::<Rd>();
+        let mut rd_granule = get_granule_if!(arg[1], GranuleState::RD)?;
+        let rd = rd_granule.content_mut::<Rd>();
         let ipa = arg[2];
         let level = arg[3];
 
-        if !is_valid_rtt_cmd(ipa, level) {
-            return Err(Error::RmiErrorInput);
-        }
-        crate::rtt::destroy(rd, rtt_addr, ipa, level)?;
-        Ok(())
+        mm::validate_ipa(rd, ipa, level)?;
+        // the table is scrubbed on its way back to the Delegated state
+        rmm.page_table.map(rtt_addr, true);
+        let ret = crate::rtt::destroy(rd, rtt_addr, ipa, level);
+        rmm.page_table.unmap(rtt_addr);
+        rd.rtt_changed();
+        ret
     });
 
-    listen!(mainloop, rmi::RTT_INIT_RIPAS, |arg, _ret, rmm| {
+    listen!(mainloop, rmi::RTT_FOLD, |arg, _ret, rmm| {
+        let rtt_addr = arg[0];
+        let mut rd_granule = get_granule_if!(arg[1], GranuleState::RD)?;
+        let rd = rd_granule.content_mut::<Rd>();
+        let ipa = arg[2];
+        let level = arg[3];
+
+        mm::validate_ipa(rd, ipa, level)?;
+        // the table is scrubbed on its way back to the Delegated state
+        rmm.page_table.map(rtt_addr, true);
+        let ret = crate::rtt::fold(rd, rtt_addr, ipa, level);
+        rmm.page_table.unmap(rtt_addr);
+        rd.rtt_changed();
+        ret
+    });
+
+    listen!(mainloop, rmi::RTT_INIT_RIPAS, |arg, ret, rmm| {
         let rd_granule = get_granule_if!(arg[0], GranuleState::RD)?;
         let rd = rd_granule.content::<Rd>();
-        let ipa = arg[1];
-        let level = arg[2];
+        let base = arg[1];
+        let top = arg[2];
 
-        if rd.state() != State::New {
-            return Err(Error::RmiErrorRealm(0));
-        }
-        if !is_valid_rtt_cmd(ipa, level) {
+        mm::validate_ipa(rd, base, RTT_PAGE_LEVEL)?;
+        if !rd.addr_in_par(base) || top > realm_par_size(rd.ipa_bits()) {
             return Err(Error::RmiErrorInput);
         }
-        crate::rtt::init_ripas(rd.id(), ipa, level)?;
+        let top = crate::rtt::init_ripas(rd, base, top)?;
 
-        HashContext::new(&rmm.rsi, &rd)?.measure_ripas_granule(ipa, level as u8)?;
+        HashContext::new(&rmm.rsi, &rd)?.measure_ripas_range(base, top)?;
 
+        // the host continues from `top` if the range isn't done
+        ret[1] = top;
         Ok(())
     });
 
@@ -106,10 +105,11 @@ pub fn set_event_handler(mainloop: &mut Mainloop) {
         let level = arg[3];
         let ripas = arg[4];
 
-        let rd_granule = get_granule_if!(arg[0], GranuleState::RD)?;
-        let rd = rd_granule.content::<Rd>();
+        let mut rd_granule = get_granule_if!(arg[0], GranuleState::RD)?;
+        let rd = rd_granule.content_mut::<Rd>();
         let mut rec_granule = get_granule_if!(arg[1], GranuleState::Rec)?;
         let rec = rec_granule.content_mut::<Rec<'_>>();
+        mm::validate_ipa(rd, ipa, level)?;
 
         let mut prot = rmi::MapProt::new(0);
         match ripas as u64 {
@@ -135,12 +135,14 @@ pub fn set_event_handler(mainloop: &mut Mainloop) {
         }
 
         if ripas as u64 == RIPAS_EMPTY {
-            crate::rtt::make_shared(rd.id(), ipa, level)?;
+            crate::rtt::make_shared(rd, ipa, level)?;
         } else if ripas as u64 == RIPAS_RAM {
-            crate::rtt::make_exclusive(rd.id(), ipa, level)?;
+            crate::rtt::make_exclusive(rd, ipa, level)?;
         } else {
             unreachable!();
         }
+        // host call buffers made shared have to be checked again
+        rd.rtt_changed();
         rec.inc_ripas_addr(map_size);
         Ok(())
     });
@@ -150,11 +152,9 @@ pub fn set_event_handler(mainloop: &mut Mainloop) {
         let rd = rd_granule.content::<Rd>();
         let ipa = arg[1];
         let level = arg[2];
-        if !is_valid_rtt_cmd(ipa, level) {
-            return Err(Error::RmiErrorInput);
-        }
+        mm::validate_ipa(rd, ipa, level)?;
 
-        let res = crate::rtt::read_entry(rd.id(), ipa, level)?;
+        let res = crate::rtt::read_entry(rd, ipa, level)?;
         ret[1..5].copy_from_slice(&res[0..4]);
 
         Ok(())
@@ -175,15 +175,16 @@ pub fn set_event_handler(mainloop: &mut Mainloop) {
         // rd granule lock
         let rd_granule = get_granule_if!(rd, GranuleState::RD)?;
         let rd = rd_granule.content::<Rd>();
-        let realm_id = rd.id();
 
         // Make sure DATA_CREATE is only processed
         // when the realm is in its New state.
-        if !rd.at_state(State::New) {
-            return Err(Error::RmiErrorRealm(0));
-        }
+        require_state!(rd, State::New);
 
-        validate_ipa(ipa, rd.ipa_bits())?;
+        mm::validate_ipa(rd, ipa, RTT_PAGE_LEVEL)?;
+        // rejected before the RIM is extended with it
+        if !is_protected_ipa(ipa, rd.ipa_bits()) {
+            return Err(Error::RmiErrorInput);
+        }
 
         if !is_not_in_realm(src_pa) {
             return Err(Error::RmiErrorInput);
@@ -191,6 +192,7 @@ pub fn set_event_handler(mainloop: &mut Mainloop) {
 
         // data granule lock for the target page
         let mut target_page_granule = get_granule_if!(target_pa, GranuleState::Delegated)?;
+        check_granule_owner(&target_page_granule, rd.id())?;
         let target_page = target_page_granule.content_mut::<DataPage>();
         rmm.page_table.map(target_pa, true);
 
@@ -203,9 +205,9 @@ pub fn set_event_handler(mainloop: &mut Mainloop) {
         *target_page = src_page;
 
         // 4. map ipa to taget_pa in S2 table
-        crate::rtt::data_create(realm_id, ipa, target_pa)?;
+        crate::rtt::data_create(rd, ipa, target_pa)?;
 
-        set_granule(&mut target_page_granule, GranuleState::Data)?;
+        set_granule_with_owner(&mut target_page_granule, GranuleState::Data, rd.id())?;
         Ok(())
     });
 
@@ -220,35 +222,36 @@ pub fn set_event_handler(mainloop: &mut Mainloop) {
         // rd granule lock
         let rd_granule = get_granule_if!(arg[1], GranuleState::RD)?;
         let rd = rd_granule.content::<Rd>();
-        let realm_id = rd.id();
 
-        validate_ipa(ipa, rd.ipa_bits())?;
+        mm::validate_ipa(rd, ipa, RTT_PAGE_LEVEL)?;
+        if !is_protected_ipa(ipa, rd.ipa_bits()) {
+            return Err(Error::RmiErrorInput);
+        }
 
         // 0. Make sure granule state can make a transition to DATA
         // data granule lock for the target page
         let mut target_page_granule = get_granule_if!(target_pa, GranuleState::Delegated)?;
+        check_granule_owner(&target_page_granule, rd.id())?;
         rmm.page_table.map(target_pa, true);
 
         // 1. map ipa to target_pa in S2 table
-        crate::rtt::data_create(realm_id, ipa, target_pa)?;
+        crate::rtt::data_create(rd, ipa, target_pa)?;
 
         // TODO: 2. perform measure
         // L0czek - not needed here see: tf-rmm/runtime/rmi/rtt.c:883
-        set_granule(&mut target_page_granule, GranuleState::Data)?;
+        set_granule_with_owner(&mut target_page_granule, GranuleState::Data, rd.id())?;
         Ok(())
     });
 
     listen!(mainloop, rmi::DATA_DESTROY, |arg, _ret, _rmm| {
         // rd granule lock
-        let rd_granule = get_granule_if!(arg[0], GranuleState::RD)?;
-        let rd = rd_granule.content::<Rd>();
-        let realm_id = rd.id();
+        let mut rd_granule = get_granule_if!(arg[0], GranuleState::RD)?;
+        let rd = rd_granule.content_mut::<Rd>();
         let ipa = arg[1];
 
-        let pa = crate::rtt::data_destroy(realm_id, ipa)?;
-
-        // data granule lock and change state
-        set_state_and_get_granule!(pa, GranuleState::Delegated)?;
+        mm::validate_ipa(rd, ipa, RTT_PAGE_LEVEL)?;
+        crate::rtt::data_destroy(rd, ipa)?;
+        rd.rtt_changed();
         Ok(())
     });
 
@@ -258,17 +261,15 @@ pub fn set_event_handler(mainloop: &mut Mainloop) {
         let level = arg[2];
         let host_s2tte = arg[3];
         let s2tte = S2TTE::from(host_s2tte);
-        if !s2tte.is_host_ns_valid(level) {
-            return Err(Error::RmiErrorInput);
-        }
 
         // rd granule lock
         let rd_granule = get_granule_if!(arg[0], GranuleState::RD)?;
         let rd = rd_granule.content::<Rd>();
-
-        if !is_valid_rtt_cmd(ipa, level) {
+        if !s2tte.is_host_ns_valid(level, rd.lpa2()) {
             return Err(Error::RmiErrorInput);
         }
+
+        mm::validate_ipa(rd, ipa, level)?;
         crate::rtt::map_unprotected(rd, ipa, level, host_s2tte)?;
         Ok(())
     });
@@ -279,13 +280,10 @@ pub fn set_event_handler(mainloop: &mut Mainloop) {
 
         let rd_granule = get_granule_if!(arg[0], GranuleState::RD)?;
         let rd = rd_granule.content::<Rd>();
-        let realm_id = rd.id();
 
         let level = arg[2];
-        if !is_valid_rtt_cmd(ipa, level) {
-            return Err(Error::RmiErrorInput);
-        }
-        crate::rtt::unmap_unprotected(realm_id, ipa, level)?;
+        mm::validate_ipa(rd, ipa, level)?;
+        crate::rtt::unmap_unprotected(rd, ipa, level)?;
         Ok(())
     });
 }
@@ -301,21 +299,3 @@ pub fn realm_par_size(ipa_bits: usize) -> usize {
 pub fn is_protected_ipa(ipa: usize, ipa_bits: usize) -> bool {
     ipa < realm_par_size(ipa_bits)
 }
-
-pub fn validate_ipa(ipa: usize, ipa_bits: usize) -> Result<(), Error> {
-    if !is_granule_aligned(ipa) {
-        error!("ipa: {:x} is not aligned with {:x}", ipa, GRANULE_SIZE);
-        return Err(Error::RmiErrorInput);
-    }
-
-    if !is_protected_ipa(ipa, ipa_bits) {
-        error!(
-            "ipa: {:x} is not in protected ipa range {:x}",
-            ipa,
-            realm_par_size(ipa_bits)
-        );
-        return Err(Error::RmiErrorInput);
-    }
-
-    Ok(())
-}
@@ -0,0 +1,187 @@
+//! RMI_SELF_TEST runs the core paths of the RMM on a granule given by the host,
+//! as a sanity check of the bring-up on new hardware. Debug builds only.
+
+use crate::asm::{smc, SMC_SUCCESS};
+use crate::crypto::hash::HashAlgo;
+use crate::event::Mainloop;
+use crate::granule::{is_granule_aligned, set_granule, GranuleState};
+use crate::listen;
+use crate::mm::alloc::is_pool_granule;
+use crate::mm::rtt::{Rtt, RttEntryState, S2Prot};
+use crate::realm::mm::stage2_tte::{desc_type, S2TTE};
+use crate::rmi;
+use crate::rmi::error::Error;
+use crate::rmi::gpt::{MARK_NONSECURE, MARK_REALM};
+use crate::rmi::rtt::RTT_PAGE_LEVEL;
+use crate::Monitor;
+use crate::{get_granule, set_state_and_get_granule};
+
+use armv9a::bits_in_reg;
+use vmsa::error::Error as MmError;
+
+extern crate alloc;
+use alloc::boxed::Box;
+
+/// Bits of the checks passed, returned in X1
+pub const GRANULE_CYCLE: usize = 1 << 0;
+pub const RTT_CYCLE: usize = 1 << 1;
+pub const HASH_KAT: usize = 1 << 2;
+pub const ALL: usize = GRANULE_CYCLE | RTT_CYCLE | HASH_KAT;
+
+// FIPS 180-2, Appendix B.1
+const SHA256_ABC: [u8; 32] = [
+    0xba, 0x78, 0x16, 0xbf, 0x8f, 0x01, 0xcf, 0xea, 0x41, 0x41, 0x40, 0xde, 0x5d, 0xae, 0x22, 0x23,
+    0xb0, 0x03, 0x61, 0xa3, 0x96, 0x17, 0x7a, 0x9c, 0xb4, 0x10, 0xff, 0x61, 0xf2, 0x00, 0x15, 0xad,
+];
+
+/// Changes of the granule protection and of the mappings of the RMM,
+/// which the checks leave to the platform
+pub trait Platform {
+    /// Moves `addr` to the Realm PAS, or back to the Non-secure PAS
+    fn mark(&self, addr: usize, realm: bool) -> bool;
+    fn map(&self, addr: usize, secure: bool);
+    fn unmap(&self, addr: usize);
+}
+
+/// EL3 firmware and the page table of the RMM
+struct Firmware<'a>(&'a Monitor);
+
+impl Platform for Firmware<'_> {
+    fn mark(&self, addr: usize, realm: bool) -> bool {
+        let cmd = match realm {
+            true => MARK_REALM,
+            false => MARK_NONSECURE,
+        };
+        smc(cmd, &[addr])[0] == SMC_SUCCESS
+    }
+
+    fn map(&self, addr: usize, secure: bool) {
+        self.0.page_table.map(addr, secure);
+    }
+
+    fn unmap(&self, addr: usize) {
+        self.0.page_table.unmap(addr);
+    }
+}
+
+/// Delegates the undelegated granule at `addr` and undelegates it back
+fn granule_cycle(addr: usize, platform: &impl Platform) -> Result<bool, Error> {
+    let mut granule = match get_granule!(addr) {
+        Err(MmError::MmNoEntry) => set_state_and_get_granule!(addr, GranuleState::Undelegated),
+        other => other,
+    }?;
+    if granule.state() != GranuleState::Undelegated || !platform.mark(addr, true) {
+        return Ok(false);
+    }
+    set_granule(&mut granule, GranuleState::Delegated)?;
+
+    if !platform.mark(addr, false) {
+        return Ok(false);
+    }
+    // scrubbed on its way back to the host
+    platform.map(addr, false);
+    let res = set_granule(&mut granule, GranuleState::Undelegated);
+    platform.unmap(addr);
+    res?;
+    Ok(granule.state() == GranuleState::Undelegated)
+}
+
+#[repr(C, align(4096))]
+struct Table([u64; 512]);
+
+/// Maps and unmaps a page in a scratch RTT starting at level 2
+fn rtt_cycle() -> Result<bool, MmError> {
+    const IPA: usize = (1 << 21) | (3 << 12);
+    const PA: usize = 0x8800_0000;
+
+    let l2 = Box::new(Table([0; 512]));
+    let l3 = Box::new(Table([0; 512]));
+    let mut rtt = Rtt::new(l2.0.as_ptr() as usize, 2, 1);
+
+    let table = l3.0.as_ptr() as u64 | bits_in_reg(S2TTE::DESC_TYPE, desc_type::L012_TABLE);
+    rtt.set(IPA, 2, table)?;
+    rtt.map_prot(IPA, PA, RTT_PAGE_LEVEL, S2Prot::data())?;
+    let walk = rtt.walk(IPA, RTT_PAGE_LEVEL)?;
+    if walk.state() != RttEntryState::Valid || walk.output_address() != PA {
+        return Ok(false);
+    }
+    Ok(rtt.unmap(IPA)? == PA && rtt.walk(IPA, RTT_PAGE_LEVEL)?.is_unassigned())
+}
+
+fn hash_kat() -> bool {
+    let mut out = [0u8; 32];
+    let mut hasher = HashAlgo::Sha256.hasher();
+    hasher.update(b"abc");
+    hasher.finalize(&mut out).is_ok() && out == SHA256_ABC
+}
+
+/// Runs all the checks and returns the bits of the passed ones
+pub fn run(addr: usize, platform: &impl Platform) -> usize {
+    let mut passed = 0;
+    if granule_cycle(addr, platform).unwrap_or(false) {
+        passed |= GRANULE_CYCLE;
+    }
+    if rtt_cycle().unwrap_or(false) {
+        passed |= RTT_CYCLE;
+    }
+    if hash_kat() {
+        passed |= HASH_KAT;
+    }
+    passed
+}
+
+pub fn set_event_handler(mainloop: &mut Mainloop) {
+    listen!(mainloop, rmi::SELF_TEST, |arg, ret, rmm| {
+        let addr = arg[0];
+        if !is_granule_aligned(addr) || is_pool_granule(addr) {
+            return Err(Error::RmiErrorInput);
+        }
+        ret[1] = run(addr, &Firmware(rmm));
+        if ret[1] != ALL {
+            warn!("RMI_SELF_TEST passed {:#x} of {:#x}", ret[1], ALL);
+        }
+        Ok(())
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::granule::test::{recreate_granule_status_table, TEST_ADDR};
+
+    /// GPT and mappings as the software model has them, which always succeed
+    struct Model;
+
+    impl Platform for Model {
+        fn mark(&self, _addr: usize, _realm: bool) -> bool {
+            true
+        }
+
+        fn map(&self, _addr: usize, _secure: bool) {}
+
+        fn unmap(&self, _addr: usize) {}
+    }
+
+    /// The Realm PAS is out of reach
+    struct NoRealmPas;
+
+    impl Platform for NoRealmPas {
+        fn mark(&self, _addr: usize, realm: bool) -> bool {
+            !realm
+        }
+
+        fn map(&self, _addr: usize, _secure: bool) {}
+
+        fn unmap(&self, _addr: usize) {}
+    }
+
+    #[test]
+    fn all_pass_on_model() {
+        recreate_granule_status_table();
+        assert_eq!(run(TEST_ADDR, &Model), ALL);
+        // the granule is given back, so the test can be run again
+        assert_eq!(run(TEST_ADDR, &Model), ALL);
+
+        assert_eq!(run(TEST_ADDR, &NoRealmPas), RTT_CYCLE | HASH_KAT);
+    }
+}
@@ -1,16 +1,76 @@
 use crate::event::Mainloop;
 use crate::listen;
 use crate::rmi;
+use crate::rmi::error::Error;
 
 extern crate alloc;
 
-fn encode_version() -> usize {
-    (rmi::ABI_MAJOR_VERSION << 16) | rmi::ABI_MINOR_VERSION
+const VERSION_MAJOR_SHIFT: usize = 16;
+const VERSION_MAJOR_MASK: usize = 0x7fff;
+const VERSION_MINOR_MASK: usize = 0xffff;
+
+/// Packs the version according to the RMM specification.
+/// - bits [30:16]: major version
+/// - bits [15:0]: minor version
+fn encode(major: usize, minor: usize) -> usize {
+    ((major & VERSION_MAJOR_MASK) << VERSION_MAJOR_SHIFT) | (minor & VERSION_MINOR_MASK)
+}
+
+fn decode(version: usize) -> (usize, usize) {
+    (
+        (version >> VERSION_MAJOR_SHIFT) & VERSION_MAJOR_MASK,
+        version & VERSION_MINOR_MASK,
+    )
+}
+
+pub fn encode_version() -> usize {
+    encode(rmi::ABI_MAJOR_VERSION, rmi::ABI_MINOR_VERSION)
+}
+
+/// The requested version is compatible if it has the same major version
+/// and a minor version not greater than the one implemented.
+// ABI_MINOR_VERSION is currently 0, which makes clippy complain about the comparison.
+#[allow(clippy::absurd_extreme_comparisons)]
+fn is_compatible(requested: usize) -> bool {
+    let (major, minor) = decode(requested);
+    major == rmi::ABI_MAJOR_VERSION && minor <= rmi::ABI_MINOR_VERSION
 }
 
 pub fn set_event_handler(mainloop: &mut Mainloop) {
-    listen!(mainloop, rmi::VERSION, |_, ret, _| {
-        ret[0] = encode_version();
+    listen!(mainloop, rmi::VERSION, |arg, ret, _| {
+        let requested = arg[0];
+
+        // lower and higher revisions supported by this implementation
+        ret[1] = encode_version();
+        ret[2] = encode_version();
+
+        if !is_compatible(requested) {
+            warn!("Unsupported RMI version requested: {:#X}", requested);
+            return Err(Error::RmiErrorInput);
+        }
         Ok(())
     });
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn version_packing() {
+        assert_eq!(encode(1, 0), 0x1_0000);
+        assert_eq!(encode(0x7fff, 0xffff), 0x7fff_ffff);
+        assert_eq!(encode_version() >> 31, 0);
+        assert_eq!(decode(encode(1, 2)), (1, 2));
+    }
+
+    #[test]
+    fn version_compatibility() {
+        assert!(is_compatible(encode_version()));
+        assert!(!is_compatible(encode(rmi::ABI_MAJOR_VERSION + 1, 0)));
+        assert!(!is_compatible(encode(
+            rmi::ABI_MAJOR_VERSION,
+            rmi::ABI_MINOR_VERSION + 1
+        )));
+    }
+}
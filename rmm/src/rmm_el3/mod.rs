@@ -9,6 +9,7 @@ mod utils;
 
 use crate::asm;
 use crate::config;
+use crate::rsi::attestation::key;
 use alloc::vec::Vec;
 use spinning_top::Spinlock;
 
@@ -29,6 +30,9 @@ pub fn setup_el3_ifc() {
 
     asm::dcache_flush(RMM_SHARED_BUFFER_START, config::PAGE_SIZE);
     iface::get_realm_attest_key();
+    if key::provision(&realm_attest_key()).is_err() {
+        error!("Failed to provision the realm attestation key");
+    }
     iface::get_plat_token();
 }
 
@@ -0,0 +1,58 @@
+use crate::crypto::ecdsa::{self, PrivateKey};
+use crate::crypto::Error;
+
+use alloc::vec::Vec;
+use sha2::{Digest, Sha384};
+use spinning_top::Spinlock;
+
+static ATTEST_KEY: Spinlock<Option<AttestKey>> = Spinlock::new(None);
+
+/// Realm attestation key (RAK) which signs the realm tokens with ES384
+#[derive(Clone)]
+pub struct AttestKey {
+    key: PrivateKey,
+}
+
+impl AttestKey {
+    pub fn from_bytes(key_priv: &[u8]) -> Result<Self, Error> {
+        Ok(Self {
+            key: PrivateKey::from_bytes(key_priv)?,
+        })
+    }
+
+    pub fn public_key(&self) -> Vec<u8> {
+        ecdsa::public_key(&self.key)
+    }
+
+    /// Signs the SHA-384 digest of `data`
+    pub fn sign(&self, data: &[u8]) -> Result<ecdsa::Signature, Error> {
+        ecdsa::sign(&self.key, &Sha384::digest(data))
+    }
+}
+
+/// Installs the key received from EL3 during the initialization.
+pub fn provision(key_priv: &[u8]) -> Result<(), Error> {
+    *ATTEST_KEY.lock() = Some(AttestKey::from_bytes(key_priv)?);
+    Ok(())
+}
+
+pub fn attest_key() -> Option<AttestKey> {
+    ATTEST_KEY.lock().clone()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sign_with_attest_key() {
+        let key = AttestKey::from_bytes(&[0x11; 48]).unwrap();
+        let signature = key.sign(b"realm token").unwrap();
+
+        let digest = Sha384::digest(b"realm token");
+        assert_eq!(
+            ecdsa::verify(&key.public_key(), &digest, &signature),
+            Ok(())
+        );
+    }
+}
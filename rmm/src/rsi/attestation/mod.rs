@@ -1,62 +1,48 @@
 pub mod claims;
+pub mod key;
+pub mod session;
+pub mod token;
 
-use alloc::{boxed::Box, string::String, vec::Vec};
+use alloc::{boxed::Box, vec::Vec};
 use ciborium::{ser, Value};
-use coset::{CoseSign1Builder, HeaderBuilder, TaggedCborSerializable};
-use ecdsa::signature::Signer;
 use tinyvec::ArrayVec;
 
-use crate::{
-    measurement::Measurement,
-    rmi::{HASH_ALGO_SHA256, HASH_ALGO_SHA512},
-};
-
-use self::claims::RealmClaims;
-
-const DUMMY_PERSONALIZATION_VALUE: [u8; 64] = [0; 64];
+use self::key::AttestKey;
+use self::session::TokenSession;
 
 const CCA_TOKEN_COLLECTION: u64 = 399;
 const CCA_PLATFORM_TOKEN: u64 = 44234;
 const CCA_REALM_DELEGATED_TOKEN: u64 = 44241;
 
-type PlatformToken = ArrayVec<[u8; 4096]>;
-// 48B - the length of EC-P384 private key
-type RAKPriv = ArrayVec<[u8; 48]>;
+const PLATFORM_TOKEN_MAX_SIZE: usize = 4096;
+// claims of the realm token with the largest digests and its COSE_Sign1 envelope
+const REALM_TOKEN_MAX_SIZE: usize = 1024;
+
+/// Upper bound of the size of the attestation token, which is returned by
+/// RSI_ATTESTATION_TOKEN_INIT for the realm to allocate its buffer.
+pub const TOKEN_SIZE_UPPER_BOUND: usize = PLATFORM_TOKEN_MAX_SIZE + REALM_TOKEN_MAX_SIZE;
+
+type PlatformToken = ArrayVec<[u8; PLATFORM_TOKEN_MAX_SIZE]>;
 
-#[derive(Debug, Default)]
 pub struct Attestation {
     platform_token: PlatformToken,
-    rak_priv: RAKPriv,
+    rak: AttestKey,
 }
 
 impl Attestation {
-    pub fn new(platform_token: &[u8], rak_priv: &[u8]) -> Self {
-        let mut at = Self::default();
-        at.set_platform_token(platform_token);
-        at.set_rak_priv(rak_priv);
-        at
-    }
-
-    fn set_platform_token(&mut self, token: &[u8]) {
-        self.platform_token = token.iter().cloned().collect();
-    }
-
-    fn set_rak_priv(&mut self, key_priv: &[u8]) {
-        self.rak_priv = key_priv.iter().cloned().collect();
+    pub fn new(platform_token: &[u8], rak: AttestKey) -> Self {
+        Self {
+            platform_token: platform_token.iter().cloned().collect(),
+            rak,
+        }
     }
 
     // TODO: Consider returning errors.
     // Though all errors in here are programmer errors
     // or a result of incorrect data passed from HES.
-    pub fn create_attestation_token(
-        &self,
-        challenge: &[u8],
-        measurements: &[Measurement],
-        hash_algo: u8,
-    ) -> Vec<u8> {
-        let mut cca_token = Vec::new();
-
-        let realm_token = self.create_realm_token(challenge, measurements, hash_algo);
+    /// Writes the token into `buf` and returns its size.
+    pub fn create_attestation_token(&self, session: &TokenSession, buf: &mut [u8]) -> usize {
+        let realm_token = self.create_realm_token(session);
 
         let realm_token_entry = (
             Value::Integer(CCA_REALM_DELEGATED_TOKEN.into()),
@@ -72,78 +58,19 @@ impl Attestation {
         token_map.push(platform_token_entry);
         token_map.push(realm_token_entry);
 
+        let size = buf.len();
+        let mut remaining = buf;
         ser::into_writer(
             &Value::Tag(CCA_TOKEN_COLLECTION.into(), Box::new(Value::Map(token_map))),
-            &mut cca_token,
+            &mut remaining,
         )
         .expect("Failed to serialize CCA token");
 
-        cca_token
-    }
-
-    fn create_realm_token(
-        &self,
-        challenge: &[u8],
-        measurements: &[Measurement],
-        hash_algo: u8,
-    ) -> Vec<u8> {
-        let hash_algo_id = match hash_algo {
-            HASH_ALGO_SHA256 => String::from("sha-256"),
-            HASH_ALGO_SHA512 => String::from("sha-512"),
-            _ => panic!("Unrecognized hash algorithm {}", hash_algo),
-        };
-
-        let secret_key =
-            p384::SecretKey::from_slice(&self.rak_priv).expect("Failed to import private RAK.");
-
-        let public_key = secret_key.public_key().to_sec1_bytes().to_vec();
-
-        let claims = RealmClaims::init(
-            challenge,
-            &DUMMY_PERSONALIZATION_VALUE,
-            measurements,
-            hash_algo_id,
-            &public_key,
-            // TODO: should this value be stored somewhere else?
-            String::from("sha-256"),
-        );
-
-        let mut claims_map: Vec<(Value, Value)> = Vec::new();
-
-        claims_map.push(claims.challenge.into());
-        claims_map.push(claims.personalization_value.into());
-        claims_map.push(claims.rim.into());
-        claims_map.push(claims.rems.into());
-        claims_map.push(claims.measurement_hash_algo.into());
-        claims_map.push(claims.rak_pub.into());
-        claims_map.push(claims.rak_pub_hash_algo.into());
-
-        let mut realm_token = Vec::new();
-        ser::into_writer(&Value::Map(claims_map), &mut realm_token)
-            .expect("Failed to serialize realm token");
-
-        let protected = HeaderBuilder::new()
-            .algorithm(coset::iana::Algorithm::ES384)
-            .build();
-
-        let sign1 = CoseSign1Builder::new()
-            .protected(protected)
-            .payload(realm_token)
-            .create_signature(b"", |payload| Self::sign(secret_key, payload))
-            .build();
-
-        sign1
-            .to_tagged_vec()
-            .expect("Failed to create tagged signed token")
+        size - remaining.len()
     }
 
-    fn sign(secret_key: p384::SecretKey, data: &[u8]) -> Vec<u8> {
-        let signing_key = p384::ecdsa::SigningKey::from_bytes(&secret_key.to_bytes())
-            .expect("Failed to generate signing key");
-
-        let signature: p384::ecdsa::Signature = signing_key
-            .try_sign(data)
-            .expect("Failed to create P384 signature");
-        signature.to_vec()
+    fn create_realm_token(&self, session: &TokenSession) -> Vec<u8> {
+        let claims = token::encode_claims(session, &self.rak.public_key());
+        token::sign_claims(claims, &self.rak)
     }
 }
@@ -0,0 +1,199 @@
+use crate::measurement::{Measurement, MEASUREMENTS_SLOT_NR};
+use crate::rmi::realm::params::RPV_SIZE;
+
+pub const CHALLENGE_SIZE: usize = 64;
+
+/// Attestation token request of a REC, which is opened by RSI_ATTESTATION_TOKEN_INIT
+/// and served by the following RSI_ATTESTATION_TOKEN_CONTINUE calls.
+#[derive(Debug)]
+pub struct TokenSession {
+    challenge: [u8; CHALLENGE_SIZE],
+    /// Snapshot of the RIM and REMs at the time of the init
+    measurements: [Measurement; MEASUREMENTS_SLOT_NR],
+    hash_algo: u8,
+    rpv: [u8; RPV_SIZE],
+    /// Number of token bytes already handed over to the realm
+    offset: usize,
+}
+
+impl TokenSession {
+    /// Starts a new session, discarding any previous one.
+    pub fn init(
+        &mut self,
+        challenge: &[u8; CHALLENGE_SIZE],
+        measurements: &[Measurement; MEASUREMENTS_SLOT_NR],
+        hash_algo: u8,
+        rpv: &[u8; RPV_SIZE],
+    ) {
+        self.challenge = *challenge;
+        self.measurements = *measurements;
+        self.hash_algo = hash_algo;
+        self.rpv = *rpv;
+        self.offset = 0;
+    }
+
+    /// Clears the challenge and the snapshot of the measurements of the session
+    pub fn discard(&mut self) {
+        *self = Self::default();
+    }
+
+    pub fn challenge(&self) -> &[u8] {
+        &self.challenge
+    }
+
+    pub fn measurements(&self) -> &[Measurement] {
+        &self.measurements
+    }
+
+    pub fn hash_algo(&self) -> u8 {
+        self.hash_algo
+    }
+
+    pub fn rpv(&self) -> &[u8] {
+        &self.rpv
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn set_offset(&mut self, offset: usize) {
+        self.offset = offset;
+    }
+
+    /// Copies the part of `token` following the last chunk into `buf`.
+    /// Returns the number of bytes copied and whether the whole token has been copied.
+    pub fn continue_token(&mut self, token: &[u8], buf: &mut [u8]) -> (usize, bool) {
+        let chunk = self.next_chunk(token, buf.len());
+        let len = chunk.len();
+        buf[..len].copy_from_slice(chunk);
+        (len, self.advance(len, token.len()))
+    }
+
+    /// Up to `size` bytes of `token` following the last chunk
+    pub fn next_chunk<'a>(&self, token: &'a [u8], size: usize) -> &'a [u8] {
+        let remaining = token.get(self.offset..).unwrap_or(&[]);
+        &remaining[..remaining.len().min(size)]
+    }
+
+    /// Moves past a chunk of `len` bytes handed over to the realm.
+    /// Returns whether the whole token of `token_len` bytes has been handed over.
+    pub fn advance(&mut self, len: usize, token_len: usize) -> bool {
+        self.offset += len;
+        self.offset >= token_len
+    }
+}
+
+impl Default for TokenSession {
+    fn default() -> Self {
+        Self {
+            challenge: [0; CHALLENGE_SIZE],
+            measurements: [Measurement::empty(); MEASUREMENTS_SLOT_NR],
+            hash_algo: 0,
+            rpv: [0; RPV_SIZE],
+            offset: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rmi::{HASH_ALGO_SHA256, HASH_ALGO_SHA512};
+    use crate::rsi::attestation::key::AttestKey;
+    use crate::rsi::attestation::{Attestation, TOKEN_SIZE_UPPER_BOUND};
+
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    #[test]
+    fn reinit_discards_session() {
+        let mut session = TokenSession::default();
+        let mut measurements = [Measurement::empty(); MEASUREMENTS_SLOT_NR];
+        measurements[1].as_mut_slice().fill(0xaa);
+
+        session.init(
+            &[0x11; CHALLENGE_SIZE],
+            &measurements,
+            HASH_ALGO_SHA512,
+            &[0x33; RPV_SIZE],
+        );
+        session.set_offset(0x100);
+
+        let fresh = [Measurement::empty(); MEASUREMENTS_SLOT_NR];
+        session.init(
+            &[0x22; CHALLENGE_SIZE],
+            &fresh,
+            HASH_ALGO_SHA256,
+            &[0; RPV_SIZE],
+        );
+        assert_eq!(session.challenge(), [0x22; CHALLENGE_SIZE]);
+        assert_eq!(session.rpv(), [0; RPV_SIZE]);
+        assert_eq!(session.hash_algo(), HASH_ALGO_SHA256);
+        assert_eq!(session.offset(), 0);
+        assert!(session
+            .measurements()
+            .iter()
+            .all(|m| m.as_slice().iter().all(|&b| b == 0)));
+    }
+
+    /// Writes the token of `session` as RSI_ATTESTATION_TOKEN_CONTINUE does on every call
+    fn token(attestation: &Attestation, session: &TokenSession) -> Vec<u8> {
+        let mut buf = vec![0u8; TOKEN_SIZE_UPPER_BOUND];
+        let len = attestation.create_attestation_token(session, &mut buf);
+        buf.truncate(len);
+        buf
+    }
+
+    #[test]
+    fn token_in_chunks() {
+        let attestation =
+            Attestation::new(&[0xa5; 0x100], AttestKey::from_bytes(&[0x11; 48]).unwrap());
+        let mut session = TokenSession::default();
+        session.init(
+            &[0x5a; CHALLENGE_SIZE],
+            &[Measurement::empty(); MEASUREMENTS_SLOT_NR],
+            HASH_ALGO_SHA256,
+            &[0x33; RPV_SIZE],
+        );
+        let one_shot = token(&attestation, &session);
+        let half = one_shot.len() / 2;
+
+        let mut assembled = vec![0u8; one_shot.len()];
+        let (written, done) =
+            session.continue_token(&token(&attestation, &session), &mut assembled[..half]);
+        assert_eq!((written, done), (half, false));
+
+        let (written, done) =
+            session.continue_token(&token(&attestation, &session), &mut assembled[half..]);
+        assert_eq!((written, done), (one_shot.len() - half, true));
+        assert_eq!(assembled, one_shot);
+
+        // nothing is left after the final chunk
+        let mut buf = [0u8; 16];
+        assert_eq!(session.continue_token(&one_shot, &mut buf), (0, true));
+    }
+
+    #[test]
+    fn discard_session() {
+        let mut session = TokenSession::default();
+        let mut measurements = [Measurement::empty(); MEASUREMENTS_SLOT_NR];
+        measurements[0].as_mut_slice().fill(0xbb);
+        session.init(
+            &[0x44; CHALLENGE_SIZE],
+            &measurements,
+            HASH_ALGO_SHA256,
+            &[0x55; RPV_SIZE],
+        );
+        session.set_offset(0x40);
+
+        session.discard();
+        assert_eq!(session.challenge(), [0; CHALLENGE_SIZE]);
+        assert_eq!(session.rpv(), [0; RPV_SIZE]);
+        assert_eq!(session.offset(), 0);
+        assert!(session
+            .measurements()
+            .iter()
+            .all(|m| m.as_slice().iter().all(|&b| b == 0)));
+    }
+}
@@ -0,0 +1,193 @@
+use alloc::{string::String, vec::Vec};
+use ciborium::{ser, Value};
+use coset::{CoseSign1Builder, HeaderBuilder, TaggedCborSerializable};
+
+use super::claims::RealmClaims;
+use super::key::AttestKey;
+use super::session::TokenSession;
+use crate::rmi::{HASH_ALGO_SHA256, HASH_ALGO_SHA512};
+
+fn hash_algo_id(hash_algo: u8) -> String {
+    match hash_algo {
+        HASH_ALGO_SHA256 => String::from("sha-256"),
+        HASH_ALGO_SHA512 => String::from("sha-512"),
+        _ => panic!("Unrecognized hash algorithm {}", hash_algo),
+    }
+}
+
+/// Encodes the claims of the realm token from the snapshot of `session` as a CBOR map.
+pub fn encode_claims(session: &TokenSession, rak_pub: &[u8]) -> Vec<u8> {
+    let claims = RealmClaims::init(
+        session.challenge(),
+        session.rpv(),
+        session.measurements(),
+        hash_algo_id(session.hash_algo()),
+        rak_pub,
+        // TODO: should this value be stored somewhere else?
+        String::from("sha-256"),
+    );
+
+    let claims_map: Vec<(Value, Value)> = alloc::vec![
+        claims.challenge.into(),
+        claims.personalization_value.into(),
+        claims.rim.into(),
+        claims.rems.into(),
+        claims.measurement_hash_algo.into(),
+        claims.rak_pub.into(),
+        claims.rak_pub_hash_algo.into(),
+    ];
+
+    let mut encoded = Vec::new();
+    ser::into_writer(&Value::Map(claims_map), &mut encoded)
+        .expect("Failed to serialize realm token");
+    encoded
+}
+
+/// Wraps the encoded claims in a COSE_Sign1 structure signed with the RAK.
+pub fn sign_claims(claims: Vec<u8>, rak: &AttestKey) -> Vec<u8> {
+    let protected = HeaderBuilder::new()
+        .algorithm(coset::iana::Algorithm::ES384)
+        .build();
+
+    CoseSign1Builder::new()
+        .protected(protected)
+        .payload(claims)
+        .create_signature(b"", |payload| {
+            rak.sign(payload)
+                .expect("Failed to create P384 signature")
+                .as_slice()
+                .to_vec()
+        })
+        .build()
+        .to_tagged_vec()
+        .expect("Failed to create tagged signed token")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::measurement::{Hasher, Measurement, MEASUREMENTS_SLOT_NR, MEASUREMENTS_SLOT_RIM};
+    use crate::rmi::realm::params::{Params, RPV_SIZE};
+    use crate::rsi::attestation::claims::*;
+    use crate::rsi::attestation::session::CHALLENGE_SIZE;
+    use coset::CoseSign1;
+
+    fn session() -> TokenSession {
+        let mut measurements = [Measurement::empty(); MEASUREMENTS_SLOT_NR];
+        for (i, m) in measurements.iter_mut().enumerate() {
+            m.as_mut_slice().fill(i as u8 + 1);
+        }
+        let mut session = TokenSession::default();
+        session.init(
+            &[0x5a; CHALLENGE_SIZE],
+            &measurements,
+            HASH_ALGO_SHA256,
+            &[0x33; RPV_SIZE],
+        );
+        session
+    }
+
+    fn claim(map: &[(Value, Value)], label: u64) -> &Value {
+        &map.iter()
+            .find(|(key, _)| *key == Value::Integer(label.into()))
+            .expect("claim not found")
+            .1
+    }
+
+    #[test]
+    fn realm_claims_from_snapshot() {
+        let session = session();
+        let encoded = encode_claims(&session, &[0x04; 97]);
+
+        let value: Value = ciborium::de::from_reader(encoded.as_slice()).unwrap();
+        let map = value.into_map().unwrap();
+        assert_eq!(map.len(), 7);
+
+        assert_eq!(
+            *claim(&map, CHALLENGE_LABEL),
+            Value::Bytes([0x5a; CHALLENGE_SIZE].to_vec())
+        );
+        assert_eq!(
+            *claim(&map, PERSONALIZATION_VALUE_LABEL),
+            Value::Bytes([0x33; 64].to_vec())
+        );
+        // sha-256 measurements take the first 32 bytes of the slots
+        assert_eq!(
+            *claim(&map, INITIAL_MEASUREMENT_LABEL),
+            Value::Bytes([1; 32].to_vec())
+        );
+        let rems: Vec<Value> = (2..=5).map(|i| Value::Bytes([i; 32].to_vec())).collect();
+        assert_eq!(
+            *claim(&map, EXTENSIBLE_MEASUREMENTS_LABEL),
+            Value::Array(rems)
+        );
+        assert_eq!(
+            *claim(&map, HASH_ALGO_ID_LABEL),
+            Value::Text(String::from("sha-256"))
+        );
+        assert_eq!(
+            *claim(&map, PUBLIC_KEY_LABEL),
+            Value::Bytes([0x04; 97].to_vec())
+        );
+    }
+
+    /// Claims of a realm created with `rpv`, with the RIM measured from its params
+    fn claims_with_rpv(rpv: [u8; RPV_SIZE]) -> (Measurement, Vec<u8>) {
+        let mut params = Params::default();
+        params.hash_algo = HASH_ALGO_SHA256;
+        params.rpv = rpv;
+        let mut measurements = [Measurement::empty(); MEASUREMENTS_SLOT_NR];
+        let rim = &mut measurements[MEASUREMENTS_SLOT_RIM];
+        Hasher::from_hash_algo(HASH_ALGO_SHA256)
+            .unwrap()
+            .hash_object_into(&params, rim)
+            .unwrap();
+
+        let mut session = TokenSession::default();
+        session.init(
+            &[0x5a; CHALLENGE_SIZE],
+            &measurements,
+            HASH_ALGO_SHA256,
+            &rpv,
+        );
+        let rim = measurements[MEASUREMENTS_SLOT_RIM];
+        (rim, encode_claims(&session, &[0x04; 97]))
+    }
+
+    #[test]
+    fn personalization_value_binds_realm() {
+        let (rim_a, claims_a) = claims_with_rpv([0xa5; RPV_SIZE]);
+        let (rim_b, claims_b) = claims_with_rpv([0x5a; RPV_SIZE]);
+        assert_ne!(rim_a.as_slice(), rim_b.as_slice());
+        assert_ne!(claims_a, claims_b);
+
+        let value: Value = ciborium::de::from_reader(claims_b.as_slice()).unwrap();
+        let map = value.into_map().unwrap();
+        assert_eq!(
+            *claim(&map, PERSONALIZATION_VALUE_LABEL),
+            Value::Bytes([0x5a; RPV_SIZE].to_vec())
+        );
+        assert_eq!(
+            *claim(&map, INITIAL_MEASUREMENT_LABEL),
+            Value::Bytes(rim_b.as_slice()[..32].to_vec())
+        );
+    }
+
+    #[test]
+    fn signed_realm_token() {
+        let rak = AttestKey::from_bytes(&[0x11; 48]).unwrap();
+        let claims = encode_claims(&session(), &rak.public_key());
+        let token = sign_claims(claims.clone(), &rak);
+
+        let sign1 = CoseSign1::from_tagged_slice(&token).unwrap();
+        assert_eq!(sign1.payload, Some(claims));
+        assert_eq!(
+            sign1.protected.header.alg,
+            Some(coset::RegisteredLabelWithPrivate::Assigned(
+                coset::iana::Algorithm::ES384
+            ))
+        );
+        // r || s of P-384
+        assert_eq!(sign1.signature.len(), 96);
+    }
+}
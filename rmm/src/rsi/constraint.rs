@@ -16,6 +16,14 @@ lazy_static! {
         m.insert(rsi::HOST_CALL, Constraint::new(rsi::HOST_CALL, 2, 1));
         m.insert(rsi::ABI_VERSION, Constraint::new(rsi::ABI_VERSION, 2, 1));
         m.insert(rsi::REALM_CONFIG, Constraint::new(rsi::REALM_CONFIG, 2, 1));
+        m.insert(
+            rsi::INJECTED_FAULT_READ,
+            Constraint::new(rsi::INJECTED_FAULT_READ, 2, 1),
+        );
+        m.insert(
+            rsi::ATTEST_TOKEN_DISCARD,
+            Constraint::new(rsi::ATTEST_TOKEN_DISCARD, 2, 1),
+        );
         m.insert(
             rsi::IPA_STATE_GET,
             Constraint::new(rsi::IPA_STATE_GET, 2, 1),
@@ -1,5 +1,7 @@
 //extern crate alloc;
 use crate::rmi::error::Error;
+use crate::rmi::rec::run::{RecExitHostCall, Run};
+use vmsa::error::Error as MmError;
 
 #[repr(C)]
 pub struct HostCall {
@@ -24,11 +26,35 @@ impl HostCall {
         Ok(())
     }
 
+    /// Copies the result the host provides in the entry gprs of `run`
+    /// into the gprs of the host call.
+    ///
+    /// # Safety
+    ///
+    /// The host call has to be parsed from a mapped buffer of the realm.
+    pub unsafe fn set_result(&mut self, run: &Run) -> Result<(), Error> {
+        for i in 0..HOST_CALL_NR_GPRS {
+            self.set_gpr(i, run.entry_gpr(i)?)?;
+        }
+        Ok(())
+    }
+
     // Safety: union type should be initialized
     // Check UB
     pub fn imm(&self) -> u16 {
         unsafe { self.inner.val.imm as u16 }
     }
+
+    /// The host call as it is surfaced to the host on REC exit
+    pub fn exit(&self) -> RecExitHostCall {
+        // Safety: union type should be initialized
+        unsafe {
+            RecExitHostCall {
+                imm: self.inner.val.imm,
+                gprs: self.inner.val.gprs,
+            }
+        }
+    }
 }
 
 impl Drop for HostCall {
@@ -51,7 +77,68 @@ impl core::fmt::Debug for HostCall {
     }
 }
 
-pub const HOST_CALL_NR_GPRS: usize = 7;
+/// x0 to x30 are passed between the realm and the host
+pub const HOST_CALL_NR_GPRS: usize = 31;
+/// Offset of the gprs in RsiHostCall, after the immediate
+pub const HOST_CALL_GPRS_OFFSET: usize = 8;
+/// Maximum number of immediates which can be allowed for a realm
+pub const HOST_CALL_IMM_MAX: usize = 16;
+
+/// Immediate values a realm may pass to the host with RSI_HOST_CALL,
+/// which is set at the realm creation. Empty allows any of them.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ImmFilter {
+    nr: usize,
+    imms: [u16; HOST_CALL_IMM_MAX],
+}
+
+impl ImmFilter {
+    pub fn new(imms: &[u16]) -> Result<Self, Error> {
+        let mut filter = Self::default();
+        filter
+            .imms
+            .get_mut(..imms.len())
+            .ok_or(Error::RmiErrorInput)?
+            .copy_from_slice(imms);
+        filter.nr = imms.len();
+        Ok(filter)
+    }
+
+    pub fn allows(&self, imm: u16) -> bool {
+        self.nr == 0 || self.imms[..self.nr].contains(&imm)
+    }
+}
+/// RsiHostCall is a 256-byte aligned structure in the realm memory
+pub const HOST_CALL_ALIGN: usize = 0x100;
+
+/// Host call buffer of a REC as it was last checked against the RTT
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BufferCache {
+    ipa: usize,
+    pa: usize,
+    rtt_gen: u64,
+}
+
+impl BufferCache {
+    /// Returns the PA of the buffer at `ipa`, which is checked by `check` unless
+    /// the same buffer has been checked while the RTT was at generation `rtt_gen`.
+    pub fn resolve(
+        cache: &mut Option<Self>,
+        ipa: usize,
+        rtt_gen: u64,
+        check: impl FnOnce() -> Result<usize, MmError>,
+    ) -> Result<usize, MmError> {
+        if let Some(cached) = cache {
+            if cached.ipa == ipa && cached.rtt_gen == rtt_gen {
+                return Ok(cached.pa);
+            }
+        }
+        *cache = None;
+        let pa = check()?;
+        *cache = Some(Self { ipa, pa, rtt_gen });
+        Ok(pa)
+    }
+}
 
 #[repr(C)]
 struct _Inner {
@@ -64,3 +151,137 @@ union Inner {
     val: core::mem::ManuallyDrop<_Inner>,
     reserved: [u8; 0x100],
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[repr(C, align(256))]
+    struct Buffer([u8; 0x100]);
+
+    #[test]
+    fn host_call_layout() {
+        // RsiHostCall is exactly filled by the immediate and the gprs
+        assert_eq!(core::mem::size_of::<HostCall>(), HOST_CALL_ALIGN);
+        assert_eq!(
+            core::mem::size_of::<_Inner>(),
+            HOST_CALL_GPRS_OFFSET + 8 * HOST_CALL_NR_GPRS
+        );
+
+        let buf = Buffer([0; 0x100]);
+        let host_call = unsafe { HostCall::parse(buf.0.as_ptr() as usize) };
+        let gprs = unsafe { host_call.inner.val.gprs.as_ptr() } as usize;
+        assert_eq!(gprs - buf.0.as_ptr() as usize, HOST_CALL_GPRS_OFFSET);
+        // all of them are surfaced in the exit gprs
+        assert_eq!(HOST_CALL_NR_GPRS, crate::rmi::rec::run::NR_GPRS);
+    }
+
+    #[test]
+    fn host_call_payload() {
+        let mut buf = Buffer([0; 0x100]);
+        buf.0[..2].copy_from_slice(&0x1234u16.to_le_bytes());
+        // gprs start at offset 8 after the padding of imm
+        for (i, gpr) in buf.0[HOST_CALL_GPRS_OFFSET..].chunks_mut(8).enumerate() {
+            gpr.copy_from_slice(&(0x10 + i as u64).to_le_bytes());
+        }
+
+        let host_call = unsafe { HostCall::parse(buf.0.as_ptr() as usize) };
+        let exit = host_call.exit();
+        assert_eq!(exit.imm, 0x1234);
+        assert_eq!(exit.gprs[0], 0x10);
+        assert_eq!(exit.gprs[30], 0x10 + 30);
+
+        let mut run = Run::default();
+        run.set_host_call(&exit);
+        // x0 to x30, the whole window of the exit gprs
+        for i in 0..HOST_CALL_NR_GPRS {
+            assert_eq!(run.exit_gpr(i), 0x10 + i as u64);
+        }
+    }
+
+    #[test]
+    fn host_call_result() {
+        let mut buf = Buffer([0xff; 0x100]);
+        buf.0[..2].copy_from_slice(&0x1234u16.to_le_bytes());
+
+        let mut run = Run::default();
+        for i in 0..HOST_CALL_NR_GPRS {
+            run.set_entry_gpr(i, 0x20 + i as u64);
+        }
+
+        let host_call = unsafe { HostCall::parse_mut(buf.0.as_mut_ptr() as usize) };
+        unsafe { host_call.set_result(&run).unwrap() };
+
+        // imm and its padding are kept and the gprs at offset 8 take the result
+        assert_eq!(buf.0[..2], 0x1234u16.to_le_bytes());
+        assert!(buf.0[2..HOST_CALL_GPRS_OFFSET].iter().all(|&b| b == 0xff));
+        for (i, gpr) in buf.0[HOST_CALL_GPRS_OFFSET..].chunks(8).enumerate() {
+            assert_eq!(gpr, (0x20 + i as u64).to_le_bytes());
+        }
+        assert!(unsafe { host_call.set_gpr(HOST_CALL_NR_GPRS, 0) }.is_err());
+    }
+
+    #[test]
+    fn host_call_imm_filter() {
+        let any = ImmFilter::default();
+        assert!(any.allows(0));
+        assert!(any.allows(0xffff));
+        assert!(ImmFilter::new(&[]).unwrap().allows(0x1234));
+
+        let filter = ImmFilter::new(&[0x10, 0x20]).unwrap();
+        assert!(filter.allows(0x10));
+        assert!(filter.allows(0x20));
+        assert!(!filter.allows(0));
+        assert!(!filter.allows(0x11));
+
+        assert!(ImmFilter::new(&[1; HOST_CALL_IMM_MAX]).is_ok());
+        assert!(matches!(
+            ImmFilter::new(&[1; HOST_CALL_IMM_MAX + 1]),
+            Err(Error::RmiErrorInput)
+        ));
+    }
+
+    #[test]
+    fn buffer_checked_once() {
+        const IPA: usize = 0x8000_1000;
+        const PA: usize = 0x8800_1000;
+        let mut cache = None;
+        let mut checks = 0;
+
+        for _ in 0..1000 {
+            let pa = BufferCache::resolve(&mut cache, IPA, 0, || {
+                checks += 1;
+                Ok(PA)
+            });
+            assert_eq!(pa, Ok(PA));
+        }
+        assert_eq!(checks, 1);
+
+        // another buffer
+        let pa = BufferCache::resolve(&mut cache, IPA + HOST_CALL_ALIGN, 0, || {
+            checks += 1;
+            Ok(PA + HOST_CALL_ALIGN)
+        });
+        assert_eq!((pa, checks), (Ok(PA + HOST_CALL_ALIGN), 2));
+    }
+
+    #[test]
+    fn buffer_rechecked_on_rtt_change() {
+        const IPA: usize = 0x8000_1000;
+        let mut cache = None;
+
+        let pa = BufferCache::resolve(&mut cache, IPA, 0, || Ok(0x8800_1000));
+        assert_eq!(pa, Ok(0x8800_1000));
+
+        // remapped to another granule
+        let pa = BufferCache::resolve(&mut cache, IPA, 1, || Ok(0x9000_0000));
+        assert_eq!(pa, Ok(0x9000_0000));
+
+        // unmapped, which isn't cached
+        let pa = BufferCache::resolve(&mut cache, IPA, 2, || Err(MmError::MmNoEntry));
+        assert_eq!(pa, Err(MmError::MmNoEntry));
+        assert_eq!(cache, None);
+        let pa = BufferCache::resolve(&mut cache, IPA, 2, || Err(MmError::MmNoEntry));
+        assert_eq!(pa, Err(MmError::MmNoEntry));
+    }
+}
@@ -6,22 +6,29 @@ pub mod psci;
 
 use crate::define_interface;
 use crate::event::RsiHandle;
-use crate::granule::{is_granule_aligned, GranuleState};
+use crate::exception::extable;
+use crate::granule::{is_granule_aligned, GranuleState, GRANULE_SIZE};
 use crate::listen;
 use crate::measurement::{
-    HashContext, Measurement, MeasurementError, MEASUREMENTS_SLOT_NR, MEASUREMENTS_SLOT_RIM,
+    HashContext, Measurement, MeasurementError, MEASUREMENTS_SLOT_MAX_SIZE, MEASUREMENTS_SLOT_NR,
+    MEASUREMENTS_SLOT_RIM,
 };
+use crate::mm;
 use crate::realm::config::realm_config;
-use crate::realm::context::{get_reg, set_reg};
-use crate::realm::mm::address::GuestPhysAddr;
+use crate::realm::context::{get_gp_regs, get_reg, set_reg};
+use crate::realm::inject::{last_injected, InjectedFault};
 use crate::realm::mm::stage2_tte::invalid_ripas;
 use crate::rmi;
+use crate::rmi::call::RmiArgs;
 use crate::rmi::error::{Error, InternalError::NotExistRealm};
 use crate::rmi::realm::Rd;
 use crate::rmi::rec::run::Run;
 use crate::rmi::rec::{Rec, RmmRecAttestState};
-use crate::rmi::rtt::{is_protected_ipa, validate_ipa, RTT_PAGE_LEVEL};
-use crate::rsi::hostcall::{HostCall, HOST_CALL_NR_GPRS};
+use crate::rmi::rtt::is_protected_ipa;
+use crate::rsi::attestation::session::{TokenSession, CHALLENGE_SIZE};
+use crate::rsi::attestation::TOKEN_SIZE_UPPER_BOUND;
+use crate::rsi::hostcall::{BufferCache, HostCall, HOST_CALL_ALIGN};
+use crate::rtt::{check_buffer, ripas_range, Access};
 use crate::Monitor;
 
 define_interface! {
@@ -35,6 +42,10 @@ define_interface! {
         IPA_STATE_SET           = 0xc400_0197,
         IPA_STATE_GET           = 0xc400_0198,
         HOST_CALL               = 0xc400_0199,
+        // Implementation defined: reads back the last exception injected to the REC
+        INJECTED_FAULT_READ     = 0xc400_01a0,
+        // Implementation defined: abandons the attestation token request in progress
+        ATTEST_TOKEN_DISCARD    = 0xc400_01a1,
     }
 }
 
@@ -45,6 +56,9 @@ pub const INCOMPLETE: usize = 3;
 
 pub const VERSION: usize = (1 << 16) | 0;
 
+/// Number of pool granules holding a token while it is handed over to the realm
+const TOKEN_GRANULES: usize = TOKEN_SIZE_UPPER_BOUND.div_ceil(GRANULE_SIZE);
+
 extern crate alloc;
 
 pub fn do_host_call(
@@ -54,33 +68,35 @@ pub fn do_host_call(
     rec: &mut Rec<'_>,
     run: &mut Run,
 ) -> core::result::Result<(), Error> {
-    let vcpuid = rec.vcpuid();
-    let realmid = rec.realmid()?;
-
-    let ipa = get_reg(realmid, vcpuid, 1).unwrap_or(0x0);
+    let regs = get_gp_regs(rec.realmid()?, rec.vcpuid())?;
+    let ipa = RmiArgs::new(&regs).get(0);
+    if ipa % HOST_CALL_ALIGN != 0 {
+        return Err(Error::RmiErrorInput);
+    }
 
-    let pa = crate::realm::registry::get_realm(realmid)
-        .ok_or(Error::RmiErrorOthers(NotExistRealm))?
-        .lock()
-        .page_table
-        .lock()
-        .ipa_to_pa(
-            crate::realm::mm::address::GuestPhysAddr::from(ipa),
-            RTT_PAGE_LEVEL,
-        )
-        .ok_or(Error::RmiErrorInput)?;
+    let (pa, filter) = {
+        let rd = get_granule_if!(rec.owner()?, GranuleState::RD)?;
+        let rd = rd.content::<Rd>();
+        let size = core::mem::size_of::<HostCall>();
+        let pa =
+            BufferCache::resolve(rec.host_call_buffer_mut(), ipa, rd.rtt_generation(), || {
+                check_buffer(&rd.rtt(), ipa, size, Access::Write)
+            })?;
+        (pa, *rd.host_call_filter())
+    };
 
     unsafe {
-        let host_call = HostCall::parse_mut(pa.into());
-        if rec.host_call_pending() {
-            for i in 0..HOST_CALL_NR_GPRS {
-                let val = run.entry_gpr(i)?;
-                host_call.set_gpr(i, val)?
-            }
-            rec.set_host_call_pending(false);
+        let host_call = HostCall::parse_mut(pa);
+        if !filter.allows(host_call.imm()) {
+            warn!(
+                "Host call with a disallowed immediate: {:#X}",
+                host_call.imm()
+            );
+            set_reg(rec.realmid()?, rec.vcpuid(), 0, ERROR_INPUT)?;
+            ret[0] = rmi::SUCCESS_REC_ENTER;
+            return Ok(());
         } else {
-            run.set_imm(host_call.imm());
-            run.set_exit_reason(rmi::EXIT_HOST_CALL);
+            run.set_host_call(&host_call.exit());
             rec.set_host_call_pending(true);
         }
         trace!("HOST_CALL param: {:#X?}", host_call)
@@ -90,6 +106,40 @@ pub fn do_host_call(
     Ok(())
 }
 
+/// Completes the host call pending on `rec` when the host enters it again,
+/// by copying the result from the entry portion of `run` back into the
+/// host call buffer of the realm.
+pub fn complete_host_call(rec: &mut Rec<'_>, run: &Run) -> core::result::Result<(), Error> {
+    let realmid = rec.realmid()?;
+    let regs = get_gp_regs(realmid, rec.vcpuid())?;
+    let ipa = RmiArgs::new(&regs).get(0);
+    rec.set_host_call_pending(false);
+
+    let pa = {
+        let rd = get_granule_if!(rec.owner()?, GranuleState::RD)?;
+        let rd = rd.content::<Rd>();
+        let size = core::mem::size_of::<HostCall>();
+        BufferCache::resolve(rec.host_call_buffer_mut(), ipa, rd.rtt_generation(), || {
+            check_buffer(&rd.rtt(), ipa, size, Access::Write)
+        })
+    };
+    // the buffer may have been unmapped while the host handled the call
+    let pa = match pa {
+        Ok(pa) if ipa % HOST_CALL_ALIGN == 0 => pa,
+        _ => {
+            warn!("Host call buffer isn't mapped anymore: {:#X}", ipa);
+            return set_reg(realmid, rec.vcpuid(), 0, ERROR_INPUT);
+        }
+    };
+
+    unsafe {
+        let host_call = HostCall::parse_mut(pa);
+        host_call.set_result(run)?;
+        trace!("HOST_CALL result: {:#X?}", host_call);
+    }
+    set_reg(realmid, rec.vcpuid(), 0, SUCCESS)
+}
+
 pub trait Interface {
     fn measurement_read(
         &self,
@@ -103,13 +153,7 @@ pub trait Interface {
         index: usize,
         f: impl Fn(&mut Measurement) -> Result<(), MeasurementError>,
     ) -> Result<(), error::Error>;
-    fn get_attestation_token(
-        &self,
-        attest_pa: usize,
-        challenge: &[u8],
-        measurements: &[Measurement],
-        hash_algo: u8,
-    ) -> usize;
+    fn get_attestation_token(&self, session: &TokenSession, buf: &mut [u8]) -> usize;
 }
 
 pub fn set_event_handler(rsi: &mut RsiHandle) {
@@ -117,8 +161,20 @@ pub fn set_event_handler(rsi: &mut RsiHandle) {
         let realmid = rec.realmid()?;
         let vcpuid = rec.vcpuid();
 
-        let mut challenge: [u8; 64] = [0; 64];
+        let rd = get_granule_if!(rec.owner()?, GranuleState::RD)?;
+        let rd = rd.content::<Rd>();
+
+        let attest_ipa = get_reg(realmid, vcpuid, 1)?;
+        if !is_granule_aligned(attest_ipa)
+            || check_buffer(&rd.rtt(), attest_ipa, GRANULE_SIZE, Access::Read).is_err()
+        {
+            warn!("Wrong ipa passed {:#X}", attest_ipa);
+            set_reg(realmid, vcpuid, 0, ERROR_INPUT)?;
+            ret[0] = rmi::SUCCESS_REC_ENTER;
+            return Ok(());
+        }
 
+        let mut challenge = [0u8; CHALLENGE_SIZE];
         for i in 0..8 {
             let challenge_part = get_reg(realmid, vcpuid, i + 2)?;
             let start_idx = i * 8;
@@ -126,13 +182,18 @@ pub fn set_event_handler(rsi: &mut RsiHandle) {
             challenge[start_idx..end_idx].copy_from_slice(&challenge_part.to_le_bytes());
         }
 
-        rec.set_attest_challenge(&challenge);
+        let measurements = crate::realm::registry::get_realm(realmid)
+            .ok_or(Error::RmiErrorOthers(NotExistRealm))?
+            .lock()
+            .measurements;
+
+        // a session already in progress is discarded
+        rec.attest_session_mut()
+            .init(&challenge, &measurements, rd.hash_algo(), rd.rpv());
         rec.set_attest_state(RmmRecAttestState::AttestInProgress);
 
         set_reg(realmid, vcpuid, 0, SUCCESS)?;
-
-        // TODO: Calculate real token size
-        set_reg(realmid, vcpuid, 1, 4096)?;
+        set_reg(realmid, vcpuid, 1, TOKEN_SIZE_UPPER_BOUND)?;
 
         ret[0] = rmi::SUCCESS_REC_ENTER;
         Ok(())
@@ -140,12 +201,6 @@ pub fn set_event_handler(rsi: &mut RsiHandle) {
 
     listen!(rsi, ATTEST_TOKEN_CONTINUE, |_arg, ret, rmm, rec, _| {
         let realmid = rec.realmid()?;
-        let ipa_bits = rec.ipa_bits()?;
-
-        let hash_algo = get_granule_if!(rec.owner()?, GranuleState::RD)?
-            .content::<Rd>()
-            .hash_algo(); // Rd dropped
-
         let vcpuid = rec.vcpuid();
 
         if rec.attest_state() != RmmRecAttestState::AttestInProgress {
@@ -156,36 +211,66 @@ pub fn set_event_handler(rsi: &mut RsiHandle) {
         }
 
         let attest_ipa = get_reg(realmid, vcpuid, 1)?;
-        if validate_ipa(attest_ipa, ipa_bits).is_err() {
-            warn!("Wrong ipa passed {}", attest_ipa);
-            set_reg(realmid, vcpuid, 0, ERROR_INPUT)?;
-            ret[0] = rmi::SUCCESS_REC_ENTER;
-            return Ok(());
-        }
+        let offset = get_reg(realmid, vcpuid, 2)?;
+        let size = get_reg(realmid, vcpuid, 3)?;
 
-        let pa = crate::realm::registry::get_realm(realmid)
-            .ok_or(Error::RmiErrorOthers(NotExistRealm))?
-            .lock()
-            .page_table
-            .lock()
-            .ipa_to_pa(GuestPhysAddr::from(attest_ipa), RTT_PAGE_LEVEL)
-            .ok_or(Error::RmiErrorInput)?;
+        let rd = get_granule_if!(rec.owner()?, GranuleState::RD)?;
+        let rtt = rd.content::<Rd>().rtt();
+        let pa = match check_buffer(&rtt, attest_ipa, GRANULE_SIZE, Access::Write) {
+            Ok(pa) if is_granule_aligned(attest_ipa) && is_chunk_in_granule(offset, size) => pa,
+            _ => {
+                warn!(
+                    "Wrong buffer passed {:#X} {:#X} {:#X}",
+                    attest_ipa, offset, size
+                );
+                set_reg(realmid, vcpuid, 0, ERROR_INPUT)?;
+                ret[0] = rmi::SUCCESS_REC_ENTER;
+                return Ok(());
+            }
+        };
 
-        let measurements = crate::realm::registry::get_realm(realmid)
-            .ok_or(Error::RmiErrorOthers(NotExistRealm))?
-            .lock()
-            .measurements;
+        // The token is generated again on every call, which gives the same bytes
+        // as long as the session remains the same since the signature is deterministic.
+        let token_pa = mm::alloc::alloc(TOKEN_GRANULES)?;
+        // Safety: the granules are handed out by the pool for this call only
+        let token = unsafe {
+            core::slice::from_raw_parts_mut(token_pa as *mut u8, TOKEN_GRANULES * GRANULE_SIZE)
+        };
+        let len = rmm.rsi.get_attestation_token(rec.attest_session(), token);
+
+        let chunk = rec.attest_session().next_chunk(&token[..len], size);
+        let written = chunk.len();
+        // Safety: the chunk is within the granule mapped to the realm,
+        // which may still be taken away from it under RMM
+        let copied = unsafe { extable::copy((pa + offset) as *mut u8, chunk) };
+        token.fill(0);
+        mm::alloc::free(token_pa, TOKEN_GRANULES)?;
+        // the realm is torn down on a fault
+        copied?;
+        let done = rec.attest_session_mut().advance(written, len);
+
+        if done {
+            rec.set_attest_state(RmmRecAttestState::NoAttestInProgress);
+            set_reg(realmid, vcpuid, 0, SUCCESS)?;
+        } else {
+            set_reg(realmid, vcpuid, 0, INCOMPLETE)?;
+        }
+        set_reg(realmid, vcpuid, 1, written)?;
 
-        let attest_size = rmm.rsi.get_attestation_token(
-            pa.into(),
-            rec.attest_challenge(),
-            &measurements,
-            hash_algo,
-        );
+        ret[0] = rmi::SUCCESS_REC_ENTER;
+        Ok(())
+    });
 
-        set_reg(realmid, vcpuid, 0, SUCCESS)?;
-        set_reg(realmid, vcpuid, 1, attest_size)?;
+    listen!(rsi, ATTEST_TOKEN_DISCARD, |_arg, ret, _rmm, rec, _| {
+        let realmid = rec.realmid()?;
+        let vcpuid = rec.vcpuid();
 
+        let res = match rec.attest_state() {
+            RmmRecAttestState::AttestInProgress => SUCCESS,
+            RmmRecAttestState::NoAttestInProgress => ERROR_STATE,
+        };
+        rec.discard_attest_session();
+        set_reg(realmid, vcpuid, 0, res)?;
         ret[0] = rmi::SUCCESS_REC_ENTER;
         Ok(())
     });
@@ -222,12 +307,7 @@ pub fn set_event_handler(rsi: &mut RsiHandle) {
 
         rmm.rsi.measurement_read(realmid, index, &mut measurement)?;
         set_reg(realmid, vcpuid, 0, SUCCESS)?;
-        for (ind, chunk) in measurement
-            .as_slice()
-            .chunks_exact(core::mem::size_of::<usize>())
-            .enumerate()
-        {
-            let reg_value = usize::from_le_bytes(chunk.try_into().unwrap());
+        for (ind, reg_value) in measurement.regs().into_iter().enumerate() {
             set_reg(realmid, vcpuid, ind + 1, reg_value)?;
         }
 
@@ -248,7 +328,7 @@ pub fn set_event_handler(rsi: &mut RsiHandle) {
                 .copy_from_slice(get_reg(realmid, vcpuid, i + 3)?.to_le_bytes().as_slice());
         }
 
-        if size > buffer.len() || index == MEASUREMENTS_SLOT_RIM || index >= MEASUREMENTS_SLOT_NR {
+        if check_extend_args(index, size).is_err() {
             warn!(
                 "Wrong index or buffer size passed: idx: {}, size: {}",
                 index, size
@@ -272,13 +352,18 @@ pub fn set_event_handler(rsi: &mut RsiHandle) {
         let ipa_bits = rec.ipa_bits()?;
         let realmid = rec.realmid()?;
         let config_ipa = get_reg(realmid, vcpuid, 1)?;
-        if validate_ipa(config_ipa, ipa_bits).is_err() {
+        if !is_granule_aligned(config_ipa) || !is_protected_ipa(config_ipa, ipa_bits) {
             set_reg(realmid, vcpuid, 0, ERROR_INPUT)?;
             ret[0] = rmi::SUCCESS_REC_ENTER;
             return Ok(());
         }
 
-        realm_config(realmid, config_ipa, ipa_bits)?;
+        let rd = get_granule_if!(rec.owner()?, GranuleState::RD)?;
+        if realm_config(rd.content::<Rd>(), config_ipa).is_err() {
+            set_reg(realmid, vcpuid, 0, ERROR_INPUT)?;
+            ret[0] = rmi::SUCCESS_REC_ENTER;
+            return Ok(());
+        }
 
         if set_reg(realmid, vcpuid, 0, SUCCESS).is_err() {
             warn!(
@@ -290,13 +375,40 @@ pub fn set_event_handler(rsi: &mut RsiHandle) {
         Ok(())
     });
 
+    listen!(rsi, INJECTED_FAULT_READ, |_arg, ret, _rmm, rec, _| {
+        let vcpuid = rec.vcpuid();
+        let realmid = rec.realmid()?;
+        let ipa = get_reg(realmid, vcpuid, 1)?;
+
+        let res = match last_injected(realmid, vcpuid)? {
+            Some(fault) if ipa % core::mem::align_of::<InjectedFault>() == 0 => {
+                let rd = get_granule_if!(rec.owner()?, GranuleState::RD)?;
+                let rd = rd.content::<Rd>();
+                let size = core::mem::size_of::<InjectedFault>();
+                match check_buffer(&rd.rtt(), ipa, size, Access::Write) {
+                    Ok(pa) => {
+                        unsafe { fault.write(pa) };
+                        SUCCESS
+                    }
+                    Err(_) => ERROR_INPUT,
+                }
+            }
+            Some(_) => ERROR_INPUT,
+            // nothing has been injected yet
+            None => ERROR_STATE,
+        };
+        set_reg(realmid, vcpuid, 0, res)?;
+        ret[0] = rmi::SUCCESS_REC_ENTER;
+        Ok(())
+    });
+
     listen!(rsi, IPA_STATE_GET, |_arg, ret, _rmm, rec, _| {
         let vcpuid = rec.vcpuid();
         let ipa_bits = rec.ipa_bits()?;
         let realmid = rec.realmid()?;
 
         let ipa_page = get_reg(realmid, vcpuid, 1)?;
-        if validate_ipa(ipa_page, ipa_bits).is_err() {
+        if !is_granule_aligned(ipa_page) || !is_protected_ipa(ipa_page, ipa_bits) {
             if set_reg(realmid, vcpuid, 0, ERROR_INPUT).is_err() {
                 warn!(
                     "Unable to set register 0. realmid: {:?} vcpuid: {:?}",
@@ -307,7 +419,16 @@ pub fn set_event_handler(rsi: &mut RsiHandle) {
             return Ok(());
         }
 
-        let ripas = crate::rtt::get_ripas(realmid, ipa_page, RTT_PAGE_LEVEL)? as usize;
+        let rd = get_granule_if!(rec.owner()?, GranuleState::RD)?;
+        let rtt = rd.content::<Rd>().rtt();
+        let ripas = match ripas_range(&rtt, ipa_page, ipa_page + GRANULE_SIZE) {
+            Ok((ripas, _)) => ripas as usize,
+            Err(_) => {
+                set_reg(realmid, vcpuid, 0, ERROR_INPUT)?;
+                ret[0] = rmi::SUCCESS_REC_ENTER;
+                return Ok(());
+            }
+        };
 
         debug!(
             "RSI_IPA_STATE_GET: ipa_page: {:X} ripas: {:X}",
@@ -357,14 +478,27 @@ pub fn set_event_handler(rsi: &mut RsiHandle) {
             return Ok(());
         }
 
-        // TODO: check ipa_state value, ipa address granularity
+        // skip the leading part of the range which already has the requested ripas
+        let rd = get_granule_if!(rec.owner()?, GranuleState::RD)?;
+        let rtt = rd.content::<Rd>().rtt();
+        let ipa_change = match ripas_range(&rtt, ipa_start, ipa_end) {
+            Ok((ripas, change)) if ripas == ipa_state as u64 => change,
+            _ => ipa_start,
+        };
+        if ipa_change == ipa_end {
+            set_reg(realmid, vcpuid, 0, SUCCESS)?;
+            set_reg(realmid, vcpuid, 1, ipa_end)?;
+            ret[0] = rmi::SUCCESS_REC_ENTER;
+            return Ok(());
+        }
+
         unsafe {
-            run.set_exit_reason(rmi::EXIT_RIPAS_CHANGE);
-            run.set_ripas(ipa_start as u64, ipa_size as u64, ipa_state);
+            run.set_exit_reason(rmi::ExitReason::RipasChange);
+            run.set_ripas(ipa_change as u64, (ipa_end - ipa_change) as u64, ipa_state);
             rec.set_ripas(
                 ipa_start as u64,
                 ipa_end as u64,
-                ipa_start as u64,
+                ipa_change as u64,
                 ipa_state,
             );
             ret[0] = rmi::SUCCESS;
@@ -380,9 +514,46 @@ pub fn set_event_handler(rsi: &mut RsiHandle) {
     });
 }
 
+/// Only the REMs can be extended by the realm, with up to 64 bytes at once.
+fn check_extend_args(index: usize, size: usize) -> Result<(), Error> {
+    if index == MEASUREMENTS_SLOT_RIM
+        || index >= MEASUREMENTS_SLOT_NR
+        || size > MEASUREMENTS_SLOT_MAX_SIZE
+    {
+        return Err(Error::RmiErrorInput);
+    }
+    Ok(())
+}
+
+fn is_chunk_in_granule(offset: usize, size: usize) -> bool {
+    offset
+        .checked_add(size)
+        .map_or(false, |end| end <= GRANULE_SIZE)
+}
+
 fn is_ripas_valid(ripas: u8) -> bool {
     match ripas as u64 {
         invalid_ripas::EMPTY | invalid_ripas::RAM => true,
         _ => false,
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn measurement_extend_args() {
+        assert!(check_extend_args(1, 64).is_ok());
+        assert!(check_extend_args(4, 0).is_ok());
+        assert!(matches!(
+            check_extend_args(MEASUREMENTS_SLOT_RIM, 8),
+            Err(Error::RmiErrorInput)
+        ));
+        assert!(matches!(check_extend_args(5, 8), Err(Error::RmiErrorInput)));
+        assert!(matches!(
+            check_extend_args(1, 65),
+            Err(Error::RmiErrorInput)
+        ));
+    }
+}
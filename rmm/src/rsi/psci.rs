@@ -1,15 +1,22 @@
+use crate::cpu::features::CpuFeatures;
 use crate::event::RsiHandle;
 use crate::granule::GranuleState;
 use crate::listen;
 use crate::realm::context::{get_reg, set_reg};
+use crate::realm::registry::get_realm;
 use crate::rmi;
+use crate::rmi::error::Error;
 use crate::rmi::realm::{rd::State, Rd};
+use crate::rmi::rec::mpidr;
 use crate::rmi::rec::run::Run;
 use crate::rmi::rec::Rec;
 use crate::Monitor;
 
 pub const SMCCC_VERSION: usize = 0x8000_0000;
 pub const SMCCC_ARCH_FEATURES: usize = 0x8000_0001;
+pub const SMCCC_ARCH_WORKAROUND_1: usize = 0x8000_8000;
+pub const SMCCC_ARCH_WORKAROUND_2: usize = 0x8000_7fff;
+pub const SMCCC_ARCH_WORKAROUND_3: usize = 0x8000_3fff;
 
 pub const PSCI_VERSION: usize = 0x8400_0000;
 
@@ -48,13 +55,13 @@ impl SMC64 {
     //pub const SYSTEM_RESET2: usize = 0xC400_0012;
 }
 
-struct PsciReturn;
+pub struct PsciReturn;
 impl PsciReturn {
-    const SUCCESS: usize = 0;
-    const NOT_SUPPORTED: usize = !0;
-    //const INVALID_PARAMS: usize = !1;
+    pub const SUCCESS: usize = 0;
+    pub const NOT_SUPPORTED: usize = !0;
+    pub const INVALID_PARAMS: usize = !1;
     //const DENIED: usize = !2;
-    //const ALREADY_ON: usize = !3;
+    pub const ALREADY_ON: usize = !3;
     //const ON_PENDING: usize = !4;
     //const INTERNAL_FAILURE: usize = !5;
     //const NOT_PRESENT: usize = !6;
@@ -65,11 +72,138 @@ impl PsciReturn {
 const SMCCC_MAJOR_VERSION: usize = 1;
 const SMCCC_MINOR_VERSION: usize = 2;
 
+// Values returned by SMCCC_ARCH_FEATURES for the workarounds
+const ARCH_WORKAROUND_UNAFFECTED: usize = 1;
+const ARCH_WORKAROUND_NOT_REQUIRED: usize = !1;
+
 const PSCI_MAJOR_VERSION: usize = 1;
 const PSCI_MINOR_VERSION: usize = 1;
 
+// Values returned by AFFINITY_INFO
+const AFFINITY_INFO_ON: usize = 0;
+const AFFINITY_INFO_OFF: usize = 1;
+
+/// Number of realm registers (x0-x3) which are forwarded to the host on a PSCI exit
+const PSCI_EXIT_GPRS: usize = 4;
+
 extern crate alloc;
 
+/// PSCI functions which need the host to take an action on behalf of the realm
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PsciFunction {
+    CpuOn,
+    CpuOff,
+    AffinityInfo,
+    SystemOff,
+    SystemReset,
+}
+
+impl PsciFunction {
+    pub fn decode(fid: usize) -> Option<Self> {
+        match fid {
+            SMC32::CPU_ON | SMC64::CPU_ON => Some(Self::CpuOn),
+            SMC32::CPU_OFF => Some(Self::CpuOff),
+            SMC32::AFFINITY_INFO | SMC64::AFFINITY_INFO => Some(Self::AffinityInfo),
+            SMC32::SYSTEM_OFF => Some(Self::SystemOff),
+            SMC32::SYSTEM_RESET => Some(Self::SystemReset),
+            _ => None,
+        }
+    }
+
+    /// Whether the host has to report the result through RMI_PSCI_COMPLETE
+    pub fn needs_completion(&self) -> bool {
+        matches!(self, Self::CpuOn | Self::AffinityInfo)
+    }
+}
+
+/// PSCI request waiting on the calling REC until the host completes it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PsciRequest {
+    pub function: PsciFunction,
+    pub target_mpidr: u64,
+    pub entry_point: u64,
+    pub context_id: u64,
+}
+
+impl PsciRequest {
+    pub fn new(function: PsciFunction, args: &[usize; PSCI_EXIT_GPRS]) -> Self {
+        Self {
+            function,
+            target_mpidr: args[1] as u64,
+            entry_point: args[2] as u64,
+            context_id: args[3] as u64,
+        }
+    }
+}
+
+/// Decides the result of the PSCI request pending on the caller once the host
+/// completes it with the given target REC.
+/// `target_runnable` is None if the realm has no REC with `target_mpidr`.
+/// Returns the PSCI status to be delivered to the caller and whether the target
+/// has to be started.
+pub fn complete(
+    req: Option<&PsciRequest>,
+    target_mpidr: u64,
+    target_runnable: Option<bool>,
+) -> Result<(usize, bool), Error> {
+    let req = match req {
+        Some(req) => req,
+        None => return Err(Error::RmiErrorInput),
+    };
+
+    if req.target_mpidr != target_mpidr {
+        return Err(Error::RmiErrorInput);
+    }
+
+    let target_runnable = match target_runnable {
+        Some(runnable) => runnable,
+        None => return Ok((PsciReturn::INVALID_PARAMS, false)),
+    };
+
+    let ret = match req.function {
+        PsciFunction::CpuOn if target_runnable => (PsciReturn::ALREADY_ON, false),
+        PsciFunction::CpuOn => (PsciReturn::SUCCESS, true),
+        PsciFunction::AffinityInfo if target_runnable => (AFFINITY_INFO_ON, false),
+        PsciFunction::AffinityInfo => (AFFINITY_INFO_OFF, false),
+        _ => return Err(Error::RmiErrorInput),
+    };
+    Ok(ret)
+}
+
+/// Arranges the function ID and its arguments (x0-x3 of the realm)
+/// in the order expected by the host in the exit gprs on a PSCI exit.
+fn exit_gprs(fid: usize, args: &[usize; PSCI_EXIT_GPRS]) -> [u64; PSCI_EXIT_GPRS] {
+    let mut gprs = [0; PSCI_EXIT_GPRS];
+    gprs[0] = fid as u64;
+    for (gpr, arg) in gprs.iter_mut().zip(args.iter()).skip(1) {
+        *gpr = *arg as u64;
+    }
+    gprs
+}
+
+fn read_args(rec: &Rec<'_>) -> Result<[usize; PSCI_EXIT_GPRS], Error> {
+    let realmid = rec.realmid()?;
+    let mut args = [0; PSCI_EXIT_GPRS];
+    for (idx, arg) in args.iter_mut().enumerate() {
+        *arg = get_reg(realmid, rec.vcpuid(), idx)?;
+    }
+    Ok(args)
+}
+
+/// Fills in `run` to exit to the host with the PSCI request made by the realm.
+fn forward_to_host(args: &[usize; PSCI_EXIT_GPRS], run: &mut Run) -> Result<(), Error> {
+    unsafe {
+        run.set_exit_reason(rmi::ExitReason::Psci);
+        run.set_esr(0);
+        run.set_far(0);
+        run.set_hpfar(0);
+        for (idx, gpr) in exit_gprs(args[0], args).iter().enumerate() {
+            run.set_gpr(idx, *gpr)?;
+        }
+    }
+    Ok(())
+}
+
 pub fn set_event_handler(rsi: &mut RsiHandle) {
     let dummy =
         |_arg: &[usize], ret: &mut [usize], _rmm: &Monitor, rec: &mut Rec<'_>, _run: &mut Run| {
@@ -86,36 +220,47 @@ pub fn set_event_handler(rsi: &mut RsiHandle) {
             Ok(())
         };
 
-    listen!(rsi, PSCI_VERSION, |_arg, ret, _rmm, rec, _run| {
-        let vcpuid = rec.vcpuid();
-        let realmid = rec.realmid()?;
+    let forward =
+        |_arg: &[usize], ret: &mut [usize], _rmm: &Monitor, rec: &mut Rec<'_>, run: &mut Run| {
+            let args = read_args(rec)?;
+            let function = PsciFunction::decode(args[0]).ok_or(Error::RmiErrorInput)?;
 
-        if set_reg(realmid, vcpuid, 0, psci_version()).is_err() {
-            warn!(
-                "Unable to set register 0. realmid: {:?} vcpuid: {:?}",
-                realmid, vcpuid
-            );
-        }
-        ret[0] = rmi::SUCCESS_REC_ENTER;
-        Ok(())
-    });
+            match function {
+                PsciFunction::CpuOn | PsciFunction::AffinityInfo => {
+                    let known = get_realm(rec.realmid()?)
+                        .map(|realm| realm.lock().find_rec_by_mpidr(args[1] as u64).is_some())
+                        .unwrap_or(false);
+                    if !mpidr::validate(args[1] as u64) || !known {
+                        set_reg(rec.realmid()?, rec.vcpuid(), 0, PsciReturn::INVALID_PARAMS)?;
+                        ret[0] = rmi::SUCCESS_REC_ENTER;
+                        return Ok(());
+                    }
+                    rec.set_psci_pending(Some(PsciRequest::new(function, &args)));
+                }
+                PsciFunction::CpuOff => {
+                    rec.set_runnable(false);
+                }
+                PsciFunction::SystemOff | PsciFunction::SystemReset => {
+                    let mut rd = get_granule_if!(rec.owner()?, GranuleState::RD)?;
+                    let rd = rd.content_mut::<Rd>();
+                    rd.set_state(State::SystemOff);
+                }
+            }
+
+            forward_to_host(&args, run)?;
+            ret[0] = rmi::SUCCESS;
+            Ok(())
+        };
 
     listen!(rsi, SMC32::CPU_SUSPEND, dummy);
     listen!(rsi, SMC64::CPU_SUSPEND, dummy);
-    listen!(rsi, SMC32::CPU_OFF, dummy);
-    listen!(rsi, SMC32::CPU_ON, dummy);
-    listen!(rsi, SMC64::CPU_ON, dummy);
-    listen!(rsi, SMC32::AFFINITY_INFO, dummy);
-    listen!(rsi, SMC64::AFFINITY_INFO, dummy);
-    listen!(rsi, SMC32::SYSTEM_RESET, dummy);
-
-    listen!(rsi, SMC32::SYSTEM_OFF, |_arg, ret, _rmm, rec, _run| {
-        let mut rd = get_granule_if!(rec.owner()?, GranuleState::RD)?;
-        let rd = rd.content_mut::<Rd>();
-        rd.set_state(State::SystemOff);
-        ret[0] = rmi::SUCCESS;
-        Ok(())
-    });
+    listen!(rsi, SMC32::CPU_OFF, forward);
+    listen!(rsi, SMC32::CPU_ON, forward);
+    listen!(rsi, SMC64::CPU_ON, forward);
+    listen!(rsi, SMC32::AFFINITY_INFO, forward);
+    listen!(rsi, SMC64::AFFINITY_INFO, forward);
+    listen!(rsi, SMC32::SYSTEM_RESET, forward);
+    listen!(rsi, SMC32::SYSTEM_OFF, forward);
 
     listen!(rsi, SMC32::FEATURES, |_arg, ret, _rmm, rec, _run| {
         let vcpuid = rec.vcpuid();
@@ -145,26 +290,193 @@ pub fn set_event_handler(rsi: &mut RsiHandle) {
         ret[0] = rmi::SUCCESS_REC_ENTER;
         Ok(())
     });
-
-    listen!(rsi, SMCCC_VERSION, |_arg, ret, _rmm, rec, _run| {
-        let vcpuid = rec.vcpuid();
-        let realmid = rec.realmid()?;
-
-        if set_reg(realmid, vcpuid, 0, smccc_version()).is_err() {
-            warn!(
-                "Unable to set register 0. realmid: {:?} vcpuid: {:?}",
-                realmid, vcpuid
-            );
-        }
-        ret[0] = rmi::SUCCESS_REC_ENTER;
-        Ok(())
-    });
 }
 
-fn psci_version() -> usize {
+/// PSCI version (major << 16 | minor) answered to the realm
+pub fn psci_version() -> usize {
     (PSCI_MAJOR_VERSION << 16) | PSCI_MINOR_VERSION
 }
 
-fn smccc_version() -> usize {
+/// SMCCC version (major << 16 | minor) answered to the realm
+pub fn smccc_version() -> usize {
     (SMCCC_MAJOR_VERSION << 16) | SMCCC_MINOR_VERSION
 }
+
+/// Arm Architecture Calls, either of SMC32 or SMC64
+pub fn is_arch_call(fid: usize) -> bool {
+    (fid & !(1 << 30)) & 0xffff_0000 == SMCCC_VERSION
+}
+
+/// SMCCC_ARCH_FEATURES answered to the realm on PEs with `features`.
+/// RMM implements none of the workarounds, so they are only reported
+/// as not required on the PEs which aren't affected.
+pub fn arch_features(fid: usize, features: &CpuFeatures) -> usize {
+    match fid {
+        SMCCC_VERSION | SMCCC_ARCH_FEATURES => PsciReturn::SUCCESS,
+        SMCCC_ARCH_WORKAROUND_1 if features.csv2() >= 1 => ARCH_WORKAROUND_UNAFFECTED,
+        SMCCC_ARCH_WORKAROUND_3 if features.csv2() >= 3 => ARCH_WORKAROUND_UNAFFECTED,
+        SMCCC_ARCH_WORKAROUND_2 if features.ssbs() => ARCH_WORKAROUND_NOT_REQUIRED,
+        _ => PsciReturn::NOT_SUPPORTED,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn psci_function_decode() {
+        assert_eq!(
+            PsciFunction::decode(SMC32::CPU_ON),
+            Some(PsciFunction::CpuOn)
+        );
+        assert_eq!(
+            PsciFunction::decode(SMC64::CPU_ON),
+            Some(PsciFunction::CpuOn)
+        );
+        assert_eq!(
+            PsciFunction::decode(SMC32::CPU_OFF),
+            Some(PsciFunction::CpuOff)
+        );
+        assert_eq!(
+            PsciFunction::decode(SMC64::AFFINITY_INFO),
+            Some(PsciFunction::AffinityInfo)
+        );
+        assert_eq!(
+            PsciFunction::decode(SMC32::SYSTEM_OFF),
+            Some(PsciFunction::SystemOff)
+        );
+        assert_eq!(
+            PsciFunction::decode(SMC32::SYSTEM_RESET),
+            Some(PsciFunction::SystemReset)
+        );
+        assert_eq!(PsciFunction::decode(PSCI_VERSION), None);
+        assert_eq!(PsciFunction::decode(SMC32::FEATURES), None);
+
+        assert!(PsciFunction::CpuOn.needs_completion());
+        assert!(!PsciFunction::SystemOff.needs_completion());
+    }
+
+    #[test]
+    fn psci_args_marshalling() {
+        let args = [SMC64::CPU_ON, 0x1, 0x8000_0000, 0xdead];
+        assert_eq!(
+            exit_gprs(args[0], &args),
+            [SMC64::CPU_ON as u64, 0x1, 0x8000_0000, 0xdead]
+        );
+
+        let req = PsciRequest::new(PsciFunction::CpuOn, &args);
+        assert_eq!(req.target_mpidr, 0x1);
+        assert_eq!(req.entry_point, 0x8000_0000);
+        assert_eq!(req.context_id, 0xdead);
+    }
+
+    #[test]
+    fn psci_complete() {
+        let args = [SMC64::CPU_ON, 0x1, 0x8000_0000, 0xdead];
+        let cpu_on = PsciRequest::new(PsciFunction::CpuOn, &args);
+        let affinity_info = PsciRequest::new(PsciFunction::AffinityInfo, &args);
+
+        assert!(matches!(
+            complete(None, 0x1, Some(false)),
+            Err(Error::RmiErrorInput)
+        ));
+        assert!(matches!(
+            complete(Some(&cpu_on), 0x2, Some(false)),
+            Err(Error::RmiErrorInput)
+        ));
+
+        assert_eq!(
+            complete(Some(&cpu_on), 0x1, Some(false)).unwrap(),
+            (PsciReturn::SUCCESS, true)
+        );
+        assert_eq!(
+            complete(Some(&cpu_on), 0x1, Some(true)).unwrap(),
+            (PsciReturn::ALREADY_ON, false)
+        );
+        assert_eq!(
+            complete(Some(&affinity_info), 0x1, Some(true)).unwrap(),
+            (AFFINITY_INFO_ON, false)
+        );
+        assert_eq!(
+            complete(Some(&affinity_info), 0x1, Some(false)).unwrap(),
+            (AFFINITY_INFO_OFF, false)
+        );
+
+        // no REC of the realm answers to the requested MPIDR
+        assert_eq!(
+            complete(Some(&cpu_on), 0x1, None).unwrap(),
+            (PsciReturn::INVALID_PARAMS, false)
+        );
+        assert_eq!(
+            complete(Some(&affinity_info), 0x1, None).unwrap(),
+            (PsciReturn::INVALID_PARAMS, false)
+        );
+
+        let error: usize = complete(None, 0x1, Some(false)).unwrap_err().into();
+        assert_eq!(error, rmi::ERROR_INPUT);
+    }
+
+    #[test]
+    fn arch_feature_probes() {
+        use crate::realm::feature::IdRegs;
+
+        // CSV2 at 1, no SSBS
+        let csv2 = CpuFeatures::parse(&IdRegs {
+            pfr0: 0x0100_0000_0000_0011,
+            ..Default::default()
+        });
+        // CSV2_3 and SSBS
+        let csv2_3 = CpuFeatures::parse(&IdRegs {
+            pfr0: 0x0300_0000_0000_0011,
+            pfr1: 0x10,
+            ..Default::default()
+        });
+        let none = CpuFeatures::parse(&IdRegs {
+            pfr0: 0x11,
+            ..Default::default()
+        });
+
+        for features in [&csv2, &csv2_3, &none] {
+            assert_eq!(arch_features(SMCCC_VERSION, features), PsciReturn::SUCCESS);
+            assert_eq!(
+                arch_features(SMCCC_ARCH_FEATURES, features),
+                PsciReturn::SUCCESS
+            );
+            assert_eq!(
+                arch_features(0x8000_0002, features),
+                PsciReturn::NOT_SUPPORTED
+            );
+            assert_eq!(
+                arch_features(PSCI_VERSION, features),
+                PsciReturn::NOT_SUPPORTED
+            );
+        }
+
+        assert_eq!(arch_features(SMCCC_ARCH_WORKAROUND_1, &csv2), 1);
+        assert_eq!(
+            arch_features(SMCCC_ARCH_WORKAROUND_2, &csv2),
+            PsciReturn::NOT_SUPPORTED
+        );
+        assert_eq!(
+            arch_features(SMCCC_ARCH_WORKAROUND_3, &csv2),
+            PsciReturn::NOT_SUPPORTED
+        );
+
+        assert_eq!(arch_features(SMCCC_ARCH_WORKAROUND_1, &csv2_3), 1);
+        assert_eq!(arch_features(SMCCC_ARCH_WORKAROUND_2, &csv2_3), !1);
+        assert_eq!(arch_features(SMCCC_ARCH_WORKAROUND_3, &csv2_3), 1);
+
+        for fid in [
+            SMCCC_ARCH_WORKAROUND_1,
+            SMCCC_ARCH_WORKAROUND_2,
+            SMCCC_ARCH_WORKAROUND_3,
+        ] {
+            assert_eq!(arch_features(fid, &none), PsciReturn::NOT_SUPPORTED);
+        }
+
+        assert!(is_arch_call(SMCCC_ARCH_WORKAROUND_1));
+        assert!(is_arch_call(0xc000_7fff));
+        assert!(!is_arch_call(PSCI_VERSION));
+    }
+}
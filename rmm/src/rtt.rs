@@ -1,172 +1,263 @@
-use crate::granule::GRANULE_SIZE;
-use crate::granule::{set_granule, GranuleState};
+use crate::granule::entry::Inner;
+use crate::granule::{check_granule_owner, is_granule_aligned, set_granule};
+use crate::granule::{GranuleState, GRANULE_SIZE};
+use crate::mm::rtt::{level_size, table_size, Rtt, RttEntryState, RttWalk, S2Prot};
+use crate::mm::tlb;
 use crate::mm::translation::PageTable;
-use crate::realm::mm::address::GuestPhysAddr;
-use crate::realm::mm::page_table::pte::attribute;
-use crate::realm::mm::page_table::pte::{permission, shareable};
+use crate::realm::mm::page_table::pte::permission;
 use crate::realm::mm::stage2_tte::{desc_type, invalid_hipas, invalid_ripas};
+use crate::realm::mm::stage2_tte::{oa_mask, pack_oa, unpack_oa};
 use crate::realm::mm::stage2_tte::{RttPage, INVALID_UNPROTECTED, S2TTE};
-use crate::realm::registry::get_realm;
 use crate::rmi::error::Error;
-use crate::rmi::error::InternalError::*;
-use crate::rmi::realm::Rd;
-use crate::rmi::rtt::RTT_PAGE_LEVEL;
-use crate::rmi::rtt::S2TTE_STRIDE;
+use crate::rmi::realm::{rd::State, Rd};
+use crate::rmi::rtt::{is_protected_ipa, RTT_MIN_BLOCK_LEVEL, RTT_PAGE_LEVEL};
 use crate::rmi::rtt_entry_state;
 use armv9a::bits_in_reg;
+use vmsa::error::Error as MmError;
 
-pub fn create(id: usize, rtt_addr: usize, ipa: usize, level: usize) -> Result<(), Error> {
+pub fn create(rd: &Rd, rtt_addr: usize, ipa: usize, level: usize) -> Result<(), Error> {
+    let mut rtt = rd.rtt();
     let mut rtt_granule = get_granule_if!(rtt_addr, GranuleState::Delegated)?;
+    let parent_level = level.checked_sub(1).ok_or(MmError::MmInvalidLevel)?;
+    let parent_pa = rtt.walk(ipa, parent_level)?.table();
+    let mut parent_granule = get_granule_if!(parent_pa, GranuleState::RTT)?;
+
     let s2tt = rtt_granule.content_mut::<RttPage>();
+    let live = link_table(&mut rtt, s2tt.entries_mut(), rtt_addr, ipa, level)?;
 
-    let (parent_s2tte, last_level) = S2TTE::get_s2tte(id, ipa, level - 1, Error::RmiErrorInput)?;
+    set_granule(&mut rtt_granule, GranuleState::RTT)?;
+    rtt_granule.add_refcount(live)?;
+    parent_granule.inc_refcount()?;
 
-    if last_level != level - 1 {
-        return Err(Error::RmiErrorRtt(last_level));
+    // The below is added to avoid a fault regarding the RTT entry
+    PageTable::get_ref().map(rtt_addr, true);
+
+    Ok(())
+}
+
+/// Links `table` located at `table_pa` into the parent entry of `ipa` at `level - 1`.
+/// The entries of the new table take over the state of the parent entry.
+/// Returns the number of entries in the new table which refer to other granules.
+fn link_table(
+    rtt: &mut Rtt,
+    table: &mut [u64],
+    table_pa: usize,
+    ipa: usize,
+    level: usize,
+) -> Result<usize, MmError> {
+    if !is_granule_aligned(table_pa) {
+        return Err(MmError::MmInvalidAddr);
+    }
+    let parent_level = level.checked_sub(1).ok_or(MmError::MmInvalidLevel)?;
+    let parent = rtt.walk(ipa, parent_level)?;
+    if parent.level != parent_level {
+        return Err(MmError::MmRttLevel(parent.level));
     }
 
-    let s2tt_len = s2tt.len();
-    if parent_s2tte.is_unassigned() {
-        if parent_s2tte.is_invalid_ripas() {
-            panic!("invalid ripas");
+    let live = match parent.state() {
+        RttEntryState::Unassigned => {
+            let new_s2tte = bits_in_reg(S2TTE::INVALID_HIPAS, invalid_hipas::UNASSIGNED)
+                | bits_in_reg(S2TTE::INVALID_RIPAS, parent.desc.get_ripas());
+            table.fill(new_s2tte);
+            0
         }
-        let ripas = parent_s2tte.get_ripas();
-        let mut new_s2tte = bits_in_reg(S2TTE::INVALID_HIPAS, invalid_hipas::UNASSIGNED);
-        if ripas == invalid_ripas::EMPTY {
-            new_s2tte |= bits_in_reg(S2TTE::INVALID_RIPAS, invalid_ripas::EMPTY);
-        } else if ripas == invalid_ripas::RAM {
-            new_s2tte |= bits_in_reg(S2TTE::INVALID_RIPAS, invalid_ripas::RAM);
-        } else {
-            panic!("Unexpected ripas:{}", ripas);
+        RttEntryState::Destroyed => {
+            table.fill(bits_in_reg(S2TTE::INVALID_HIPAS, invalid_hipas::DESTROYED));
+            0
         }
-
-        for i in 0..s2tt_len {
-            if let Some(elem) = s2tt.get_mut(i) {
-                *elem = new_s2tte;
+        RttEntryState::Assigned => {
+            let pa = parent.output_address();
+            let flags = bits_in_reg(S2TTE::INVALID_HIPAS, invalid_hipas::ASSIGNED)
+                | bits_in_reg(S2TTE::INVALID_RIPAS, invalid_ripas::EMPTY);
+            for (i, entry) in table.iter_mut().enumerate() {
+                *entry = pack_oa((pa + i * level_size(level)) as u64, rtt.lpa2()) | flags;
             }
+            table.len()
         }
-    } else if parent_s2tte.is_destroyed() {
-        let new_s2tte = bits_in_reg(S2TTE::INVALID_HIPAS, invalid_hipas::DESTROYED);
-        for i in 0..s2tt_len {
-            if let Some(elem) = s2tt.get_mut(i) {
-                *elem = new_s2tte;
-            }
-        }
-    } else if parent_s2tte.is_assigned() {
-        let mut pa: usize = parent_s2tte
-            .address(level - 1)
-            .ok_or(Error::RmiErrorRtt(0))?
-            .into(); //XXX: check this again
-        let map_size = match level {
-            3 => GRANULE_SIZE, // 4096
-            2 => GRANULE_SIZE << S2TTE_STRIDE,
-            1 => GRANULE_SIZE << S2TTE_STRIDE * 2,
-            0 => GRANULE_SIZE << S2TTE_STRIDE * 3,
-            _ => unreachable!(),
-        };
-        let mut flags = bits_in_reg(S2TTE::INVALID_HIPAS, invalid_hipas::ASSIGNED);
-        flags |= bits_in_reg(S2TTE::INVALID_RIPAS, invalid_ripas::EMPTY);
-        let mut new_s2tte = pa as u64 | flags;
-        for i in 0..s2tt_len {
-            if let Some(elem) = s2tt.get_mut(i) {
-                *elem = new_s2tte;
+        RttEntryState::Valid => {
+            let pa = parent.output_address();
+            let desc_type = match level {
+                RTT_PAGE_LEVEL => desc_type::L3_PAGE,
+                _ => desc_type::L012_BLOCK,
+            };
+            let attrs = parent.desc.get() & !(oa_mask(rtt.lpa2()) | S2TTE::DESC_TYPE)
+                | bits_in_reg(S2TTE::DESC_TYPE, desc_type);
+            for (i, entry) in table.iter_mut().enumerate() {
+                *entry = pack_oa((pa + i * level_size(level)) as u64, rtt.lpa2()) | attrs;
             }
-            pa += map_size;
-            new_s2tte = pa as u64 | flags;
+            table.len()
         }
-    } else if parent_s2tte.is_valid(level - 1, false) {
-        unimplemented!();
-    } else if parent_s2tte.is_valid(level - 1, true) {
-        unimplemented!();
-    } else if parent_s2tte.is_table(level - 1) {
-        return Err(Error::RmiErrorRtt(level - 1));
-    } else {
-        panic!("Unexpected s2tte value:{:X}", parent_s2tte.get());
-    }
-
-    set_granule(&mut rtt_granule, GranuleState::RTT)?;
-
-    let parent_s2tte = rtt_addr as u64 | bits_in_reg(S2TTE::DESC_TYPE, desc_type::L012_TABLE);
-    get_realm(id)
-        .ok_or(Error::RmiErrorOthers(NotExistRealm))?
-        .lock()
-        .page_table
-        .lock()
-        .ipa_to_pte_set(GuestPhysAddr::from(ipa), level - 1, parent_s2tte)?;
-
-    // The below is added to avoid a fault regarding the RTT entry
-    PageTable::get_ref().map(rtt_addr, true);
-
-    Ok(())
+        RttEntryState::ValidNs => return Err(MmError::MmUnimplemented),
+        RttEntryState::Table | RttEntryState::Unknown => return Err(MmError::MmStateError),
+    };
+
+    rtt.set(
+        ipa,
+        parent_level,
+        table_pa as u64 | bits_in_reg(S2TTE::DESC_TYPE, desc_type::L012_TABLE),
+    )?;
+    Ok(live)
 }
 
 pub fn destroy(rd: &Rd, rtt_addr: usize, ipa: usize, level: usize) -> Result<(), Error> {
-    let id = rd.id();
-    let (parent_s2tte, last_level) = S2TTE::get_s2tte(id, ipa, level - 1, Error::RmiErrorRtt(0))?;
+    let mut rtt = rd.rtt();
+    let parent = find_table(&rtt, rtt_addr, ipa, level)?;
+    let mut parent_granule = get_granule_if!(parent.table(), GranuleState::RTT)?;
+    let mut rtt_granule = get_granule_if!(rtt_addr, GranuleState::RTT)?;
+
+    destroy_table(
+        &mut rtt,
+        &mut parent_granule,
+        &rtt_granule,
+        rtt_addr,
+        ipa,
+        level,
+        rd.addr_in_par(ipa),
+    )?;
+    tlb::invalidate_ipa(rd.vttbr(), ipa);
+
+    set_granule(&mut rtt_granule, GranuleState::Delegated)?;
+    Ok(())
+}
 
-    if last_level != level - 1 {
-        return Err(Error::RmiErrorRtt(last_level));
+/// Finds the parent entry linking the table at `rtt_addr` which translates `ipa` at `level`.
+fn find_table(rtt: &Rtt, rtt_addr: usize, ipa: usize, level: usize) -> Result<RttWalk, MmError> {
+    let parent_level = level.checked_sub(1).ok_or(MmError::MmInvalidLevel)?;
+    let parent = rtt.walk(ipa, parent_level)?;
+    if parent.level != parent_level || parent.state() != RttEntryState::Table {
+        return Err(MmError::MmRttLevel(parent.level));
     }
-
-    if !parent_s2tte.is_table(level - 1) {
-        return Err(Error::RmiErrorRtt(level - 1));
+    if parent.output_address() != rtt_addr {
+        return Err(MmError::MmInvalidAddr);
     }
+    Ok(parent)
+}
 
-    let pa_table = parent_s2tte
-        .address(RTT_PAGE_LEVEL)
-        .ok_or(Error::RmiErrorInput)?
-        .try_into()
-        .or(Err(Error::RmiErrorInput))?;
-    if rtt_addr != pa_table {
-        return Err(Error::RmiErrorInput);
+/// Unlinks the table which has no live entries left from its parent.
+/// The parent entry becomes destroyed for a protected `ipa`.
+fn destroy_table(
+    rtt: &mut Rtt,
+    parent_granule: &mut Inner,
+    rtt_granule: &Inner,
+    rtt_addr: usize,
+    ipa: usize,
+    level: usize,
+    protected: bool,
+) -> Result<(), MmError> {
+    if rtt_granule.refcount() != 0 {
+        return Err(MmError::MmIsInUse);
     }
+    find_table(rtt, rtt_addr, ipa, level)?;
+    parent_granule.dec_refcount()?;
+
+    let parent_s2tte = match protected {
+        true => bits_in_reg(S2TTE::INVALID_HIPAS, invalid_hipas::DESTROYED),
+        false => INVALID_UNPROTECTED,
+    };
+    rtt.set(ipa, level - 1, parent_s2tte)
+}
 
-    let mut g_rtt = get_granule_if!(rtt_addr, GranuleState::RTT)?;
+/// Replaces the table at `rtt_addr` with a single entry in its parent
+/// and moves the live entries of the table over to the parent.
+pub fn fold(rd: &Rd, rtt_addr: usize, ipa: usize, level: usize) -> Result<(), Error> {
+    let mut rtt = rd.rtt();
+    let parent = find_table(&rtt, rtt_addr, ipa, level)?;
+    let mut parent_granule = get_granule_if!(parent.table(), GranuleState::RTT)?;
+    let mut rtt_granule = get_granule_if!(rtt_addr, GranuleState::RTT)?;
+
+    let desc = fold_entry(
+        rtt_granule.content::<RttPage>().entries(),
+        level,
+        rtt.lpa2(),
+    )?;
+    parent_granule.add_refcount(rtt_granule.refcount())?;
+    parent_granule.dec_refcount()?;
+
+    // break-before-make, the table may still be in use by the walk caches
+    rtt.set(ipa, level - 1, 0)?;
+    tlb::invalidate_ipa(rd.vttbr(), ipa);
+    rtt.set(ipa, level - 1, desc)?;
+
+    set_granule(&mut rtt_granule, GranuleState::Delegated)?;
+    Ok(())
+}
 
-    let parent_s2tte;
-    if rd.addr_in_par(ipa) {
-        parent_s2tte = bits_in_reg(S2TTE::INVALID_HIPAS, invalid_hipas::DESTROYED);
-    } else {
-        parent_s2tte = INVALID_UNPROTECTED;
+/// Returns the entry which can take the place of `table` at `level - 1`.
+///
+/// All entries of the table must be in the same state. Unassigned and destroyed
+/// entries must be identical, while assigned and valid ones must map contiguous
+/// output addresses, aligned to the size of the parent entry, with the same attributes.
+/// Otherwise the table at `level` can't be folded.
+fn fold_entry(table: &[u64], level: usize, lpa2: bool) -> Result<u64, MmError> {
+    let parent_level = level.checked_sub(1).ok_or(MmError::MmInvalidLevel)?;
+    let first = S2TTE::new(*table.first().ok_or(MmError::MmInvalidAddr)?);
+    let contiguous = |stride: usize| {
+        table
+            .iter()
+            .enumerate()
+            .all(|(i, &entry)| entry == first.get() + (i * stride) as u64)
+    };
+
+    match RttEntryState::of(first, level) {
+        RttEntryState::Unassigned | RttEntryState::Destroyed => match contiguous(0) {
+            true => Ok(first.get()),
+            false => Err(MmError::MmRttLevel(level)),
+        },
+        state @ (RttEntryState::Assigned | RttEntryState::Valid) => {
+            let pa = unpack_oa(first.get(), lpa2) as usize;
+            if parent_level < RTT_MIN_BLOCK_LEVEL
+                || pa & (level_size(parent_level) - 1) != 0
+                || !contiguous(level_size(level))
+            {
+                return Err(MmError::MmRttLevel(level));
+            }
+            match state {
+                RttEntryState::Valid => Ok(first.get() & !(S2TTE::DESC_TYPE | S2TTE::CONTIG)
+                    | bits_in_reg(S2TTE::DESC_TYPE, desc_type::L012_BLOCK)),
+                _ => Ok(first.get()),
+            }
+        }
+        _ => Err(MmError::MmRttLevel(level)),
     }
-
-    get_realm(id)
-        .ok_or(Error::RmiErrorOthers(NotExistRealm))?
-        .lock()
-        .page_table
-        .lock()
-        .ipa_to_pte_set(GuestPhysAddr::from(ipa), level - 1, parent_s2tte)?;
-
-    set_granule(&mut g_rtt, GranuleState::Delegated)?;
-    Ok(())
 }
 
-pub fn init_ripas(id: usize, ipa: usize, level: usize) -> Result<(), Error> {
-    let (s2tte, last_level) = S2TTE::get_s2tte(id, ipa, level, Error::RmiErrorRtt(0))?;
+/// Sets RIPAS RAM on the unassigned entries within [base, top) of a realm in the New state,
+/// and returns the top of the range actually processed.
+pub fn init_ripas(rd: &Rd, base: usize, top: usize) -> Result<usize, Error> {
+    rd.require_state(State::New)?;
+    Ok(init_ripas_range(&mut rd.rtt(), base, top)?)
+}
 
-    if level != last_level {
-        return Err(Error::RmiErrorRtt(last_level));
+/// Processes the entries of the table reached from `base`, up to the end of the table,
+/// `top` or the first entry which isn't unassigned, whichever comes first.
+fn init_ripas_range(rtt: &mut Rtt, base: usize, top: usize) -> Result<usize, MmError> {
+    let level = rtt.walk(base, RTT_PAGE_LEVEL)?.level;
+    let size = level_size(level);
+    if base & (size - 1) != 0 {
+        return Err(MmError::MmInvalidAddr);
     }
 
-    if s2tte.is_table(level) || !s2tte.is_unassigned() {
-        return Err(Error::RmiErrorRtt(level));
+    let end = top.min((base | (table_size(level) - 1)) + 1);
+    let mut ipa = base;
+    while ipa + size <= end {
+        let walk = rtt.walk(ipa, level)?;
+        if !walk.is_unassigned() {
+            break;
+        }
+        let desc = walk.desc.get() & !S2TTE::INVALID_RIPAS
+            | bits_in_reg(S2TTE::INVALID_RIPAS, invalid_ripas::RAM);
+        rtt.set(ipa, level, desc)?;
+        ipa += size;
     }
 
-    let mut new_s2tte = s2tte.get();
-    new_s2tte |= bits_in_reg(S2TTE::INVALID_RIPAS, invalid_ripas::RAM);
-
-    get_realm(id)
-        .ok_or(Error::RmiErrorOthers(NotExistRealm))?
-        .lock()
-        .page_table
-        .lock()
-        .ipa_to_pte_set(GuestPhysAddr::from(ipa), level, new_s2tte)?;
-
-    Ok(())
+    if ipa == base {
+        return Err(MmError::MmStateError);
+    }
+    Ok(ipa)
 }
 
-pub fn get_ripas(id: usize, ipa: usize, level: usize) -> Result<u64, Error> {
-    let (s2tte, last_level) = S2TTE::get_s2tte(id, ipa, level, Error::RmiErrorRtt(0))?;
+pub fn get_ripas(rd: &Rd, ipa: usize, level: usize) -> Result<u64, Error> {
+    let (s2tte, last_level) = S2TTE::get_s2tte(rd, ipa, level, Error::RmiErrorRtt(0))?;
 
     if level != last_level {
         return Err(Error::RmiErrorRtt(last_level));
@@ -179,122 +270,184 @@ pub fn get_ripas(id: usize, ipa: usize, level: usize) -> Result<u64, Error> {
     Ok(s2tte.get_ripas())
 }
 
-pub fn read_entry(id: usize, ipa: usize, level: usize) -> Result<[usize; 4], Error> {
-    let (s2tte, last_level) = S2TTE::get_s2tte(id, ipa, level, Error::RmiErrorRtt(0))?;
-
-    let r1 = last_level;
-    let (mut r2, mut r3, mut r4) = (0, 0, 0);
-
-    if s2tte.is_unassigned() {
-        let ripas = s2tte.get_masked_value(S2TTE::INVALID_RIPAS);
-        r2 = rtt_entry_state::RMI_UNASSIGNED;
-        r4 = ripas as usize;
-    } else if s2tte.is_destroyed() {
-        r2 = rtt_entry_state::RMI_DESTROYED;
-    } else if s2tte.is_assigned() {
-        r2 = rtt_entry_state::RMI_ASSIGNED;
-        r3 = s2tte
-            .address(last_level)
-            .ok_or(Error::RmiErrorRtt(0))?
-            .into(); //XXX: check this again
-        r4 = invalid_ripas::EMPTY as usize;
-    } else if s2tte.is_valid(last_level, false) {
-        r2 = rtt_entry_state::RMI_ASSIGNED;
-        r3 = s2tte
-            .address(last_level)
-            .ok_or(Error::RmiErrorRtt(0))?
-            .into(); //XXX: check this again
-        r4 = invalid_ripas::RAM as usize;
-    } else if s2tte.is_valid(last_level, true) {
-        r2 = rtt_entry_state::RMI_VALID_NS;
-        let addr_mask = match level {
-            1 => S2TTE::ADDR_L1_PAGE,
-            2 => S2TTE::ADDR_L2_PAGE,
-            3 => S2TTE::ADDR_L3_PAGE,
-            _ => {
-                return Err(Error::RmiErrorRtt(0)); //XXX: check this again
-            }
-        };
-        let mask = addr_mask | S2TTE::MEMATTR | S2TTE::AP | S2TTE::SH;
-        r3 = (s2tte.get() & mask) as usize;
-    } else if s2tte.is_table(last_level) {
-        r2 = rtt_entry_state::RMI_TABLE;
-        r3 = s2tte
-            .address(RTT_PAGE_LEVEL)
-            .ok_or(Error::RmiErrorRtt(0))?
-            .into(); //XXX: check this again
-    } else {
-        error!("Unexpected S2TTE value retrieved!");
-    }
-    Ok([r1, r2, r3, r4])
+pub fn read_entry(rd: &Rd, ipa: usize, level: usize) -> Result<[usize; 4], Error> {
+    let walk = rd.rtt().walk(ipa, level)?;
+    Ok(entry_info(&walk))
 }
 
-pub fn map_unprotected(rd: &Rd, ipa: usize, level: usize, host_s2tte: usize) -> Result<(), Error> {
-    if rd.addr_in_par(ipa) {
-        return Err(Error::RmiErrorInput);
-    }
+/// Describes the entry found by the walk with the level, state,
+/// output address and ripas as returned by RMI_RTT_READ_ENTRY.
+fn entry_info(walk: &RttWalk) -> [usize; 4] {
+    let s2tte = walk.desc;
+    let (state, addr, ripas) = match walk.state() {
+        RttEntryState::Unassigned => (
+            rtt_entry_state::RMI_UNASSIGNED,
+            0,
+            s2tte.get_masked_value(S2TTE::INVALID_RIPAS),
+        ),
+        RttEntryState::Destroyed => (rtt_entry_state::RMI_DESTROYED, 0, 0),
+        RttEntryState::Assigned => (
+            rtt_entry_state::RMI_ASSIGNED,
+            walk.output_address(),
+            invalid_ripas::EMPTY,
+        ),
+        RttEntryState::Valid => (
+            rtt_entry_state::RMI_ASSIGNED,
+            walk.output_address(),
+            invalid_ripas::RAM,
+        ),
+        RttEntryState::ValidNs => {
+            // the output address has OA[51:50] in place of SH with LPA2
+            let attrs = match walk.lpa2() {
+                true => S2TTE::MEMATTR | S2TTE::AP,
+                false => S2TTE::MEMATTR | S2TTE::AP | S2TTE::SH,
+            };
+            (
+                rtt_entry_state::RMI_VALID_NS,
+                walk.output_address() | (s2tte.get() & attrs) as usize,
+                0,
+            )
+        }
+        RttEntryState::Table => (rtt_entry_state::RMI_TABLE, walk.output_address(), 0),
+        RttEntryState::Unknown => {
+            error!("Unexpected S2TTE value retrieved!");
+            (0, 0, 0)
+        }
+    };
+    [walk.level, state, addr, ripas as usize]
+}
 
-    let id = rd.id();
-    let (s2tte, last_level) = S2TTE::get_s2tte(id, ipa, level, Error::RmiErrorRtt(0))?;
+/// Access made by RMM to a buffer in the realm memory
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+}
 
-    if level != last_level {
-        return Err(Error::RmiErrorRtt(last_level));
+/// Returns the physical address backing `ipa` if it is mapped to the realm
+/// as protected memory, which also allows the realm the `access`.
+fn accessible_pa(rtt: &Rtt, ipa: usize, access: Access) -> Result<usize, MmError> {
+    let walk = rtt.walk(ipa, RTT_PAGE_LEVEL)?;
+    if walk.state() != RttEntryState::Valid {
+        return Err(MmError::MmNoEntry);
     }
-
-    if !s2tte.is_unassigned() {
-        return Err(Error::RmiErrorRtt(level));
+    let ap = walk.desc.get_masked_value(S2TTE::AP);
+    if ap & permission::RO == 0 || (access == Access::Write && ap & permission::WO == 0) {
+        return Err(MmError::MmPermissionFault);
     }
+    Ok(walk.output_address() | (ipa & (level_size(walk.level) - 1)))
+}
 
-    let mut new_s2tte = host_s2tte as u64;
-    if level == RTT_PAGE_LEVEL {
-        new_s2tte |= bits_in_reg(S2TTE::NS, 1)
-            | bits_in_reg(S2TTE::XN, 1)
-            | bits_in_reg(S2TTE::AF, 1)
-            | bits_in_reg(S2TTE::DESC_TYPE, desc_type::L3_PAGE);
-    } else {
-        new_s2tte |= bits_in_reg(S2TTE::NS, 1)
-            | bits_in_reg(S2TTE::XN, 1)
-            | bits_in_reg(S2TTE::AF, 1)
-            | bits_in_reg(S2TTE::DESC_TYPE, desc_type::L012_BLOCK);
+/// Checks that the realm buffer [ipa, ipa + len) is mapped to protected RAM
+/// which allows `access` and is physically contiguous, and returns the PA of `ipa`.
+/// Every access to realm memory by IPA must go through it.
+pub fn check_buffer(rtt: &Rtt, ipa: usize, len: usize, access: Access) -> Result<usize, MmError> {
+    let end = ipa.checked_add(len).ok_or(MmError::MmInvalidAddr)?;
+    if len == 0 {
+        return Err(MmError::MmInvalidAddr);
     }
 
-    get_realm(id)
-        .ok_or(Error::RmiErrorOthers(NotExistRealm))?
-        .lock()
-        .page_table
-        .lock()
-        .ipa_to_pte_set(GuestPhysAddr::from(ipa), level, new_s2tte)?;
-
-    Ok(())
+    let pa = accessible_pa(rtt, ipa, access)?;
+    let mut page = (ipa | (GRANULE_SIZE - 1)) + 1;
+    while page < end {
+        if accessible_pa(rtt, page, access)? != pa + (page - ipa) {
+            return Err(MmError::MmInvalidAddr);
+        }
+        page += GRANULE_SIZE;
+    }
+    Ok(pa)
 }
 
-pub fn unmap_unprotected(id: usize, ipa: usize, level: usize) -> Result<(), Error> {
-    let (s2tte, last_level) = S2TTE::get_s2tte(id, ipa, level, Error::RmiErrorRtt(0))?;
-
-    if level != last_level {
-        return Err(Error::RmiErrorRtt(last_level));
+/// Ripas of a protected entry, which is `None` for entries without one
+fn entry_ripas(walk: &RttWalk) -> Option<u64> {
+    match walk.state() {
+        RttEntryState::Valid => Some(invalid_ripas::RAM),
+        RttEntryState::Unassigned | RttEntryState::Assigned => Some(walk.desc.get_ripas()),
+        _ => None,
     }
+}
 
-    if !s2tte.is_valid(level, true) {
-        return Err(Error::RmiErrorRtt(level));
+/// Returns the ripas of `base` and the address where the ripas
+/// first differs within [base, top), which is `top` if it doesn't change.
+pub fn ripas_range(rtt: &Rtt, base: usize, top: usize) -> Result<(u64, usize), MmError> {
+    let ripas = entry_ripas(&rtt.walk(base, RTT_PAGE_LEVEL)?).ok_or(MmError::MmStateError)?;
+
+    let mut addr = base;
+    while addr < top {
+        let walk = rtt.walk(addr, RTT_PAGE_LEVEL)?;
+        if entry_ripas(&walk) != Some(ripas) {
+            break;
+        }
+        addr = (addr | (level_size(walk.level) - 1)) + 1;
     }
+    Ok((ripas, addr.min(top)))
+}
 
-    let new_s2tte: u64 = INVALID_UNPROTECTED;
+pub fn map_unprotected(rd: &Rd, ipa: usize, level: usize, host_s2tte: usize) -> Result<(), Error> {
+    let mut rtt = rd.rtt();
+    create_unprotected_entry(&mut rtt, ipa, level, host_s2tte as u64, rd.ipa_bits())?;
+    Ok(())
+}
 
-    get_realm(id)
-        .ok_or(Error::RmiErrorOthers(NotExistRealm))?
-        .lock()
-        .page_table
-        .lock()
-        .ipa_to_pte_set(GuestPhysAddr::from(ipa), level, new_s2tte)?;
+/// Maps the NS physical address held in `host_s2tte` to the unprotected `ipa`.
+/// `host_s2tte` carries the output address and the attributes chosen by the host.
+fn create_unprotected_entry(
+    rtt: &mut Rtt,
+    ipa: usize,
+    level: usize,
+    host_s2tte: u64,
+    ipa_bits: usize,
+) -> Result<(), MmError> {
+    if is_protected_ipa(ipa, ipa_bits) {
+        return Err(MmError::MmInvalidAddr);
+    }
+    let walk = rtt.walk(ipa, level)?;
+    if walk.level != level {
+        return Err(MmError::MmRttLevel(walk.level));
+    }
+    if !walk.is_unassigned() {
+        return Err(MmError::MmRttLevel(level));
+    }
 
-    //TODO: add page/block invalidation
+    let pa = unpack_oa(host_s2tte, rtt.lpa2());
+    let prot =
+        host_s2tte & !oa_mask(rtt.lpa2()) | bits_in_reg(S2TTE::NS, 1) | bits_in_reg(S2TTE::XN, 1);
+    rtt.map(ipa, pa as usize, level, prot)
+}
 
+pub fn unmap_unprotected(rd: &Rd, ipa: usize, level: usize) -> Result<(), Error> {
+    let mut rtt = rd.rtt();
+    remove_unprotected_entry(&mut rtt, ipa, level, rd.ipa_bits())?;
+    // TLB entries of a block may have been splintered into smaller ones
+    match level {
+        RTT_PAGE_LEVEL => tlb::invalidate_ipa(rd.vttbr(), ipa),
+        _ => tlb::invalidate_vmid(rd.vttbr()),
+    }
     Ok(())
 }
 
-pub fn make_shared(id: usize, ipa: usize, level: usize) -> Result<(), Error> {
-    let (s2tte, last_level) = S2TTE::get_s2tte(id, ipa, level, Error::RmiErrorRtt(0))?;
+fn remove_unprotected_entry(
+    rtt: &mut Rtt,
+    ipa: usize,
+    level: usize,
+    ipa_bits: usize,
+) -> Result<(), MmError> {
+    if is_protected_ipa(ipa, ipa_bits) {
+        return Err(MmError::MmInvalidAddr);
+    }
+    let walk = rtt.walk(ipa, level)?;
+    if walk.level != level {
+        return Err(MmError::MmRttLevel(walk.level));
+    }
+    if walk.state() != RttEntryState::ValidNs {
+        return Err(MmError::MmRttLevel(level));
+    }
+    rtt.set(ipa, level, INVALID_UNPROTECTED)
+}
+
+pub fn make_shared(rd: &Rd, ipa: usize, level: usize) -> Result<(), Error> {
+    let mut rtt = rd.rtt();
+    let (s2tte, last_level) = S2TTE::get_s2tte(rd, ipa, level, Error::RmiErrorRtt(0))?;
 
     if level != last_level {
         return Err(Error::RmiErrorRtt(last_level)); //XXX: check this again
@@ -306,36 +459,30 @@ pub fn make_shared(id: usize, ipa: usize, level: usize) -> Result<(), Error> {
     //           (rmm-spec)    : Figure D2.1 Realm shared memory protocol flow
     if s2tte.is_valid(level, false) {
         // the case for ipa's range 0x8840_0000 - in realm-linux booting
-        let pa: usize = s2tte.address(level).ok_or(Error::RmiErrorRtt(0))?.into(); //XXX: check this again
+        let pa = rtt.walk(ipa, level)?.output_address();
         let mut flags = 0;
         flags |= bits_in_reg(S2TTE::INVALID_HIPAS, invalid_hipas::ASSIGNED);
         flags |= bits_in_reg(S2TTE::INVALID_RIPAS, invalid_ripas::EMPTY);
-        let new_s2tte = pa as u64 | flags;
-
-        get_realm(id)
-            .ok_or(Error::RmiErrorOthers(NotExistRealm))?
-            .lock()
-            .page_table
-            .lock()
-            .ipa_to_pte_set(GuestPhysAddr::from(ipa), level, new_s2tte)?;
+        let new_s2tte = pack_oa(pa as u64, rtt.lpa2()) | flags;
+
+        rtt.set(ipa, level, new_s2tte)?;
+        match level {
+            RTT_PAGE_LEVEL => tlb::invalidate_ipa(rd.vttbr(), ipa),
+            _ => tlb::invalidate_vmid(rd.vttbr()),
+        }
     } else if s2tte.is_unassigned() || s2tte.is_assigned() {
-        let pa: usize = s2tte.address(level).ok_or(Error::RmiErrorRtt(0))?.into(); //XXX: check this again
+        let pa = rtt.walk(ipa, level)?.output_address();
         let flags = bits_in_reg(S2TTE::INVALID_RIPAS, invalid_ripas::EMPTY);
-        let new_s2tte = pa as u64 | flags;
+        let new_s2tte = pack_oa(pa as u64, rtt.lpa2()) | flags;
 
-        get_realm(id)
-            .ok_or(Error::RmiErrorOthers(NotExistRealm))?
-            .lock()
-            .page_table
-            .lock()
-            .ipa_to_pte_set(GuestPhysAddr::from(ipa), level, new_s2tte)?;
+        rtt.set(ipa, level, new_s2tte)?;
     }
 
     Ok(())
 }
 
-pub fn make_exclusive(id: usize, ipa: usize, level: usize) -> Result<(), Error> {
-    let (s2tte, last_level) = S2TTE::get_s2tte(id, ipa, level, Error::RmiErrorRtt(0))?;
+pub fn make_exclusive(rd: &Rd, ipa: usize, level: usize) -> Result<(), Error> {
+    let (s2tte, last_level) = S2TTE::get_s2tte(rd, ipa, level, Error::RmiErrorRtt(0))?;
 
     if level != last_level {
         return Err(Error::RmiErrorRtt(last_level)); //XXX: check this again
@@ -347,12 +494,7 @@ pub fn make_exclusive(id: usize, ipa: usize, level: usize) -> Result<(), Error>
         let flags = bits_in_reg(S2TTE::INVALID_RIPAS, invalid_ripas::RAM);
         let new_s2tte = s2tte.get() | flags;
 
-        get_realm(id)
-            .ok_or(Error::RmiErrorOthers(NotExistRealm))?
-            .lock()
-            .page_table
-            .lock()
-            .ipa_to_pte_set(GuestPhysAddr::from(ipa), level, new_s2tte)?;
+        rd.rtt().set(ipa, level, new_s2tte)?;
     } else {
         return Err(Error::RmiErrorRtt(level)); //XXX: check this again
     }
@@ -360,80 +502,855 @@ pub fn make_exclusive(id: usize, ipa: usize, level: usize) -> Result<(), Error>
     Ok(())
 }
 
-pub fn data_create(id: usize, ipa: usize, target_pa: usize) -> Result<(), Error> {
-    let level = RTT_PAGE_LEVEL;
-    let (s2tte, last_level) = S2TTE::get_s2tte(id, ipa, level, Error::RmiErrorRtt(0))?;
+/// Maps the data granule at the protected `ipa`, which the handlers check
+/// before anything is measured or copied.
+pub fn data_create(rd: &Rd, ipa: usize, target_pa: usize) -> Result<(), Error> {
+    let mut rtt = rd.rtt();
+    let rtt_pa = rtt.walk(ipa, RTT_PAGE_LEVEL)?.table();
+    let mut rtt_granule = get_granule_if!(rtt_pa, GranuleState::RTT)?;
 
-    if level != last_level {
-        return Err(Error::RmiErrorRtt(last_level));
-    }
+    create_data_entry(&mut rtt, ipa, target_pa)?;
+    rtt_granule.inc_refcount()?;
+    Ok(())
+}
 
-    if !s2tte.is_unassigned() {
-        return Err(Error::RmiErrorRtt(RTT_PAGE_LEVEL));
+/// Maps the data granule at `target_pa` to `ipa`, which must be unassigned.
+/// The ripas of the entry decides whether the new entry is valid.
+fn create_data_entry(rtt: &mut Rtt, ipa: usize, target_pa: usize) -> Result<(), MmError> {
+    if !is_granule_aligned(ipa) || !is_granule_aligned(target_pa) {
+        return Err(MmError::MmInvalidAddr);
     }
 
-    let mut new_s2tte = target_pa as u64;
-    if s2tte.is_invalid_ripas() {
-        panic!("invalid ripas");
+    let level = RTT_PAGE_LEVEL;
+    let walk = rtt.walk(ipa, level)?;
+    // the walk ends short of the page on a missing table, and on a block
+    // covering `ipa`, which must not be aliased by a page
+    if walk.level != level {
+        return Err(MmError::MmRttLevel(walk.level));
     }
-    let ripas = s2tte.get_ripas();
-    if ripas == invalid_ripas::EMPTY {
-        new_s2tte |= bits_in_reg(S2TTE::INVALID_HIPAS, invalid_hipas::ASSIGNED);
-        new_s2tte |= bits_in_reg(S2TTE::INVALID_RIPAS, invalid_ripas::EMPTY);
-    } else if ripas == invalid_ripas::RAM {
-        // S2TTE_PAGE  : S2TTE_ATTRS | S2TTE_L3_PAGE
-        new_s2tte |= bits_in_reg(S2TTE::DESC_TYPE, desc_type::L3_PAGE);
+    if !walk.is_unassigned() {
+        return Err(MmError::MmRttLevel(level));
+    }
+
+    match walk.desc.get_ripas() {
+        invalid_ripas::EMPTY => {
+            let new_s2tte = target_pa as u64
+                | bits_in_reg(S2TTE::INVALID_HIPAS, invalid_hipas::ASSIGNED)
+                | bits_in_reg(S2TTE::INVALID_RIPAS, invalid_ripas::EMPTY);
+            rtt.set(ipa, level, new_s2tte)
+        }
         // S2TTE_ATTRS : S2TTE_MEMATTR_FWB_NORMAL_WB | S2TTE_AP_RW | S2TTE_SH_IS | S2TTE_AF
-        new_s2tte |= bits_in_reg(S2TTE::MEMATTR, attribute::NORMAL_FWB);
-        new_s2tte |= bits_in_reg(S2TTE::AP, permission::RW);
-        new_s2tte |= bits_in_reg(S2TTE::SH, shareable::INNER);
-        new_s2tte |= bits_in_reg(S2TTE::AF, 1);
-    } else {
-        panic!("Unexpected ripas: {}", ripas);
+        _ => rtt.map_prot(ipa, target_pa, level, S2Prot::data()),
     }
+}
 
-    get_realm(id)
-        .ok_or(Error::RmiErrorOthers(NotExistRealm))?
-        .lock()
-        .page_table
-        .lock()
-        .ipa_to_pte_set(GuestPhysAddr::from(ipa), level, new_s2tte)?;
+/// Unmaps the data granule at `ipa` and returns it back to the Delegated state.
+pub fn data_destroy(rd: &Rd, ipa: usize) -> Result<usize, Error> {
+    let mut rtt = rd.rtt();
+    let walk = find_data_entry(&rtt, ipa)?;
+    let pa = walk.output_address();
+
+    // the entry must point to a data granule, not to a live RTT or anything else
+    let mut data_granule = get_granule_if!(pa, GranuleState::Data)?;
+    check_granule_owner(&data_granule, rd.id())?;
+    let mut rtt_granule = get_granule_if!(walk.table(), GranuleState::RTT)?;
+
+    destroy_data_entry(
+        &mut rtt,
+        &walk,
+        ipa,
+        &mut rtt_granule,
+        &mut data_granule,
+        rd.vttbr(),
+    )?;
+    Ok(pa)
+}
 
-    Ok(())
+fn find_data_entry(rtt: &Rtt, ipa: usize) -> Result<RttWalk, MmError> {
+    let walk = rtt.walk(ipa, RTT_PAGE_LEVEL)?;
+    if walk.level != RTT_PAGE_LEVEL {
+        return Err(MmError::MmRttLevel(walk.level));
+    }
+    match walk.state() {
+        RttEntryState::Valid | RttEntryState::Assigned => Ok(walk),
+        _ => Err(MmError::MmRttLevel(RTT_PAGE_LEVEL)),
+    }
 }
 
-pub fn data_destroy(id: usize, ipa: usize) -> Result<usize, Error> {
-    let level = RTT_PAGE_LEVEL;
-    let (s2tte, last_level) = S2TTE::get_s2tte(id, ipa, level, Error::RmiErrorRtt(0))?;
+/// Replaces the entry of the data granule found by `walk` at `ipa`, and
+/// gives the granule back once the realm can no longer access it.
+fn destroy_data_entry(
+    rtt: &mut Rtt,
+    walk: &RttWalk,
+    ipa: usize,
+    rtt_granule: &mut Inner,
+    data_granule: &mut Inner,
+    vttbr: u64,
+) -> Result<(), Error> {
+    rtt_granule.dec_refcount()?;
+    rtt.set(ipa, RTT_PAGE_LEVEL, destroyed_data_entry(walk))?;
+    tlb::invalidate_ipa(vttbr, ipa);
+
+    set_granule(data_granule, GranuleState::Delegated)
+}
 
-    if last_level != level {
-        return Err(Error::RmiErrorRtt(last_level));
+/// The entry for a destroyed data granule
+/// A valid entry becomes destroyed as the realm might still access it.
+fn destroyed_data_entry(walk: &RttWalk) -> u64 {
+    match walk.state() {
+        RttEntryState::Valid => bits_in_reg(S2TTE::INVALID_HIPAS, invalid_hipas::DESTROYED),
+        _ => {
+            bits_in_reg(S2TTE::INVALID_HIPAS, invalid_hipas::UNASSIGNED)
+                | bits_in_reg(S2TTE::INVALID_RIPAS, invalid_ripas::EMPTY)
+        }
     }
+}
 
-    let valid = s2tte.is_valid(last_level, false);
-    if !valid && !s2tte.is_assigned() {
-        return Err(Error::RmiErrorRtt(RTT_PAGE_LEVEL));
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::granule::test::{recreate_granule_status_table, TEST_ADDR, TEST_ADDR2};
+    use crate::mm::rtt::test::{Table, IPA};
+    use crate::mm::tlb::test::issued;
+    use crate::mm::tlb::Invalidation;
+    use crate::realm::mm::page_table::pte::{attribute, shareable};
+    use crate::rmi::realm::rd;
+    use crate::set_state_and_get_granule;
+
+    #[test]
+    fn read_back_mapped_entry() {
+        let mut l1 = Table::new();
+        let mut l2 = Table::new();
+        let l3 = Table::new();
+        l1.link(3, &l2);
+        l2.link(2, &l3);
+
+        let mut rtt = Rtt::new(l1.addr(), 1, 1);
+        let prot = bits_in_reg(S2TTE::AP, permission::RW);
+        rtt.map(IPA, 0x8800_0000, 3, prot).unwrap();
+
+        let walk = rtt.walk(IPA, 3).unwrap();
+        assert_eq!(
+            entry_info(&walk),
+            [
+                3,
+                rtt_entry_state::RMI_ASSIGNED,
+                0x8800_0000,
+                invalid_ripas::RAM as usize
+            ]
+        );
+
+        let walk = rtt.walk(IPA, 2).unwrap();
+        assert_eq!(
+            entry_info(&walk),
+            [2, rtt_entry_state::RMI_TABLE, l3.addr(), 0]
+        );
+
+        // stops at level 2 which has no table for the ipa
+        let walk = rtt.walk(IPA + (1 << 21), 3).unwrap();
+        assert_eq!(
+            entry_info(&walk),
+            [
+                2,
+                rtt_entry_state::RMI_UNASSIGNED,
+                0,
+                invalid_ripas::EMPTY as usize
+            ]
+        );
     }
 
-    let pa = s2tte
-        .address(last_level)
-        .ok_or(Error::RmiErrorRtt(0))?
-        .into(); //XXX: check this again
+    #[test]
+    fn realm_buffer_range() {
+        let mut l1 = Table::new();
+        let mut l2 = Table::new();
+        let l3 = Table::new();
+        l1.link(3, &l2);
+        l2.link(2, &l3);
+
+        let mut rtt = Rtt::new(l1.addr(), 1, 1);
+        let rw = bits_in_reg(S2TTE::AP, permission::RW);
+        let ro = bits_in_reg(S2TTE::AP, permission::RO);
+        let page = |i: usize| IPA + i * GRANULE_SIZE;
+        rtt.map(page(0), 0x8800_0000, 3, rw).unwrap();
+        rtt.map(page(1), 0x8800_1000, 3, rw).unwrap();
+        rtt.map(page(2), 0x9000_0000, 3, rw).unwrap();
+        rtt.map(page(4), 0x8800_4000, 3, ro).unwrap();
+        let (r, w) = (Access::Read, Access::Write);
+
+        // straddles two contiguous pages
+        assert_eq!(
+            check_buffer(&rtt, page(0) + 0xf00, 0x200, w),
+            Ok(0x8800_0f00)
+        );
+        assert_eq!(
+            check_buffer(&rtt, page(0), 2 * GRANULE_SIZE, w),
+            Ok(0x8800_0000)
+        );
+        // the next page isn't contiguous in PA
+        assert_eq!(
+            check_buffer(&rtt, page(1) + 0xf00, 0x200, w),
+            Err(MmError::MmInvalidAddr)
+        );
+
+        // unmapped
+        assert_eq!(
+            check_buffer(&rtt, page(2) + 0xf00, 0x200, r),
+            Err(MmError::MmNoEntry)
+        );
+        assert_eq!(check_buffer(&rtt, page(3), 8, r), Err(MmError::MmNoEntry));
+
+        // read-only
+        assert_eq!(
+            check_buffer(&rtt, page(4), GRANULE_SIZE, r),
+            Ok(0x8800_4000)
+        );
+        assert_eq!(
+            check_buffer(&rtt, page(4), GRANULE_SIZE, w),
+            Err(MmError::MmPermissionFault)
+        );
+
+        assert_eq!(
+            check_buffer(&rtt, page(0), 0, r),
+            Err(MmError::MmInvalidAddr)
+        );
+        assert_eq!(
+            check_buffer(&rtt, page(0), usize::MAX, r),
+            Err(MmError::MmInvalidAddr)
+        );
+    }
 
-    let mut flags = 0 as u64;
-    if valid {
-        flags |= bits_in_reg(S2TTE::INVALID_HIPAS, invalid_hipas::DESTROYED);
-    } else {
-        flags |= bits_in_reg(S2TTE::INVALID_HIPAS, invalid_hipas::UNASSIGNED);
-        flags |= bits_in_reg(S2TTE::INVALID_RIPAS, invalid_ripas::EMPTY);
+    #[test]
+    fn init_ripas_partial_range() {
+        let mut l1 = Table::new();
+        let mut l2 = Table::new();
+        let l3 = Table::new();
+        l1.link(3, &l2);
+        l2.link(2, &l3);
+
+        let mut rtt = Rtt::new(l1.addr(), 1, 1);
+        let prot = bits_in_reg(S2TTE::AP, permission::RW);
+        rtt.map(IPA + 3 * GRANULE_SIZE, 0x8800_0000, 3, prot)
+            .unwrap();
+
+        // stops at the assigned entry
+        let top = IPA + 8 * GRANULE_SIZE;
+        assert_eq!(
+            init_ripas_range(&mut rtt, IPA, top),
+            Ok(IPA + 3 * GRANULE_SIZE)
+        );
+        let ripas = |rtt: &Rtt, ipa| entry_ripas(&rtt.walk(ipa, 3).unwrap());
+        assert_eq!(
+            ripas(&rtt, IPA + 2 * GRANULE_SIZE),
+            Some(invalid_ripas::RAM)
+        );
+        assert_eq!(
+            init_ripas_range(&mut rtt, IPA + 3 * GRANULE_SIZE, top),
+            Err(MmError::MmStateError)
+        );
+
+        // stops at the end of the level 3 table
+        let table_end = (IPA | 0x1f_ffff) + 1;
+        assert_eq!(
+            init_ripas_range(&mut rtt, IPA + 4 * GRANULE_SIZE, usize::MAX),
+            Ok(table_end)
+        );
+        assert_eq!(
+            ripas(&rtt, table_end - GRANULE_SIZE),
+            Some(invalid_ripas::RAM)
+        );
+
+        // level 2 entries are processed as a whole
+        assert_eq!(
+            init_ripas_range(&mut rtt, table_end, table_end + (4 << 20) + GRANULE_SIZE),
+            Ok(table_end + (4 << 20))
+        );
+        assert_eq!(
+            init_ripas_range(&mut rtt, table_end + (4 << 20), table_end + (5 << 20)),
+            Err(MmError::MmStateError)
+        );
+        assert_eq!(
+            init_ripas_range(&mut rtt, table_end + (4 << 20) + GRANULE_SIZE, usize::MAX),
+            Err(MmError::MmInvalidAddr)
+        );
     }
-    let new_s2tte = flags;
-    get_realm(id)
-        .ok_or(Error::RmiErrorOthers(NotExistRealm))?
-        .lock()
-        .page_table
-        .lock()
-        .ipa_to_pte_set(GuestPhysAddr::from(ipa), level, new_s2tte)?;
 
-    Ok(pa)
+    #[test]
+    fn init_ripas_only_new_realm() {
+        let mut l1 = Table::new();
+        let mut l2 = Table::new();
+        let l3 = Table::new();
+        l1.link(3, &l2);
+        l2.link(2, &l3);
+
+        // zeroed memory in place of the RD granule
+        let rd_page = Table::new();
+        let rd = unsafe { &mut *(rd_page.addr() as *mut Rd) };
+        rd.init(0, l1.addr(), 32, 1);
+
+        let top = IPA + GRANULE_SIZE;
+        assert!(matches!(init_ripas(rd, IPA, top), Ok(t) if t == top));
+
+        rd.activate().unwrap();
+        assert!(matches!(
+            init_ripas(rd, top, top + GRANULE_SIZE),
+            Err(Error::RmiErrorInput)
+        ));
+    }
+
+    #[test]
+    fn ripas_boundary() {
+        let mut l1 = Table::new();
+        let mut l2 = Table::new();
+        let mut l3 = Table::new();
+        l1.link(3, &l2);
+        l2.link(2, &l3);
+
+        let ram = bits_in_reg(S2TTE::INVALID_RIPAS, invalid_ripas::RAM);
+        l3.0[3] = ram;
+        l3.0[4] = ram;
+        // the rest of the level 3 table and the next level 2 block are empty
+        let rtt = Rtt::new(l1.addr(), 1, 1);
+
+        let top = IPA + 4 * GRANULE_SIZE;
+        assert_eq!(
+            ripas_range(&rtt, IPA, top),
+            Ok((invalid_ripas::RAM, IPA + 2 * GRANULE_SIZE))
+        );
+        assert_eq!(
+            ripas_range(&rtt, IPA + 2 * GRANULE_SIZE, top),
+            Ok((invalid_ripas::EMPTY, top))
+        );
+        assert_eq!(
+            ripas_range(&rtt, IPA, IPA + GRANULE_SIZE),
+            Ok((invalid_ripas::RAM, IPA + GRANULE_SIZE))
+        );
+
+        // crosses the level 2 block, which covers 2MB at once
+        let block = (IPA & !0x1f_ffff) + (1 << 21);
+        assert_eq!(
+            ripas_range(&rtt, IPA + 2 * GRANULE_SIZE, block + 0x4000),
+            Ok((invalid_ripas::EMPTY, block + 0x4000))
+        );
+
+        l3.0[3] = 0x8800_0000 | bits_in_reg(S2TTE::DESC_TYPE, desc_type::L3_PAGE);
+        assert_eq!(
+            ripas_range(&rtt, IPA, top),
+            Ok((invalid_ripas::RAM, IPA + 2 * GRANULE_SIZE))
+        );
+
+        l3.0[3] = bits_in_reg(S2TTE::INVALID_HIPAS, invalid_hipas::DESTROYED);
+        assert_eq!(ripas_range(&rtt, IPA, top), Err(MmError::MmStateError));
+    }
+
+    #[test]
+    fn link_tables() {
+        let l0 = Table::new();
+        let mut l1 = Table::new();
+        let mut l2 = Table::new();
+        let mut l3 = Table::new();
+        let mut rtt = Rtt::new(l0.addr(), 0, 1);
+
+        for (table, level) in [(&mut l1, 1), (&mut l2, 2), (&mut l3, 3)] {
+            table.0.fill(0xff);
+            let addr = table.addr();
+            assert_eq!(link_table(&mut rtt, &mut table.0, addr, IPA, level), Ok(0));
+
+            let walk = rtt.walk(IPA, level - 1).unwrap();
+            assert_eq!(walk.state(), RttEntryState::Table);
+            assert_eq!(walk.output_address(), addr);
+            assert!(rtt.walk(IPA, level).unwrap().is_unassigned());
+            assert!(table.0.iter().all(|&e| S2TTE::new(e).is_unassigned()));
+        }
+
+        // a table is already linked
+        let mut other = Table::new();
+        let addr = other.addr();
+        assert_eq!(
+            link_table(&mut rtt, &mut other.0, addr, IPA, 2),
+            Err(MmError::MmStateError)
+        );
+        assert_eq!(
+            link_table(&mut rtt, &mut other.0, addr, IPA, 0),
+            Err(MmError::MmInvalidLevel)
+        );
+        // the parent at level 2 doesn't exist, the host has to create the one at level 1 first
+        assert_eq!(
+            link_table(&mut rtt, &mut other.0, addr, IPA + (1 << 30), 3),
+            Err(MmError::MmRttLevel(1))
+        );
+        assert_eq!(rtt.walk(IPA, 2).unwrap().output_address(), l3.addr());
+    }
+
+    #[test]
+    fn destroy_table_refcount() {
+        recreate_granule_status_table();
+        let mut parent_granule =
+            set_state_and_get_granule!(TEST_ADDR, GranuleState::Delegated).unwrap();
+        set_granule(&mut parent_granule, GranuleState::RTT).unwrap();
+        let mut rtt_granule =
+            set_state_and_get_granule!(TEST_ADDR2, GranuleState::Delegated).unwrap();
+        set_granule(&mut rtt_granule, GranuleState::RTT).unwrap();
+
+        let l1 = Table::new();
+        let mut l2 = Table::new();
+        let mut rtt = Rtt::new(l1.addr(), 1, 1);
+        let addr = l2.addr();
+        link_table(&mut rtt, &mut l2.0, addr, IPA, 2).unwrap();
+        parent_granule.inc_refcount().unwrap();
+
+        // a data granule is still mapped in the table
+        rtt_granule.inc_refcount().unwrap();
+        assert_eq!(
+            destroy_table(
+                &mut rtt,
+                &mut parent_granule,
+                &rtt_granule,
+                addr,
+                IPA,
+                2,
+                true
+            ),
+            Err(MmError::MmIsInUse)
+        );
+        rtt_granule.dec_refcount().unwrap();
+
+        let other = Table::new();
+        assert_eq!(
+            destroy_table(
+                &mut rtt,
+                &mut parent_granule,
+                &rtt_granule,
+                other.addr(),
+                IPA,
+                2,
+                true
+            ),
+            Err(MmError::MmInvalidAddr)
+        );
+        assert_eq!(parent_granule.refcount(), 1);
+
+        destroy_table(
+            &mut rtt,
+            &mut parent_granule,
+            &rtt_granule,
+            addr,
+            IPA,
+            2,
+            true,
+        )
+        .unwrap();
+        assert_eq!(parent_granule.refcount(), 0);
+        assert_eq!(rtt.walk(IPA, 2).unwrap().state(), RttEntryState::Destroyed);
+        assert_eq!(
+            destroy_table(
+                &mut rtt,
+                &mut parent_granule,
+                &rtt_granule,
+                addr,
+                IPA,
+                2,
+                true
+            ),
+            Err(MmError::MmRttLevel(1))
+        );
+
+        // nor is there a table at level 2 to find one at level 3 from
+        assert_eq!(
+            find_table(&rtt, addr, IPA, 3).map(|_| ()),
+            Err(MmError::MmRttLevel(1))
+        );
+
+        // the parent lost track of the table
+        link_table(&mut rtt, &mut l2.0, addr, IPA, 2).unwrap();
+        assert_eq!(
+            destroy_table(
+                &mut rtt,
+                &mut parent_granule,
+                &rtt_granule,
+                addr,
+                IPA,
+                2,
+                false
+            ),
+            Err(MmError::MmRefcountError)
+        );
+
+        set_granule(&mut rtt_granule, GranuleState::Delegated).unwrap();
+        set_granule(&mut parent_granule, GranuleState::Delegated).unwrap();
+    }
+
+    #[test]
+    fn fold_homogeneous_table() {
+        let mut table = Table::new();
+
+        let unassigned_ram = bits_in_reg(S2TTE::INVALID_HIPAS, invalid_hipas::UNASSIGNED)
+            | bits_in_reg(S2TTE::INVALID_RIPAS, invalid_ripas::RAM);
+        table.0.fill(unassigned_ram);
+        assert_eq!(fold_entry(&table.0, 3, false), Ok(unassigned_ram));
+        table.0[7] = bits_in_reg(S2TTE::INVALID_HIPAS, invalid_hipas::UNASSIGNED);
+        assert_eq!(fold_entry(&table.0, 3, false), Err(MmError::MmRttLevel(3)));
+
+        let assigned = bits_in_reg(S2TTE::INVALID_HIPAS, invalid_hipas::ASSIGNED);
+        for (i, entry) in table.0.iter_mut().enumerate() {
+            *entry = (0x8820_0000 + i * GRANULE_SIZE) as u64 | assigned;
+        }
+        assert_eq!(fold_entry(&table.0, 3, false), Ok(0x8820_0000 | assigned));
+        // the output addresses aren't contiguous
+        table.0[5] += GRANULE_SIZE as u64;
+        assert_eq!(fold_entry(&table.0, 3, false), Err(MmError::MmRttLevel(3)));
+        // nor aligned to the size of a level 2 block
+        for (i, entry) in table.0.iter_mut().enumerate() {
+            *entry = (0x8820_1000 + i * GRANULE_SIZE) as u64 | assigned;
+        }
+        assert_eq!(fold_entry(&table.0, 3, false), Err(MmError::MmRttLevel(3)));
+        // level 1 blocks aren't supported
+        for (i, entry) in table.0.iter_mut().enumerate() {
+            *entry = (0x8000_0000 + i * level_size(2)) as u64 | assigned;
+        }
+        assert_eq!(fold_entry(&table.0, 2, false), Err(MmError::MmRttLevel(2)));
+
+        let prot = bits_in_reg(S2TTE::MEMATTR, attribute::NORMAL_FWB)
+            | bits_in_reg(S2TTE::AP, permission::RW)
+            | bits_in_reg(S2TTE::SH, shareable::INNER)
+            | bits_in_reg(S2TTE::AF, 1);
+        for (i, entry) in table.0.iter_mut().enumerate() {
+            *entry = (0x8820_0000 + i * GRANULE_SIZE) as u64
+                | prot
+                | bits_in_reg(S2TTE::DESC_TYPE, desc_type::L3_PAGE);
+        }
+        let block = fold_entry(&table.0, 3, false).unwrap();
+        assert_eq!(
+            block,
+            0x8820_0000 | prot | bits_in_reg(S2TTE::DESC_TYPE, desc_type::L012_BLOCK)
+        );
+
+        // creating the table again brings back the same pages
+        let mut l1 = Table::new();
+        let mut l2 = Table::new();
+        l1.link(3, &l2);
+        l2.0[2] = block;
+        let mut rtt = Rtt::new(l1.addr(), 1, 1);
+        let mut unfolded = Table::new();
+        let addr = unfolded.addr();
+        assert_eq!(
+            link_table(&mut rtt, &mut unfolded.0, addr, IPA, 3),
+            Ok(unfolded.0.len())
+        );
+        assert_eq!(unfolded.0, table.0);
+
+        // the attributes differ
+        table.0[9] |= bits_in_reg(S2TTE::XN, 1);
+        assert_eq!(fold_entry(&table.0, 3, false), Err(MmError::MmRttLevel(3)));
+    }
+
+    #[test]
+    fn unprotected_mapping() {
+        const IPA_BITS: usize = 33;
+        let unprotected = IPA | 1 << (IPA_BITS - 1);
+
+        let mut l1 = Table::new();
+        let mut l2 = Table::new();
+        let l3 = Table::new();
+        l1.link(unprotected >> 30, &l2);
+        l2.link(2, &l3);
+        let mut rtt = Rtt::new(l1.addr(), 1, 1);
+
+        let host_s2tte = 0x9000_0000
+            | bits_in_reg(S2TTE::MEMATTR, attribute::NORMAL_FWB)
+            | bits_in_reg(S2TTE::AP, permission::RW);
+        // the protected half of the IPA space
+        assert_eq!(
+            create_unprotected_entry(&mut rtt, IPA, 3, host_s2tte, IPA_BITS),
+            Err(MmError::MmInvalidAddr)
+        );
+        assert_eq!(
+            remove_unprotected_entry(&mut rtt, IPA, 3, IPA_BITS),
+            Err(MmError::MmInvalidAddr)
+        );
+
+        create_unprotected_entry(&mut rtt, unprotected, 3, host_s2tte, IPA_BITS).unwrap();
+        let walk = rtt.walk(unprotected, 3).unwrap();
+        assert_eq!(walk.state(), RttEntryState::ValidNs);
+        assert_eq!(walk.desc.get_masked_value(S2TTE::NS), 1);
+        assert_eq!(walk.desc.get_masked_value(S2TTE::XN), 1);
+        assert_eq!(walk.output_address(), 0x9000_0000);
+        assert_eq!(
+            create_unprotected_entry(&mut rtt, unprotected, 3, host_s2tte, IPA_BITS),
+            Err(MmError::MmRttLevel(3))
+        );
+        // without a table at level 3
+        let missing = unprotected + level_size(2);
+        assert_eq!(
+            create_unprotected_entry(&mut rtt, missing, 3, host_s2tte, IPA_BITS),
+            Err(MmError::MmRttLevel(2))
+        );
+        assert_eq!(
+            remove_unprotected_entry(&mut rtt, missing, 3, IPA_BITS),
+            Err(MmError::MmRttLevel(2))
+        );
+
+        remove_unprotected_entry(&mut rtt, unprotected, 3, IPA_BITS).unwrap();
+        assert!(rtt.walk(unprotected, 3).unwrap().is_unassigned());
+        assert_eq!(
+            remove_unprotected_entry(&mut rtt, unprotected, 3, IPA_BITS),
+            Err(MmError::MmRttLevel(3))
+        );
+
+        // OA[51:50] is read back in place of SH with LPA2
+        let mut rtt = rtt.with_lpa2(true);
+        let host_s2tte52 = pack_oa(0xc_0000_9000_0000, true) | host_s2tte;
+        create_unprotected_entry(&mut rtt, unprotected, 3, host_s2tte52, IPA_BITS).unwrap();
+        let walk = rtt.walk(unprotected, 3).unwrap();
+        assert_eq!(walk.output_address(), 0xc_0000_9000_0000);
+        assert_eq!(
+            entry_info(&walk),
+            [
+                3,
+                rtt_entry_state::RMI_VALID_NS,
+                0xc_0000_9000_0000 | (host_s2tte & !0x9000_0000) as usize,
+                0
+            ]
+        );
+    }
+
+    #[test]
+    fn data_create_overlap() {
+        let mut l1 = Table::new();
+        let mut l2 = Table::new();
+        let mut l3 = Table::new();
+        l1.link(3, &l2);
+        l2.link(2, &l3);
+        let mut rtt = Rtt::new(l1.addr(), 1, 1);
+
+        // the same ipa, which is assigned by the first one
+        create_data_entry(&mut rtt, IPA, 0x8800_0000).unwrap();
+        assert!(rtt.walk(IPA, 3).unwrap().is_assigned());
+        assert_eq!(
+            create_data_entry(&mut rtt, IPA, 0x8800_1000),
+            Err(MmError::MmRttLevel(3))
+        );
+
+        // or valid with the ripas RAM
+        let next = IPA + GRANULE_SIZE;
+        l3.0[4] = bits_in_reg(S2TTE::INVALID_RIPAS, invalid_ripas::RAM);
+        create_data_entry(&mut rtt, next, 0x8800_2000).unwrap();
+        assert_eq!(rtt.walk(next, 3).unwrap().state(), RttEntryState::Valid);
+        assert_eq!(
+            create_data_entry(&mut rtt, next, 0x8800_3000),
+            Err(MmError::MmRttLevel(3))
+        );
+        assert_eq!(rtt.walk(next, 3).unwrap().output_address(), 0x8800_2000);
+
+        // a valid block at level 2 covers the ipa
+        let block = (IPA & !0x1f_ffff) + (1 << 21);
+        let prot = bits_in_reg(S2TTE::AP, permission::RW);
+        rtt.map(block, 0x8820_0000, 2, prot).unwrap();
+        assert_eq!(
+            create_data_entry(&mut rtt, block + 3 * GRANULE_SIZE, 0x8800_4000),
+            Err(MmError::MmRttLevel(2))
+        );
+
+        // so does an assigned one
+        let block = block + (1 << 21);
+        rtt.set(
+            block,
+            2,
+            0x8840_0000 | bits_in_reg(S2TTE::INVALID_HIPAS, invalid_hipas::ASSIGNED),
+        )
+        .unwrap();
+        assert!(rtt.walk(block, 3).unwrap().is_assigned());
+        assert_eq!(
+            create_data_entry(&mut rtt, block, 0x8800_4000),
+            Err(MmError::MmRttLevel(2))
+        );
+
+        // without a table at level 3 for the ipa
+        let unmapped = block + (1 << 21);
+        assert_eq!(
+            create_data_entry(&mut rtt, unmapped, 0x8800_4000),
+            Err(MmError::MmRttLevel(2))
+        );
+    }
+
+    #[test]
+    fn data_create() {
+        let mut l1 = Table::new();
+        let mut l2 = Table::new();
+        let mut l3 = Table::new();
+        l1.link(3, &l2);
+        l2.link(2, &l3);
+        let mut rtt = Rtt::new(l1.addr(), 1, 1);
+
+        // the ripas EMPTY keeps the new entry invalid
+        create_data_entry(&mut rtt, IPA, 0x8800_0000).unwrap();
+        let walk = rtt.walk(IPA, 3).unwrap();
+        assert_eq!(walk.state(), RttEntryState::Assigned);
+        assert_eq!(walk.desc.get_ripas(), invalid_ripas::EMPTY);
+        assert_eq!(walk.output_address(), 0x8800_0000);
+
+        // the ripas RAM makes it a valid data mapping
+        let next = IPA + GRANULE_SIZE;
+        l3.0[4] = bits_in_reg(S2TTE::INVALID_RIPAS, invalid_ripas::RAM);
+        create_data_entry(&mut rtt, next, 0x8800_1000).unwrap();
+        let walk = rtt.walk(next, 3).unwrap();
+        assert_eq!(walk.state(), RttEntryState::Valid);
+        assert_eq!(walk.output_address(), 0x8800_1000);
+        let attrs = bits_in_reg(S2TTE::AP, permission::RW) | bits_in_reg(S2TTE::AF, 1);
+        assert_eq!(walk.desc.get() & attrs, attrs);
+    }
+
+    #[test]
+    fn data_create_unaligned() {
+        let mut l1 = Table::new();
+        let mut l2 = Table::new();
+        let l3 = Table::new();
+        l1.link(3, &l2);
+        l2.link(2, &l3);
+        let mut rtt = Rtt::new(l1.addr(), 1, 1);
+
+        assert_eq!(
+            create_data_entry(&mut rtt, IPA + 0x800, 0x8800_0000),
+            Err(MmError::MmInvalidAddr)
+        );
+        assert_eq!(
+            create_data_entry(&mut rtt, IPA, 0x8800_0800),
+            Err(MmError::MmInvalidAddr)
+        );
+        // neither leaves anything behind
+        assert!(rtt.walk(IPA, 3).unwrap().is_unassigned());
+    }
+
+    #[test]
+    fn data_destroy_entry() {
+        const VMID: u16 = 0x5d;
+        const VTTBR: u64 = (VMID as u64) << 48;
+        const RTT_ADDR: usize = 0x880c_4000;
+        const DATA_ADDR: usize = 0x880c_5000;
+
+        recreate_granule_status_table();
+        let mut rtt_granule =
+            set_state_and_get_granule!(RTT_ADDR, GranuleState::Delegated).unwrap();
+        set_granule(&mut rtt_granule, GranuleState::RTT).unwrap();
+        let mut data_granule =
+            set_state_and_get_granule!(DATA_ADDR, GranuleState::Delegated).unwrap();
+
+        let mut l1 = Table::new();
+        let mut l2 = Table::new();
+        let mut l3 = Table::new();
+        l1.link(3, &l2);
+        l2.link(2, &l3);
+        let mut rtt = Rtt::new(l1.addr(), 1, 1);
+
+        // an assigned entry with the ripas EMPTY becomes unassigned again,
+        // while a valid one becomes destroyed as the realm may have accessed it
+        let next = IPA + GRANULE_SIZE;
+        l3.0[4] = bits_in_reg(S2TTE::INVALID_RIPAS, invalid_ripas::RAM);
+        for (ipa, state) in [
+            (IPA, RttEntryState::Unassigned),
+            (next, RttEntryState::Destroyed),
+        ] {
+            set_granule(&mut data_granule, GranuleState::Data).unwrap();
+            create_data_entry(&mut rtt, ipa, DATA_ADDR).unwrap();
+            rtt_granule.inc_refcount().unwrap();
+
+            let walk = find_data_entry(&rtt, ipa).unwrap();
+            assert_eq!(walk.output_address(), DATA_ADDR);
+            destroy_data_entry(
+                &mut rtt,
+                &walk,
+                ipa,
+                &mut rtt_granule,
+                &mut data_granule,
+                VTTBR,
+            )
+            .unwrap();
+
+            assert_eq!(rtt.walk(ipa, 3).unwrap().state(), state);
+            assert_eq!(rtt_granule.refcount(), 0);
+            assert_eq!(data_granule.state(), GranuleState::Delegated);
+            assert_eq!(issued(VMID), [Invalidation::Ipa { vttbr: VTTBR, ipa }]);
+            // nothing is left to destroy
+            assert_eq!(
+                find_data_entry(&rtt, ipa).map(|_| ()),
+                Err(MmError::MmRttLevel(3))
+            );
+        }
+        assert_eq!(
+            rtt.walk(IPA, 3).unwrap().desc.get_ripas(),
+            invalid_ripas::EMPTY
+        );
+        // nor is there a table at level 3
+        assert_eq!(
+            find_data_entry(&rtt, IPA + level_size(2)).map(|_| ()),
+            Err(MmError::MmRttLevel(2))
+        );
+
+        set_granule(&mut rtt_granule, GranuleState::Delegated).unwrap();
+    }
+
+    #[test]
+    fn invalidation_on_unmap() {
+        const VMID: u16 = 0x5e;
+        const IPA_BITS: usize = 33;
+        let unprotected = IPA | 1 << (IPA_BITS - 1);
+        let unprotected_block = unprotected & !(level_size(2) - 1) | 3 << 21;
+
+        let mut l1 = Table::new();
+        let mut l2 = Table::new();
+        let l3 = Table::new();
+        let mut l2_ns = Table::new();
+        let l3_ns = Table::new();
+        l1.link(IPA >> 30, &l2);
+        l2.link(2, &l3);
+        l1.link(unprotected >> 30, &l2_ns);
+        l2_ns.link(2, &l3_ns);
+
+        let mut rd = rd::test::rd();
+        rd.init(1, l1.addr(), IPA_BITS, 1);
+        rd.set_vmid(VMID);
+        assert_eq!(rd.vttbr(), (VMID as u64) << 48 | l1.addr() as u64);
+
+        let host_s2tte = bits_in_reg(S2TTE::MEMATTR, attribute::NORMAL_FWB)
+            | bits_in_reg(S2TTE::AP, permission::RW);
+        map_unprotected(&rd, unprotected, 3, (0x9000_0000 | host_s2tte) as usize).unwrap();
+        map_unprotected(
+            &rd,
+            unprotected_block,
+            2,
+            (0x9020_0000 | host_s2tte) as usize,
+        )
+        .unwrap();
+        assert!(issued(VMID).is_empty());
+
+        unmap_unprotected(&rd, unprotected, 3).unwrap();
+        assert_eq!(
+            issued(VMID),
+            [Invalidation::Ipa {
+                vttbr: rd.vttbr(),
+                ipa: unprotected
+            }]
+        );
+        // a block may have been cached as smaller entries
+        unmap_unprotected(&rd, unprotected_block, 2).unwrap();
+        assert_eq!(issued(VMID), [Invalidation::Vmid(rd.vttbr())]);
+
+        // a valid protected page becomes assigned with RIPAS EMPTY
+        let prot = bits_in_reg(S2TTE::AP, permission::RW);
+        rd.rtt().map(IPA, 0x8800_0000, 3, prot).unwrap();
+        make_shared(&rd, IPA, 3).unwrap();
+        assert_eq!(
+            rd.rtt().walk(IPA, 3).unwrap().state(),
+            RttEntryState::Assigned
+        );
+        assert_eq!(
+            issued(VMID),
+            [Invalidation::Ipa {
+                vttbr: rd.vttbr(),
+                ipa: IPA
+            }]
+        );
+        // nothing was mapped that could have been cached
+        make_exclusive(&rd, IPA, 3).unwrap();
+        assert!(issued(VMID).is_empty());
+    }
 }